@@ -0,0 +1,182 @@
+use crate::{
+    chess::{
+        Color,
+        GameResult,
+    },
+    position::Position,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    WhiteWin,
+    BlackWin,
+    Draw,
+}
+
+// When to call a game before it's over on the board. Datagen and engine matches both go
+// through these, so a game gets the same result whichever played it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AdjudicationRules {
+    // a win once both sides agree on a score past `resign_score` centipawns (or a mate score)
+    // for `resign_moves` moves each in a row, `None` plays it out
+    pub resign_moves: Option<u32>,
+    pub resign_score: i16,
+    // games still going after this many plies are draws
+    pub max_plies: Option<u32>,
+    // a position seen twice before is a draw, as an arbiter would call the threefold
+    pub repetition: bool,
+}
+
+impl Default for AdjudicationRules {
+    fn default() -> Self {
+        AdjudicationRules {
+            resign_moves: Some(4),
+            resign_score: 480,
+            max_plies: None,
+            repetition: true,
+        }
+    }
+}
+
+// Tracks a game against the rules. Scores are fed in as the engines report them, in
+// centipawns from the side to move's point of view.
+#[derive(Debug, Clone)]
+pub struct Adjudicator {
+    rules: AdjudicationRules,
+    // who the last run of scores agrees is winning, and for how many plies
+    winner: Option<Outcome>,
+    plies: u32,
+}
+
+impl Adjudicator {
+    pub fn new(rules: AdjudicationRules) -> Self {
+        Adjudicator {
+            rules,
+            winner: None,
+            plies: 0,
+        }
+    }
+
+    // The result if the game is over by the rules of chess or by the ply limit, before the side
+    // to move plays. `plies` counts the plies played so far.
+    pub fn position_result(&self, pos: &Position, plies: u32) -> Option<Outcome> {
+        match pos.game_result() {
+            Some(GameResult::Loss) => {
+                return Some(match pos.side {
+                    Color::White => Outcome::BlackWin,
+                    Color::Black => Outcome::WhiteWin,
+                })
+            }
+            Some(_) => return Some(Outcome::Draw),
+            None => {}
+        }
+
+        if self.rules.repetition && pos.is_repetition(2) {
+            return Some(Outcome::Draw);
+        }
+        if self.rules.max_plies.is_some_and(|max| plies >= max) {
+            return Some(Outcome::Draw);
+        }
+        None
+    }
+
+    // Takes the score the side to move searched its move with. Both sides search every other
+    // ply, so after 2 * resign_moves plies in a row each side has confirmed the winner
+    // resign_moves times.
+    pub fn update(&mut self, side: Color, score: i16) -> Option<Outcome> {
+        let resign_moves = self.rules.resign_moves?;
+        let white_score = match side {
+            Color::White => score,
+            Color::Black => -score,
+        };
+        let winner = match white_score {
+            s if s >= self.rules.resign_score => Some(Outcome::WhiteWin),
+            s if s <= -self.rules.resign_score => Some(Outcome::BlackWin),
+            _ => None,
+        };
+
+        if winner.is_some() && winner == self.winner {
+            self.plies += 1;
+        } else {
+            self.winner = winner;
+            self.plies = winner.map_or(0, |_| 1);
+        }
+
+        if self.plies >= 2 * resign_moves {
+            self.winner
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        eval,
+        fen::Fen,
+        movegen::init_tables,
+        zobrist::init_zobrist,
+    };
+
+    #[test]
+    fn test_resign() {
+        let rules = AdjudicationRules::default();
+        let resign_moves = rules.resign_moves.unwrap();
+        let mut adjudicator = Adjudicator::new(rules);
+        let sides = [Color::White, Color::Black];
+        // white is winning, both sides agree for all but the last ply
+        for ply in 0..2 * resign_moves - 1 {
+            let side = sides[ply as usize % 2];
+            let score = if side == Color::White { 600 } else { -550 };
+            assert_eq!(adjudicator.update(side, score), None);
+        }
+        assert_eq!(adjudicator.update(Color::Black, -400), None);
+        assert_eq!(adjudicator.plies, 0);
+
+        // a single mate score isn't enough on its own
+        assert_eq!(adjudicator.update(Color::White, -eval::MATE + 10), None);
+        for ply in 1..2 * resign_moves - 1 {
+            let side = sides[ply as usize % 2];
+            let score = if side == Color::White { -1_000 } else { 1_000 };
+            assert_eq!(adjudicator.update(side, score), None);
+        }
+        assert_eq!(adjudicator.update(Color::Black, 700), Some(Outcome::BlackWin));
+
+        let mut adjudicator = Adjudicator::new(AdjudicationRules {
+            resign_moves: None,
+            ..rules
+        });
+        for _ in 0..20 {
+            assert_eq!(adjudicator.update(Color::White, eval::MATE - 1), None);
+        }
+    }
+
+    #[test]
+    fn test_position_result() {
+        init_tables();
+        init_zobrist();
+
+        let adjudicator = Adjudicator::new(AdjudicationRules {
+            max_plies: Some(100),
+            ..Default::default()
+        });
+        let result = |fen: &str, plies| {
+            let Fen(pos) = fen.parse().unwrap();
+            adjudicator.position_result(&pos, plies)
+        };
+        let startpos = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1";
+        assert_eq!(result(startpos, 0), None);
+        assert_eq!(result(startpos, 100), Some(Outcome::Draw));
+        assert_eq!(result("7k/6Q1/6K1/8/8/8/8/8 b - - 0 1", 0), Some(Outcome::WhiteWin));
+        assert_eq!(result("7k/5Q2/6K1/8/8/8/8/8 b - - 0 1", 0), Some(Outcome::Draw));
+
+        let Fen(mut pos) = startpos.parse().unwrap();
+        for mv in ["g1f3", "g8f6", "f3g1", "f6g8", "g1f3", "g8f6", "f3g1", "f6g8"] {
+            assert_eq!(adjudicator.position_result(&pos, 0), None);
+            pos.make_move(pos.legal_move(mv.parse().unwrap()).unwrap());
+        }
+        assert_eq!(adjudicator.position_result(&pos, 0), Some(Outcome::Draw));
+    }
+}
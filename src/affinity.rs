@@ -0,0 +1,160 @@
+use std::fmt::{self, Display, Formatter};
+
+// The cpus of every NUMA node on the machine. Threads are handed out node by node so a
+// search that fits on one node keeps all of its tt traffic local, and bigger pools spill
+// over onto the next node instead of being shuffled around by the scheduler.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Topology {
+    nodes: Vec<Vec<usize>>,
+}
+
+impl Topology {
+    // Only the cpus the process may run on, taskset or a cgroup can leave out whole nodes
+    pub fn detect() -> Topology {
+        let allowed = allowed_cpus();
+        let nodes = read_numa_nodes().unwrap_or_default();
+        let topology = Topology::restrict(nodes, allowed.as_deref());
+        if topology.cpus() > 0 {
+            return topology;
+        }
+
+        let cpus = std::thread::available_parallelism().map_or(1, |n| n.get());
+        let cpus = allowed.unwrap_or_else(|| (0..cpus).collect());
+        Topology::restrict(vec![cpus], None)
+    }
+
+    // Drops the cpus that aren't allowed or can't be pinned to, and the nodes left empty
+    fn restrict(nodes: Vec<Vec<usize>>, allowed: Option<&[usize]>) -> Topology {
+        let nodes = nodes
+            .into_iter()
+            .map(|cpus| {
+                cpus.into_iter()
+                    .filter(|&cpu| cpu < MAX_CPUS && allowed.is_none_or(|a| a.contains(&cpu)))
+                    .collect::<Vec<_>>()
+            })
+            .filter(|cpus| !cpus.is_empty())
+            .collect();
+        Topology { nodes }
+    }
+
+    pub fn cpus(&self) -> usize {
+        self.nodes.iter().map(Vec::len).sum()
+    }
+
+    pub fn cpu_for(&self, thread: usize) -> usize {
+        let index = thread % self.cpus();
+        self.nodes.iter().flatten().nth(index).copied().unwrap()
+    }
+}
+
+// Beyond what a cpu_set_t holds
+#[cfg(target_os = "linux")]
+const MAX_CPUS: usize = libc::CPU_SETSIZE as usize;
+#[cfg(not(target_os = "linux"))]
+const MAX_CPUS: usize = 1024;
+
+impl Display for Topology {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{} numa nodes, {} cpus", self.nodes.len(), self.cpus())
+    }
+}
+
+fn read_numa_nodes() -> Option<Vec<Vec<usize>>> {
+    let mut nodes = std::fs::read_dir("/sys/devices/system/node")
+        .ok()?
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let name = entry.file_name().into_string().ok()?;
+            let id = name.strip_prefix("node")?.parse::<usize>().ok()?;
+            let list = std::fs::read_to_string(entry.path().join("cpulist")).ok()?;
+            Some((id, parse_cpu_list(&list)?))
+        })
+        .collect::<Vec<_>>();
+    nodes.sort_by_key(|&(id, _)| id);
+    Some(nodes.into_iter().map(|(_, cpus)| cpus).collect())
+}
+
+// Parses the kernel's cpu list format, e.g. "0-3,8-11"
+fn parse_cpu_list(list: &str) -> Option<Vec<usize>> {
+    let mut cpus = Vec::new();
+    for range in list.trim().split(',').filter(|r| !r.is_empty()) {
+        match range.split_once('-') {
+            Some((start, end)) => cpus.extend(start.parse::<usize>().ok()?..=end.parse().ok()?),
+            None => cpus.push(range.parse().ok()?),
+        }
+    }
+    Some(cpus)
+}
+
+// The process' affinity mask, none if it can't be read
+#[cfg(target_os = "linux")]
+fn allowed_cpus() -> Option<Vec<usize>> {
+    unsafe {
+        let mut set = std::mem::zeroed::<libc::cpu_set_t>();
+        if libc::sched_getaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &mut set) != 0 {
+            return None;
+        }
+        Some((0..MAX_CPUS).filter(|&cpu| libc::CPU_ISSET(cpu, &set)).collect())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn allowed_cpus() -> Option<Vec<usize>> {
+    None
+}
+
+// Pins the calling thread to a single cpu. Returns false if that isn't supported here.
+#[cfg(target_os = "linux")]
+pub fn pin_current_thread(cpu: usize) -> bool {
+    if cpu >= MAX_CPUS {
+        return false;
+    }
+    unsafe {
+        let mut set = std::mem::zeroed::<libc::cpu_set_t>();
+        libc::CPU_SET(cpu, &mut set);
+        libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) == 0
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn pin_current_thread(_cpu: usize) -> bool {
+    false
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_cpu_list() {
+        assert_eq!(parse_cpu_list("0-3,8-9\n"), Some(vec![0, 1, 2, 3, 8, 9]));
+        assert_eq!(parse_cpu_list("5"), Some(vec![5]));
+        assert_eq!(parse_cpu_list("\n"), Some(vec![]));
+        assert_eq!(parse_cpu_list("a-b"), None);
+    }
+
+    #[test]
+    fn test_cpu_for_fills_nodes_in_order() {
+        let topology = Topology {
+            nodes: vec![vec![0, 1], vec![4, 5]],
+        };
+        assert_eq!(topology.cpus(), 4);
+        let cpus = (0..6).map(|i| topology.cpu_for(i)).collect::<Vec<_>>();
+        assert_eq!(cpus, vec![0, 1, 4, 5, 0, 1]);
+    }
+
+    #[test]
+    fn test_restrict_to_allowed_cpus() {
+        let nodes = vec![vec![0, 1, 2, 3], vec![4, 5, 6, 7], vec![1024, 1025]];
+        let topology = Topology::restrict(nodes.clone(), Some(&[2, 3, 1024]));
+        assert_eq!(topology.nodes, vec![vec![2, 3]]);
+        let topology = Topology::restrict(nodes, None);
+        assert_eq!(topology.cpus(), 8);
+
+        let detected = Topology::detect();
+        assert!(detected.cpus() > 0);
+        if let Some(allowed) = allowed_cpus() {
+            assert!(detected.nodes.iter().flatten().all(|cpu| allowed.contains(cpu)));
+        }
+    }
+}
@@ -0,0 +1,338 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context, Result};
+use rand::Rng;
+
+use crate::{
+    chess::{Color, GameResult, Role},
+    fen::Fen,
+    movegen::MoveGen,
+    moves::{Move, MoveType},
+    pgn::{parse_san, PgnReader},
+    position::Position,
+    uci::Uci,
+};
+
+// A book entry, written as 16 big-endian bytes with the entries sorted by key. The key is
+// pounce's own zobrist key, so these books are read by `Book` and not by Polyglot tools, which
+// lay out their entries the same way but hash positions differently.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BookEntry {
+    pub key: u64,
+    pub mv: u16,
+    pub weight: u16,
+    pub learn: u32,
+}
+
+impl BookEntry {
+    pub fn write(&self, writer: &mut impl Write) -> std::io::Result<()> {
+        writer.write_all(&self.key.to_be_bytes())?;
+        writer.write_all(&self.mv.to_be_bytes())?;
+        writer.write_all(&self.weight.to_be_bytes())?;
+        writer.write_all(&self.learn.to_be_bytes())
+    }
+
+    pub fn read(reader: &mut impl Read) -> std::io::Result<Option<Self>> {
+        let mut bytes = [0; 16];
+        match reader.read_exact(&mut bytes) {
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+            result => result?,
+        }
+        Ok(Some(BookEntry {
+            key: u64::from_be_bytes(bytes[..8].try_into().unwrap()),
+            mv: u16::from_be_bytes([bytes[8], bytes[9]]),
+            weight: u16::from_be_bytes([bytes[10], bytes[11]]),
+            learn: u32::from_be_bytes(bytes[12..].try_into().unwrap()),
+        }))
+    }
+}
+
+// A book written by `make_book`, held in memory
+#[derive(Debug, Clone)]
+pub struct Book {
+    pub path: PathBuf,
+    entries: Vec<BookEntry>,
+}
+
+impl Book {
+    pub fn open(path: &Path) -> Result<Book> {
+        let file = File::open(path).with_context(|| format!("opening {}", path.display()))?;
+        let len = file.metadata()?.len();
+        if len % 16 != 0 {
+            return Err(anyhow!(
+                "{} isn't a book, its size isn't a multiple of 16",
+                path.display()
+            ));
+        }
+
+        let mut reader = BufReader::new(file);
+        let mut entries = Vec::with_capacity(len as usize / 16);
+        while let Some(entry) = BookEntry::read(&mut reader)? {
+            entries.push(entry);
+        }
+        if !entries.is_sorted_by_key(|e| e.key) {
+            return Err(anyhow!(
+                "{} isn't a book, its entries aren't sorted",
+                path.display()
+            ));
+        }
+        Ok(Book {
+            path: path.to_path_buf(),
+            entries,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // The legal book moves of the position with their weights, entries for moves that aren't
+    // legal here (a key collision) are skipped
+    pub fn moves(&self, pos: &Position) -> Vec<(Move, u16)> {
+        let key = u64::from(pos.key);
+        let start = self.entries.partition_point(|e| e.key < key);
+        let legal = MoveGen::new(pos).collect::<Vec<_>>();
+        self.entries[start..]
+            .iter()
+            .take_while(|e| e.key == key)
+            .filter_map(|e| {
+                let mv = legal.iter().find(|&&mv| book_move(mv) == e.mv)?;
+                Some((*mv, e.weight))
+            })
+            .collect()
+    }
+
+    // Picks one of the position's book moves, as often as their weights say
+    pub fn pick(&self, pos: &Position, rng: &mut impl Rng) -> Option<Move> {
+        let moves = self.moves(pos);
+        let total = moves.iter().map(|&(_, weight)| weight as u32).sum::<u32>();
+        if total == 0 {
+            return None;
+        }
+        let mut pick = rng.gen_range(0..total);
+        for (mv, weight) in moves {
+            if pick < weight as u32 {
+                return Some(mv);
+            }
+            pick -= weight as u32;
+        }
+        unreachable!()
+    }
+}
+
+// The move packed as to file, to row, from file, from row and promotion, 3 bits each.
+// Castling is written as the king capturing its own rook.
+pub fn book_move(mv: Move) -> u16 {
+    let (from, mut to) = (mv.from() as u16, mv.to() as u16);
+    if mv.move_type() == MoveType::Castle {
+        to = if to % 8 == 6 { to + 1 } else { to - 2 };
+    }
+    let promotion = match mv.promotion() {
+        Some(Role::Knight) => 1,
+        Some(Role::Bishop) => 2,
+        Some(Role::Rook) => 3,
+        Some(Role::Queen) => 4,
+        _ => 0,
+    };
+    (to % 8) | (to / 8) << 3 | (from % 8) << 6 | (from / 8) << 9 | promotion << 12
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct MoveStats {
+    games: u32,
+    // 2 per win and 1 per draw for the side playing the move
+    points: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct BookConfig {
+    pub in_files: Vec<PathBuf>,
+    pub out_file: PathBuf,
+    // only the first `depth` plies of every game are used
+    pub depth: usize,
+    // moves played in fewer games than this are left out
+    pub min_count: u32,
+}
+
+pub fn make_book(config: &BookConfig) -> Result<()> {
+    let mut stats: HashMap<(u64, u16), MoveStats> = HashMap::new();
+    let mut games = 0;
+    let mut skipped = 0;
+
+    for path in &config.in_files {
+        let file = File::open(path).with_context(|| format!("opening {}", path.display()))?;
+        for game in PgnReader::new(BufReader::new(file)) {
+            let game = game?;
+            let Some(result) = game.result else {
+                skipped += 1;
+                continue;
+            };
+            let Fen(mut pos) = match game.tag("FEN") {
+                Some(fen) => fen.parse()?,
+                None => Uci::STARTPOS.parse()?,
+            };
+
+            games += 1;
+            for san in game.moves.iter().take(config.depth) {
+                let Ok(mv) = parse_san(&pos, san) else {
+                    skipped += 1;
+                    break;
+                };
+                let key = u64::from(pos.key);
+                let entry = stats.entry((key, book_move(mv))).or_default();
+                entry.games += 1;
+                entry.points += match (result, pos.side) {
+                    (GameResult::Draw, _) => 1,
+                    (GameResult::Win, Color::White) | (GameResult::Loss, Color::Black) => 2,
+                    _ => 0,
+                };
+                pos.make_move(mv);
+            }
+        }
+    }
+
+    let entries = book_entries(&stats, config.min_count);
+    let mut writer = BufWriter::new(File::create(&config.out_file)?);
+    for entry in &entries {
+        entry.write(&mut writer)?;
+    }
+    writer.flush()?;
+
+    println!(
+        "{} games ({} skipped), {} positions, {} entries written to {}",
+        games,
+        skipped,
+        stats
+            .keys()
+            .map(|(key, _)| key)
+            .collect::<HashSet<_>>()
+            .len(),
+        entries.len(),
+        config.out_file.display()
+    );
+    Ok(())
+}
+
+// Filters and scales the stats so every position's weights fit in a u16
+fn book_entries(stats: &HashMap<(u64, u16), MoveStats>, min_count: u32) -> Vec<BookEntry> {
+    let mut max_points: HashMap<u64, u32> = HashMap::new();
+    for (&(key, _), s) in stats.iter().filter(|(_, s)| s.games >= min_count) {
+        let max = max_points.entry(key).or_default();
+        *max = (*max).max(s.points);
+    }
+
+    let mut entries = stats
+        .iter()
+        .filter(|(_, s)| s.games >= min_count && s.points > 0)
+        .map(|(&(key, mv), s)| {
+            let max = max_points[&key].max(u16::MAX as u32) as u64;
+            BookEntry {
+                key,
+                mv,
+                weight: (s.points as u64 * u16::MAX as u64 / max).max(1) as u16,
+                learn: 0,
+            }
+        })
+        .collect::<Vec<_>>();
+    entries.sort_by_key(|e| (e.key, std::cmp::Reverse(e.weight), e.mv));
+    entries
+}
+
+#[cfg(test)]
+mod test {
+    use rand::SeedableRng;
+
+    use super::*;
+    use crate::chess::Square;
+
+    #[test]
+    fn test_book_move() {
+        let e2e4 = Move::new_double_pawn_push(Square::E2, Square::E4);
+        assert_eq!(book_move(e2e4), 0x31c);
+
+        let castle = Move::new_castle(Square::E1, Square::G1);
+        assert_eq!(book_move(castle), 0x107);
+        let castle = Move::new_castle(Square::E8, Square::C8);
+        assert_eq!(book_move(castle), 0xf38);
+
+        let promo = Move::new(Square::A7, Square::A8, Some(Role::Queen));
+        assert_eq!(book_move(promo), 0x4c38);
+    }
+
+    #[test]
+    fn test_book_entries() {
+        let stats = [
+            ((1, 10), 5, 8),
+            ((1, 11), 5, 4),
+            ((1, 12), 1, 2),
+            ((0, 13), 9, 0),
+        ]
+        .into_iter()
+        .map(|(key, games, points)| (key, MoveStats { games, points }))
+        .collect::<HashMap<_, _>>();
+
+        let entries = book_entries(&stats, 2);
+        assert_eq!(entries.len(), 2);
+        assert_eq!((entries[0].mv, entries[1].mv), (10, 11));
+        assert!(entries[0].weight > entries[1].weight);
+
+        let mut bytes = Vec::new();
+        entries[0].write(&mut bytes).unwrap();
+        assert_eq!(bytes.len(), 16);
+        assert_eq!(&bytes[8..10], &10u16.to_be_bytes());
+    }
+
+    #[test]
+    fn test_read_book() {
+        crate::movegen::init_tables();
+        crate::zobrist::init_zobrist();
+
+        let dir = std::env::temp_dir().join(format!("pounce_book_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let pgn = dir.join("games.pgn");
+        std::fs::write(
+            &pgn,
+            "[Result \"1-0\"]\n\n1. e4 e5 2. Nf3 1-0\n\n\
+             [Result \"1/2-1/2\"]\n\n1. e4 c5 1/2-1/2\n\n\
+             [Result \"0-1\"]\n\n1. d4 d5 0-1\n",
+        )
+        .unwrap();
+        let config = BookConfig {
+            in_files: vec![pgn],
+            out_file: dir.join("book.bin"),
+            depth: 2,
+            min_count: 1,
+        };
+        make_book(&config).unwrap();
+
+        let book = Book::open(&config.out_file).unwrap();
+        // moves that only lost are left out, that's d4 and e5
+        assert_eq!(book.len(), 3);
+        let Fen(mut pos) = Uci::STARTPOS.parse().unwrap();
+        let e2e4 = Move::new_double_pawn_push(Square::E2, Square::E4);
+        // 2 points for the win and 1 for the draw
+        assert_eq!(book.moves(&pos), vec![(e2e4, 3)]);
+        let mut rng = rand::rngs::SmallRng::seed_from_u64(1);
+        assert_eq!(book.pick(&pos, &mut rng), Some(e2e4));
+
+        // black lost with e5
+        pos.make_move(e2e4);
+        let c7c5 = Move::new_double_pawn_push(Square::C7, Square::C5);
+        assert_eq!(book.moves(&pos), vec![(c7c5, 1)]);
+        pos.make_move(c7c5);
+        assert_eq!(book.pick(&pos, &mut rng), None);
+
+        std::fs::write(&config.out_file, [0; 17]).unwrap();
+        assert!(Book::open(&config.out_file).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,30 +1,56 @@
 use std::{
+    collections::{hash_map::Entry, HashMap, HashSet},
     fmt::{self, Debug, Formatter},
     fs::OpenOptions,
-    io::{Read, Write},
-    path::PathBuf,
+    io::{self, BufRead, BufReader, BufWriter, Read, Seek, Write},
+    num::NonZeroU32,
+    path::{Path, PathBuf},
     sync::{
         atomic::{AtomicBool, AtomicU32},
-        Arc,
+        Arc, Once,
     },
     time::Duration,
 };
 
 use rand::{rngs::SmallRng, seq::SliceRandom, Rng, SeedableRng};
 use serde::{Deserialize, Serialize};
+use thiserror::Error;
 
 use crate::{
+    adjudicate::{AdjudicationRules, Adjudicator, Outcome},
+    affinity::{pin_current_thread, Topology},
     bitboard::Bitboard,
-    chess::{Color, GameResult},
+    chess::{CastleRights, Color, Piece, Rank, Role, Square},
     eval,
     fen::Fen,
     limits::Limits,
-    movegen::MoveGen,
+    movegen::{
+        get_bishop_moves, get_knight_moves, get_pawn_attacks, get_rook_moves, MoveBuffer, MoveGen,
+    },
+    moves::{Move, MoveType},
     position::Position,
     search::Search,
     tt::Table,
 };
 
+// Progress goes to stderr when records are streamed to stdout
+static STREAMING: AtomicBool = AtomicBool::new(false);
+
+macro_rules! log {
+    ($($arg:tt)*) => {
+        if $crate::datagen::STREAMING.load(std::sync::atomic::Ordering::Relaxed) {
+            eprintln!($($arg)*);
+        } else {
+            println!($($arg)*);
+        }
+    };
+}
+
+mod distributed;
+pub mod reader;
+
+pub use distributed::{connect, coordinate};
+
 static STOP: AtomicBool = AtomicBool::new(false);
 static TOTAL_GAMES: AtomicU32 = AtomicU32::new(0);
 static WHITE_WINS: AtomicU32 = AtomicU32::new(0);
@@ -34,6 +60,93 @@ static NUM_AT_RESTART: AtomicU32 = AtomicU32::new(0);
 
 const STARTPOS: &str = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1";
 
+// Data files are synced to disk every this many games per thread
+const SYNC_GAMES: u32 = 64;
+
+// Every .dat file starts with a header: magic, format version, record size and the
+// (json encoded) config that produced the file. Records follow immediately after.
+pub const DATA_MAGIC: [u8; 4] = *b"PNCD";
+pub const DATA_VERSION: u16 = 4;
+
+#[derive(Debug, Error)]
+pub enum DataHeaderError {
+    #[error("not a pounce data file (bad magic {0:?})")]
+    InvalidMagic([u8; 4]),
+    #[error("unsupported data format version {0}, expected {DATA_VERSION}")]
+    UnsupportedVersion(u16),
+    #[error("record size mismatch: file has {0} bytes, expected {1}")]
+    RecordSizeMismatch(u16, u16),
+    #[error("invalid config in header")]
+    InvalidConfig(#[from] serde_json::Error),
+    #[error("could not read header")]
+    Io(#[from] io::Error),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DataHeader {
+    pub version: u16,
+    pub record_size: u16,
+    pub config: Option<DatagenConfig>,
+}
+
+impl DataHeader {
+    pub fn new(config: Option<DatagenConfig>) -> Self {
+        Self {
+            version: DATA_VERSION,
+            record_size: std::mem::size_of::<CompressedPosition>() as u16,
+            config,
+        }
+    }
+
+    pub fn write<W: Write>(&self, writer: &mut W) -> Result<(), DataHeaderError> {
+        let config = serde_json::to_vec(&self.config)?;
+
+        writer.write_all(&DATA_MAGIC)?;
+        writer.write_all(&self.version.to_le_bytes())?;
+        writer.write_all(&self.record_size.to_le_bytes())?;
+        writer.write_all(&(config.len() as u32).to_le_bytes())?;
+        writer.write_all(&config)?;
+        Ok(())
+    }
+
+    pub fn read<R: Read>(reader: &mut R) -> Result<Self, DataHeaderError> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if magic != DATA_MAGIC {
+            return Err(DataHeaderError::InvalidMagic(magic));
+        }
+
+        let mut buf = [0; 2];
+        reader.read_exact(&mut buf)?;
+        let version = u16::from_le_bytes(buf);
+        if version != DATA_VERSION {
+            return Err(DataHeaderError::UnsupportedVersion(version));
+        }
+
+        reader.read_exact(&mut buf)?;
+        let record_size = u16::from_le_bytes(buf);
+        let expected_size = std::mem::size_of::<CompressedPosition>() as u16;
+        if record_size != expected_size {
+            return Err(DataHeaderError::RecordSizeMismatch(
+                record_size,
+                expected_size,
+            ));
+        }
+
+        let mut buf = [0; 4];
+        reader.read_exact(&mut buf)?;
+        let mut config = vec![0; u32::from_le_bytes(buf) as usize];
+        reader.read_exact(&mut config)?;
+        let config = serde_json::from_slice(&config)?;
+
+        Ok(Self {
+            version,
+            record_size,
+            config,
+        })
+    }
+}
+
 #[repr(u8)]
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum Wdl {
@@ -42,19 +155,84 @@ pub enum Wdl {
     WhiteWin,
 }
 
-// 32 bytes (needs to be a multiple of 8 because that's the alignment of Bitboard)
+impl From<Outcome> for Wdl {
+    fn from(outcome: Outcome) -> Self {
+        match outcome {
+            Outcome::WhiteWin => Wdl::WhiteWin,
+            Outcome::BlackWin => Wdl::BlackWin,
+            Outcome::Draw => Wdl::Draw,
+        }
+    }
+}
+
+// Policy data goes to a `.policy` file next to every `.dat` file, one record per position in
+// the same order. A record is the searched move, a count and that many root moves with their
+// scores from the side to move's point of view, all little endian. The moves are flipped like
+// the `.dat` record's board and bestmove, so they're moves of its `to_position()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PolicyRecord {
+    pub bestmove: Move,
+    pub moves: Vec<(Move, i16)>,
+}
+
+impl PolicyRecord {
+    pub fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(&u16::from(self.bestmove).to_le_bytes())?;
+        writer.write_all(&[self.moves.len() as u8])?;
+        for &(mv, score) in &self.moves {
+            writer.write_all(&u16::from(mv).to_le_bytes())?;
+            writer.write_all(&score.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    pub fn read<R: Read>(reader: &mut R) -> io::Result<Option<Self>> {
+        let mut header = [0; 3];
+        match reader.read_exact(&mut header) {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            result => result?,
+        }
+
+        let bestmove: Move = u16::from_le_bytes([header[0], header[1]]).into();
+        let mut moves = Vec::with_capacity(header[2] as usize);
+        for _ in 0..header[2] {
+            let mut buf = [0; 4];
+            reader.read_exact(&mut buf)?;
+            moves.push((
+                u16::from_le_bytes([buf[0], buf[1]]).into(),
+                i16::from_le_bytes([buf[2], buf[3]]),
+            ));
+        }
+        Ok(Some(Self { bestmove, moves }))
+    }
+}
+
+// 40 bytes (needs to be a multiple of 8 because that's the alignment of Bitboard)
 #[repr(C)]
-#[derive(Copy, Clone, PartialEq, Eq)]
+#[derive(Copy, Clone, PartialEq, Eq, Default)]
 pub struct CompressedPosition {
-    occ: Bitboard,    // 8 bytes
-    pieces: [u8; 16], // 16 bytes
-    score: i16,       // 2 bytes
-    pub wdl: u8,      // 1 byte
-    extra: [u8; 5],   // 5 bytes
+    occ: Bitboard,         // 8 bytes
+    pieces: [u8; 16],      // 16 bytes
+    score: i16,            // 2 bytes
+    pub ply: u16,          // 2 bytes, plies since the start of the game
+    pub plies_to_end: u16, // 2 bytes, plies until the game was decided
+    pub wdl: u8,           // 1 byte
+    pub fullmove: u8,      // 1 byte, saturates at 255
+    bestmove: u16,         // 2 bytes, the searched move, flipped along with the board
+    reserved: [u8; 6],     // 6 bytes, always zero
 }
 
+const _: () = assert!(std::mem::size_of::<CompressedPosition>() == 40);
+
 impl CompressedPosition {
-    pub fn new(pos: &Position, score: i16, wdl: Wdl) -> Self {
+    pub fn new(
+        pos: &Position,
+        score: i16,
+        bestmove: Move,
+        wdl: Wdl,
+        ply: u16,
+        plies_to_end: u16,
+    ) -> Self {
         let mut occ = pos.occupancy;
         let mailbox = pos.mailbox;
         let mut wdl = wdl as u8;
@@ -84,9 +262,50 @@ impl CompressedPosition {
             occ,
             pieces,
             score,
+            ply,
+            plies_to_end,
             wdl,
-            extra: [0; 5],
+            fullmove: pos.fullmove_number.get().min(u8::MAX as u32) as u8,
+            bestmove: u16::from(flip_move(bestmove, pos.side)),
+            reserved: [0; 6],
+        }
+    }
+
+    // The searched move, from the side to move's point of view like the rest of the record
+    pub fn bestmove(&self) -> Move {
+        self.bestmove.into()
+    }
+
+    // Rebuilds the position from the side to move's point of view, so the result is always
+    // white to move. Castling rights and en passant are not stored and come back empty.
+    pub fn to_position(&self) -> Option<Position> {
+        if self.occ.count() > 32 {
+            return None;
+        }
+
+        let mut pos = Position::new();
+        pos.castling = CastleRights::empty();
+
+        for (idx, sq) in self.occ.enumerate() {
+            let bit_pc = (self.pieces[idx / 2] >> (4 * (idx % 2))) & 0b1111;
+            let role = bit_pc & 0b0111;
+            if role as usize >= Role::NUM {
+                return None;
+            }
+            pos.set(sq, Piece::new(Color::new(bit_pc >> 3), Role::new(role)));
         }
+
+        if pos.king_of(Color::White).count() != 1 || pos.king_of(Color::Black).count() != 1 {
+            return None;
+        }
+
+        pos.refresh_checks_and_pins();
+        pos.key = pos.zobrist_hash();
+        Some(pos)
+    }
+
+    pub fn score(&self) -> i16 {
+        self.score
     }
 
     pub fn as_bytes(&self) -> &[u8] {
@@ -100,6 +319,138 @@ impl CompressedPosition {
             std::slice::from_raw_parts_mut(self as *mut _ as *mut u8, std::mem::size_of::<Self>())
         }
     }
+
+    pub fn validate(&self) -> Result<(), RecordError> {
+        if self.wdl > 2 {
+            return Err(RecordError::InvalidWdl(self.wdl));
+        }
+
+        if self.score.abs() > eval::MATE {
+            return Err(RecordError::ScoreOutOfBounds(self.score));
+        }
+
+        let pos = self.to_position().ok_or(RecordError::Unreadable)?;
+
+        let back_ranks = Bitboard::from(Rank::R1) | Bitboard::from(Rank::R8);
+        if (pos.by_role[Role::Pawn] & back_ranks).any() {
+            return Err(RecordError::PawnOnBackRank);
+        }
+
+        // records are always white to move, so black must not be in check
+        let ksq = Square::from(pos.their_king());
+        let attackers = (get_rook_moves(ksq, pos.occupancy)
+            & (pos.our(Role::Rook) | pos.our(Role::Queen)))
+            | (get_bishop_moves(ksq, pos.occupancy)
+                & (pos.our(Role::Bishop) | pos.our(Role::Queen)))
+            | (get_knight_moves(ksq) & pos.our(Role::Knight))
+            | (get_pawn_attacks(ksq, Color::Black) & pos.our(Role::Pawn));
+        if attackers.any() {
+            return Err(RecordError::OpponentInCheck);
+        }
+
+        self.checked_bestmove(&pos).ok_or(RecordError::IllegalBestmove(self.bestmove))?;
+        Ok(())
+    }
+
+    // The bestmove if it's none or a move of `pos`, the record's position. Records don't keep
+    // castling rights or en passant, so those moves only need to fit the board.
+    fn checked_bestmove(&self, pos: &Position) -> Option<Move> {
+        let mv = self.bestmove();
+        if mv == Move::NONE {
+            return Some(mv);
+        }
+        let fits = match mv.move_type() {
+            MoveType::Castle => {
+                mv.from() == Square::E1
+                    && matches!(mv.to(), Square::C1 | Square::G1)
+                    && (pos.our(Role::King) & Square::E1).any()
+            }
+            MoveType::EnPassant => {
+                let captured = mv.to().south()?;
+                mv.from().rank() == Rank::R5
+                    && mv.to().rank() == Rank::R6
+                    && (mv.from() as u8).abs_diff(captured as u8) == 1
+                    && (pos.our(Role::Pawn) & mv.from()).any()
+                    && (pos.their(Role::Pawn) & captured).any()
+                    && !(pos.occupancy & mv.to()).any()
+            }
+            // the rest of the flags, including promotions to anything that can't be promoted to,
+            // never come out of move generation
+            _ => MoveGen::new(pos).any(|legal| legal == mv),
+        };
+        fits.then_some(mv)
+    }
+
+    // Text records are `FEN | score | wdl | ply | plies to end | bestmove`, with the last three
+    // fields optional on input. Score and wdl (1.0, 0.5, 0.0) are from white's point of view.
+    pub fn to_text(&self) -> Option<String> {
+        let mut pos = self.to_position()?;
+        let bestmove = self.checked_bestmove(&pos)?;
+        if self.fullmove > 0 {
+            pos.fullmove_number = NonZeroU32::new(self.fullmove as u32).unwrap();
+        }
+
+        let wdl = match self.wdl {
+            2 => "1.0",
+            1 => "0.5",
+            _ => "0.0",
+        };
+
+        Some(format!(
+            "{} | {} | {} | {} | {} | {}",
+            pos.to_fen(),
+            self.score,
+            wdl,
+            self.ply,
+            self.plies_to_end,
+            bestmove
+        ))
+    }
+
+    pub fn from_text(line: &str) -> anyhow::Result<Self> {
+        let parts: Vec<&str> = line.split('|').map(str::trim).collect();
+        if !matches!(parts.len(), 3 | 5 | 6) {
+            return Err(anyhow::anyhow!(
+                "expected 3, 5 or 6 fields, found {}",
+                parts.len()
+            ));
+        }
+
+        let Fen(pos) = Fen::parse(parts[0])?;
+        let score: i16 = parts[1].parse()?;
+        let wdl = match parts[2] {
+            "1" | "1.0" => Wdl::WhiteWin,
+            "0.5" => Wdl::Draw,
+            "0" | "0.0" => Wdl::BlackWin,
+            w => return Err(anyhow::anyhow!("invalid wdl: {}", w)),
+        };
+        let (ply, plies_to_end) = if parts.len() >= 5 {
+            (parts[3].parse()?, parts[4].parse()?)
+        } else {
+            (0, 0)
+        };
+        let bestmove = match parts.get(5) {
+            Some(&"0000") | None => Move::NONE,
+            Some(mv) => pos
+                .legal_move(mv.parse()?)
+                .ok_or(anyhow::anyhow!("illegal move: {}", mv))?,
+        };
+
+        let score = match pos.side {
+            Color::White => score,
+            Color::Black => -score,
+        };
+
+        Ok(Self::new(&pos, score, bestmove, wdl, ply, plies_to_end))
+    }
+}
+
+fn flip_move(mv: Move, side: Color) -> Move {
+    if side == Color::White || mv == Move::NONE {
+        return mv;
+    }
+    // flip the rank of both squares, keeping the move type flags
+    (u16::from(mv) ^ (56 | 56 << 6)).into()
 }
 
 impl Debug for CompressedPosition {
@@ -110,7 +461,18 @@ impl Debug for CompressedPosition {
         for p in self.pieces {
             writeln!(f, "{:#010b}", p)?;
         }
-        writeln!(f, "score: {}, wdl: {}", self.score, self.wdl)
+        writeln!(
+            f,
+            "score: {}, wdl: {}, bestmove: {}",
+            self.score,
+            self.wdl,
+            self.bestmove()
+        )?;
+        writeln!(
+            f,
+            "ply: {}, fullmove: {}, plies to end: {}",
+            self.ply, self.fullmove, self.plies_to_end
+        )
     }
 }
 
@@ -122,6 +484,77 @@ pub struct DatagenConfig {
     pub concurrency: u32,
     pub out_path: PathBuf,
     pub state_path: Option<PathBuf>,
+    #[serde(default)]
+    pub shard_games: Option<u32>,
+    #[serde(default)]
+    pub shard_bytes: Option<u64>,
+    #[serde(default)]
+    pub pin_threads: bool,
+    // games still going after this many plies are adjudicated as draws
+    #[serde(default)]
+    pub max_plies: Option<u32>,
+    // also write policy records with this many of the best root moves
+    #[serde(default)]
+    pub policy_moves: Option<u8>,
+    #[serde(default)]
+    pub opening: OpeningConfig,
+    // all workers search with one table of tt_size_mb * concurrency instead of a private one
+    // each. Searches reuse each other's work, but the games are no longer independent.
+    #[serde(default)]
+    pub shared_tt: bool,
+}
+
+// How every game's opening is randomized before the engine takes over
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone, Copy)]
+pub struct OpeningConfig {
+    // the number of random plies is picked uniformly from min_plies..=max_plies
+    pub min_plies: u32,
+    pub max_plies: u32,
+    // only pick among this many of the best moves by a `depth` search, instead of all of them
+    pub top_k: Option<u32>,
+    pub depth: u8,
+    // centipawns, a move this much worse than the best is picked 1/e times as often. 0 picks
+    // uniformly among the top k.
+    pub temperature: u32,
+    // centipawns, openings the engine scores further from level than this are thrown away
+    pub max_eval: i16,
+}
+
+impl Default for OpeningConfig {
+    fn default() -> Self {
+        OpeningConfig {
+            min_plies: 8,
+            max_plies: 9,
+            top_k: None,
+            depth: 1,
+            temperature: 0,
+            max_eval: 720,
+        }
+    }
+}
+
+impl DatagenConfig {
+    // `--out -` streams records to stdout instead of writing files
+    pub fn streaming(&self) -> bool {
+        self.out_path.as_os_str() == "-"
+    }
+}
+
+fn start_streaming(config: &DatagenConfig) -> anyhow::Result<()> {
+    if config.shard_games.is_some() || config.shard_bytes.is_some() {
+        return Err(anyhow::anyhow!(
+            "can't shard output when streaming to stdout"
+        ));
+    }
+    if config.policy_moves.is_some() {
+        return Err(anyhow::anyhow!(
+            "can't write policy data when streaming to stdout"
+        ));
+    }
+
+    STREAMING.store(true, std::sync::atomic::Ordering::Relaxed);
+    DataHeader::new(Some(config.clone())).write(&mut io::stdout().lock())?;
+    Ok(())
 }
 
 pub fn datagen(mut config: DatagenConfig) -> anyhow::Result<()> {
@@ -130,33 +563,37 @@ pub fn datagen(mut config: DatagenConfig) -> anyhow::Result<()> {
         STOP.store(true, std::sync::atomic::Ordering::Relaxed);
     })?;
 
-    std::fs::create_dir_all(&config.out_path)?;
-    println!("Output directory: {:?}", config.out_path);
+    if config.streaming() {
+        start_streaming(&config)?;
+    } else {
+        std::fs::create_dir_all(&config.out_path)?;
+        log!("Output directory: {:?}", config.out_path);
+    }
 
     if let Some(ref state_path) = config.state_path {
         let state: DatagenState = match std::fs::read_to_string(state_path) {
             Ok(s) => {
-                println!("Loaded state from {:?}", state_path);
+                log!("Loaded state from {:?}", state_path);
                 let state: DatagenState = serde_json::from_str(&s)?;
                 if state.config != config {
                     return Err(anyhow::anyhow!("Config mismatch"));
                 }
-                println!(
+                log!(
                     "Found {} previous games.\nWhite wins: {}, Black wins: {}, Draws: {}",
                     state.white_wins + state.black_wins + state.draws,
                     state.white_wins,
                     state.black_wins,
                     state.draws
                 );
-                println!();
+                log!();
 
                 config = state.config.to_owned();
 
                 state
             }
             Err(_) => {
-                println!("Creating new state file at {:?}", state_path);
-                println!();
+                log!("Creating new state file at {:?}", state_path);
+                log!();
                 DatagenState {
                     white_wins: 0,
                     black_wins: 0,
@@ -180,66 +617,109 @@ pub fn datagen(mut config: DatagenConfig) -> anyhow::Result<()> {
 
     let games_remaing = config.num_games - TOTAL_GAMES.load(std::sync::atomic::Ordering::Relaxed);
 
-    println!("Starting datagen with the following configuration:");
-    println!("Limits: {:?}", config.limits);
-    println!("TT size: {} MB", config.tt_size_mb);
-    println!("Concurrency: {}", config.concurrency);
-    println!("Output path: {:?}", config.out_path);
+    log!("Starting datagen with the following configuration:");
+    log!("Limits: {:?}", config.limits);
+    if config.shared_tt {
+        log!("TT size: {} MB shared", config.tt_size_mb * config.concurrency);
+    } else {
+        log!("TT size: {} MB per thread", config.tt_size_mb);
+    }
+    log!("Concurrency: {}", config.concurrency);
+    log!("Output path: {:?}", config.out_path);
     if let Some(ref state_path) = config.state_path {
-        println!("State path: {:?}", state_path);
+        log!("State path: {:?}", state_path);
     } else {
-        println!("State path: None");
+        log!("State path: None");
     }
-    println!("Total games: {}", config.num_games);
-    println!("Games remaining: {}", games_remaing);
-    println!();
+    log!("Total games: {}", config.num_games);
+    log!("Games remaining: {}", games_remaing);
+    log!();
 
-    std::thread::scope(|s| {
-        println!("Starting threads");
-        for i in 0..config.concurrency {
-            s.spawn({
-                let config = config.clone();
-                move || thread_worker(i, &config)
-            });
-        }
-        println!(
-            "{}/{} threads started",
-            config.concurrency, config.concurrency
-        );
-        println!();
-        println!("Let 'er rip!!!!");
-    });
+    run_threads(&config, None);
 
     if STOP.load(std::sync::atomic::Ordering::Relaxed) {
-        println!("Stopped by user");
+        log!("Stopped by user");
     } else {
-        println!("All games finished");
+        log!("All games finished");
     }
 
     if let Some(ref state_path) = config.state_path {
-        println!("Saving state to {:?}", config.state_path);
-        let state = DatagenState {
-            white_wins: WHITE_WINS.load(std::sync::atomic::Ordering::Relaxed),
-            black_wins: BLACK_WINS.load(std::sync::atomic::Ordering::Relaxed),
-            draws: DRAWS.load(std::sync::atomic::Ordering::Relaxed),
-            config: config.clone(),
-        };
-        let state = serde_json::to_string(&state)?;
-        std::fs::write(state_path, state)?;
+        log!("Saving state to {:?}", config.state_path);
+        DatagenState::current(&config).save(state_path)?;
     };
 
-    println!();
-    println!(
+    log!();
+    log!(
         "Total: {}, White wins: {}, Black wins: {}, Draws: {}",
         TOTAL_GAMES.load(std::sync::atomic::Ordering::Relaxed),
         WHITE_WINS.load(std::sync::atomic::Ordering::Relaxed),
         BLACK_WINS.load(std::sync::atomic::Ordering::Relaxed),
         DRAWS.load(std::sync::atomic::Ordering::Relaxed)
     );
-    println!("See ya!");
+    log!("See ya!");
     Ok(())
 }
 
+// Plays games on `config.concurrency` threads until `config.num_games` have been played in
+// total. With a seed, each thread derives its own rng from it so batches are reproducible.
+fn run_threads(config: &DatagenConfig, seed: Option<u64>) {
+    install_panic_hook();
+    let shared_tt = config
+        .shared_tt
+        .then(|| Arc::new(Table::new_mb((config.tt_size_mb * config.concurrency) as usize)));
+    std::thread::scope(|s| {
+        log!("Starting threads");
+        let topology = config.pin_threads.then(Topology::detect);
+        if let Some(topology) = &topology {
+            log!("Pinning threads, topology: {}", topology);
+        }
+        let mut handles = Vec::new();
+        for i in 0..config.concurrency {
+            let cpu = topology.as_ref().map(|t| t.cpu_for(i as usize));
+            handles.push(s.spawn({
+                let config = config.clone();
+                let shared_tt = shared_tt.clone();
+                move || {
+                    if let Some(cpu) = cpu {
+                        pin_current_thread(cpu);
+                    }
+                    thread_worker(i, &config, seed, shared_tt)
+                }
+            }));
+        }
+        log!(
+            "{}/{} threads started",
+            config.concurrency,
+            config.concurrency
+        );
+        log!();
+        log!("Let 'er rip!!!!");
+
+        // joined here so a panicking worker doesn't take down the rest before they've flushed
+        for (i, handle) in handles.into_iter().enumerate() {
+            match handle.join() {
+                Ok(Err(e)) => log!("Thread {} failed: {}", i, e),
+                Err(_) => log!("Thread {} panicked", i),
+                Ok(Ok(())) => {}
+            }
+        }
+    });
+}
+
+// Stops every worker as soon as one of them panics. The others finish their current game and
+// flush their files, the panicking one flushes while unwinding (see `ShardWriter`'s drop), and
+// the state is saved as on ctrl-c.
+fn install_panic_hook() {
+    static INSTALLED: Once = Once::new();
+    INSTALLED.call_once(|| {
+        let default_hook = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            STOP.store(true, std::sync::atomic::Ordering::Relaxed);
+            default_hook(info);
+        }));
+    });
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct DatagenState {
     white_wins: u32,
@@ -249,13 +729,258 @@ struct DatagenState {
     config: DatagenConfig,
 }
 
-fn thread_worker(id: u32, config: &DatagenConfig) -> anyhow::Result<()> {
-    let out_path = config.out_path.join(format!("{}.dat", id));
-    let tt = Arc::new(Table::new_mb(config.tt_size_mb as usize));
+impl DatagenState {
+    fn current(config: &DatagenConfig) -> Self {
+        DatagenState {
+            white_wins: WHITE_WINS.load(std::sync::atomic::Ordering::Relaxed),
+            black_wins: BLACK_WINS.load(std::sync::atomic::Ordering::Relaxed),
+            draws: DRAWS.load(std::sync::atomic::Ordering::Relaxed),
+            config: config.clone(),
+        }
+    }
+
+    // Writes to a temp file next to `path` and renames it over, so a crash mid write leaves
+    // the previous state intact
+    fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+
+        let mut file = std::fs::File::create(&tmp)?;
+        serde_json::to_writer(&mut file, self)?;
+        file.sync_all()?;
+        std::fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
+
+// Writes one thread's games to `{id}.dat`, or with sharding enabled to `{id}_0001.dat`,
+// `{id}_0002.dat`, ... starting a new file whenever the game or byte limit is hit. When
+// streaming there is no file and games go straight to stdout. Files are synced every
+// SYNC_GAMES games and when the writer is dropped, including while unwinding from a panic.
+struct ShardWriter {
+    id: u32,
+    config: DatagenConfig,
+    shard: u32,
+    games: u32,
+    unsynced_games: u32,
+    bytes: u64,
+    file: Option<std::fs::File>,
+    policy: Option<std::fs::File>,
+}
+
+impl ShardWriter {
+    fn new(id: u32, config: &DatagenConfig) -> anyhow::Result<Self> {
+        if config.streaming() {
+            if config.policy_moves.is_some() {
+                return Err(anyhow::anyhow!(
+                    "can't write policy data when streaming to stdout"
+                ));
+            }
+            return Ok(Self {
+                id,
+                config: config.clone(),
+                shard: 0,
+                games: 0,
+                unsynced_games: 0,
+                bytes: 0,
+                file: None,
+                policy: None,
+            });
+        }
+
+        let sharded = config.shard_games.is_some() || config.shard_bytes.is_some();
+
+        // resumed runs continue in a fresh shard rather than topping up the last one
+        let mut shard = 0;
+        if sharded {
+            shard = 1;
+            while Self::path(config, id, shard).exists() {
+                shard += 1;
+            }
+        }
+
+        let path = Self::path(config, id, shard);
+        let (file, records) = Self::open(&path, config)?;
+        Ok(Self {
+            id,
+            config: config.clone(),
+            shard,
+            games: 0,
+            unsynced_games: 0,
+            bytes: file.metadata()?.len(),
+            file: Some(file),
+            policy: Self::open_policy(&path, config, records)?,
+        })
+    }
+
+    fn path(config: &DatagenConfig, id: u32, shard: u32) -> PathBuf {
+        if shard == 0 {
+            config.out_path.join(format!("{}.dat", id))
+        } else {
+            config.out_path.join(format!("{}_{:04}.dat", id, shard))
+        }
+    }
+
+    // Returns the file along with the number of whole records already in it
+    fn open(path: &Path, config: &DatagenConfig) -> anyhow::Result<(std::fs::File, u64)> {
+        // new files get a header, existing ones (from a resumed run) must have a valid one
+        let mut records = 0;
+        match std::fs::File::open(path) {
+            Ok(file) if file.metadata()?.len() > 0 => {
+                let len = file.metadata()?.len();
+                let mut reader = BufReader::new(file);
+                DataHeader::read(&mut reader)?;
+
+                // drop a record cut off by a crash, appending after it would misalign the rest
+                let record_size = std::mem::size_of::<CompressedPosition>() as u64;
+                let data = len - reader.stream_position()?;
+                records = data / record_size;
+                let partial = data % record_size;
+                if partial > 0 {
+                    log!("Truncating {} bytes from {:?}", partial, path);
+                    OpenOptions::new()
+                        .write(true)
+                        .open(path)?
+                        .set_len(len - partial)?;
+                }
+            }
+            _ => {
+                let mut file = std::fs::File::create(path)?;
+                DataHeader::new(Some(config.clone())).write(&mut file)?;
+            }
+        }
+
+        Ok((OpenOptions::new().append(true).open(path)?, records))
+    }
+
+    // The policy file has to pair up with the `records` positions of the `.dat` file, so
+    // anything after them, like a record cut off by a crash, is dropped
+    fn open_policy(
+        path: &Path,
+        config: &DatagenConfig,
+        records: u64,
+    ) -> anyhow::Result<Option<std::fs::File>> {
+        if config.policy_moves.is_none() {
+            return Ok(None);
+        }
+        let path = path.with_extension("policy");
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+
+        let len = file.metadata()?.len();
+        let mut reader = BufReader::new(std::fs::File::open(&path)?);
+        let mut end = 0;
+        for read in 0..records {
+            match PolicyRecord::read(&mut reader) {
+                Ok(Some(record)) => end += 3 + 4 * record.moves.len() as u64,
+                Ok(None) => return Err(policy_mismatch(&path, read, records)),
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                    return Err(policy_mismatch(&path, read, records))
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        if end < len {
+            log!("Truncating {} bytes from {:?}", len - end, path);
+            file.set_len(end)?;
+        }
+        Ok(Some(file))
+    }
+
+    fn write_game(
+        &mut self,
+        positions: &[CompressedPosition],
+        policy: &[PolicyRecord],
+    ) -> anyhow::Result<()> {
+        let full = self.config.shard_games.is_some_and(|n| self.games >= n)
+            || self.config.shard_bytes.is_some_and(|n| self.bytes >= n);
+        if full {
+            self.sync()?;
+            self.shard += 1;
+            self.games = 0;
+            let path = Self::path(&self.config, self.id, self.shard);
+            let (file, records) = Self::open(&path, &self.config)?;
+            self.bytes = file.metadata()?.len();
+            self.file = Some(file);
+            self.policy = Self::open_policy(&path, &self.config, records)?;
+        }
+
+        let bytes = positions
+            .iter()
+            .flat_map(|p| p.as_bytes())
+            .copied()
+            .collect::<Vec<u8>>();
+        match self.file {
+            Some(ref mut file) => file.write_all(&bytes)?,
+            // one write per game under the lock so threads never interleave records
+            None => io::stdout().lock().write_all(&bytes)?,
+        }
+        if let Some(ref mut file) = self.policy {
+            let mut bytes = Vec::new();
+            for record in policy {
+                record.write(&mut bytes)?;
+            }
+            file.write_all(&bytes)?;
+        }
+
+        self.games += 1;
+        self.bytes += bytes.len() as u64;
+        self.unsynced_games += 1;
+        if self.unsynced_games >= SYNC_GAMES {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.unsynced_games = 0;
+        match self.file {
+            Some(ref file) => file.sync_data()?,
+            None => io::stdout().flush()?,
+        }
+        if let Some(ref file) = self.policy {
+            file.sync_data()?;
+        }
+        Ok(())
+    }
+}
+
+fn policy_mismatch(path: &Path, found: u64, records: u64) -> anyhow::Error {
+    anyhow::anyhow!(
+        "{:?} has {} records for {} positions, can't resume with policy data",
+        path,
+        found,
+        records
+    )
+}
+
+impl Drop for ShardWriter {
+    fn drop(&mut self) {
+        if let Err(e) = self.sync() {
+            log!("Failed to sync thread {} output: {}", self.id, e);
+        }
+    }
+}
+
+fn thread_worker(
+    id: u32,
+    config: &DatagenConfig,
+    seed: Option<u64>,
+    shared_tt: Option<Arc<Table>>,
+) -> anyhow::Result<()> {
+    // a shared table is never cleared, the other threads are still searching with it
+    let private = shared_tt.is_none();
+    let tt = shared_tt.unwrap_or_else(|| Arc::new(Table::new_mb(config.tt_size_mb as usize)));
     let start = std::time::Instant::now();
     let mut last_log = std::time::Instant::now();
 
     let Fen(pos) = STARTPOS.parse().unwrap();
+    let mut rng = match seed {
+        Some(seed) => SmallRng::seed_from_u64(seed.wrapping_add(id as u64)),
+        None => SmallRng::from_entropy(),
+    };
+
+    let mut writer = ShardWriter::new(id, config)?;
 
     while TOTAL_GAMES.load(std::sync::atomic::Ordering::Relaxed) < config.num_games {
         if STOP.load(std::sync::atomic::Ordering::Relaxed) {
@@ -278,133 +1003,148 @@ fn thread_worker(id: u32, config: &DatagenConfig) -> anyhow::Result<()> {
                 * 60.0;
             let est_remaining = (config.num_games - num_since_restart) as f64 / games_per_min;
 
-            println!();
-            println!(
+            log!();
+            log!(
                 "{}/{} Games, White wins: {}, Black wins: {}, Draws: {}",
-                total, config.num_games, white_wins, black_wins, draws
+                total,
+                config.num_games,
+                white_wins,
+                black_wins,
+                draws
             );
-            println!("Games per minute: {:.1}", games_per_min);
-            println!("Estimated time remaining: {:.1} minutes", est_remaining);
+            log!("Games per minute: {:.1}", games_per_min);
+            log!("Estimated time remaining: {:.1} minutes", est_remaining);
 
             if let Some(ref state_path) = config.state_path {
-                let state = DatagenState {
-                    white_wins: WHITE_WINS.load(std::sync::atomic::Ordering::Relaxed),
-                    black_wins: BLACK_WINS.load(std::sync::atomic::Ordering::Relaxed),
-                    draws: DRAWS.load(std::sync::atomic::Ordering::Relaxed),
-                    config: config.to_owned(),
-                };
-
-                let state = serde_json::to_string(&state).unwrap();
-                std::fs::write(state_path, state)?;
+                DatagenState::current(config).save(state_path)?;
             };
         }
 
-        tt.clear();
-        if let Ok(positions) = playout(&pos, config.limits, tt.clone()) {
+        if private {
+            tt.clear();
+        }
+        if let Ok((positions, policy)) = playout(&pos, config, tt.clone(), &mut rng) {
             TOTAL_GAMES.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-            let mut file = OpenOptions::new()
-                .read(true)
-                .create(true)
-                .append(true)
-                .open(&out_path)
-                .unwrap();
-
-            for p in positions {
-                file.write_all(p.as_bytes())?;
-            }
+            writer.write_game(&positions, &policy)?;
         }
     }
 
     Ok(())
 }
 
+// Picks an opening move from `moves`. Without a top k any legal move goes, otherwise every move
+// gets a shallow search and the best ones are weighted by how close they are to the best.
+fn random_move(
+    pos: &Position,
+    moves: &[Move],
+    opening: &OpeningConfig,
+    tt: &Arc<Table>,
+    rng: &mut SmallRng,
+) -> Option<Move> {
+    let Some(top_k) = opening.top_k else {
+        return moves.choose(rng).copied();
+    };
+
+    let limits = Limits {
+        depth: Some(opening.depth.max(1)),
+        ..Limits::new()
+    };
+    let mut scored = moves
+        .iter()
+        .map(|&mv| {
+            let mut pos = pos.clone();
+            pos.make_move(mv);
+            let mut search = Search::new(pos, limits, tt.clone(), Arc::new(AtomicBool::new(false)));
+            search.set_silent(true);
+            (mv, -eval::normalize(search.think().score) as i32)
+        })
+        .collect::<Vec<_>>();
+    scored.sort_by_key(|&(_, score)| std::cmp::Reverse(score));
+    scored.truncate(top_k.max(1) as usize);
+
+    if opening.temperature == 0 {
+        return scored.choose(rng).map(|&(mv, _)| mv);
+    }
+    let best = scored.first()?.1;
+    let weight = |&(_, score): &(Move, i32)| {
+        ((score - best) as f64 / opening.temperature as f64).exp()
+    };
+    scored.choose_weighted(rng, weight).ok().map(|&(mv, _)| mv)
+}
+
 fn playout(
     startpos: &Position,
-    limits: Limits,
+    config: &DatagenConfig,
     tt: Arc<Table>,
-) -> anyhow::Result<Vec<CompressedPosition>> {
+    rng: &mut SmallRng,
+) -> anyhow::Result<(Vec<CompressedPosition>, Vec<PolicyRecord>)> {
     let mut pos = startpos.clone();
-    let mut rng = SmallRng::from_entropy();
 
     let stop = Arc::new(AtomicBool::new(false));
 
     let mut positions = Vec::new();
+    let mut policy = Vec::new();
 
     // make random moves
-    let num_random = if rng.gen_bool(0.5) { 8 } else { 9 };
+    let opening = &config.opening;
+    let num_random =
+        rng.gen_range(opening.min_plies..=opening.max_plies.max(opening.min_plies)) as usize;
 
+    let mut moves = MoveBuffer::new();
     for _ in 0..num_random {
-        let moves = MoveGen::new(&pos).collect::<Vec<_>>();
-        if moves.is_empty() {
+        MoveGen::new(&pos).collect_into(&mut moves);
+        let Some(mv) = random_move(&pos, &moves, opening, &tt, rng) else {
             return Err(anyhow::anyhow!("No moves"));
-        }
-        let mv = *moves.choose(&mut rng).unwrap();
+        };
         pos.make_move(mv);
     }
     let startpos = pos.clone();
-    let num_moves = MoveGen::new(&pos).len();
-    if num_moves == 0 {
-        return Err(anyhow::anyhow!("No moves"));
-    }
-
-    match pos.is_draw() {
-        Some(GameResult::Loss) => {
-            return Err(anyhow::anyhow!("Loss"));
-        }
-        Some(GameResult::Draw) => return Err(anyhow::anyhow!("Draw")),
-        Some(GameResult::Win) => unreachable!(),
-        None => {}
+    if let Some(result) = pos.game_result() {
+        return Err(anyhow::anyhow!("Opening already over: {:?}", result));
     }
 
     // break early if eval is too extreme
-    let mut search = Search::new(pos.clone(), limits, tt.clone(), stop.clone());
+    let mut search = Search::new(pos.clone(), config.limits, tt.clone(), stop.clone());
     search.set_silent(true);
     let res = search.think();
-    if res.score.abs() > 1_500 {
+    if eval::normalize(res.score).abs() > opening.max_eval {
         return Err(anyhow::anyhow!("Extreme score"));
     }
 
+    // repetitions are played on, the search already scores them as draws
+    let mut adjudicator = Adjudicator::new(AdjudicationRules {
+        max_plies: config.max_plies,
+        repetition: false,
+        ..Default::default()
+    });
     let result = loop {
         if STOP.load(std::sync::atomic::Ordering::Relaxed) {
             return Err(anyhow::anyhow!("Stopped"));
         }
-        let num_moves = MoveGen::new(&pos).len();
-        if num_moves == 0 {
-            if pos.in_check() {
-                match pos.side {
-                    Color::Black => break Wdl::WhiteWin,
-                    Color::White => break Wdl::BlackWin,
-                }
-            }
-            break Wdl::Draw;
-        }
 
-        match pos.is_draw() {
-            Some(GameResult::Loss) => match pos.side {
-                Color::Black => break Wdl::WhiteWin,
-                Color::White => break Wdl::BlackWin,
-            },
-            Some(GameResult::Draw) => break Wdl::Draw,
-            Some(GameResult::Win) => unreachable!(),
-            None => {}
+        let plies = (num_random + positions.len()) as u32;
+        if let Some(outcome) = adjudicator.position_result(&pos, plies) {
+            break Wdl::from(outcome);
         }
 
-        let mut search = Search::new(pos.clone(), limits, tt.clone(), stop.clone());
+        tt.new_search();
+        let mut search = Search::new(pos.clone(), config.limits, tt.clone(), stop.clone());
         search.set_silent(true);
         let res = search.think();
-        // exit if we find a mate score
-        if res.score < (-eval::MATE_IN_PLY) {
-            // current side is losing
-            match pos.side {
-                Color::Black => break Wdl::WhiteWin,
-                Color::White => break Wdl::BlackWin,
-            }
-        } else if res.score > eval::MATE_IN_PLY {
-            // current side is winning
-            match pos.side {
-                Color::Black => break Wdl::BlackWin,
-                Color::White => break Wdl::WhiteWin,
-            }
+        if let Some(outcome) = adjudicator.update(pos.side, eval::normalize(res.score)) {
+            break Wdl::from(outcome);
+        }
+
+        if let Some(count) = config.policy_moves {
+            let mut moves = search.root_move_scores();
+            moves.truncate(count as usize);
+            policy.push(PolicyRecord {
+                bestmove: flip_move(res.bestmove, pos.side),
+                moves: moves
+                    .into_iter()
+                    .map(|(mv, score)| (flip_move(mv, pos.side), score))
+                    .collect(),
+            });
         }
 
         pos.make_move(res.bestmove);
@@ -419,55 +1159,102 @@ fn playout(
 
     let mut compressed_positions = Vec::with_capacity(positions.len());
     let mut pos = startpos.clone();
+    let num_positions = positions.len();
     // build up CompressedPositions
-    for p in positions {
-        compressed_positions.push(CompressedPosition::new(&pos, p.score, result));
+    for (i, p) in positions.into_iter().enumerate() {
+        compressed_positions.push(CompressedPosition::new(
+            &pos,
+            p.score,
+            p.bestmove,
+            result,
+            (num_random + i) as u16,
+            (num_positions - i) as u16,
+        ));
         pos.make_move(p.bestmove);
     }
 
-    Ok(compressed_positions)
+    Ok((compressed_positions, policy))
 }
 
-pub fn shuffle_interleave(inputs: &[PathBuf], output: &PathBuf) {
-    let mut rng = SmallRng::from_entropy();
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dedup {
+    Off,
+    KeepFirst,
+    AverageScores,
+}
 
-    let mut all_positions = Vec::new();
-    for input in inputs.iter() {
-        let file = std::fs::File::open(input).unwrap();
-        let mut reader = std::io::BufReader::new(file);
-        loop {
-            let mut cp = CompressedPosition {
-                occ: Bitboard::EMPTY,
-                pieces: [0; 16],
-                score: 0,
-                wdl: 0,
-                extra: [0; 5],
-            };
+pub fn read_data_file(path: &Path) -> anyhow::Result<(DataHeader, Vec<CompressedPosition>)> {
+    let file = reader::DataFile::open(path)?;
+    Ok((file.header().clone(), file.iter().collect()))
+}
+
+// Drops records whose reconstructed position was already seen. The first record wins, but its
+// score can be replaced by the mean over all of its duplicates.
+fn dedup_positions(positions: Vec<CompressedPosition>, dedup: Dedup) -> Vec<CompressedPosition> {
+    if dedup == Dedup::Off {
+        return positions;
+    }
+
+    // zobrist key -> (index into unique, score sum, count)
+    let mut seen: HashMap<u64, (usize, i32, i32)> = HashMap::new();
+    let mut unique = Vec::with_capacity(positions.len());
+
+    for cp in positions {
+        let Some(pos) = cp.to_position() else {
+            continue;
+        };
 
-            let bytes_read = reader.read(cp.as_mut_bytes()).unwrap();
-            if bytes_read == 0 {
-                break;
+        match seen.entry(u64::from(pos.key)) {
+            Entry::Occupied(mut entry) => {
+                let (_, sum, count) = entry.get_mut();
+                *sum += cp.score as i32;
+                *count += 1;
             }
-            if cp.score.abs() > 20_000 {
-                continue;
+            Entry::Vacant(entry) => {
+                entry.insert((unique.len(), cp.score as i32, 1));
+                unique.push(cp);
             }
+        }
+    }
 
-            all_positions.push(cp);
+    if dedup == Dedup::AverageScores {
+        for (idx, sum, count) in seen.into_values() {
+            unique[idx].score = (sum / count) as i16;
         }
     }
 
+    unique
+}
+
+pub fn shuffle_interleave(
+    inputs: &[PathBuf],
+    output: &PathBuf,
+    dedup: Dedup,
+) -> anyhow::Result<()> {
+    let mut rng = SmallRng::from_entropy();
+
+    let mut all_positions = Vec::new();
+    for input in inputs.iter() {
+        let (_, positions) = read_data_file(input)?;
+        all_positions.extend(positions.into_iter().filter(|cp| cp.score.abs() <= 20_000));
+    }
+
+    let num_read = all_positions.len();
+    let mut all_positions = dedup_positions(all_positions, dedup);
+    if dedup != Dedup::Off {
+        println!(
+            "Removed {} duplicate positions",
+            num_read - all_positions.len()
+        );
+    }
+
     all_positions.shuffle(&mut rng);
 
-    let mut file = OpenOptions::new()
-        .read(true)
-        .create(true)
-        .append(true)
-        .open(output)
-        .unwrap();
+    let mut file = std::fs::File::create(output)?;
+    DataHeader::new(None).write(&mut file)?;
 
     for p in all_positions.iter() {
-        file.write_all(p.as_bytes())
-            .expect("Failed to write to file");
+        file.write_all(p.as_bytes())?;
     }
 
     println!(
@@ -475,24 +1262,676 @@ pub fn shuffle_interleave(inputs: &[PathBuf], output: &PathBuf) {
         all_positions.len(),
         output.display()
     );
+
+    Ok(())
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum RecordError {
+    #[error("bad piece encoding or missing king")]
+    Unreadable,
+    #[error("pawn on first or last rank")]
+    PawnOnBackRank,
+    #[error("side not to move is in check")]
+    OpponentInCheck,
+    #[error("score {0} out of bounds")]
+    ScoreOutOfBounds(i16),
+    #[error("invalid wdl {0}")]
+    InvalidWdl(u8),
+    #[error("bestmove {0:#06x} is not a move of the position")]
+    IllegalBestmove(u16),
+}
+
+pub fn data_verify(inputs: &[PathBuf]) -> anyhow::Result<()> {
+    const MAX_REPORTED: usize = 10;
+
+    let record_size = std::mem::size_of::<CompressedPosition>() as u64;
+    let mut total_corrupt = 0;
+
+    for input in inputs.iter() {
+        let file = std::fs::File::open(input)?;
+        let file_len = file.metadata()?.len();
+        let mut reader = BufReader::new(file);
+
+        if let Err(e) = DataHeader::read(&mut reader) {
+            println!("{}: bad header: {}", input.display(), e);
+            total_corrupt += 1;
+            continue;
+        }
+
+        let data_start = reader.stream_position()?;
+        let num_records = (file_len - data_start) / record_size;
+        let trailing = (file_len - data_start) % record_size;
+
+        let mut corrupt = 0;
+        for idx in 0..num_records {
+            let mut cp = CompressedPosition::default();
+            reader.read_exact(cp.as_mut_bytes())?;
+
+            if let Err(e) = cp.validate() {
+                if corrupt < MAX_REPORTED {
+                    println!(
+                        "{}: record {} at offset {}: {}",
+                        input.display(),
+                        idx,
+                        data_start + idx * record_size,
+                        e
+                    );
+                }
+                corrupt += 1;
+            }
+        }
+
+        if corrupt > MAX_REPORTED {
+            println!(
+                "{}: ... and {} more corrupt records",
+                input.display(),
+                corrupt - MAX_REPORTED
+            );
+        }
+
+        if trailing > 0 {
+            println!(
+                "{}: truncated record at offset {} ({} trailing bytes)",
+                input.display(),
+                data_start + num_records * record_size,
+                trailing
+            );
+            corrupt += 1;
+        }
+
+        println!(
+            "{}: {} records, {} corrupt",
+            input.display(),
+            num_records,
+            corrupt
+        );
+        total_corrupt += corrupt;
+    }
+
+    if total_corrupt > 0 {
+        return Err(anyhow::anyhow!("found {} problems", total_corrupt));
+    }
+
+    println!("All files ok");
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum DataFormat {
+    Dat,
+    Text,
+}
+
+pub fn data_convert(
+    input: &Path,
+    output: &Path,
+    from: DataFormat,
+    to: DataFormat,
+) -> anyhow::Result<()> {
+    if from == to {
+        return Err(anyhow::anyhow!("input and output formats are the same"));
+    }
+
+    let positions = match from {
+        DataFormat::Dat => read_data_file(input)?.1,
+        DataFormat::Text => {
+            let reader = BufReader::new(std::fs::File::open(input)?);
+            let mut positions = Vec::new();
+            for (idx, line) in reader.lines().enumerate() {
+                let line = line?;
+                if line.trim().is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                let cp = CompressedPosition::from_text(&line)
+                    .map_err(|e| anyhow::anyhow!("line {}: {}", idx + 1, e))?;
+                positions.push(cp);
+            }
+            positions
+        }
+    };
+
+    let mut writer = BufWriter::new(std::fs::File::create(output)?);
+    match to {
+        DataFormat::Dat => {
+            DataHeader::new(None).write(&mut writer)?;
+            for cp in positions.iter() {
+                writer.write_all(cp.as_bytes())?;
+            }
+        }
+        DataFormat::Text => {
+            for (idx, cp) in positions.iter().enumerate() {
+                let line = cp
+                    .to_text()
+                    .ok_or_else(|| anyhow::anyhow!("record {} can't be written as text", idx))?;
+                writeln!(writer, "{}", line)?;
+            }
+        }
+    }
+    writer.flush()?;
+
+    println!(
+        "Converted {} positions from {} to {}",
+        positions.len(),
+        input.display(),
+        output.display()
+    );
+
+    Ok(())
+}
+
+#[derive(Debug)]
+struct DataStats {
+    records: u64,
+    invalid: u64,
+    duplicates: u64,
+    // win, draw, loss from the side to move's point of view
+    wdl: [u64; 3],
+    // 100cp wide buckets from -1000 to 1000, plus one bucket for each tail
+    scores: [u64; 22],
+    pieces: [u64; 33],
+}
+
+impl DataStats {
+    fn new() -> Self {
+        Self {
+            records: 0,
+            invalid: 0,
+            duplicates: 0,
+            wdl: [0; 3],
+            scores: [0; 22],
+            pieces: [0; 33],
+        }
+    }
+
+    fn score_bucket(score: i16) -> usize {
+        if score < -1000 {
+            0
+        } else if score >= 1000 {
+            21
+        } else {
+            ((score as i32 + 1000) / 100 + 1) as usize
+        }
+    }
+
+    fn percent(&self, count: u64) -> f64 {
+        count as f64 * 100.0 / self.records.max(1) as f64
+    }
+}
+
+pub fn data_stats(inputs: &[PathBuf]) -> anyhow::Result<()> {
+    let mut stats = DataStats::new();
+    let mut seen = HashSet::new();
+
+    for input in inputs.iter() {
+        let (header, positions) = read_data_file(input)?;
+        println!(
+            "{}: {} records (format v{})",
+            input.display(),
+            positions.len(),
+            header.version
+        );
+
+        for cp in positions {
+            stats.records += 1;
+
+            match cp.wdl {
+                2 => stats.wdl[0] += 1,
+                1 => stats.wdl[1] += 1,
+                _ => stats.wdl[2] += 1,
+            }
+
+            stats.scores[DataStats::score_bucket(cp.score)] += 1;
+            stats.pieces[(cp.occ.count() as usize).min(32)] += 1;
+
+            match cp.to_position() {
+                Some(pos) => {
+                    if !seen.insert(u64::from(pos.key)) {
+                        stats.duplicates += 1;
+                    }
+                }
+                None => stats.invalid += 1,
+            }
+        }
+    }
+
+    println!();
+    println!("Records: {}", stats.records);
+    println!(
+        "Invalid: {} ({:.2}%)",
+        stats.invalid,
+        stats.percent(stats.invalid)
+    );
+    println!(
+        "Duplicates: {} ({:.2}%)",
+        stats.duplicates,
+        stats.percent(stats.duplicates)
+    );
+
+    println!();
+    println!("WDL (side to move):");
+    for (name, count) in ["Win", "Draw", "Loss"].iter().zip(stats.wdl) {
+        println!("{:>6}: {:>10} ({:.2}%)", name, count, stats.percent(count));
+    }
+
+    println!();
+    println!("Scores:");
+    for (bucket, &count) in stats.scores.iter().enumerate() {
+        let label = match bucket {
+            0 => "< -1000".to_string(),
+            21 => ">= 1000".to_string(),
+            _ => {
+                let low = (bucket as i32 - 1) * 100 - 1000;
+                format!("{}..{}", low, low + 100)
+            }
+        };
+        println!(
+            "{:>12}: {:>10} ({:.2}%)",
+            label,
+            count,
+            stats.percent(count)
+        );
+    }
+
+    println!();
+    println!("Piece count:");
+    for (num_pieces, &count) in stats.pieces.iter().enumerate() {
+        if count > 0 {
+            println!(
+                "{:>6}: {:>10} ({:.2}%)",
+                num_pieces,
+                count,
+                stats.percent(count)
+            );
+        }
+    }
+
+    Ok(())
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::fen::Fen;
+    use crate::{fen::Fen, movegen::init_tables, zobrist::init_zobrist};
 
     const STARTPOS: &str = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1";
     const STARTPOS_FLIPPED: &str = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1";
 
+    #[test]
+    fn test_policy_record_roundtrip() {
+        let records = vec![
+            PolicyRecord {
+                bestmove: Move::new(Square::E2, Square::E4, None),
+                moves: vec![
+                    (Move::new(Square::E2, Square::E4, None), 35),
+                    (Move::new(Square::D2, Square::D4, None), -12),
+                ],
+            },
+            PolicyRecord {
+                bestmove: Move::new(Square::A7, Square::A8, Some(Role::Queen)),
+                moves: vec![],
+            },
+        ];
+
+        let mut bytes = Vec::new();
+        for record in &records {
+            record.write(&mut bytes).unwrap();
+        }
+        assert_eq!(bytes.len(), 3 + 2 * 4 + 3);
+
+        let mut reader = bytes.as_slice();
+        for record in &records {
+            assert_eq!(
+                PolicyRecord::read(&mut reader).unwrap().as_ref(),
+                Some(record)
+            );
+        }
+        assert_eq!(PolicyRecord::read(&mut reader).unwrap(), None);
+    }
+
+    #[test]
+    fn test_policy_matches_positions() {
+        init_tables();
+        init_zobrist();
+        crate::search::init_reductions();
+
+        // no castling and no pawns that could take en passant, records can't show either
+        let Fen(startpos) = Fen::parse("r3k3/7p/8/8/8/8/P7/R3K3 w - - 0 1").unwrap();
+        let config = DatagenConfig {
+            limits: Limits {
+                depth: Some(4),
+                ..Limits::new()
+            },
+            max_plies: Some(12),
+            policy_moves: Some(4),
+            opening: OpeningConfig {
+                min_plies: 1,
+                max_plies: 1,
+                ..OpeningConfig::default()
+            },
+            ..test_config()
+        };
+        let tt = Arc::new(Table::new_mb(1));
+        let mut rng = SmallRng::seed_from_u64(7);
+        let (positions, policy) = (0..10)
+            .find_map(|_| playout(&startpos, &config, tt.clone(), &mut rng).ok())
+            .unwrap();
+
+        assert_eq!(positions.len(), policy.len());
+        // the random ply leaves black to move first
+        assert_eq!(positions[0].ply % 2, 1);
+        for (position, record) in positions.iter().zip(&policy) {
+            assert_eq!(position.validate(), Ok(()));
+            let legal = MoveGen::new(&position.to_position().unwrap()).collect::<Vec<_>>();
+            assert_eq!(record.bestmove, position.bestmove());
+            assert!(legal.contains(&record.bestmove));
+            assert!(!record.moves.is_empty());
+            assert!(record.moves.iter().all(|(mv, _)| legal.contains(mv)));
+        }
+    }
+
+    #[test]
+    fn test_random_move() {
+        init_tables();
+        init_zobrist();
+        crate::search::init_reductions();
+
+        // the queen hangs to the rook
+        let Fen(pos) = Fen::parse("3qk3/8/8/8/8/8/8/3RK3 w - - 0 1").unwrap();
+        let moves = MoveGen::legal_vec(&pos);
+        let tt = Arc::new(Table::new_mb(1));
+        let mut rng = SmallRng::seed_from_u64(7);
+        let rxd8 = "d1d8".parse::<Move>().unwrap();
+
+        let best = OpeningConfig {
+            top_k: Some(1),
+            ..Default::default()
+        };
+        for _ in 0..4 {
+            assert_eq!(random_move(&pos, &moves, &best, &tt, &mut rng), Some(rxd8));
+        }
+
+        // a low temperature all but rules out the moves that leave the queen alone
+        let cold = OpeningConfig {
+            top_k: Some(4),
+            temperature: 10,
+            ..Default::default()
+        };
+        assert_eq!(random_move(&pos, &moves, &cold, &tt, &mut rng), Some(rxd8));
+
+        let any = OpeningConfig::default();
+        let mv = random_move(&pos, &moves, &any, &tt, &mut rng).unwrap();
+        assert!(moves.contains(&mv));
+        assert_eq!(random_move(&pos, &[], &any, &tt, &mut rng), None);
+    }
+
     #[test]
     fn test_startpos() {
         let Fen(pos) = Fen::parse(STARTPOS).unwrap();
         let Fen(flipped_pos) = Fen::parse(STARTPOS_FLIPPED).unwrap();
 
-        let comp = CompressedPosition::new(&pos, 0, Wdl::BlackWin);
-        let comp_flipped = CompressedPosition::new(&flipped_pos, 0, Wdl::BlackWin);
+        let comp = CompressedPosition::new(&pos, 0, Move::NONE, Wdl::BlackWin, 0, 0);
+        let comp_flipped =
+            CompressedPosition::new(&flipped_pos, 0, Move::NONE, Wdl::BlackWin, 0, 0);
 
         assert_eq!(comp, comp_flipped);
     }
+
+    #[test]
+    fn test_metadata() {
+        let Fen(pos) = Fen::parse("8/8/4k3/8/8/4K3/4P3/8 w - - 3 300").unwrap();
+
+        let comp = CompressedPosition::new(&pos, 120, Move::NONE, Wdl::WhiteWin, 42, 7);
+
+        assert_eq!(comp.ply, 42);
+        assert_eq!(comp.plies_to_end, 7);
+        assert_eq!(comp.fullmove, u8::MAX);
+        assert_eq!(comp.wdl, Wdl::WhiteWin as u8);
+    }
+
+    #[test]
+    fn test_to_position() {
+        init_tables();
+        init_zobrist();
+
+        let Fen(pos) =
+            Fen::parse("r1bqkbnr/pppp1ppp/2n5/4p3/4P3/5N2/PPPP1PPP/RNBQKB1R w KQkq - 2 3").unwrap();
+        let comp = CompressedPosition::new(&pos, 0, Move::NONE, Wdl::Draw, 0, 0);
+        assert_eq!(
+            comp.to_position().unwrap().to_fen(),
+            "r1bqkbnr/pppp1ppp/2n5/4p3/4P3/5N2/PPPP1PPP/RNBQKB1R w - - 0 1"
+        );
+
+        // black to move comes back mirrored with white to move
+        let Fen(pos) =
+            Fen::parse("rnbqkbnr/pppp1ppp/8/4p3/4P3/5N2/PPPP1PPP/RNBQKB1R b KQkq - 1 2").unwrap();
+        let comp = CompressedPosition::new(&pos, 0, Move::NONE, Wdl::Draw, 0, 0);
+        assert_eq!(
+            comp.to_position().unwrap().to_fen(),
+            "rnbqkb1r/pppp1ppp/5n2/4p3/4P3/8/PPPP1PPP/RNBQKBNR w - - 0 1"
+        );
+    }
+
+    #[test]
+    fn test_dedup() {
+        init_tables();
+        init_zobrist();
+
+        let Fen(pos) = Fen::parse(STARTPOS).unwrap();
+        let a = CompressedPosition::new(&pos, 10, Move::NONE, Wdl::Draw, 0, 0);
+        let b = CompressedPosition::new(&pos, 30, Move::NONE, Wdl::Draw, 0, 0);
+
+        let unique = dedup_positions(vec![a, b], Dedup::KeepFirst);
+        assert_eq!(unique, vec![a]);
+
+        let unique = dedup_positions(vec![a, b], Dedup::AverageScores);
+        assert_eq!(unique.len(), 1);
+        assert_eq!(unique[0].score(), 20);
+    }
+
+    #[test]
+    fn test_score_buckets() {
+        assert_eq!(DataStats::score_bucket(-20_000), 0);
+        assert_eq!(DataStats::score_bucket(-1000), 1);
+        assert_eq!(DataStats::score_bucket(-1), 10);
+        assert_eq!(DataStats::score_bucket(0), 11);
+        assert_eq!(DataStats::score_bucket(999), 20);
+        assert_eq!(DataStats::score_bucket(1000), 21);
+    }
+
+    #[test]
+    fn test_text_roundtrip() {
+        init_tables();
+        init_zobrist();
+
+        let line =
+            "rnbqkb1r/pppp1ppp/5n2/4p3/4P3/8/PPPP1PPP/RNBQKBNR w - - 0 2 | -35 | 0.5 | 3 | 80 \
+                    | f1c4";
+        let cp = CompressedPosition::from_text(line).unwrap();
+        assert_eq!(cp.to_text().unwrap(), line);
+
+        // black to move gets flipped, along with the score, result and move
+        let cp = CompressedPosition::from_text(
+            "rnbqkbnr/pppp1ppp/8/4p3/4P3/5N2/PPPP1PPP/RNBQKB1R b - - 0 2 | 35 | 0.0 | 3 | 80 \
+             | b8c6",
+        )
+        .unwrap();
+        assert_eq!(
+            cp.to_text().unwrap(),
+            "rnbqkb1r/pppp1ppp/5n2/4p3/4P3/8/PPPP1PPP/RNBQKBNR w - - 0 2 | -35 | 1.0 | 3 | 80 \
+             | b1c3"
+        );
+
+        // the move is optional
+        let cp = CompressedPosition::from_text(
+            "rnbqkb1r/pppp1ppp/5n2/4p3/4P3/8/PPPP1PPP/RNBQKBNR w - - 0 2 | -35 | 0.5",
+        )
+        .unwrap();
+        assert_eq!(cp.bestmove(), Move::NONE);
+    }
+
+    #[test]
+    fn test_validate() {
+        init_tables();
+        init_zobrist();
+
+        let Fen(pos) = Fen::parse(STARTPOS).unwrap();
+        let mut cp = CompressedPosition::new(&pos, 25, Move::NONE, Wdl::Draw, 0, 0);
+        assert_eq!(cp.validate(), Ok(()));
+
+        cp.wdl = 7;
+        assert_eq!(cp.validate(), Err(RecordError::InvalidWdl(7)));
+
+        let Fen(pos) = Fen::parse("4k3/8/8/8/8/8/8/P3K3 w - - 0 1").unwrap();
+        let cp = CompressedPosition::new(&pos, 25, Move::NONE, Wdl::Draw, 0, 0);
+        assert_eq!(cp.validate(), Err(RecordError::PawnOnBackRank));
+
+        let Fen(pos) = Fen::parse("4k3/8/8/8/8/8/8/4RK2 b - - 0 1").unwrap();
+        let cp = CompressedPosition::new(&pos, 25, Move::NONE, Wdl::Draw, 0, 0);
+        assert_eq!(cp.validate(), Ok(()));
+
+        let Fen(pos) = Fen::parse("4k3/8/8/8/8/8/8/4RK2 w - - 0 1").unwrap();
+        let cp = CompressedPosition::new(&pos, 25, Move::NONE, Wdl::Draw, 0, 0);
+        assert_eq!(cp.validate(), Err(RecordError::OpponentInCheck));
+
+        let mut cp = CompressedPosition::new(&pos, 25, Move::NONE, Wdl::Draw, 0, 0);
+        cp.pieces = [0; 16];
+        assert_eq!(cp.validate(), Err(RecordError::Unreadable));
+
+        let Fen(pos) = Fen::parse("r3k3/8/8/3pP3/8/8/8/R3K2R w KQq d6 0 1").unwrap();
+        let bestmove = |mv: Move| CompressedPosition::new(&pos, 25, mv, Wdl::Draw, 0, 0);
+        let moves = [
+            Move::new(Square::A1, Square::A8, None),
+            Move::new_castle(Square::E1, Square::G1),
+            Move::new_en_passant(Square::E5, Square::D6),
+        ];
+        for mv in moves {
+            assert_eq!(bestmove(mv).validate(), Ok(()));
+            assert!(bestmove(mv).to_text().is_some());
+        }
+
+        // flags no move has, a promotion to a king and one to nothing at all
+        let moves = [
+            Move::new(Square::A1, Square::B2, None),
+            Move::new_castle(Square::A1, Square::C1),
+            Move::new_en_passant(Square::E5, Square::F6),
+            (u16::from(Move::new(Square::E5, Square::E6, None)) | 0b1110 << 12).into(),
+            Move::NULL,
+        ];
+        for mv in moves {
+            let cp = bestmove(mv);
+            assert_eq!(cp.validate(), Err(RecordError::IllegalBestmove(u16::from(mv))));
+            assert_eq!(cp.to_text(), None);
+        }
+    }
+
+    fn test_config() -> DatagenConfig {
+        DatagenConfig {
+            limits: Limits {
+                depth: Some(7),
+                ..Limits::new()
+            },
+            num_games: 100,
+            tt_size_mb: 16,
+            concurrency: 2,
+            out_path: PathBuf::from("data"),
+            state_path: None,
+            shard_games: None,
+            shard_bytes: None,
+            pin_threads: false,
+            max_plies: Some(400),
+            policy_moves: None,
+            opening: OpeningConfig::default(),
+            shared_tt: false,
+        }
+    }
+
+    #[test]
+    fn test_header_roundtrip() {
+        let header = DataHeader::new(Some(test_config()));
+
+        let mut bytes = Vec::new();
+        header.write(&mut bytes).unwrap();
+        assert_eq!(DataHeader::read(&mut bytes.as_slice()).unwrap(), header);
+
+        bytes[0] = b'X';
+        assert!(matches!(
+            DataHeader::read(&mut bytes.as_slice()),
+            Err(DataHeaderError::InvalidMagic(_))
+        ));
+    }
+
+    #[test]
+    fn test_state_save() {
+        let dir = std::env::temp_dir().join(format!("pounce_state_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("state.json");
+
+        let state = DatagenState {
+            white_wins: 3,
+            black_wins: 2,
+            draws: 1,
+            config: test_config(),
+        };
+        state.save(&path).unwrap();
+        state.save(&path).unwrap();
+
+        let loaded: DatagenState =
+            serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(loaded.white_wins + loaded.black_wins + loaded.draws, 6);
+        assert_eq!(loaded.config, state.config);
+        // the temp file is renamed away
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_resume_policy() {
+        init_tables();
+        init_zobrist();
+
+        let dir = std::env::temp_dir().join(format!("pounce_policy_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = DatagenConfig {
+            out_path: dir.clone(),
+            policy_moves: Some(4),
+            ..test_config()
+        };
+        let Fen(pos) = Fen::parse(STARTPOS).unwrap();
+        let e2e4 = Move::new(Square::E2, Square::E4, None);
+        let position = CompressedPosition::new(&pos, 0, e2e4, Wdl::Draw, 0, 0);
+        let record = PolicyRecord {
+            bestmove: e2e4,
+            moves: vec![(e2e4, 20)],
+        };
+        let policy_path = dir.join("0.policy");
+        let policy_len = || std::fs::metadata(&policy_path).unwrap().len();
+
+        let mut writer = ShardWriter::new(0, &config).unwrap();
+        writer.write_game(&[position; 2], &[record.clone(), record.clone()]).unwrap();
+        drop(writer);
+        let written = policy_len();
+
+        // a crash mid game leaves part of a position and more policy than positions
+        let mut dat = OpenOptions::new().append(true).open(dir.join("0.dat")).unwrap();
+        dat.write_all(&[0; 5]).unwrap();
+        let mut policy = OpenOptions::new().append(true).open(&policy_path).unwrap();
+        record.write(&mut policy).unwrap();
+        policy.write_all(&[1, 2]).unwrap();
+        drop(ShardWriter::new(0, &config).unwrap());
+        assert_eq!(policy_len(), written);
+
+        // positions without their policy can't be paired up again
+        std::fs::write(&policy_path, &[0; 3]).unwrap();
+        assert!(ShardWriter::new(0, &config).is_err());
+
+        let streaming = DatagenConfig {
+            out_path: PathBuf::from("-"),
+            ..config
+        };
+        assert!(ShardWriter::new(0, &streaming).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -0,0 +1,248 @@
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+    sync::{atomic::Ordering, Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use rand::{rngs::SmallRng, Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    run_threads, start_streaming, DatagenConfig, BLACK_WINS, DRAWS, STOP, TOTAL_GAMES, WHITE_WINS,
+};
+
+// The protocol is one json message per line. A worker always opens with a report (empty on the
+// first message) and the coordinator answers every report with either more work or done.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GameStats {
+    pub white_wins: u32,
+    pub black_wins: u32,
+    pub draws: u32,
+}
+
+impl GameStats {
+    fn total(&self) -> u32 {
+        self.white_wins + self.black_wins + self.draws
+    }
+
+    fn current() -> Self {
+        GameStats {
+            white_wins: WHITE_WINS.load(Ordering::Relaxed),
+            black_wins: BLACK_WINS.load(Ordering::Relaxed),
+            draws: DRAWS.load(Ordering::Relaxed),
+        }
+    }
+
+    fn since(&self, earlier: &GameStats) -> Self {
+        GameStats {
+            white_wins: self.white_wins - earlier.white_wins,
+            black_wins: self.black_wins - earlier.black_wins,
+            draws: self.draws - earlier.draws,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Report {
+    completed: GameStats,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum Assignment {
+    Work { games: u32, seed: u64 },
+    Done,
+}
+
+struct CoordinatorState {
+    active_workers: u32,
+    unassigned: u32,
+    completed: GameStats,
+    rng: SmallRng,
+}
+
+fn send<T: Serialize>(stream: &mut TcpStream, msg: &T) -> anyhow::Result<()> {
+    let mut line = serde_json::to_string(msg)?;
+    line.push('\n');
+    stream.write_all(line.as_bytes())?;
+    Ok(())
+}
+
+fn receive<T: for<'de> Deserialize<'de>>(reader: &mut impl BufRead) -> anyhow::Result<T> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Err(anyhow::anyhow!("connection closed"));
+    }
+    Ok(serde_json::from_str(&line)?)
+}
+
+pub fn coordinate(addr: &str, num_games: u32, batch_size: u32) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    listener.set_nonblocking(true)?;
+    println!("Coordinating {} games on {}", num_games, addr);
+
+    let state = Arc::new(Mutex::new(CoordinatorState {
+        active_workers: 0,
+        unassigned: num_games,
+        completed: GameStats::default(),
+        rng: SmallRng::from_entropy(),
+    }));
+
+    let mut last_log = Instant::now();
+    loop {
+        let (completed, active_workers) = {
+            let state = state.lock().unwrap();
+            (state.completed, state.active_workers)
+        };
+        // keep going until every worker has been told we're done
+        if completed.total() >= num_games && active_workers == 0 {
+            break;
+        }
+
+        if last_log.elapsed() > Duration::from_secs(60) {
+            last_log = Instant::now();
+            println!(
+                "{}/{} Games, White wins: {}, Black wins: {}, Draws: {}",
+                completed.total(),
+                num_games,
+                completed.white_wins,
+                completed.black_wins,
+                completed.draws
+            );
+        }
+
+        match listener.accept() {
+            Ok((stream, peer)) => {
+                println!("Worker connected from {}", peer);
+                stream.set_nonblocking(false)?;
+                state.lock().unwrap().active_workers += 1;
+
+                let state = state.clone();
+                std::thread::spawn(move || {
+                    if let Err(e) = serve_worker(stream, &state, batch_size) {
+                        println!("Worker {} disconnected: {}", peer, e);
+                    }
+                    state.lock().unwrap().active_workers -= 1;
+                });
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                std::thread::sleep(Duration::from_millis(100));
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    let completed = state.lock().unwrap().completed;
+    println!();
+    println!(
+        "Total: {}, White wins: {}, Black wins: {}, Draws: {}",
+        completed.total(),
+        completed.white_wins,
+        completed.black_wins,
+        completed.draws
+    );
+    println!("See ya!");
+    Ok(())
+}
+
+fn serve_worker(
+    mut stream: TcpStream,
+    state: &Mutex<CoordinatorState>,
+    batch_size: u32,
+) -> anyhow::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut outstanding = 0;
+
+    loop {
+        let report = match receive::<Report>(&mut reader) {
+            Ok(report) => report,
+            Err(e) => {
+                // hand whatever this worker didn't finish to someone else
+                state.lock().unwrap().unassigned += outstanding;
+                return Err(e);
+            }
+        };
+
+        let assignment = {
+            let mut state = state.lock().unwrap();
+            let completed = report.completed;
+            state.completed.white_wins += completed.white_wins;
+            state.completed.black_wins += completed.black_wins;
+            state.completed.draws += completed.draws;
+
+            // workers can overshoot their quota by a game or two per thread
+            state.unassigned += outstanding.saturating_sub(completed.total());
+
+            let games = state.unassigned.min(batch_size);
+            state.unassigned -= games;
+            outstanding = games;
+
+            if games == 0 {
+                Assignment::Done
+            } else {
+                Assignment::Work {
+                    games,
+                    seed: state.rng.gen(),
+                }
+            }
+        };
+
+        send(&mut stream, &assignment)?;
+        if let Assignment::Done = assignment {
+            return Ok(());
+        }
+    }
+}
+
+pub fn connect(addr: &str, mut config: DatagenConfig) -> anyhow::Result<()> {
+    ctrlc::set_handler(move || {
+        STOP.store(true, Ordering::Relaxed);
+    })?;
+
+    if config.streaming() {
+        start_streaming(&config)?;
+    } else {
+        std::fs::create_dir_all(&config.out_path)?;
+    }
+
+    let mut stream = TcpStream::connect(addr)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    log!("Connected to coordinator at {}", addr);
+
+    let mut completed = GameStats::default();
+    loop {
+        send(&mut stream, &Report { completed })?;
+        if STOP.load(Ordering::Relaxed) {
+            log!("Stopped by user");
+            break;
+        }
+
+        match receive::<Assignment>(&mut reader)? {
+            Assignment::Work { games, seed } => {
+                log!("Received {} games (seed {:#x})", games, seed);
+                let before = GameStats::current();
+
+                config.num_games = TOTAL_GAMES.load(Ordering::Relaxed) + games;
+                run_threads(&config, Some(seed));
+
+                completed = GameStats::current().since(&before);
+            }
+            Assignment::Done => {
+                log!("All games finished");
+                break;
+            }
+        }
+    }
+
+    let stats = GameStats::current();
+    log!();
+    log!(
+        "Total: {}, White wins: {}, Black wins: {}, Draws: {}",
+        stats.total(),
+        stats.white_wins,
+        stats.black_wins,
+        stats.draws
+    );
+    log!("See ya!");
+    Ok(())
+}
@@ -0,0 +1,282 @@
+use std::{ops::Deref, path::Path};
+
+use anyhow::{Context, Result};
+use rand::{rngs::SmallRng, Rng, SeedableRng};
+
+use super::{CompressedPosition, DataHeader};
+
+const RECORD_SIZE: usize = std::mem::size_of::<CompressedPosition>();
+
+// A file's bytes, mapped read only where that's supported and read into memory otherwise
+enum Bytes {
+    #[cfg(target_os = "linux")]
+    Mapped(Mmap),
+    Owned(Vec<u8>),
+}
+
+impl Deref for Bytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            #[cfg(target_os = "linux")]
+            Bytes::Mapped(mmap) => mmap,
+            Bytes::Owned(bytes) => bytes,
+        }
+    }
+}
+
+impl Bytes {
+    fn open(path: &Path) -> std::io::Result<Bytes> {
+        #[cfg(target_os = "linux")]
+        {
+            let file = std::fs::File::open(path)?;
+            let len = file.metadata()?.len() as usize;
+            // mapping an empty file fails, there's nothing to map anyway
+            if len > 0 {
+                return Mmap::new(&file, len).map(Bytes::Mapped);
+            }
+        }
+        std::fs::read(path).map(Bytes::Owned)
+    }
+}
+
+#[cfg(target_os = "linux")]
+struct Mmap {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+// the mapping is private and read only, so it can be shared like a &[u8]
+#[cfg(target_os = "linux")]
+unsafe impl Send for Mmap {}
+#[cfg(target_os = "linux")]
+unsafe impl Sync for Mmap {}
+
+#[cfg(target_os = "linux")]
+impl Mmap {
+    fn new(file: &std::fs::File, len: usize) -> std::io::Result<Mmap> {
+        use std::os::fd::AsRawFd;
+
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_PRIVATE,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(Mmap { ptr, len })
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Deref for Mmap {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr as *const u8, self.len) }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ptr, self.len);
+        }
+    }
+}
+
+// One .dat file with random access to its records. A record cut off at the end of the file
+// (from a crashed run) is ignored.
+pub struct DataFile {
+    header: DataHeader,
+    bytes: Bytes,
+    // where the records start, right after the header
+    offset: usize,
+}
+
+impl DataFile {
+    pub fn open(path: &Path) -> Result<DataFile> {
+        let bytes = Bytes::open(path).with_context(|| format!("opening {}", path.display()))?;
+        let mut rest = &bytes[..];
+        let header = DataHeader::read(&mut rest)
+            .map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?;
+        let offset = bytes.len() - rest.len();
+        Ok(DataFile {
+            header,
+            bytes,
+            offset,
+        })
+    }
+
+    pub fn header(&self) -> &DataHeader {
+        &self.header
+    }
+
+    pub fn len(&self) -> usize {
+        (self.bytes.len() - self.offset) / RECORD_SIZE
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get(&self, index: usize) -> Option<CompressedPosition> {
+        if index >= self.len() {
+            return None;
+        }
+        let start = self.offset + index * RECORD_SIZE;
+        let mut cp = CompressedPosition::default();
+        // copied out since the mapping gives no alignment guarantees
+        cp.as_mut_bytes()
+            .copy_from_slice(&self.bytes[start..start + RECORD_SIZE]);
+        Some(cp)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = CompressedPosition> + '_ {
+        (0..self.len()).map(|i| self.get(i).unwrap())
+    }
+}
+
+// Reads the records of several files one after the other, or with a shuffle buffer, in a
+// random order. Shuffling keeps `buffer_size` records in memory and hands out a random one
+// of them each time, which mixes positions from different games without loading everything.
+//
+//     let reader = DataReader::open(&paths)?.shuffled(1 << 20, seed);
+//     for position in reader { ... }
+pub struct DataReader {
+    files: Vec<DataFile>,
+    file: usize,
+    record: usize,
+    shuffle: Option<(Vec<CompressedPosition>, usize, SmallRng)>,
+}
+
+impl DataReader {
+    pub fn open<P: AsRef<Path>>(paths: &[P]) -> Result<DataReader> {
+        let files = paths
+            .iter()
+            .map(|path| DataFile::open(path.as_ref()))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(DataReader::new(files))
+    }
+
+    pub fn new(files: Vec<DataFile>) -> DataReader {
+        DataReader {
+            files,
+            file: 0,
+            record: 0,
+            shuffle: None,
+        }
+    }
+
+    pub fn shuffled(mut self, buffer_size: usize, seed: u64) -> DataReader {
+        let buffer = Vec::with_capacity(buffer_size.max(1));
+        self.shuffle = Some((buffer, buffer_size.max(1), SmallRng::seed_from_u64(seed)));
+        self
+    }
+
+    pub fn files(&self) -> &[DataFile] {
+        &self.files
+    }
+
+    // total records over all files
+    pub fn total_records(&self) -> usize {
+        self.files.iter().map(DataFile::len).sum()
+    }
+
+    fn next_in_order(&mut self) -> Option<CompressedPosition> {
+        while let Some(file) = self.files.get(self.file) {
+            if let Some(cp) = file.get(self.record) {
+                self.record += 1;
+                return Some(cp);
+            }
+            self.file += 1;
+            self.record = 0;
+        }
+        None
+    }
+}
+
+impl Iterator for DataReader {
+    type Item = CompressedPosition;
+
+    fn next(&mut self) -> Option<CompressedPosition> {
+        let Some((mut buffer, size, mut rng)) = self.shuffle.take() else {
+            return self.next_in_order();
+        };
+
+        while buffer.len() < size {
+            match self.next_in_order() {
+                Some(cp) => buffer.push(cp),
+                None => break,
+            }
+        }
+        let next = (!buffer.is_empty()).then(|| {
+            let index = rng.gen_range(0..buffer.len());
+            buffer.swap_remove(index)
+        });
+
+        self.shuffle = Some((buffer, size, rng));
+        next
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::HashSet, io::Write};
+
+    use super::*;
+
+    // records that only differ in their ply so they're easy to tell apart
+    fn write_file(path: &Path, plies: std::ops::Range<u16>, tail: &[u8]) {
+        let mut file = std::fs::File::create(path).unwrap();
+        DataHeader::new(None).write(&mut file).unwrap();
+        for ply in plies {
+            let cp = CompressedPosition {
+                ply,
+                ..Default::default()
+            };
+            file.write_all(cp.as_bytes()).unwrap();
+        }
+        file.write_all(tail).unwrap();
+    }
+
+    #[test]
+    fn test_reader() {
+        let dir = std::env::temp_dir().join(format!("pounce_reader_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let paths = [dir.join("0.dat"), dir.join("1.dat")];
+        write_file(&paths[0], 0..100, &[]);
+        write_file(&paths[1], 100..150, &[1, 2, 3]);
+
+        let file = DataFile::open(&paths[1]).unwrap();
+        assert_eq!(file.len(), 50);
+        assert_eq!(file.get(3).unwrap().ply, 103);
+        assert!(file.get(50).is_none());
+
+        let reader = DataReader::open(&paths).unwrap();
+        assert_eq!(reader.total_records(), 150);
+        let plies = reader.map(|cp| cp.ply).collect::<Vec<_>>();
+        assert_eq!(plies, (0..150).collect::<Vec<_>>());
+
+        let shuffled = DataReader::open(&paths)
+            .unwrap()
+            .shuffled(32, 1)
+            .map(|cp| cp.ply)
+            .collect::<Vec<_>>();
+        assert_ne!(shuffled, plies);
+        assert_eq!(
+            shuffled.iter().collect::<HashSet<_>>(),
+            plies.iter().collect::<HashSet<_>>()
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,404 @@
+use std::{
+    fmt::Display,
+    fs::File,
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    process::{Child, ChildStdin, ChildStdout, Command, Stdio},
+    time::Instant,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+
+use crate::{
+    adjudicate::{AdjudicationRules, Adjudicator, Outcome},
+    chess::Color,
+    eval,
+    fen::Fen,
+    limits::{Limits, TimeControl},
+    movegen::MoveGen,
+    pgn::{parse_san, PgnReader},
+    position::Position,
+    uci::Uci,
+};
+
+#[derive(Debug, Clone)]
+pub struct EngineConfig {
+    pub command: PathBuf,
+    pub options: Vec<(String, String)>,
+}
+
+// Parses `Name=Value` pairs as given to `setoption`
+pub fn parse_option(s: &str) -> Result<(String, String)> {
+    let (name, value) = s
+        .split_once('=')
+        .ok_or_else(|| anyhow!("expected `Name=Value`, got `{}`", s))?;
+    Ok((name.trim().to_string(), value.trim().to_string()))
+}
+
+// Where a game starts, a position and the moves played from it, which are sent along with it
+// so the engines know the game's history
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Opening {
+    pub fen: String,
+    pub moves: Vec<String>,
+}
+
+impl Opening {
+    pub fn startpos() -> Opening {
+        Opening {
+            fen: Uci::STARTPOS.to_string(),
+            moves: Vec::new(),
+        }
+    }
+
+    fn position(&self) -> Result<Position> {
+        let Fen(mut pos) = self.fen.parse()?;
+        for mv in &self.moves {
+            let legal = MoveGen::legal_vec(&pos);
+            let &mv = legal
+                .iter()
+                .find(|legal| legal.to_string() == *mv)
+                .ok_or_else(|| anyhow!("illegal opening move {} in {}", mv, self.fen))?;
+            pos.make_move(mv);
+        }
+        Ok(pos)
+    }
+}
+
+// Openings from a PGN file, each game's moves from its start, or otherwise from FEN or EPD
+// lines. EPD lines only need the first four fields.
+pub fn read_openings(path: &Path) -> Result<Vec<Opening>> {
+    let file = File::open(path).with_context(|| format!("opening {}", path.display()))?;
+    let mut openings = Vec::new();
+
+    if path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("pgn")) {
+        for game in PgnReader::new(BufReader::new(file)) {
+            let game = game?;
+            let fen = game.tag("FEN").unwrap_or(Uci::STARTPOS);
+            let Fen(mut pos) = fen.parse()?;
+            let mut moves = Vec::new();
+            for san in &game.moves {
+                let mv = parse_san(&pos, san)
+                    .with_context(|| format!("opening {}: {}", openings.len() + 1, san))?;
+                pos.make_move(mv);
+                moves.push(mv.to_string());
+            }
+            openings.push(Opening {
+                fen: fen.to_string(),
+                moves,
+            });
+        }
+    } else {
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            if fields.is_empty() || fields[0].starts_with('#') {
+                continue;
+            }
+            // a FEN's move counters, or made up when it's EPD and the rest are operations
+            let counters = match fields.get(4..6) {
+                Some(&[halfmove, fullmove])
+                    if halfmove.parse::<u32>().is_ok() && fullmove.parse::<u32>().is_ok() =>
+                {
+                    [halfmove, fullmove]
+                }
+                _ => ["0", "1"],
+            };
+            let fen = [fields.get(..4).unwrap_or(&fields), &counters].concat().join(" ");
+            Fen::parse(&fen).with_context(|| format!("bad opening: {}", line))?;
+            openings.push(Opening {
+                fen,
+                moves: Vec::new(),
+            });
+        }
+    }
+
+    if openings.is_empty() {
+        bail!("no openings in {}", path.display());
+    }
+    Ok(openings)
+}
+
+// A child process speaking UCI. The engine is told to quit when this is dropped.
+pub struct UciEngine {
+    pub name: String,
+    child: Child,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+}
+
+impl UciEngine {
+    pub fn spawn(config: &EngineConfig) -> Result<Self> {
+        let mut child = Command::new(&config.command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .with_context(|| format!("failed to start {}", config.command.display()))?;
+        let stdin = child.stdin.take().unwrap();
+        let stdout = BufReader::new(child.stdout.take().unwrap());
+
+        let mut engine = UciEngine {
+            name: config.command.display().to_string(),
+            child,
+            stdin,
+            stdout,
+        };
+
+        engine.send("uci")?;
+        loop {
+            let line = engine.read_line()?;
+            if let Some(name) = line.strip_prefix("id name ") {
+                engine.name = name.trim().to_string();
+            } else if line.trim() == "uciok" {
+                break;
+            }
+        }
+
+        for (name, value) in &config.options {
+            engine.send(&format!("setoption name {} value {}", name, value))?;
+        }
+        engine.ready()?;
+        Ok(engine)
+    }
+
+    fn send(&mut self, cmd: &str) -> Result<()> {
+        writeln!(self.stdin, "{}", cmd)?;
+        self.stdin.flush()?;
+        Ok(())
+    }
+
+    fn read_line(&mut self) -> Result<String> {
+        let mut line = String::new();
+        if self.stdout.read_line(&mut line)? == 0 {
+            bail!("{} closed its output", self.name);
+        }
+        Ok(line)
+    }
+
+    pub fn ready(&mut self) -> Result<()> {
+        self.send("isready")?;
+        while self.read_line()?.trim() != "readyok" {}
+        Ok(())
+    }
+
+    pub fn new_game(&mut self) -> Result<()> {
+        self.send("ucinewgame")?;
+        self.ready()
+    }
+
+    // Searches `fen` plus `moves` with the given limits, returning the bestmove and the last
+    // score the engine reported
+    pub fn go(
+        &mut self,
+        fen: &str,
+        moves: &[String],
+        limits: &Limits,
+    ) -> Result<(String, Option<i16>)> {
+        let position = if moves.is_empty() {
+            format!("position fen {}", fen)
+        } else {
+            format!("position fen {} moves {}", fen, moves.join(" "))
+        };
+        self.send(&position)?;
+        self.send(&format!("go {}", limits))?;
+
+        let mut score = None;
+        loop {
+            let line = self.read_line()?;
+            if line.starts_with("info") {
+                score = parse_score(&line).or(score);
+            } else if let Some(rest) = line.strip_prefix("bestmove") {
+                let bestmove = rest
+                    .split_whitespace()
+                    .next()
+                    .map(str::to_string)
+                    .ok_or_else(|| anyhow!("{} sent an empty bestmove", self.name))?;
+                return Ok((bestmove, score));
+            }
+        }
+    }
+}
+
+// The score of an info line in centipawns, mates as mate scores. Bounds count as scores too.
+fn parse_score(line: &str) -> Option<i16> {
+    let mut tokens = line.split_whitespace().skip_while(|&t| t != "score").skip(1);
+    let kind = tokens.next()?;
+    let value = tokens.next()?.parse::<i16>().ok()?;
+    match kind {
+        "cp" => Some(value),
+        "mate" if value > 0 => Some(eval::mate_in((2 * value - 1) as usize)),
+        "mate" => Some(eval::mated_in((-2 * value) as usize)),
+        _ => None,
+    }
+}
+
+impl Drop for UciEngine {
+    fn drop(&mut self) {
+        let _ = self.send("quit");
+        let _ = self.child.wait();
+    }
+}
+
+// Plays one game from `opening`. `engines` is indexed by color.
+pub fn play_game(
+    mut engines: [&mut UciEngine; 2],
+    opening: &Opening,
+    tc: &TimeControl,
+    rules: AdjudicationRules,
+) -> Result<Outcome> {
+    for engine in engines.iter_mut() {
+        engine.new_game()?;
+    }
+
+    let mut pos = opening.position()?;
+    let mut moves = opening.moves.clone();
+    let mut clocks = [tc.sessions[0].base; 2];
+    let mut adjudicator = Adjudicator::new(rules);
+
+    let loss = |pos: &Position| match pos.side {
+        Color::White => Outcome::BlackWin,
+        Color::Black => Outcome::WhiteWin,
+    };
+
+    loop {
+        if let Some(outcome) = adjudicator.position_result(&pos, moves.len() as u32) {
+            return Ok(outcome);
+        }
+        let legal = MoveGen::legal_vec(&pos);
+
+        let side = pos.side as usize;
+        let start = Instant::now();
+        // the moves the side to move has already played on the clock
+        let moves_played = ((moves.len() - opening.moves.len()) / 2) as u32;
+        let limits = tc.limits(clocks, moves_played);
+        let (bestmove, score) = engines[side].go(&opening.fen, &moves, &limits)?;
+        let elapsed = start.elapsed();
+        if elapsed > clocks[side] {
+            return Ok(loss(&pos));
+        }
+        clocks[side] = clocks[side] - elapsed + tc.bonus(moves_played);
+
+        let Some(&mv) = legal.iter().find(|mv| mv.to_string() == bestmove) else {
+            eprintln!("{} played illegal move {}", engines[side].name, bestmove);
+            return Ok(loss(&pos));
+        };
+        // no score breaks a run of agreeing ones like a level score would
+        if let Some(outcome) = adjudicator.update(pos.side, score.unwrap_or(0)) {
+            return Ok(outcome);
+        }
+        pos.make_move(mv);
+        moves.push(bestmove);
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct MatchScore {
+    pub wins: u32,
+    pub losses: u32,
+    pub draws: u32,
+}
+
+impl Display for MatchScore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let games = self.wins + self.losses + self.draws;
+        let points = self.wins as f64 + self.draws as f64 / 2.;
+        write!(
+            f,
+            "+{} -{} ={} ({:.1}/{})",
+            self.wins, self.losses, self.draws, points, games
+        )
+    }
+}
+
+// Plays `games` games between two engines, swapping colors every game. Each opening is played
+// twice in a row, once with either engine as white, and the startpos is played when there are
+// none. The score is from the first engine's point of view.
+pub fn play_match(
+    first: &EngineConfig,
+    second: &EngineConfig,
+    tc: &TimeControl,
+    games: u32,
+    openings: &[Opening],
+    rules: AdjudicationRules,
+) -> Result<MatchScore> {
+    let mut a = UciEngine::spawn(first)?;
+    let mut b = UciEngine::spawn(second)?;
+    println!("{} vs {}, tc {}", a.name, b.name, tc);
+
+    let startpos = [Opening::startpos()];
+    let openings = if openings.is_empty() { &startpos[..] } else { openings };
+    let mut score = MatchScore::default();
+    for game in 0..games {
+        let opening = &openings[(game / 2) as usize % openings.len()];
+        let a_is_white = game % 2 == 0;
+        let outcome = if a_is_white {
+            play_game([&mut a, &mut b], opening, tc, rules)?
+        } else {
+            play_game([&mut b, &mut a], opening, tc, rules)?
+        };
+        match (outcome, a_is_white) {
+            (Outcome::Draw, _) => score.draws += 1,
+            (Outcome::WhiteWin, true) | (Outcome::BlackWin, false) => score.wins += 1,
+            _ => score.losses += 1,
+        }
+        println!("game {}: {:?}, score {}", game + 1, outcome, score);
+    }
+    Ok(score)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_option() {
+        let (name, value) = parse_option("Hash = 128").unwrap();
+        assert_eq!((name.as_str(), value.as_str()), ("Hash", "128"));
+        assert!(parse_option("Hash").is_err());
+    }
+
+    #[test]
+    fn test_read_openings() {
+        crate::movegen::init_tables();
+        crate::zobrist::init_zobrist();
+
+        let dir = std::env::temp_dir().join(format!("pounce_openings_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let pgn = dir.join("openings.pgn");
+        std::fs::write(&pgn, "[Result \"*\"]\n\n1. e4 c5 2. Nf3 *\n\n1. d4 d5 *\n").unwrap();
+        let openings = read_openings(&pgn).unwrap();
+        assert_eq!(openings.len(), 2);
+        assert_eq!(openings[0].fen, Uci::STARTPOS);
+        assert_eq!(openings[0].moves, ["e2e4", "c7c5", "g1f3"]);
+        assert_eq!(openings[0].position().unwrap().side, Color::Black);
+
+        let epd = dir.join("openings.epd");
+        std::fs::write(
+            &epd,
+            "# kiwipete and a FEN\n\
+             r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - bm e2a6;\n\n\
+             8/8/4k3/8/8/4K3/4P3/8 b - - 3 40\n",
+        )
+        .unwrap();
+        let openings = read_openings(&epd).unwrap();
+        assert_eq!(openings.len(), 2);
+        assert!(openings[0].fen.ends_with(" w KQkq - 0 1"));
+        assert_eq!(openings[1].fen, "8/8/4k3/8/8/4K3/4P3/8 b - - 3 40");
+        assert!(openings.iter().all(|opening| opening.position().is_ok()));
+
+        std::fs::write(&epd, "not a position\n").unwrap();
+        assert!(read_openings(&epd).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_parse_score() {
+        assert_eq!(parse_score("info depth 9 score cp -35 nodes 1000 pv e2e4"), Some(-35));
+        assert_eq!(parse_score("info depth 20 score cp 12 lowerbound"), Some(12));
+        assert_eq!(parse_score("info depth 12 score mate 3 pv h5f7"), Some(eval::MATE - 5));
+        assert_eq!(parse_score("info depth 12 score mate -2"), Some(-eval::MATE + 4));
+        assert_eq!(parse_score("info string score cp"), None);
+        assert_eq!(parse_score("info depth 3 nodes 30"), None);
+    }
+}
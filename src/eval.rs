@@ -1,18 +1,145 @@
+use std::{
+    fs::File,
+    io::{
+        BufRead,
+        BufReader,
+    },
+    path::PathBuf,
+};
+
+use anyhow::{
+    bail,
+    Context,
+    Result,
+};
+use thiserror::Error;
+
 use crate::{
     chess::{
         Color,
         Role,
         Square,
     },
+    fen::Fen,
     position::Position,
     search,
 };
 
+mod imbalance;
+mod majors;
+mod pins;
+mod scaling;
+
+// Every score lives in -INFINITY..=INFINITY. Mates are scored MATE less the plies to them, so
+// anything at or past MATE_IN_PLY is a mate and static evals are kept inside MAX_EVAL.
 pub const INFINITY: i16 = 32_001;
 pub const MATE: i16 = 32_000;
 pub const MATE_IN_PLY: i16 = MATE - search::MAX_PLY as i16;
+pub const MAX_EVAL: i16 = MATE_IN_PLY - 1;
 pub const DRAW: i16 = 0;
 
+pub fn is_mate_score(score: i16) -> bool {
+    score.abs() >= MATE_IN_PLY
+}
+
+// The score of mating in `ply` plies
+pub fn mate_in(ply: usize) -> i16 {
+    MATE - ply as i16
+}
+
+// The score of being mated in `ply` plies
+pub fn mated_in(ply: usize) -> i16 {
+    -MATE + ply as i16
+}
+
+// The internal score that gets reported as +1.00, roughly an endgame pawn. Reported scores are
+// rescaled so that a pawn up means about the same win chance whatever the eval's own units are,
+// internal scores are left alone.
+pub const NORMALIZE_TO_PAWN_VALUE: i32 = 208;
+
+// Converts an internal score to centipawns for reporting, mate scores are passed through
+pub fn normalize(score: i16) -> i16 {
+    if is_mate_score(score) {
+        return score;
+    }
+    (score as i32 * 100 / NORMALIZE_TO_PAWN_VALUE) as i16
+}
+
+// An internal score on its way out to a gui or a log
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Score(pub i16);
+
+impl Score {
+    // Moves rather than plies to the mate, negative when getting mated. A mate on our move
+    // takes one more ply than it has moves of ours, being mated takes two plies per move.
+    pub fn mate_moves(self) -> Option<i16> {
+        if !is_mate_score(self.0) {
+            return None;
+        }
+        let plies = MATE - self.0.abs();
+        Some(self.0.signum() * (plies + 1) / 2)
+    }
+
+    // "cp 31" or "mate -3", as reported after `score` in uci info lines
+    pub fn to_uci_string(self) -> String {
+        match self.mate_moves() {
+            Some(moves) => format!("mate {}", moves),
+            None => format!("cp {}", normalize(self.0)),
+        }
+    }
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+#[error("eval {eval} but {mirrored} after mirroring {fen}")]
+pub struct SymmetryError {
+    pub fen: String,
+    pub eval: i16,
+    pub mirrored: i16,
+}
+
+// The eval is for the side to move, so a position and its mirror have to get the same score, or
+// from white's point of view eval(pos) == -eval(mirror(pos)). A term that flips the wrong
+// squares or forgets a sign for black breaks this.
+pub fn verify_symmetry(pos: &Position) -> Result<(), SymmetryError> {
+    let eval = pos.eval();
+    let mirrored = pos.mirror().eval();
+    if eval != mirrored {
+        return Err(SymmetryError {
+            fen: pos.to_fen(),
+            eval,
+            mirrored,
+        });
+    }
+    Ok(())
+}
+
+// Runs `verify_symmetry` over files of FENs, one per line like the `fens` command writes them
+pub fn check_symmetry(in_files: &[PathBuf]) -> Result<()> {
+    let mut positions = 0;
+    let mut failures = 0;
+    for path in in_files {
+        let file = File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let Fen(pos) = Fen::parse(&line).with_context(|| format!("bad FEN `{}`", line))?;
+            positions += 1;
+            if let Err(e) = verify_symmetry(&pos) {
+                failures += 1;
+                println!("{}", e);
+            }
+        }
+    }
+
+    println!("{} positions, {} asymmetric", positions, failures);
+    if failures > 0 {
+        bail!("eval isn't symmetric in {} positions", failures);
+    }
+    Ok(())
+}
+
 impl Position {
     pub fn eval(&self) -> i16 {
         debug_assert_eq!(self.psqt_mg(), self.psqt_mg);
@@ -30,19 +157,36 @@ impl Position {
         let brooks = self.by_color_role(Color::Black, Role::Rook).count() as i32;
         let bqueens = self.by_color_role(Color::Black, Role::Queen).count() as i32;
 
-        let score_mg = (wpawns - bpawns) * PIECE_VALUES_MG[Role::Pawn]
-            + (wknights - bknights) * PIECE_VALUES_MG[Role::Knight]
-            + (wbishops - bbishops) * PIECE_VALUES_MG[Role::Bishop]
-            + (wrooks - brooks) * PIECE_VALUES_MG[Role::Rook]
-            + (wqueens - bqueens) * PIECE_VALUES_MG[Role::Queen]
-            + self.psqt_mg;
+        let imbalance = imbalance::imbalance(
+            &[wpawns, wknights, wbishops, wrooks, wqueens, 1],
+            &[bpawns, bknights, bbishops, brooks, bqueens, 1],
+            &imbalance::IMBALANCE,
+        );
+        let (majors_mg, majors_eg) = majors::majors(self, &majors::MAJORS);
+        let (pins_mg, pins_eg) = match (pins::pins(self, &pins::PINS), self.side) {
+            ((mg, eg), Color::White) => (mg, eg),
+            ((mg, eg), Color::Black) => (-mg, -eg),
+        };
+
+        let score_mg = (wpawns - bpawns) * PIECE_VALUES.mg[Role::Pawn]
+            + (wknights - bknights) * PIECE_VALUES.mg[Role::Knight]
+            + (wbishops - bbishops) * PIECE_VALUES.mg[Role::Bishop]
+            + (wrooks - brooks) * PIECE_VALUES.mg[Role::Rook]
+            + (wqueens - bqueens) * PIECE_VALUES.mg[Role::Queen]
+            + self.psqt_mg
+            + imbalance
+            + majors_mg
+            + pins_mg;
 
-        let score_eg = (wpawns - bpawns) * PIECE_VALUES_EG[Role::Pawn]
-            + (wknights - bknights) * PIECE_VALUES_EG[Role::Knight]
-            + (wbishops - bbishops) * PIECE_VALUES_EG[Role::Bishop]
-            + (wrooks - brooks) * PIECE_VALUES_EG[Role::Rook]
-            + (wqueens - bqueens) * PIECE_VALUES_EG[Role::Queen]
-            + self.psqt_eg;
+        let score_eg = (wpawns - bpawns) * PIECE_VALUES.eg[Role::Pawn]
+            + (wknights - bknights) * PIECE_VALUES.eg[Role::Knight]
+            + (wbishops - bbishops) * PIECE_VALUES.eg[Role::Bishop]
+            + (wrooks - brooks) * PIECE_VALUES.eg[Role::Rook]
+            + (wqueens - bqueens) * PIECE_VALUES.eg[Role::Queen]
+            + self.psqt_eg
+            + imbalance
+            + majors_eg
+            + pins_eg;
 
         let phase = (wknights + bknights)
             + (wbishops + bbishops)
@@ -52,11 +196,16 @@ impl Position {
         let phase = 24 - phase;
         let phase = (phase * 256 + (24 / 2)) / 24;
 
+        let score_eg = score_eg * scaling::scale_factor(self, score_eg, &scaling::SCALING)
+            / scaling::SCALE_NORMAL;
+
         let score = (score_mg * (256 - phase) + score_eg * phase) / 256;
+        // however lopsided the position, it can't look like a mate
+        let score = score.clamp(-MAX_EVAL as i32, MAX_EVAL as i32) as i16;
 
         match self.side {
-            Color::White => score as i16,
-            Color::Black => -score as i16,
+            Color::White => score,
+            Color::Black => -score,
         }
     }
 
@@ -91,8 +240,19 @@ impl Position {
     }
 }
 
-pub const PIECE_VALUES_MG: [i32; Role::NUM] = [126, 781, 825, 1276, 2538, 0];
-pub const PIECE_VALUES_EG: [i32; Role::NUM] = [208, 854, 915, 1380, 2682, 0];
+// Piece values for everything that needs them, indexed by role. `mg` and `eg` are the material
+// eval, `see` is a single scale in the same units for comparing captures and exchanges.
+pub struct PieceValues {
+    pub mg: [i32; Role::NUM],
+    pub eg: [i32; Role::NUM],
+    pub see: [i16; Role::NUM],
+}
+
+pub const PIECE_VALUES: PieceValues = PieceValues {
+    mg: [126, 781, 825, 1276, 2538, 0],
+    eg: [208, 854, 915, 1380, 2682, 0],
+    see: [200, 800, 850, 1300, 2600, 0],
+};
 
 #[rustfmt::skip]
 pub const PSQT_MG: [[i32; Square::NUM]; Role::NUM] = [
@@ -209,3 +369,51 @@ pub const PSQT_EG: [[i32; Square::NUM]; Role::NUM] = [
       -27, -11,   4,  13,  14,   4,  -5,  -17,
       -53, -34, -21, -11, -28, -14, -24,  -43],
 ];
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize(NORMALIZE_TO_PAWN_VALUE as i16), 100);
+        assert_eq!(normalize(-2 * NORMALIZE_TO_PAWN_VALUE as i16), -200);
+        assert_eq!(normalize(DRAW), 0);
+        assert_eq!(normalize(MATE - 3), MATE - 3);
+        assert_eq!(normalize(-MATE + 4), -MATE + 4);
+    }
+
+    #[test]
+    fn test_mate_scores() {
+        assert_eq!(mate_in(3), MATE - 3);
+        assert_eq!(mated_in(4), -MATE + 4);
+        assert!(is_mate_score(mate_in(search::MAX_PLY)));
+        assert!(is_mate_score(mated_in(1)));
+        assert!(!is_mate_score(MAX_EVAL) && !is_mate_score(-MAX_EVAL));
+        assert!(!is_mate_score(DRAW));
+    }
+
+    #[test]
+    fn test_mate_display() {
+        assert_eq!(Score(mate_in(1)).to_uci_string(), "mate 1");
+        assert_eq!(Score(mate_in(3)).to_uci_string(), "mate 2");
+        assert_eq!(Score(mate_in(4)).to_uci_string(), "mate 2");
+        assert_eq!(Score(mated_in(2)).to_uci_string(), "mate -1");
+        assert_eq!(Score(mated_in(4)).to_uci_string(), "mate -2");
+        // already mated
+        assert_eq!(Score(mated_in(0)).mate_moves(), Some(0));
+        assert_eq!(Score(MAX_EVAL).mate_moves(), None);
+        assert_eq!(Score(NORMALIZE_TO_PAWN_VALUE as i16).to_uci_string(), "cp 100");
+    }
+
+    #[test]
+    fn test_symmetry() {
+        crate::movegen::init_tables();
+        crate::zobrist::init_zobrist();
+
+        for fen in crate::positions::BENCHMARK_FENS {
+            let Fen(pos) = Fen::parse(fen).unwrap();
+            verify_symmetry(&pos).unwrap();
+        }
+    }
+}
@@ -0,0 +1,98 @@
+use crate::chess::Role;
+
+// Second order material terms: how much a piece is worth depends on what else is on the
+// board, e.g. knights get better with more pawns around and a second rook adds less than the
+// first. Indexed by [piece][other piece] with the bishop pair as an extra first "piece".
+pub struct ImbalanceWeights {
+    // interactions with our own pieces, the diagonal is the linear term
+    pub ours: [[i32; 6]; 6],
+    // interactions with the opponent's pieces
+    pub theirs: [[i32; 6]; 6],
+}
+
+#[rustfmt::skip]
+pub const IMBALANCE: ImbalanceWeights = ImbalanceWeights {
+    ours: [
+        // pair pawn knight bishop rook queen
+        [1419,    0,    0,    0,    0,   0], // bishop pair
+        [ 101,   37,    0,    0,    0,   0], // pawn
+        [  57,  249,  -49,    0,    0,   0], // knight
+        [   0,  118,   10,    0,    0,   0], // bishop
+        [ -63,   -5,  100,  132, -246,   0], // rook
+        [-210,   37,  147,  161, -158,  -9], // queen
+    ],
+    theirs: [
+        [   0,    0,    0,    0,    0,   0], // bishop pair
+        [  33,    0,    0,    0,    0,   0], // pawn
+        [  46,  106,    0,    0,    0,   0], // knight
+        [  75,   59,   60,    0,    0,   0], // bishop
+        [  26,    6,   38,  -12,    0,   0], // rook
+        [  97,  100,  -58,  112,  276,   0], // queen
+    ],
+};
+
+// the weights are in 1/16ths of the eval's units
+const SCALE: i32 = 16;
+
+// Piece counts for one side, indexed by role
+pub type Counts = [i32; Role::NUM];
+
+fn side_imbalance(us: &Counts, them: &Counts, weights: &ImbalanceWeights) -> i32 {
+    let expand = |counts: &Counts| {
+        [
+            (counts[Role::Bishop] > 1) as i32,
+            counts[Role::Pawn],
+            counts[Role::Knight],
+            counts[Role::Bishop],
+            counts[Role::Rook],
+            counts[Role::Queen],
+        ]
+    };
+    let (us, them) = (expand(us), expand(them));
+
+    let mut bonus = 0;
+    for piece in 0..6 {
+        if us[piece] == 0 {
+            continue;
+        }
+        let mut v = weights.ours[piece][piece] * us[piece];
+        for other in 0..piece {
+            v +=
+                weights.ours[piece][other] * us[other] + weights.theirs[piece][other] * them[other];
+        }
+        bonus += us[piece] * v;
+    }
+    bonus
+}
+
+// The imbalance from white's point of view
+pub fn imbalance(white: &Counts, black: &Counts, weights: &ImbalanceWeights) -> i32 {
+    (side_imbalance(white, black, weights) - side_imbalance(black, white, weights)) / SCALE
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn counts(pawns: i32, knights: i32, bishops: i32, rooks: i32, queens: i32) -> Counts {
+        [pawns, knights, bishops, rooks, queens, 1]
+    }
+
+    #[test]
+    fn test_imbalance() {
+        let start = counts(8, 2, 2, 2, 1);
+        assert_eq!(imbalance(&start, &start, &IMBALANCE), 0);
+
+        // the bishop pair is worth something on its own
+        let no_pair = counts(8, 3, 1, 2, 1);
+        assert!(imbalance(&start, &no_pair, &IMBALANCE) > 0);
+
+        // mirrored material gives the mirrored score
+        let a = counts(6, 1, 2, 2, 0);
+        let b = counts(7, 2, 0, 1, 1);
+        assert_eq!(
+            imbalance(&a, &b, &IMBALANCE),
+            -imbalance(&b, &a, &IMBALANCE)
+        );
+    }
+}
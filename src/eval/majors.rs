@@ -0,0 +1,106 @@
+use crate::{
+    bitboard::Bitboard,
+    chess::{Color, Role, Square},
+    movegen::get_rook_moves,
+    position::Position,
+};
+
+// Bonuses for rooks and queens, as (mg, eg) pairs
+pub struct MajorWeights {
+    // on the opponent's second rank while their king is on the back rank or pawns are left
+    // on that rank to attack
+    pub rook_on_seventh: (i32, i32),
+    pub queen_on_seventh: (i32, i32),
+    // a pair of rooks defending each other with at least one of them on an open file
+    pub connected_rooks: (i32, i32),
+}
+
+pub const MAJORS: MajorWeights = MajorWeights {
+    rook_on_seventh: (20, 40),
+    queen_on_seventh: (10, 20),
+    connected_rooks: (15, 5),
+};
+
+fn side_majors(pos: &Position, color: Color, weights: &MajorWeights) -> (i32, i32) {
+    let them = color.opponent();
+    let seventh = Bitboard::from(them.home_rank());
+    let mut mg = 0;
+    let mut eg = 0;
+
+    let king_trapped = (pos.by_color_role(them, Role::King) & them.back_rank()).any();
+    if king_trapped || (pos.by_color_role(them, Role::Pawn) & seventh).any() {
+        let rooks = (pos.by_color_role(color, Role::Rook) & seventh).count() as i32;
+        let queens = (pos.by_color_role(color, Role::Queen) & seventh).count() as i32;
+        mg += rooks * weights.rook_on_seventh.0 + queens * weights.queen_on_seventh.0;
+        eg += rooks * weights.rook_on_seventh.1 + queens * weights.queen_on_seventh.1;
+    }
+
+    let rooks = pos.by_color_role(color, Role::Rook);
+    let pawns = pos.by_role[Role::Pawn];
+    let open = |sq: Square| (pawns & sq.file()).none();
+    if rooks.count() >= 2 {
+        for sq in rooks {
+            // only counted from the lower square so every pair is seen once
+            let partners = (get_rook_moves(sq, pos.occupancy) & rooks).filter(|&p| p > sq);
+            for partner in partners {
+                if open(sq) || open(partner) {
+                    mg += weights.connected_rooks.0;
+                    eg += weights.connected_rooks.1;
+                }
+            }
+        }
+    }
+
+    (mg, eg)
+}
+
+// The bonuses from white's point of view
+pub fn majors(pos: &Position, weights: &MajorWeights) -> (i32, i32) {
+    let (white_mg, white_eg) = side_majors(pos, Color::White, weights);
+    let (black_mg, black_eg) = side_majors(pos, Color::Black, weights);
+    (white_mg - black_mg, white_eg - black_eg)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{fen::Fen, movegen::init_tables, zobrist::init_zobrist};
+
+    fn score(fen: &str) -> (i32, i32) {
+        init_tables();
+        init_zobrist();
+        let Fen(pos) = fen.parse().unwrap();
+        majors(&pos, &MAJORS)
+    }
+
+    #[test]
+    fn test_seventh_rank() {
+        assert_eq!(
+            score("6k1/R7/8/8/8/8/8/6K1 w - - 0 1"),
+            MAJORS.rook_on_seventh
+        );
+        // the king is off the back rank and there are no pawns to attack
+        assert_eq!(score("8/R5k1/8/8/8/8/8/6K1 w - - 0 1"), (0, 0));
+        assert_eq!(
+            score("8/p5k1/8/8/8/8/q7/6K1 w - - 0 1"),
+            (-MAJORS.queen_on_seventh.0, -MAJORS.queen_on_seventh.1)
+        );
+    }
+
+    #[test]
+    fn test_connected_rooks() {
+        assert_eq!(
+            score("6k1/8/8/8/8/8/8/R4RK1 w - - 0 1"),
+            MAJORS.connected_rooks
+        );
+        // a piece in between
+        assert_eq!(score("6k1/8/8/8/8/8/8/R1B2RK1 w - - 0 1"), (0, 0));
+        // closed files
+        assert_eq!(score("6k1/p4p2/8/8/8/8/P4P2/R4RK1 w - - 0 1"), (0, 0));
+        // doubled on an open file
+        assert_eq!(
+            score("6k1/8/8/8/8/3R4/8/3R2K1 w - - 0 1"),
+            MAJORS.connected_rooks
+        );
+    }
+}
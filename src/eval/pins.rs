@@ -0,0 +1,71 @@
+use crate::{
+    chess::{Role, Square},
+    movegen::{between, bishop_rays, rook_rays},
+    position::Position,
+};
+
+// Terms for the side to move, since that's whose pins `Position` keeps, as (mg, eg) pairs
+pub struct PinWeights {
+    // per piece absolutely pinned to our king, indexed by role
+    pub pinned: [(i32, i32); Role::NUM],
+    // per piece of ours that's the only thing between one of our sliders and their king, so
+    // moving it uncovers a check
+    pub discovery: (i32, i32),
+}
+
+pub const PINS: PinWeights = PinWeights {
+    pinned: [(5, 10), (20, 25), (20, 25), (25, 30), (50, 60), (0, 0)],
+    discovery: (15, 10),
+};
+
+// The terms from the side to move's point of view
+pub fn pins(pos: &Position, weights: &PinWeights) -> (i32, i32) {
+    let mut mg = 0;
+    let mut eg = 0;
+
+    for sq in pos.pinned {
+        let (pin_mg, pin_eg) = weights.pinned[pos.role_at(sq).unwrap()];
+        mg -= pin_mg;
+        eg -= pin_eg;
+    }
+
+    let ksq = Square::new_unchecked(pos.their_king().0.trailing_zeros() as u8);
+    let snipers = ((pos.our(Role::Bishop) | pos.our(Role::Queen)) & bishop_rays(ksq))
+        | ((pos.our(Role::Rook) | pos.our(Role::Queen)) & rook_rays(ksq));
+    for sniper in snipers {
+        let blockers = between(ksq, sniper) & pos.occupancy;
+        if blockers.count() == 1 && (blockers & pos.us()).any() {
+            mg += weights.discovery.0;
+            eg += weights.discovery.1;
+        }
+    }
+
+    (mg, eg)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{fen::Fen, movegen::init_tables, zobrist::init_zobrist};
+
+    fn score(fen: &str) -> (i32, i32) {
+        init_tables();
+        init_zobrist();
+        let Fen(pos) = fen.parse().unwrap();
+        pins(&pos, &PINS)
+    }
+
+    #[test]
+    fn test_pins() {
+        assert_eq!(score("4k3/8/8/8/8/8/8/4K3 w - - 0 1"), (0, 0));
+
+        // white's knight is pinned by the rook
+        let (mg, eg) = PINS.pinned[Role::Knight];
+        assert_eq!(score("4r1k1/8/8/8/8/8/4N3/4K3 w - - 0 1"), (-mg, -eg));
+
+        // the rook behind white's bishop is aimed at the king
+        assert_eq!(score("4k3/8/8/8/8/4B3/8/4RK2 w - - 0 1"), PINS.discovery);
+        // but it isn't black's to use
+        assert_eq!(score("4k3/8/8/8/8/4B3/8/4RK2 b - - 0 1"), (0, 0));
+    }
+}
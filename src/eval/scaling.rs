@@ -0,0 +1,131 @@
+use crate::{
+    bitboard::Bitboard,
+    chess::{Color, Role},
+    eval::PIECE_VALUES,
+    position::Position,
+};
+
+// Endgame scores get multiplied by a factor out of SCALE_NORMAL when the stronger side's pawns
+// make the win unlikely. Several rules can apply, the lowest factor is used.
+pub const SCALE_NORMAL: i32 = 64;
+
+// None of the rules apply once the stronger side is more than a minor piece up in pieces,
+// a rook or a queen more wins whatever the pawns look like
+const MAX_PIECE_LEAD: i32 = PIECE_VALUES.see[Role::Bishop as usize] as i32;
+
+pub struct ScaleWeights {
+    // the stronger side has no pawns left
+    pub no_pawns: i32,
+    // the stronger side has one pawn left
+    pub one_pawn: i32,
+    // every pawn on the board fits within four adjacent files and the stronger side is at
+    // most one pawn up
+    pub one_flank: i32,
+}
+
+pub const SCALING: ScaleWeights = ScaleWeights {
+    no_pawns: 24,
+    one_pawn: 48,
+    one_flank: 48,
+};
+
+// The files with at least one piece on them, as the low 8 bits
+fn file_mask(bb: Bitboard) -> u64 {
+    let mut x = u64::from(bb);
+    x |= x >> 32;
+    x |= x >> 16;
+    x |= x >> 8;
+    x & 0xff
+}
+
+// The distance between the outermost occupied files, 0 for an empty board
+pub fn file_span(bb: Bitboard) -> u32 {
+    let files = file_mask(bb);
+    if files == 0 {
+        return 0;
+    }
+    7 - files.leading_zeros().saturating_sub(56) - files.trailing_zeros()
+}
+
+fn piece_material(pos: &Position, color: Color) -> i32 {
+    [Role::Knight, Role::Bishop, Role::Rook, Role::Queen]
+        .into_iter()
+        .map(|role| pos.by_color_role(color, role).count() as i32 * PIECE_VALUES.see[role] as i32)
+        .sum()
+}
+
+// The factor for an endgame score `eg` from white's point of view
+pub fn scale_factor(pos: &Position, eg: i32, weights: &ScaleWeights) -> i32 {
+    let strong = if eg >= 0 { Color::White } else { Color::Black };
+    if piece_material(pos, strong) - piece_material(pos, strong.opponent()) > MAX_PIECE_LEAD {
+        return SCALE_NORMAL;
+    }
+
+    let strong_pawns = pos.by_color_role(strong, Role::Pawn).count() as i32;
+    let weak_pawns = pos.by_color_role(strong.opponent(), Role::Pawn).count() as i32;
+
+    let mut scale = SCALE_NORMAL;
+    if strong_pawns == 0 {
+        scale = scale.min(weights.no_pawns);
+    } else if strong_pawns == 1 {
+        scale = scale.min(weights.one_pawn);
+    }
+
+    let pawns = pos.by_role[Role::Pawn];
+    if pawns.any() && file_span(pawns) <= 3 && strong_pawns - weak_pawns <= 1 {
+        scale = scale.min(weights.one_flank);
+    }
+    scale
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{fen::Fen, movegen::init_tables, zobrist::init_zobrist};
+
+    fn scale(fen: &str, eg: i32) -> i32 {
+        init_tables();
+        init_zobrist();
+        let Fen(pos) = fen.parse().unwrap();
+        scale_factor(&pos, eg, &SCALING)
+    }
+
+    #[test]
+    fn test_file_span() {
+        assert_eq!(file_span(Bitboard::EMPTY), 0);
+        assert_eq!(file_span(Bitboard::from(0x0000_1000_0000_0000)), 0);
+        assert_eq!(file_span(Bitboard::from(0x0000_0000_0000_FF00)), 7);
+        assert_eq!(file_span(Bitboard::from(0x0020_0000_0000_4000)), 1);
+    }
+
+    #[test]
+    fn test_scale_factor() {
+        let start = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1";
+        assert_eq!(scale(start, 50), SCALE_NORMAL);
+
+        // rook endgame with 4 vs 3 on the kingside
+        let rook_ending = "3r2k1/5pp1/7p/8/8/6PP/5PP1/3R2K1 w - - 0 1";
+        assert_eq!(scale(rook_ending, 200), SCALING.one_flank);
+        // pawns on both wings
+        let rook_ending = "3r2k1/p4pp1/7p/8/8/6PP/P4PP1/3R2K1 w - - 0 1";
+        assert_eq!(scale(rook_ending, 200), SCALE_NORMAL);
+
+        // black is the stronger side here and has no pawns
+        assert_eq!(
+            scale("4k3/8/8/8/8/8/P7/r3KB2 w - - 0 1", -300),
+            SCALING.no_pawns
+        );
+        // a bishop up with one pawn
+        assert_eq!(scale("4k3/8/8/8/8/8/P7/4KB2 w - - 0 1", 900), SCALING.one_pawn);
+    }
+
+    #[test]
+    fn test_won_endings_not_scaled() {
+        assert_eq!(scale("4k3/8/8/8/8/8/8/3QK3 w - - 0 1", 2500), SCALE_NORMAL);
+        assert_eq!(scale("4k3/8/8/8/8/8/8/3RK3 w - - 0 1", 1300), SCALE_NORMAL);
+        assert_eq!(scale("4k3/8/8/8/8/8/8/r3K3 w - - 0 1", -1300), SCALE_NORMAL);
+        // a rook up with one pawn, or with pawns on one flank
+        assert_eq!(scale("4k3/8/8/8/8/8/P7/R3K3 w - - 0 1", 1500), SCALE_NORMAL);
+        assert_eq!(scale("4k3/5pp1/8/8/8/8/5PPP/R3K3 w - - 0 1", 1500), SCALE_NORMAL);
+    }
+}
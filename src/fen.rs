@@ -69,6 +69,8 @@ impl Fen {
 
         position.refresh_checks_and_pins();
         position.key = position.zobrist_hash();
+        position.pawn_key = position.pawn_hash();
+        position.major_minor_key = position.major_minor_hash();
 
         Ok(Fen(position))
     }
@@ -201,6 +203,40 @@ impl Position {
     }
 }
 
+impl Position {
+    // The same position with the colors swapped and the board flipped top to bottom, black to
+    // move where white was. It's as good for black as the original is for white.
+    pub fn mirror(&self) -> Position {
+        let swap_case = |s: &str| {
+            s.chars()
+                .map(|c| match c.is_ascii_uppercase() {
+                    true => c.to_ascii_lowercase(),
+                    false => c.to_ascii_uppercase(),
+                })
+                .collect::<String>()
+        };
+
+        let fen = self.to_fen();
+        let parts = fen.split_whitespace().collect::<Vec<_>>();
+        let board = parts[0].rsplit('/').map(swap_case).collect::<Vec<_>>();
+        let ep_square = self.ep_square.map_or_else(
+            || "-".to_string(),
+            |sq| Square::new_unchecked(sq as u8 ^ 56).to_string(),
+        );
+        let fen = format!(
+            "{} {} {} {} {} {}",
+            board.join("/"),
+            self.side.opponent().to_fen(),
+            swap_case(parts[2]),
+            ep_square,
+            parts[4],
+            parts[5]
+        );
+        let Fen(position) = Fen::parse(&fen).expect("mirrored FEN parses");
+        position
+    }
+}
+
 impl Color {
     fn to_fen(self) -> &'static str {
         match self {
@@ -211,7 +247,7 @@ impl Color {
 }
 
 impl CastleRights {
-    fn to_fen(self) -> String {
+    pub(crate) fn to_fen(self) -> String {
         if self.is_empty() {
             "-".to_string()
         } else {
@@ -248,4 +284,19 @@ mod test {
         assert_eq!(position.halfmove_clock, 0);
         assert_eq!(position.fullmove_number, NonZeroU32::new(1).unwrap());
     }
+
+    #[test]
+    fn test_mirror() {
+        crate::movegen::init_tables();
+        crate::zobrist::init_zobrist();
+
+        let fen = "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K1R1 b Qkq e3 0 1";
+        let Fen(position) = Fen::parse(fen).unwrap();
+        let mirrored = position.mirror();
+        assert_eq!(
+            mirrored.to_fen(),
+            "r3k1r1/pppbbppp/2n2q1P/1P2p3/3pn3/BN2PNP1/P1PPQPB1/R3K2R w KQq e6 0 1"
+        );
+        assert_eq!(mirrored.mirror().to_fen(), fen);
+    }
 }
@@ -0,0 +1,166 @@
+use std::fmt::{
+    self,
+    Display,
+    Formatter,
+};
+
+use crate::{
+    nnue::{
+        self,
+        Backend,
+    },
+    uci::{
+        default_options,
+        UciOption,
+    },
+    util::engine_name,
+};
+
+// What a build is and what it runs on, for recording next to test results
+#[derive(Debug, Clone)]
+pub struct BuildInfo {
+    pub name: String,
+    // cargo features and the target features the build was compiled for
+    pub features: Vec<&'static str>,
+    pub nnue_backend: Backend,
+    // what the cpu running the build supports, whether or not it was compiled in
+    pub cpu_features: Vec<(&'static str, bool)>,
+    pub options: Vec<UciOption>,
+}
+
+impl BuildInfo {
+    pub fn detect() -> BuildInfo {
+        let compiled = [
+            ("datagen", cfg!(feature = "datagen")),
+            ("popcnt", cfg!(target_feature = "popcnt")),
+            // pext and pdep, sliders still use magics either way
+            ("bmi2", cfg!(target_feature = "bmi2")),
+            ("avx2", cfg!(target_feature = "avx2")),
+            ("neon", cfg!(target_feature = "neon")),
+        ];
+
+        BuildInfo {
+            name: engine_name(),
+            features: compiled
+                .into_iter()
+                .filter(|&(_, enabled)| enabled)
+                .map(|(feature, _)| feature)
+                .collect(),
+            nnue_backend: nnue::backend(),
+            cpu_features: cpu_features(),
+            options: default_options(),
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        let features = self
+            .features
+            .iter()
+            .map(|feature| json_string(feature))
+            .collect::<Vec<_>>();
+        let cpu_features = self
+            .cpu_features
+            .iter()
+            .map(|(feature, supported)| format!("{}: {}", json_string(feature), supported))
+            .collect::<Vec<_>>();
+        let options = self
+            .options
+            .iter()
+            .map(|option| {
+                format!(
+                    "{}: {}",
+                    json_string(option.name()),
+                    json_string(&option.default_value())
+                )
+            })
+            .collect::<Vec<_>>();
+
+        format!(
+            "{{\"name\": {}, \"features\": [{}], \"nnue_backend\": {}, \"cpu_features\": {{{}}}, \
+             \"options\": {{{}}}}}",
+            json_string(&self.name),
+            features.join(", "),
+            json_string(&format!("{:?}", self.nnue_backend).to_lowercase()),
+            cpu_features.join(", "),
+            options.join(", ")
+        )
+    }
+}
+
+impl Display for BuildInfo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}", self.name)?;
+        let features = match self.features.is_empty() {
+            true => "none".to_string(),
+            false => self.features.join(" "),
+        };
+        writeln!(f, "Features: {}", features)?;
+        writeln!(f, "NNUE backend: {:?}", self.nnue_backend)?;
+        let cpu_features = self
+            .cpu_features
+            .iter()
+            .map(|&(feature, supported)| match supported {
+                true => format!("+{}", feature),
+                false => format!("-{}", feature),
+            })
+            .collect::<Vec<_>>();
+        writeln!(f, "CPU: {}", cpu_features.join(" "))?;
+        writeln!(f, "Options:")?;
+        for option in &self.options {
+            writeln!(f, "  {}", option)?;
+        }
+        Ok(())
+    }
+}
+
+fn cpu_features() -> Vec<(&'static str, bool)> {
+    #[cfg(target_arch = "x86_64")]
+    {
+        vec![
+            ("popcnt", is_x86_feature_detected!("popcnt")),
+            ("ssse3", is_x86_feature_detected!("ssse3")),
+            ("avx2", is_x86_feature_detected!("avx2")),
+            ("bmi2", is_x86_feature_detected!("bmi2")),
+            ("avx512f", is_x86_feature_detected!("avx512f")),
+        ]
+    }
+    #[cfg(target_arch = "aarch64")]
+    {
+        vec![("neon", std::arch::is_aarch64_feature_detected!("neon"))]
+    }
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    {
+        Vec::new()
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_json() {
+        assert_eq!(json_string("a \"b\"\n"), "\"a \\\"b\\\"\\u000a\"");
+
+        let info = BuildInfo::detect();
+        let json = info.to_json();
+        assert!(json.starts_with("{\"name\": \"pounce "));
+        assert!(json.contains("\"Hash\": \"64\""));
+        assert!(json.contains("\"UCI_Opponent\": \"\""));
+        assert_eq!(json.matches('{').count(), json.matches('}').count());
+    }
+}
@@ -1,15 +1,24 @@
-mod eval;
+mod affinity;
 mod movepicker;
 mod util;
 
+pub mod adjudicate;
 pub mod bench;
 pub mod bitboard;
+pub mod book;
 pub mod chess;
+pub mod engine;
+pub mod eval;
 pub mod fen;
+pub mod info;
 pub mod limits;
 pub mod movegen;
 pub mod moves;
+pub mod nnue;
+pub mod opponent;
+pub mod pgn;
 pub mod position;
+pub mod positions;
 pub mod search;
 pub mod tt;
 pub mod uci;
@@ -17,3 +26,62 @@ pub mod zobrist;
 
 #[cfg(feature = "datagen")]
 pub mod datagen;
+
+// What most users of the library need: set up a position, list its moves and search it. The
+// tables have to be initialized once before any of it is used.
+pub mod prelude {
+    pub use crate::{
+        chess::{
+            Color,
+            Role,
+            Square,
+        },
+        fen::Fen,
+        limits::Limits,
+        movegen::{
+            init_tables,
+            MoveGen,
+        },
+        moves::Move,
+        position::Position,
+        search::{
+            init_reductions,
+            Search,
+            SearchResult,
+        },
+        tt::Table,
+        zobrist::init_zobrist,
+    };
+
+    #[cfg(test)]
+    mod test {
+        use std::sync::{
+            atomic::AtomicBool,
+            Arc,
+        };
+
+        use super::*;
+
+        #[test]
+        fn test_prelude_search() {
+            init_tables();
+            init_zobrist();
+            init_reductions();
+
+            let Fen(position) = "4k3/8/8/8/8/8/4q3/4K3 w - - 0 1".parse().unwrap();
+            let legal = MoveGen::new(&position).collect::<Vec<Move>>();
+            let limits = Limits {
+                depth: Some(4),
+                ..Default::default()
+            };
+            let tt = Arc::new(Table::new_mb(1));
+            let mut search = Search::new(position, limits, tt, Arc::new(AtomicBool::new(false)));
+            search.set_silent(true);
+            let result = search.think();
+
+            // the king has to take the queen
+            assert_eq!(legal, ["e1e2".parse::<Move>().unwrap()]);
+            assert_eq!(result.bestmove, legal[0]);
+        }
+    }
+}
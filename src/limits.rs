@@ -1,7 +1,18 @@
-use std::borrow::Borrow;
+use std::{
+    borrow::Borrow,
+    fmt::{
+        self,
+        Display,
+        Formatter,
+    },
+    str::FromStr,
+    time::Duration,
+};
 
 use thiserror::Error;
 
+use crate::chess::Color;
+
 #[cfg(feature = "serde")]
 use serde::{Deserialize, Serialize};
 
@@ -10,6 +21,9 @@ use serde::{Deserialize, Serialize};
 pub struct Limits {
     pub depth: Option<u8>,
     pub nodes: Option<u64>,
+    // only checked between iterations, unlike `nodes` which aborts mid-search
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub soft_nodes: Option<u64>,
     pub wtime: Option<i32>,
     pub btime: Option<i32>,
     pub winc: Option<u32>,
@@ -17,6 +31,8 @@ pub struct Limits {
     pub movestogo: Option<u32>,
     pub movetime: Option<i32>,
     pub infinite: bool,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub ponder: bool,
 }
 
 #[derive(Debug, Error)]
@@ -25,11 +41,142 @@ pub enum LimitsParseError {
     InvalidTime(#[from] std::num::ParseIntError),
 }
 
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum TimeControlParseError {
+    #[error("invalid time `{0}`, expected seconds")]
+    InvalidTime(String),
+    #[error("invalid move count `{0}`")]
+    InvalidMoves(String),
+    #[error("only the last session can be for the rest of the game")]
+    SuddenDeathNotLast,
+}
+
+// One stage of a time control: `moves` moves in `base` with `increment` added after every move.
+// A session without a move count lasts the rest of the game.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TcSession {
+    pub moves: Option<u32>,
+    pub base: Duration,
+    pub increment: Duration,
+}
+
+// Time controls written the way cutechess and PGN headers do, in seconds: `300+3` for
+// increment, `40/300` for classical and colon separated sessions like `40/5400+30:1800+30`.
+// The last session repeats if it has a move count.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TimeControl {
+    pub sessions: Vec<TcSession>,
+}
+
+impl TimeControl {
+    // The session move `moves_played` (counted from 0 for each side) falls in, and how many
+    // moves are left in it including that one
+    pub fn session(&self, moves_played: u32) -> (&TcSession, Option<u32>) {
+        let mut moves_played = moves_played;
+        let last = self.sessions.len() - 1;
+        for (i, session) in self.sessions.iter().enumerate() {
+            match session.moves {
+                Some(moves) if i == last => {
+                    return (session, Some(moves - moves_played % moves));
+                }
+                Some(moves) if moves_played >= moves => moves_played -= moves,
+                Some(moves) => return (session, Some(moves - moves_played)),
+                None => return (session, None),
+            }
+        }
+        unreachable!("the last session always matches")
+    }
+
+    // Time added to a side's clock after its move `moves_played`, the increment plus the next
+    // session's time when the move ends a session
+    pub fn bonus(&self, moves_played: u32) -> Duration {
+        let (session, left) = self.session(moves_played);
+        if left == Some(1) {
+            let (next, _) = self.session(moves_played + 1);
+            session.increment + next.base
+        } else {
+            session.increment
+        }
+    }
+
+    // The go limits for a side about to play its move `moves_played`, with the clocks indexed
+    // by color
+    pub fn limits(&self, clocks: [Duration; 2], moves_played: u32) -> Limits {
+        let (session, movestogo) = self.session(moves_played);
+        let increment = session.increment.as_millis() as u32;
+        Limits {
+            wtime: Some(clocks[Color::White as usize].as_millis() as i32),
+            btime: Some(clocks[Color::Black as usize].as_millis() as i32),
+            winc: Some(increment),
+            binc: Some(increment),
+            movestogo,
+            ..Limits::new()
+        }
+    }
+}
+
+impl FromStr for TimeControl {
+    type Err = TimeControlParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let seconds = |part: &str| {
+            part.parse::<f64>()
+                .ok()
+                .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+                .ok_or_else(|| TimeControlParseError::InvalidTime(part.to_string()))
+        };
+
+        let mut sessions = Vec::new();
+        for session in s.trim().split(':') {
+            let (moves, time) = match session.split_once('/') {
+                Some((moves, time)) => match moves.parse::<u32>() {
+                    Ok(moves) if moves > 0 => (Some(moves), time),
+                    _ => return Err(TimeControlParseError::InvalidMoves(moves.to_string())),
+                },
+                None => (None, session),
+            };
+            let (base, increment) = time.split_once('+').unwrap_or((time, "0"));
+            sessions.push(TcSession {
+                moves,
+                base: seconds(base)?,
+                increment: seconds(increment)?,
+            });
+        }
+
+        if sessions[..sessions.len() - 1]
+            .iter()
+            .any(|session| session.moves.is_none())
+        {
+            return Err(TimeControlParseError::SuddenDeathNotLast);
+        }
+        Ok(TimeControl { sessions })
+    }
+}
+
+impl Display for TimeControl {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        for (i, session) in self.sessions.iter().enumerate() {
+            if i > 0 {
+                write!(f, ":")?;
+            }
+            if let Some(moves) = session.moves {
+                write!(f, "{}/", moves)?;
+            }
+            write!(f, "{}", session.base.as_secs_f64())?;
+            if !session.increment.is_zero() {
+                write!(f, "+{}", session.increment.as_secs_f64())?;
+            }
+        }
+        Ok(())
+    }
+}
+
 impl Limits {
     pub fn new() -> Self {
         Limits {
             depth: None,
             nodes: None,
+            soft_nodes: None,
             wtime: None,
             btime: None,
             winc: None,
@@ -37,9 +184,29 @@ impl Limits {
             movestogo: None,
             movetime: None,
             infinite: false,
+            ponder: false,
         }
     }
 
+    // whether a search with these limits finishes without being told to stop
+    pub fn is_bounded(&self) -> bool {
+        !self.infinite
+            && !self.ponder
+            && (self.depth.is_some()
+                || self.nodes.is_some()
+                || self.soft_nodes.is_some()
+                || self.movetime.is_some()
+                || self.wtime.is_some()
+                || self.btime.is_some())
+    }
+
+    // The limits for the first move under a time control like `40/300+3`
+    pub fn from_tc(tc: &str) -> Result<Self, TimeControlParseError> {
+        let tc = tc.parse::<TimeControl>()?;
+        let base = tc.sessions[0].base;
+        Ok(tc.limits([base; 2], 0))
+    }
+
     pub fn from_tokens<T>(tokens: &[T]) -> Result<Self, LimitsParseError>
     where
         T: AsRef<str> + Borrow<str>,
@@ -48,6 +215,7 @@ impl Limits {
             Pre,
             Depth,
             Nodes,
+            SoftNodes,
             WTime,
             BTime,
             WInc,
@@ -67,6 +235,9 @@ impl Limits {
                 "nodes" => {
                     parse_stage = ParseStage::Nodes;
                 }
+                "softnodes" => {
+                    parse_stage = ParseStage::SoftNodes;
+                }
                 "wtime" => {
                     parse_stage = ParseStage::WTime;
                 }
@@ -88,6 +259,9 @@ impl Limits {
                 "infinite" => {
                     limits.infinite = true;
                 }
+                "ponder" => {
+                    limits.ponder = true;
+                }
                 _ => match parse_stage {
                     ParseStage::Depth => {
                         limits.depth = Some(token.as_ref().parse()?);
@@ -95,6 +269,9 @@ impl Limits {
                     ParseStage::Nodes => {
                         limits.nodes = Some(token.as_ref().parse()?);
                     }
+                    ParseStage::SoftNodes => {
+                        limits.soft_nodes = Some(token.as_ref().parse()?);
+                    }
                     ParseStage::WTime => {
                         limits.wtime = Some(token.as_ref().parse()?);
                     }
@@ -127,3 +304,152 @@ impl Default for Limits {
         Limits::new()
     }
 }
+
+// The limits as `go` tokens, the inverse of `from_tokens`
+impl Display for Limits {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let mut tokens = Vec::new();
+        let mut push = |name: &str, value: Option<String>| {
+            if let Some(value) = value {
+                tokens.push(format!("{} {}", name, value));
+            }
+        };
+        push("depth", self.depth.map(|v| v.to_string()));
+        push("nodes", self.nodes.map(|v| v.to_string()));
+        push("softnodes", self.soft_nodes.map(|v| v.to_string()));
+        push("wtime", self.wtime.map(|v| v.to_string()));
+        push("btime", self.btime.map(|v| v.to_string()));
+        push("winc", self.winc.map(|v| v.to_string()));
+        push("binc", self.binc.map(|v| v.to_string()));
+        push("movestogo", self.movestogo.map(|v| v.to_string()));
+        push("movetime", self.movetime.map(|v| v.to_string()));
+        if self.infinite {
+            tokens.push("infinite".to_string());
+        }
+        if self.ponder {
+            tokens.push("ponder".to_string());
+        }
+        write!(f, "{}", tokens.join(" "))
+    }
+}
+
+// Overruns this small are the clock's own noise
+const OVERRUN_TOLERANCE: Duration = Duration::from_millis(2);
+// the most the overhead grows by on its own
+const MAX_EXTRA_OVERHEAD: Duration = Duration::from_secs(1);
+
+// Extra move overhead for the rest of a game, learned from moves whose `bestmove` came after
+// the deadline, the search's time plus the overhead held back for the gui. Whatever made one move late, a slow machine or a
+// loaded one, is assumed to happen again, so the overhead keeps twice the worst overrun.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OverheadTracker {
+    extra: Duration,
+}
+
+impl OverheadTracker {
+    pub fn extra(&self) -> Duration {
+        self.extra
+    }
+
+    // Returns the overrun when the move was late
+    pub fn record(&mut self, deadline: Duration, elapsed: Duration) -> Option<Duration> {
+        let overrun = elapsed
+            .checked_sub(deadline)
+            .filter(|&overrun| overrun > OVERRUN_TOLERANCE)?;
+        self.extra = self.extra.max(2 * overrun).min(MAX_EXTRA_OVERHEAD);
+        Some(overrun)
+    }
+
+    pub fn reset(&mut self) {
+        self.extra = Duration::ZERO;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_time_control() {
+        let tc = "8+0.08".parse::<TimeControl>().unwrap();
+        assert_eq!(
+            tc.sessions,
+            [TcSession {
+                moves: None,
+                base: Duration::from_secs(8),
+                increment: Duration::from_millis(80),
+            }]
+        );
+        assert_eq!(
+            "60".parse::<TimeControl>().unwrap().sessions[0].increment,
+            Duration::ZERO
+        );
+
+        let tc = "40/5400+30:20/1800:900+30".parse::<TimeControl>().unwrap();
+        assert_eq!(tc.sessions.len(), 3);
+        assert_eq!(tc.sessions[1].moves, Some(20));
+        assert_eq!(tc.to_string(), "40/5400+30:20/1800:900+30");
+
+        assert!("fast".parse::<TimeControl>().is_err());
+        assert!("0/60".parse::<TimeControl>().is_err());
+        assert_eq!(
+            "60:40/60".parse::<TimeControl>(),
+            Err(TimeControlParseError::SuddenDeathNotLast)
+        );
+    }
+
+    #[test]
+    fn test_sessions() {
+        let tc = "40/300+3".parse::<TimeControl>().unwrap();
+        assert_eq!(tc.session(0).1, Some(40));
+        assert_eq!(tc.session(39).1, Some(1));
+        // classical controls start over after every session
+        assert_eq!(tc.session(40).1, Some(40));
+        assert_eq!(tc.bonus(38), Duration::from_secs(3));
+        assert_eq!(tc.bonus(39), Duration::from_secs(303));
+
+        let tc = "40/5400:900+30".parse::<TimeControl>().unwrap();
+        assert_eq!(tc.bonus(39), Duration::from_secs(900));
+        assert_eq!(tc.session(40).1, None);
+        assert_eq!(tc.bonus(40), Duration::from_secs(30));
+    }
+
+    #[test]
+    fn test_from_tc() {
+        let limits = Limits::from_tc("40/300+3").unwrap();
+        assert_eq!(limits.wtime, Some(300_000));
+        assert_eq!(limits.btime, Some(300_000));
+        assert_eq!(limits.winc, Some(3_000));
+        assert_eq!(limits.movestogo, Some(40));
+
+        assert_eq!(
+            limits.to_string(),
+            "wtime 300000 btime 300000 winc 3000 binc 3000 movestogo 40"
+        );
+        let tokens = limits.to_string();
+        let tokens = tokens.split_whitespace().collect::<Vec<_>>();
+        assert_eq!(Limits::from_tokens(&tokens).unwrap(), limits);
+
+        assert_eq!(Limits::from_tc("10+0.1").unwrap().movestogo, None);
+    }
+
+    #[test]
+    fn test_overhead_tracker() {
+        let ms = Duration::from_millis;
+        let mut tracker = OverheadTracker::default();
+        assert_eq!(tracker.record(ms(100), ms(80)), None);
+        assert_eq!(tracker.record(ms(100), ms(101)), None);
+        assert_eq!(tracker.extra(), Duration::ZERO);
+
+        assert_eq!(tracker.record(ms(100), ms(130)), Some(ms(30)));
+        assert_eq!(tracker.extra(), ms(60));
+        // it only goes up over a game
+        tracker.record(ms(100), ms(110));
+        assert_eq!(tracker.extra(), ms(60));
+        tracker.record(ms(100), ms(5000));
+        assert_eq!(tracker.extra(), MAX_EXTRA_OVERHEAD);
+
+        tracker.reset();
+        assert_eq!(tracker.extra(), Duration::ZERO);
+    }
+}
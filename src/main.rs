@@ -3,22 +3,32 @@ use std::path::PathBuf;
 use anyhow::{Ok, Result};
 use clap::{Parser, Subcommand};
 use pounce::{
+    adjudicate::AdjudicationRules,
     bench::bench,
+    book::{make_book, BookConfig},
+    engine::{parse_option, play_match, read_openings, EngineConfig},
+    eval::check_symmetry,
     fen::Fen,
-    limits::Limits,
-    movegen::{init_tables, perft},
+    info::BuildInfo,
+    limits::{Limits, TimeControl},
+    movegen::{init_tables, perft_divide},
+    pgn::{extract_fens, FensConfig},
     search::init_reductions,
     uci::Uci,
     zobrist::init_zobrist,
 };
 
 #[cfg(feature = "datagen")]
-use pounce::datagen::{self, DatagenConfig};
+use pounce::datagen::{self, DataFormat, DatagenConfig, Dedup, OpeningConfig};
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 #[command(propagate_version = true)]
 struct Cli {
+    /// Apply the `Name = value` options in this file before reading UCI commands
+    #[arg(long)]
+    profile: Option<PathBuf>,
+
     #[clap(subcommand)]
     command: Option<Commands>,
 }
@@ -31,17 +41,111 @@ enum Commands {
     Bench {
         #[arg(default_value_t = 7)]
         depth: u8,
+
+        /// Run the suite on this many threads at once, each with its own hash table
+        #[arg(short, long, default_value_t = 1)]
+        threads: usize,
+    },
+    /// Build an opening book from the first plies of PGN games, played with the OwnBook and
+    /// BookFile options. It's keyed by pounce's own hash, so Polyglot tools can't read it
+    Makebook {
+        #[arg(required = true)]
+        in_files: Vec<PathBuf>,
+
+        /// Number of plies taken from every game
+        #[arg(short, long, default_value_t = 12)]
+        depth: usize,
+
+        /// Leave out moves played in fewer games than this
+        #[arg(long, default_value_t = 5)]
+        min_count: u32,
+
+        #[arg(short, long, default_value = "book.bin")]
+        out: PathBuf,
+    },
+    /// Write FENs of positions sampled from PGN games
+    Fens {
+        #[arg(required = true)]
+        in_files: Vec<PathBuf>,
+
+        /// Take every nth position of a game
+        #[arg(long, default_value_t = 1)]
+        every: usize,
+
+        /// Skip positions before this ply
+        #[arg(long, default_value_t = 0)]
+        min_ply: usize,
+
+        /// Stop each game after this ply
+        #[arg(long)]
+        max_ply: Option<usize>,
+
+        /// Write every position only once
+        #[arg(long)]
+        unique: bool,
+
+        #[arg(short, long, default_value = "fens.epd")]
+        out: PathBuf,
+    },
+    /// Check that every FEN in the files evaluates the same as its color-flipped mirror
+    Evalcheck {
+        #[arg(required = true)]
+        in_files: Vec<PathBuf>,
+    },
+    /// Print the version, build features, cpu features and default options
+    Info {
+        /// Print it as one line of JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Play a match between pounce and an external UCI engine
+    Match {
+        /// Path to the opponent's binary
+        engine: PathBuf,
+
+        /// Option for the opponent as `Name=Value`, can be repeated
+        #[arg(long = "engine-option", value_parser = parse_option)]
+        engine_options: Vec<(String, String)>,
+
+        /// Option for pounce as `Name=Value`, can be repeated
+        #[arg(long = "option", value_parser = parse_option)]
+        options: Vec<(String, String)>,
+
+        /// Time control in seconds, like `8+0.08`, `40/300+3` or `40/5400+30:1800+30`
+        #[arg(long, default_value = "8+0.08")]
+        tc: TimeControl,
+
+        #[arg(short, long, default_value_t = 10)]
+        games: u32,
+
+        /// Start the games from these openings, each played once with either color: the games
+        /// of a PGN file or FEN/EPD lines. Without it every game starts from the startpos.
+        #[arg(long)]
+        openings: Option<PathBuf>,
+
+        /// Call games still going after this many plies draws
+        #[arg(long, default_value_t = 600)]
+        max_plies: u32,
+
+        /// Play games out instead of adjudicating them once both engines agree one side wins
+        #[arg(long)]
+        no_resign: bool,
     },
     #[cfg(feature = "datagen")]
     Datagen {
         #[arg(short, long, default_value_t = 7)]
         depth: u8,
 
-        #[arg(short, long)]
-        out_path: PathBuf,
+        /// Stop searching at the end of the iteration that passes this many nodes
+        #[arg(long)]
+        soft_nodes: Option<u64>,
 
-        #[arg(short, long)]
-        num_games: u32,
+        /// Output directory, or `-` to stream records to stdout
+        #[arg(short, long, required_unless_present = "coordinate")]
+        out_path: Option<PathBuf>,
+
+        #[arg(short, long, required_unless_present = "connect")]
+        num_games: Option<u32>,
 
         #[arg(short, long, default_value_t = 1)]
         concurrency: u32,
@@ -51,6 +155,66 @@ enum Commands {
 
         #[arg(long)]
         state: Option<PathBuf>,
+
+        /// Start a new output file after this many games
+        #[arg(long)]
+        shard_games: Option<u32>,
+
+        /// Start a new output file once it reaches this many bytes
+        #[arg(long)]
+        shard_bytes: Option<u64>,
+
+        /// Hand out games to workers connecting on this address instead of playing them
+        #[arg(long, conflicts_with = "connect")]
+        coordinate: Option<String>,
+
+        /// Play games handed out by the coordinator at this address
+        #[arg(long, conflicts_with = "state")]
+        connect: Option<String>,
+
+        /// Number of games handed to a worker at a time
+        #[arg(long, default_value_t = 100)]
+        batch_size: u32,
+
+        /// Adjudicate games as draws once they reach this many plies
+        #[arg(long, default_value_t = 400)]
+        max_plies: u32,
+
+        /// Also write `.policy` files with the searched move and this many of the best root moves
+        #[arg(long)]
+        policy_moves: Option<u8>,
+
+        /// Pin each worker thread to its own cpu, filling one numa node at a time
+        #[arg(long)]
+        pin_threads: bool,
+
+        /// Fewest random plies every game starts with
+        #[arg(long, default_value_t = 8)]
+        min_random_plies: u32,
+
+        /// Most random plies every game starts with
+        #[arg(long, default_value_t = 9)]
+        max_random_plies: u32,
+
+        /// Pick random moves only among this many of the best by a shallow search
+        #[arg(long)]
+        random_top_k: Option<u32>,
+
+        /// Depth of the search ranking random moves for --random-top-k
+        #[arg(long, default_value_t = 1)]
+        random_depth: u8,
+
+        /// Centipawns, favor better moves among the top k, 0 picks uniformly
+        #[arg(long, default_value_t = 0)]
+        random_temperature: u32,
+
+        /// Centipawns, throw away openings scored further from level than this
+        #[arg(long, default_value_t = 720)]
+        max_opening_eval: i16,
+
+        /// Search with one table shared by all threads, sized --table-size per thread
+        #[arg(long)]
+        shared_tt: bool,
     },
 
     #[cfg(feature = "datagen")]
@@ -58,6 +222,37 @@ enum Commands {
         in_files: Vec<PathBuf>,
         #[arg(short, long, required = true)]
         out_file: PathBuf,
+
+        #[arg(long)]
+        dedup: bool,
+
+        #[arg(long, requires = "dedup")]
+        average_scores: bool,
+    },
+
+    #[cfg(feature = "datagen")]
+    Dataconvert {
+        input: PathBuf,
+        #[arg(short, long)]
+        output: PathBuf,
+
+        #[arg(long, value_enum)]
+        from: DataFormat,
+
+        #[arg(long, value_enum)]
+        to: DataFormat,
+    },
+
+    #[cfg(feature = "datagen")]
+    Datastats {
+        #[arg(required = true)]
+        in_files: Vec<PathBuf>,
+    },
+
+    #[cfg(feature = "datagen")]
+    Dataverify {
+        #[arg(required = true)]
+        in_files: Vec<PathBuf>,
     },
 }
 
@@ -69,8 +264,13 @@ fn main() -> Result<()> {
         Some(Commands::Perft { depth }) => {
             let Fen(mut pos) = Uci::STARTPOS.parse()?;
             let now = std::time::Instant::now();
-            let nodes = perft(&mut pos, *depth);
+            let counts = perft_divide(&mut pos, *depth);
             let elapsed = now.elapsed();
+            let nodes = counts.iter().map(|(_, count)| count).sum::<u64>();
+            for (mv, count) in counts {
+                println!("{}: {}", mv, count);
+            }
+            println!();
             println!(
                 "Nodes: {}, Time: {}s {}ms, Nodes/s: {:.2}M",
                 nodes,
@@ -80,44 +280,188 @@ fn main() -> Result<()> {
             );
             return Ok(());
         }
-        Some(Commands::Bench { depth }) => {
+        Some(Commands::Bench { depth, threads }) => {
             let limits = Limits {
                 depth: Some(*depth),
                 ..Default::default()
             };
-            return bench(16, limits);
+            return bench(16, limits, *threads);
+        }
+        Some(Commands::Makebook {
+            in_files,
+            depth,
+            min_count,
+            out,
+        }) => {
+            return make_book(&BookConfig {
+                in_files: in_files.clone(),
+                out_file: out.clone(),
+                depth: *depth,
+                min_count: *min_count,
+            });
+        }
+        Some(Commands::Fens {
+            in_files,
+            every,
+            min_ply,
+            max_ply,
+            unique,
+            out,
+        }) => {
+            return extract_fens(&FensConfig {
+                in_files: in_files.clone(),
+                out_file: out.clone(),
+                every: *every,
+                min_ply: *min_ply,
+                max_ply: *max_ply,
+                unique: *unique,
+            });
+        }
+        Some(Commands::Evalcheck { in_files }) => {
+            return check_symmetry(in_files);
+        }
+        Some(Commands::Info { json }) => {
+            let info = BuildInfo::detect();
+            if *json {
+                println!("{}", info.to_json());
+            } else {
+                print!("{}", info);
+            }
+            return Ok(());
+        }
+        Some(Commands::Match {
+            engine,
+            engine_options,
+            options,
+            tc,
+            games,
+            openings,
+            max_plies,
+            no_resign,
+        }) => {
+            let pounce = EngineConfig {
+                command: std::env::current_exe()?,
+                options: options.clone(),
+            };
+            let opponent = EngineConfig {
+                command: engine.clone(),
+                options: engine_options.clone(),
+            };
+            let rules = AdjudicationRules {
+                max_plies: Some(*max_plies),
+                resign_moves: AdjudicationRules::default()
+                    .resign_moves
+                    .filter(|_| !*no_resign),
+                ..Default::default()
+            };
+            let openings = match openings {
+                Some(path) => read_openings(path)?,
+                None => Vec::new(),
+            };
+            let score = play_match(&pounce, &opponent, tc, *games, &openings, rules)?;
+            println!("Final score: {}", score);
+            return Ok(());
         }
         #[cfg(feature = "datagen")]
         Some(Commands::Datagen {
             depth,
+            soft_nodes,
             out_path,
             num_games,
             concurrency,
             table_size,
             state,
+            shard_games,
+            shard_bytes,
+            coordinate,
+            connect,
+            batch_size,
+            max_plies,
+            policy_moves,
+            pin_threads,
+            min_random_plies,
+            max_random_plies,
+            random_top_k,
+            random_depth,
+            random_temperature,
+            max_opening_eval,
+            shared_tt,
         }) => {
-            return datagen::datagen(DatagenConfig {
+            if let Some(addr) = coordinate {
+                return datagen::coordinate(addr, num_games.unwrap(), *batch_size);
+            }
+
+            let config = DatagenConfig {
                 limits: Limits {
                     depth: Some(*depth),
+                    soft_nodes: *soft_nodes,
                     ..Limits::new()
                 },
-                num_games: num_games.to_owned(),
+                num_games: num_games.unwrap_or(0),
                 tt_size_mb: *table_size,
                 concurrency: concurrency.to_owned(),
-                out_path: out_path.to_owned(),
+                out_path: out_path.clone().unwrap(),
                 state_path: state.clone(),
-            });
+                shard_games: *shard_games,
+                shard_bytes: *shard_bytes,
+                pin_threads: *pin_threads,
+                max_plies: Some(*max_plies),
+                policy_moves: *policy_moves,
+                opening: OpeningConfig {
+                    min_plies: *min_random_plies,
+                    max_plies: *max_random_plies,
+                    top_k: *random_top_k,
+                    depth: *random_depth,
+                    temperature: *random_temperature,
+                    max_eval: *max_opening_eval,
+                },
+                shared_tt: *shared_tt,
+            };
+
+            if let Some(addr) = connect {
+                return datagen::connect(addr, config);
+            }
+            return datagen::datagen(config);
         }
         #[cfg(feature = "datagen")]
-        Some(Commands::Datamix { in_files, out_file }) => {
-            datagen::shuffle_interleave(in_files, out_file);
-            return Ok(());
+        Some(Commands::Datamix {
+            in_files,
+            out_file,
+            dedup,
+            average_scores,
+        }) => {
+            let dedup = match (dedup, average_scores) {
+                (false, _) => Dedup::Off,
+                (true, false) => Dedup::KeepFirst,
+                (true, true) => Dedup::AverageScores,
+            };
+            return datagen::shuffle_interleave(in_files, out_file, dedup);
+        }
+        #[cfg(feature = "datagen")]
+        Some(Commands::Dataconvert {
+            input,
+            output,
+            from,
+            to,
+        }) => {
+            return datagen::data_convert(input, output, *from, *to);
+        }
+        #[cfg(feature = "datagen")]
+        Some(Commands::Datastats { in_files }) => {
+            return datagen::data_stats(in_files);
+        }
+        #[cfg(feature = "datagen")]
+        Some(Commands::Dataverify { in_files }) => {
+            return datagen::data_verify(in_files);
         }
 
         _ => {}
     }
 
     let mut uci = Uci::new();
+    if let Some(path) = &cli.profile {
+        uci.load_profile(path)?;
+    }
 
     uci.run_loop()
 }
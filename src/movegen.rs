@@ -13,20 +13,27 @@ pub use tables::{
     init_tables,
     line,
     rook_rays,
+    xray_bishop_attacks,
+    xray_rook_attacks,
 };
 pub use types::{
     BishopType,
     KingType,
     KnightType,
+    MoveBuffer,
     MoveGen,
     MoveList,
     Mover,
     PawnType,
     QueenType,
     RookType,
+    MAX_LEGAL_MOVES,
 };
 
-use crate::position::Position;
+use crate::{
+    moves::Move,
+    position::Position,
+};
 
 mod magic;
 mod magic_gen;
@@ -43,7 +50,7 @@ mod rook;
 pub mod magic_finder;
 
 #[inline]
-pub fn perft(pos: &mut Position, depth: u8) -> usize {
+pub fn perft(pos: &mut Position, depth: u8) -> u64 {
     let mut total = 0;
     let mut mg = MoveGen::new(pos);
 
@@ -52,7 +59,7 @@ pub fn perft(pos: &mut Position, depth: u8) -> usize {
     }
 
     if depth == 1 {
-        return mg.len();
+        return mg.len() as u64;
     }
 
     for m in &mut mg {
@@ -63,8 +70,56 @@ pub fn perft(pos: &mut Position, depth: u8) -> usize {
     total
 }
 
+// Perft counts below every root move, in move generation order
+pub fn perft_divide(pos: &mut Position, depth: u8) -> Vec<(Move, u64)> {
+    if depth == 0 {
+        return Vec::new();
+    }
+
+    let mut counts = Vec::new();
+    for m in MoveGen::new(pos) {
+        pos.make_move(m);
+        counts.push((m, perft(pos, depth - 1)));
+        pos.unmake_move(m)
+    }
+    counts
+}
+
+// Perft counts for every line of `split` moves from the root, in move generation order.
+// With a split of 1 this is `perft_divide`.
+pub fn split_perft(pos: &mut Position, depth: u8, split: u8) -> Vec<(Vec<Move>, u64)> {
+    let mut counts = Vec::new();
+    let mut line = Vec::new();
+    split_perft_inner(pos, depth, split.clamp(1, depth.max(1)), &mut line, &mut counts);
+    counts
+}
+
+fn split_perft_inner(
+    pos: &mut Position,
+    depth: u8,
+    split: u8,
+    line: &mut Vec<Move>,
+    counts: &mut Vec<(Vec<Move>, u64)>,
+) {
+    if depth == 0 {
+        return;
+    }
+
+    for m in MoveGen::new(pos) {
+        pos.make_move(m);
+        line.push(m);
+        if split == 1 {
+            counts.push((line.clone(), perft(pos, depth - 1)));
+        } else {
+            split_perft_inner(pos, depth - 1, split - 1, line, counts);
+        }
+        line.pop();
+        pos.unmake_move(m)
+    }
+}
+
 #[cfg(test)]
-fn masked_perft(pos: &mut Position, depth: u8) -> usize {
+fn masked_perft(pos: &mut Position, depth: u8) -> u64 {
     use crate::bitboard::Bitboard;
 
     if depth == 0 {
@@ -96,16 +151,115 @@ fn masked_perft(pos: &mut Position, depth: u8) -> usize {
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::fen::Fen;
+    use crate::{
+        bitboard::Bitboard,
+        chess::Square,
+        fen::Fen,
+        moves::MoveType,
+        positions::{
+            KIWIPETE,
+            POSITION_3,
+            POSITION_4,
+            POSITION_5,
+            POSITION_6,
+            STARTPOS,
+        },
+    };
+
+    fn check_counts(pos: &mut Position, depth: u8) {
+        let len = MoveGen::new(pos).len();
+        assert_eq!(MoveGen::count(pos), len);
+        assert_eq!(MoveGen::has_legal_move(pos), len > 0);
+        if depth > 0 {
+            for m in MoveGen::new(pos) {
+                pos.make_move(m);
+                check_counts(pos, depth - 1);
+                pos.unmake_move(m);
+            }
+        }
+    }
+
+    #[test]
+    fn count_and_has_legal_move() {
+        init_tables();
+        for fen in [KIWIPETE, POSITION_3, POSITION_4, POSITION_5] {
+            let Fen(mut position) = Fen::parse(fen).unwrap();
+            check_counts(&mut position, 2);
+        }
+
+        // checkmate and stalemate
+        for fen in ["7k/6Q1/6K1/8/8/8/8/8 b - - 0 1", "7k/5Q2/6K1/8/8/8/8/8 b - - 0 1"] {
+            let Fen(position) = Fen::parse(fen).unwrap();
+            assert_eq!(MoveGen::count(&position), 0);
+            assert!(!MoveGen::has_legal_move(&position));
+        }
+    }
+
+    #[test]
+    fn collect_helpers() {
+        init_tables();
+        let Fen(position) = Fen::parse(KIWIPETE).unwrap();
+        let legal = MoveGen::legal_vec(&position);
+        assert_eq!(legal, MoveGen::new(&position).collect::<Vec<_>>());
+        assert_eq!(legal.len(), 48);
+
+        let mut buffer = MoveBuffer::new();
+        MoveGen::new(&position).collect_into(&mut buffer);
+        assert_eq!(buffer.as_slice(), legal);
 
-    const STARTPOS: &str = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1";
-    const KIWIPETE_FEN: &str =
-        "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1";
-    const POSITTION_3_FEN: &str = "8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - - 0 1";
-    const POSITION_4_FEN: &str = "r3k2r/Pppp1ppp/1b3nbN/nP6/BBP1P3/q4N2/Pp1P2PP/R2Q1RK1 w kq - 0 1";
-    const POSITION_5_FEN: &str = "rnbq1k1r/pp1Pbppp/2p5/8/2B5/8/PPP1NnPP/RNBQK2R w KQ - 1 8";
-    const POSITION_6_FEN: &str =
-        "r4rk1/1pp1qppp/p1np1n2/2b1p1B1/2B1P1b1/P1NP1N2/1PP1QPPP/R4RK1 w - - 0 10";
+        // the buffer is replaced, not appended to
+        let Fen(position) = Fen::parse(STARTPOS).unwrap();
+        MoveGen::new(&position).collect_into(&mut buffer);
+        assert_eq!(buffer.len(), 20);
+    }
+
+    #[test]
+    fn xray_attacks() {
+        init_tables();
+        let Fen(position) = Fen::parse("8/1k6/8/8/4n3/8/8/R3K2B w - - 0 1").unwrap();
+        let sq = |s: &str| s.parse::<Square>().unwrap();
+        let occ = position.occupancy;
+
+        // the rook sees through its own king along the rank, there's nothing to see through on
+        // the file
+        let xray = xray_rook_attacks(sq("a1"), occ, position.us());
+        assert_eq!(xray, Bitboard::from(sq("f1")) | sq("g1") | sq("h1"));
+        assert_eq!(xray_rook_attacks(sq("a1"), occ, position.them()), Bitboard::EMPTY);
+
+        // the knight is pinned: right behind it on the diagonal is its king
+        let xray = xray_bishop_attacks(sq("h1"), occ, position.them());
+        assert_eq!(xray, Bitboard::from(sq("d5")) | sq("c6") | sq("b7"));
+        assert_eq!(xray_bishop_attacks(sq("h1"), occ, Bitboard::EMPTY), Bitboard::EMPTY);
+    }
+
+    #[test]
+    fn move_types() {
+        init_tables();
+        let count = |fen: &str, move_type: MoveType| {
+            let Fen(position) = Fen::parse(fen).unwrap();
+            MoveGen::new(&position)
+                .filter(|mv| mv.move_type() == move_type)
+                .count()
+        };
+        assert_eq!(count(KIWIPETE, MoveType::Castle), 2);
+        assert_eq!(count(KIWIPETE, MoveType::DoublePawnPush), 2);
+        assert_eq!(count(KIWIPETE, MoveType::EnPassant), 0);
+        assert_eq!(count(POSITION_4, MoveType::Promotion), 0);
+        assert_eq!(count(STARTPOS, MoveType::DoublePawnPush), 8);
+
+        let ep = "rnbqkbnr/ppp1p1pp/8/3pPp2/8/8/PPPP1PPP/RNBQKBNR w KQkq f6 0 3";
+        assert_eq!(count(ep, MoveType::EnPassant), 1);
+
+        // parsed moves don't know their type until they're matched against the legal ones
+        let Fen(position) = Fen::parse(KIWIPETE).unwrap();
+        let castle = "e1g1".parse::<Move>().unwrap();
+        assert_eq!(castle.move_type(), MoveType::Normal);
+        let castle = position.legal_move(castle).unwrap();
+        assert_eq!(castle.move_type(), MoveType::Castle);
+        assert!(MoveGen::new(&position).contains(castle));
+        assert!(!MoveGen::new(&position).contains("e1g1".parse().unwrap()));
+        assert_eq!(position.legal_move("e1e3".parse().unwrap()), None);
+    }
 
     #[test]
     fn perft_normal() {
@@ -118,6 +272,28 @@ mod test {
         assert_eq!(perft(&mut position.clone(), 6), 119_060_324);
     }
 
+    #[test]
+    fn split_perft_normal() {
+        init_tables();
+        let Fen(position) = Fen::parse(KIWIPETE).unwrap();
+        let divide = perft_divide(&mut position.clone(), 3);
+        assert_eq!(divide.len(), 48);
+        assert_eq!(divide.iter().map(|(_, n)| n).sum::<u64>(), 97_862);
+        let split = split_perft(&mut position.clone(), 3, 1);
+        assert!(split
+            .iter()
+            .zip(&divide)
+            .all(|((line, a), (mv, b))| line == &[*mv] && a == b));
+
+        let split = split_perft(&mut position.clone(), 3, 2);
+        assert_eq!(split.len(), 2039);
+        assert_eq!(split.iter().map(|(_, n)| n).sum::<u64>(), 97_862);
+        assert!(split.iter().all(|(line, _)| line.len() == 2));
+
+        // a split deeper than the depth is clamped
+        assert_eq!(split_perft(&mut position.clone(), 2, 5).len(), 2039);
+    }
+
     #[test]
     fn masked_perft_normal() {
         init_tables();
@@ -131,7 +307,7 @@ mod test {
     #[test]
     fn perft_kiwipete() {
         init_tables();
-        let Fen(position) = Fen::parse(KIWIPETE_FEN).unwrap();
+        let Fen(position) = Fen::parse(KIWIPETE).unwrap();
         assert_eq!(perft(&mut position.clone(), 1), 48);
         assert_eq!(perft(&mut position.clone(), 2), 2_039);
         assert_eq!(perft(&mut position.clone(), 3), 97_862);
@@ -142,7 +318,7 @@ mod test {
     #[test]
     fn perft_pos_3() {
         init_tables();
-        let Fen(position) = Fen::parse(POSITTION_3_FEN).unwrap();
+        let Fen(position) = Fen::parse(POSITION_3).unwrap();
         assert_eq!(perft(&mut position.clone(), 1), 14);
         assert_eq!(perft(&mut position.clone(), 2), 191);
         assert_eq!(perft(&mut position.clone(), 3), 2_812);
@@ -155,7 +331,7 @@ mod test {
     #[test]
     fn perft_pos_4() {
         init_tables();
-        let Fen(position) = Fen::parse(POSITION_4_FEN).unwrap();
+        let Fen(position) = Fen::parse(POSITION_4).unwrap();
         assert_eq!(perft(&mut position.clone(), 1), 6);
         assert_eq!(perft(&mut position.clone(), 2), 264);
         assert_eq!(perft(&mut position.clone(), 3), 9_467);
@@ -166,7 +342,7 @@ mod test {
     #[test]
     fn perft_pos_5() {
         init_tables();
-        let Fen(position) = Fen::parse(POSITION_5_FEN).unwrap();
+        let Fen(position) = Fen::parse(POSITION_5).unwrap();
         assert_eq!(perft(&mut position.clone(), 1), 44);
         assert_eq!(perft(&mut position.clone(), 2), 1_486);
         assert_eq!(perft(&mut position.clone(), 3), 62_379);
@@ -177,17 +353,52 @@ mod test {
     #[test]
     fn masked_perft_pos_5() {
         init_tables();
-        let Fen(position) = Fen::parse(POSITION_5_FEN).unwrap();
+        let Fen(position) = Fen::parse(POSITION_5).unwrap();
         assert_eq!(masked_perft(&mut position.clone(), 1), 44);
         assert_eq!(masked_perft(&mut position.clone(), 2), 1_486);
         assert_eq!(masked_perft(&mut position.clone(), 3), 62_379);
         assert_eq!(masked_perft(&mut position.clone(), 4), 2_103_487);
     }
 
+    #[test]
+    fn masked_perft_pos_4() {
+        init_tables();
+        let Fen(position) = Fen::parse(POSITION_4).unwrap();
+        assert_eq!(masked_perft(&mut position.clone(), 1), 6);
+        assert_eq!(masked_perft(&mut position.clone(), 2), 264);
+        assert_eq!(masked_perft(&mut position.clone(), 3), 9_467);
+        assert_eq!(masked_perft(&mut position.clone(), 4), 422_333);
+    }
+
+    #[test]
+    fn mask_changed_mid_promotions() {
+        init_tables();
+        let Fen(position) = Fen::parse("r1n1k3/1P1P4/8/8/8/8/8/4K3 w - - 0 1").unwrap();
+        let mut legal = MoveGen::new(&position).collect::<Vec<_>>();
+
+        // switch masks after every move, starting part way through a square's promotions
+        let masks = [position.them(), !position.occupancy, Bitboard::FULL];
+        let mut mg = MoveGen::new(&position);
+        let mut moves = Vec::new();
+        for mask in masks.iter().cycle().take(24) {
+            mg.set_mask(*mask);
+            if let Some(mv) = mg.next() {
+                assert!((*mask & mv.to()).any());
+                moves.push(mv);
+            }
+        }
+        mg.set_mask(Bitboard::FULL);
+        moves.extend(mg);
+
+        legal.sort_by_key(|mv| u16::from(*mv));
+        moves.sort_by_key(|mv| u16::from(*mv));
+        assert_eq!(moves, legal);
+    }
+
     #[test]
     fn perft_pos_6() {
         init_tables();
-        let Fen(position) = Fen::parse(POSITION_6_FEN).unwrap();
+        let Fen(position) = Fen::parse(POSITION_6).unwrap();
         assert_eq!(perft(&mut position.clone(), 1), 46);
         assert_eq!(perft(&mut position.clone(), 2), 2_079);
         assert_eq!(perft(&mut position.clone(), 3), 89_890);
@@ -1,6 +1,7 @@
 use types::{
     FromAndMoves,
     KingType,
+    MovesKind,
 };
 
 use crate::{
@@ -74,7 +75,7 @@ impl Mover for KingType {
 
         if moves != Bitboard::EMPTY {
             unsafe {
-                movelist.push_unchecked(FromAndMoves::new(ksq, moves, false));
+                movelist.push_unchecked(FromAndMoves::new(ksq, moves, MovesKind::King));
             }
         }
     }
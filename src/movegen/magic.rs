@@ -36,8 +36,8 @@ impl Magic {
 const ROOK_TABLE_SIZE: usize = calc_size(&ROOK_MAGICS);
 const BISHOP_TABLE_SIZE: usize = calc_size(&BISHOP_MAGICS);
 
-pub static ROOK_ATTACKS: [Bitboard; ROOK_TABLE_SIZE] = init_rook_magics();
-pub static BISHOP_ATTACKS: [Bitboard; BISHOP_TABLE_SIZE] = init_bishop_magics();
+pub(crate) static ROOK_ATTACKS: [Bitboard; ROOK_TABLE_SIZE] = init_rook_magics();
+pub(crate) static BISHOP_ATTACKS: [Bitboard; BISHOP_TABLE_SIZE] = init_bishop_magics();
 
 const fn init_rook_magics() -> [Bitboard; ROOK_TABLE_SIZE] {
     let mut table = [Bitboard(0); ROOK_TABLE_SIZE];
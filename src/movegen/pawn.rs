@@ -1,5 +1,6 @@
 use types::{
     FromAndMoves,
+    MovesKind,
     PawnType,
 };
 
@@ -52,6 +53,24 @@ impl Mover for PawnType {
         let checkers = pos.checkers;
 
         let promotion_bb = Bitboard::from(side.opponent().home_rank());
+        // promoting pawns get an entry per role, queens first
+        let push = |movelist: &mut MoveList, sq: Square, moves: Bitboard| {
+            if (promotion_bb & sq).any() {
+                for role in [Role::Queen, Role::Rook, Role::Bishop, Role::Knight] {
+                    unsafe {
+                        movelist.push_unchecked(FromAndMoves::new(
+                            sq,
+                            moves,
+                            MovesKind::Promotion(role),
+                        ));
+                    }
+                }
+            } else {
+                unsafe {
+                    movelist.push_unchecked(FromAndMoves::new(sq, moves, MovesKind::Pawn));
+                }
+            }
+        };
 
         let check_mask = if CHECK {
             between(Square::from(checkers), ksq) ^ checkers
@@ -62,13 +81,7 @@ impl Mover for PawnType {
         for sq in pieces & !pinned {
             let moves = Self::pseudo_legal_moves::<BLACK>(sq, pos) & check_mask;
             if moves != Bitboard::EMPTY {
-                unsafe {
-                    movelist.push_unchecked(FromAndMoves::new(
-                        sq,
-                        moves,
-                        promotion_bb & Bitboard::from(sq) != Bitboard::EMPTY,
-                    ));
-                }
+                push(movelist, sq, moves);
             }
         }
 
@@ -76,13 +89,7 @@ impl Mover for PawnType {
             for sq in pieces & pinned {
                 let moves = Self::pseudo_legal_moves::<BLACK>(sq, pos) & line(ksq, sq);
                 if moves != Bitboard::EMPTY {
-                    unsafe {
-                        movelist.push_unchecked(FromAndMoves::new(
-                            sq,
-                            moves,
-                            promotion_bb & Bitboard::from(sq) != Bitboard::EMPTY,
-                        ));
-                    }
+                    push(movelist, sq, moves);
                 }
             }
         }
@@ -94,7 +101,11 @@ impl Mover for PawnType {
             for sq in ep_source_squares {
                 if Self::legal_ep_move::<BLACK>(sq, ep, pos) {
                     unsafe {
-                        movelist.push_unchecked(FromAndMoves::new(sq, Bitboard::from(ep), false));
+                        movelist.push_unchecked(FromAndMoves::new(
+                            sq,
+                            Bitboard::from(ep),
+                            MovesKind::EnPassant,
+                        ));
                     }
                 }
             }
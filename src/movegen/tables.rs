@@ -75,6 +75,23 @@ pub fn get_bishop_moves(sq: Square, occ: Bitboard) -> Bitboard {
     }
 }
 
+// The squares a rook on `sq` would attack if the pieces of `blockers` it attacks directly were
+// gone, i.e. what it attacks through them. Pins and skewers are the x-rayed king or piece.
+#[inline(always)]
+pub fn xray_rook_attacks(sq: Square, occ: Bitboard, blockers: Bitboard) -> Bitboard {
+    let attacks = get_rook_moves(sq, occ);
+    let blockers = blockers & attacks;
+    attacks ^ get_rook_moves(sq, occ ^ blockers)
+}
+
+// Like `xray_rook_attacks` along the diagonals
+#[inline(always)]
+pub fn xray_bishop_attacks(sq: Square, occ: Bitboard, blockers: Bitboard) -> Bitboard {
+    let attacks = get_bishop_moves(sq, occ);
+    let blockers = blockers & attacks;
+    attacks ^ get_bishop_moves(sq, occ ^ blockers)
+}
+
 #[inline(always)]
 pub fn get_knight_moves(sq: Square) -> Bitboard {
     unsafe { *KNIGHT_MOVES.get_unchecked(sq as usize) }
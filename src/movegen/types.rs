@@ -12,40 +12,53 @@ use crate::{
     position::Position,
 };
 
-pub type MoveList = ArrayVec<FromAndMoves, 18>;
+// One entry per piece, except pawns about to promote which get one per promotion role, plus
+// up to two en passant captures: 8 * 4 + 8 + 2
+pub type MoveList = ArrayVec<FromAndMoves, 42>;
+
+// No position has more legal moves than this
+pub const MAX_LEGAL_MOVES: usize = 218;
+// Holds every legal move of a position without allocating, see `MoveGen::collect_into`
+pub type MoveBuffer = ArrayVec<Move, MAX_LEGAL_MOVES>;
+
+// What the moves of an entry are, so the iterator can flag their move type from the squares
+// alone
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MovesKind {
+    Normal,
+    // single and double pushes and captures
+    Pawn,
+    // the promotions to one role
+    Promotion(Role),
+    EnPassant,
+    // steps and castles
+    King,
+}
 
 #[derive(Debug, Clone, Copy)]
 pub struct FromAndMoves {
     from: Square,
     moves: Bitboard,
-    is_promotion: bool,
+    kind: MovesKind,
 }
 
 impl FromAndMoves {
-    pub fn new(from: Square, moves: Bitboard, is_promotion: bool) -> Self {
-        FromAndMoves {
-            from,
-            moves,
-            is_promotion,
-        }
+    pub fn new(from: Square, moves: Bitboard, kind: MovesKind) -> Self {
+        FromAndMoves { from, moves, kind }
     }
-}
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-enum PromotionIndex {
-    Queen,
-    Rook,
-    Bishop,
-    Knight,
-}
-
-impl PromotionIndex {
-    fn next(self) -> Self {
-        match self {
-            PromotionIndex::Queen => PromotionIndex::Rook,
-            PromotionIndex::Rook => PromotionIndex::Bishop,
-            PromotionIndex::Bishop => PromotionIndex::Knight,
-            PromotionIndex::Knight => PromotionIndex::Queen,
+    #[inline]
+    fn to_move(self, to: Square) -> Move {
+        match self.kind {
+            MovesKind::Promotion(role) => Move::new(self.from, to, Some(role)),
+            MovesKind::Pawn if self.from.rank().distance(to.rank()) == 2 => {
+                Move::new_double_pawn_push(self.from, to)
+            }
+            MovesKind::King if self.from.file().distance(to.file()) == 2 => {
+                Move::new_castle(self.from, to)
+            }
+            MovesKind::EnPassant => Move::new_en_passant(self.from, to),
+            _ => Move::new(self.from, to, None),
         }
     }
 }
@@ -54,12 +67,50 @@ impl PromotionIndex {
 pub struct MoveGen {
     moves: MoveList,
     index: usize,
-    promotion_index: PromotionIndex,
     iter_mask: Bitboard,
 }
 
 impl MoveGen {
     pub fn new(pos: &Position) -> Self {
+        MoveGen {
+            moves: Self::generate(pos),
+            index: 0,
+            iter_mask: Bitboard::FULL,
+        }
+    }
+
+    // The number of legal moves, without setting up an iterator over them
+    pub fn count(pos: &Position) -> usize {
+        count_moves(&Self::generate(pos), Bitboard::FULL)
+    }
+
+    // The legal moves in a Vec, sized up front
+    pub fn legal_vec(pos: &Position) -> Vec<Move> {
+        let mg = MoveGen::new(pos);
+        let mut moves = Vec::with_capacity(mg.len());
+        moves.extend(mg);
+        moves
+    }
+
+    // Replaces the contents of `buffer` with the moves not handed out yet. Reusing one buffer
+    // keeps a loop over positions free of allocations.
+    pub fn collect_into(self, buffer: &mut MoveBuffer) {
+        buffer.clear();
+        buffer.extend(self);
+    }
+
+    // Stops at the first piece type with a legal move, the king first since in check it's the
+    // most likely to have one
+    pub fn has_legal_move(pos: &Position) -> bool {
+        match (pos.checkers.count(), pos.side) {
+            (0, Color::White) => has_legal_move::<false, false>(pos),
+            (0, Color::Black) => has_legal_move::<false, true>(pos),
+            (_, Color::White) => has_legal_move::<true, false>(pos),
+            (_, Color::Black) => has_legal_move::<true, true>(pos),
+        }
+    }
+
+    fn generate(pos: &Position) -> MoveList {
         let mut moves = MoveList::new();
         let checkers = pos.checkers;
 
@@ -112,35 +163,52 @@ impl MoveGen {
             }
         }
 
-        MoveGen {
-            moves,
-            index: 0,
-            promotion_index: PromotionIndex::Queen,
-            iter_mask: Bitboard::FULL,
-        }
+        moves
     }
 
     pub fn set_mask(&mut self, mask: Bitboard) {
         self.index = 0;
         self.iter_mask = mask;
     }
+
+    // Whether `mv` is legal and hasn't been handed out yet
+    pub fn contains(&self, mv: Move) -> bool {
+        self.moves.iter().any(|entry| {
+            entry.from == mv.from() && (entry.moves & mv.to()).any() && entry.to_move(mv.to()) == mv
+        })
+    }
 }
 
 impl ExactSizeIterator for MoveGen {
     fn len(&self) -> usize {
-        let mut res = 0;
-        for i in self.index..self.moves.len() {
-            let move_count = (self.moves[i].moves & self.iter_mask).count();
-            if self.moves[i].is_promotion {
-                res += move_count * 4;
-            } else {
-                res += move_count;
-            }
-        }
-        res as usize
+        count_moves(&self.moves[self.index..], self.iter_mask)
     }
 }
 
+fn count_moves(moves: &[FromAndMoves], mask: Bitboard) -> usize {
+    moves
+        .iter()
+        .map(|from_and_moves| (from_and_moves.moves & mask).count() as usize)
+        .sum()
+}
+
+fn has_moves<M: Mover, const CHECK: bool, const BLACK: bool>(pos: &Position) -> bool {
+    let mut moves = MoveList::new();
+    M::legal_moves::<CHECK, BLACK>(pos, &mut moves);
+    !moves.is_empty()
+}
+
+fn has_legal_move<const CHECK: bool, const BLACK: bool>(pos: &Position) -> bool {
+    // in double check only the king can move
+    has_moves::<KingType, CHECK, BLACK>(pos)
+        || (pos.checkers.count() < 2
+            && (has_moves::<PawnType, CHECK, BLACK>(pos)
+                || has_moves::<KnightType, CHECK, BLACK>(pos)
+                || has_moves::<BishopType, CHECK, BLACK>(pos)
+                || has_moves::<RookType, CHECK, BLACK>(pos)
+                || has_moves::<QueenType, CHECK, BLACK>(pos)))
+}
+
 impl Iterator for MoveGen {
     type Item = Move;
 
@@ -149,48 +217,15 @@ impl Iterator for MoveGen {
         (len, Some(len))
     }
 
+    // Every destination is taken out of its entry as it's handed out, so changing the mask
+    // part way through never repeats or skips a move
     fn next(&mut self) -> Option<Self::Item> {
-        if self.index >= self.moves.len() {
-            None
-        } else if self.moves[self.index].is_promotion {
-            let moves = &mut self.moves[self.index];
-            let masked = moves.moves & self.iter_mask;
-            if masked == Bitboard::EMPTY {
-                self.index += 1;
-                return self.next();
-            }
-            let to = Square::from(masked);
-
-            match self.promotion_index {
-                PromotionIndex::Queen => {
-                    self.promotion_index = self.promotion_index.next();
-                    Some(Move::new(moves.from, to, Some(Role::Queen)))
-                }
-                PromotionIndex::Rook => {
-                    self.promotion_index = self.promotion_index.next();
-                    Some(Move::new(moves.from, to, Some(Role::Rook)))
-                }
-                PromotionIndex::Bishop => {
-                    self.promotion_index = self.promotion_index.next();
-                    Some(Move::new(moves.from, to, Some(Role::Bishop)))
-                }
-                PromotionIndex::Knight => {
-                    self.promotion_index = self.promotion_index.next();
-
-                    moves.moves ^= Bitboard::from(to);
-                    if moves.moves == Bitboard::EMPTY {
-                        self.index += 1;
-                    }
-
-                    Some(Move::new(moves.from, to, Some(Role::Knight)))
-                }
-            }
-        } else {
+        while self.index < self.moves.len() {
             let moves = &mut self.moves[self.index];
             let masked = moves.moves & self.iter_mask;
             if masked == Bitboard::EMPTY {
                 self.index += 1;
-                return self.next();
+                continue;
             }
             let to = Square::from(masked);
 
@@ -199,8 +234,9 @@ impl Iterator for MoveGen {
                 self.index += 1;
             }
 
-            Some(Move::new(moves.from, to, None))
+            return Some(moves.to_move(to));
         }
+        None
     }
 }
 
@@ -241,7 +277,7 @@ pub trait Mover {
                     movelist.push_unchecked(FromAndMoves {
                         from: sq,
                         moves,
-                        is_promotion: false,
+                        kind: MovesKind::Normal,
                     })
                 }
             }
@@ -255,7 +291,7 @@ pub trait Mover {
                         movelist.push_unchecked(FromAndMoves {
                             from: sq,
                             moves,
-                            is_promotion: false,
+                            kind: MovesKind::Normal,
                         });
                     }
                 }
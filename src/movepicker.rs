@@ -4,27 +4,38 @@ use crate::{
     bitboard::Bitboard,
     chess::{
         Color,
+        Role,
         Square,
     },
+    eval::PIECE_VALUES,
     movegen::MoveGen,
     moves::Move,
     position::Position,
 };
 
 const CAPTURE_SCORE: i16 = 30_000;
-const KILLER_1_SCORE: i16 = 29_001;
-const KILLER_2_SCORE: i16 = 29_000;
 
-pub const MAX_MOVES: usize = 256;
+pub(crate) const MAX_MOVES: usize = 256;
+// quiets with worse history than this aren't sorted, they're tried in generation order
+const QUIET_SORT_LIMIT: i32 = -1024;
+
+// Quiet history is kept per side, piece and destination, with promotions in a bucket of their
+// own after the roles
+pub(crate) const HISTORY_BUCKETS: usize = Role::NUM + 1;
+pub(crate) type History = [[[i16; Square::NUM]; HISTORY_BUCKETS]; Color::NUM];
+
+// Where `mv`'s history lives, the piece is looked up so it has to be called before the move is
+// made
+pub(crate) fn history_bucket(position: &Position, mv: Move) -> usize {
+    match mv.promotion() {
+        Some(_) => Role::NUM,
+        None => position.role_at(mv.from()).unwrap() as usize,
+    }
+}
 
-const MVV_LVA: [[i16; 6]; 6] = [
-    [15, 25, 35, 45, 55, 0], // attacker pawn, victim P, N, B, R, Q,  K
-    [14, 24, 34, 44, 54, 0], // attacker knight, victim P, N, B, R, Q,  K
-    [13, 23, 33, 43, 53, 0], // attacker bishop, victim P, N, B, R, Q,  K
-    [12, 22, 32, 42, 52, 0], // attacker rook, victim P, N, B, R, Q,  K
-    [11, 21, 31, 41, 51, 0], // attacker queen, victim P, N, B, R, Q,  K
-    [10, 20, 30, 40, 50, 0], // attacker king, victim P, N, B, R, Q,  K
-];
+pub(crate) fn quiet_history(history: &History, position: &Position, mv: Move) -> i16 {
+    history[position.side][history_bucket(position, mv)][mv.to()]
+}
 
 struct MoveWithScore {
     m: Move,
@@ -33,22 +44,23 @@ struct MoveWithScore {
 
 type MoveList = ArrayVec<MoveWithScore, MAX_MOVES>;
 
-// TODO: killers, history, etc.
 enum MovePickerStage {
     TT,
     ScoreCaptures,
     Captures,
+    // tried before the other quiets are generated, nodes that cut on a killer never get that far
+    Killers(usize),
     ScoreQuiets,
     Quiets,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum MovePickerMode {
+pub(crate) enum MovePickerMode {
     Normal,
     Quiescence,
 }
 
-pub struct MovePicker {
+pub(crate) struct MovePicker {
     move_generator: MoveGen,
     stage: MovePickerStage,
     mode: MovePickerMode,
@@ -60,7 +72,7 @@ pub struct MovePicker {
 }
 
 impl MovePicker {
-    pub fn new(
+    pub(crate) fn new(
         pos: &Position,
         mode: MovePickerMode,
         tt_move: Move,
@@ -78,53 +90,92 @@ impl MovePicker {
         }
     }
 
-    pub fn new_quiescence(pos: &Position, mut tt_move: Move) -> MovePicker {
+    pub(crate) fn new_quiescence(pos: &Position, mut tt_move: Move) -> MovePicker {
         // If the tt move isn't a capture, we can't use it in quiescence search
         if tt_move != Move::NONE && (pos.occupancy & tt_move.to()).none() {
             tt_move = Move::NONE;
         }
+        if is_underpromotion(tt_move) {
+            tt_move = Move::NONE;
+        }
 
         MovePicker::new(pos, MovePickerMode::Quiescence, tt_move, [Move::NONE; 2])
     }
 
-    pub fn new_ab_search(pos: &Position, tt_move: Move, killers: [Move; 2]) -> MovePicker {
+    // Every legal move, for quiescence nodes in check where a capture may not be the only way out
+    pub(crate) fn new_evasions(pos: &Position, tt_move: Move) -> MovePicker {
+        MovePicker::new(pos, MovePickerMode::Normal, tt_move, [Move::NONE; 2])
+    }
+
+    pub(crate) fn new_ab_search(pos: &Position, tt_move: Move, killers: [Move; 2]) -> MovePicker {
         MovePicker::new(pos, MovePickerMode::Normal, tt_move, killers)
     }
 
-    fn mvv_lva(&self, m: Move, position: &Position) -> i16 {
+    fn mvv_lva(&self, m: Move, position: &Position) -> i32 {
         let attacker = position.role_at(m.from());
         let victim = position.role_at(m.to());
 
         match (attacker, victim) {
             (None, _) => 0,
             (_, None) => 0,
-            (Some(attacker), Some(victim)) => CAPTURE_SCORE + MVV_LVA[attacker][victim],
+            // most valuable victim first, ties go to the least valuable attacker
+            (Some(attacker), Some(victim)) => {
+                CAPTURE_SCORE as i32 + PIECE_VALUES.see[victim] as i32 - attacker as i32
+            }
         }
     }
 
     fn score_captures(&mut self, position: &Position) {
         for i in 0..self.scored_moves.len() {
-            self.scored_moves[i].score = self.mvv_lva(self.scored_moves[i].m, position) as i32;
+            let m = self.scored_moves[i].m;
+            // a promotion gains what the new piece is worth over the pawn, so it goes ahead of
+            // captures of the same victim
+            let promotion = m.promotion().map_or(0, |role| {
+                (PIECE_VALUES.see[role] - PIECE_VALUES.see[Role::Pawn]) as i32
+            });
+            self.scored_moves[i].score = self.mvv_lva(m, position) + promotion;
         }
     }
 
     fn score_quiets(
         &mut self,
         position: &Position,
-        history: &[[[i16; Square::NUM]; Square::NUM]; Color::NUM],
+        history: &History,
     ) {
         for i in 0..self.scored_moves.len() {
             let m = self.scored_moves[i].m;
-            if m == self.killers[0] {
-                self.scored_moves[i].score = KILLER_1_SCORE as i32;
-            } else if m == self.killers[1] {
-                self.scored_moves[i].score = KILLER_2_SCORE as i32;
-            } else {
-                self.scored_moves[i].score = history[position.side][m.from()][m.to()] as i32;
+            self.scored_moves[i].score = quiet_history(history, position, m) as i32;
+        }
+    }
+
+    // Insertion sort of just the quiets at or above the limit, best first. The rest stay behind
+    // them in generation order, most nodes cut long before reaching them.
+    fn partial_sort_quiets(&mut self) {
+        let mut sorted = 0;
+        for i in 0..self.scored_moves.len() {
+            if self.scored_moves[i].score < QUIET_SORT_LIMIT {
+                continue;
             }
+            self.scored_moves.swap(sorted, i);
+            let mut j = sorted;
+            while j > 0 && self.scored_moves[j - 1].score < self.scored_moves[j].score {
+                self.scored_moves.swap(j - 1, j);
+                j -= 1;
+            }
+            sorted += 1;
         }
     }
 
+    // A killer from a sibling node, if it's still a legal quiet here
+    fn killer(&self, index: usize, position: &Position) -> Option<Move> {
+        let killer = self.killers[index];
+        (killer != Move::NONE
+            && killer != self.tt_move
+            && (position.occupancy & killer.to()).none()
+            && self.move_generator.contains(killer))
+        .then_some(killer)
+    }
+
     fn select_sorted(&mut self) -> Option<Move> {
         let mut best_score = i32::MIN;
         let mut best_index = 0;
@@ -148,10 +199,10 @@ impl MovePicker {
         Some(self.scored_moves[self.scored_index - 1].m)
     }
 
-    pub fn next(
+    pub(crate) fn next(
         &mut self,
         position: &Position,
-        history: &[[[i16; Square::NUM]; Square::NUM]; Color::NUM],
+        history: &History,
     ) -> Option<Move> {
         match self.stage {
             MovePickerStage::TT => {
@@ -167,7 +218,13 @@ impl MovePicker {
 
                 self.move_generator.set_mask(position.occupancy);
 
+                let quiescence = self.mode == MovePickerMode::Quiescence;
                 for m in self.move_generator.by_ref() {
+                    // rook and bishop promotions are almost never better than a queen, and a
+                    // knight's fork is too rare to be worth the nodes in quiescence
+                    if quiescence && is_underpromotion(m) {
+                        continue;
+                    }
                     self.scored_moves.push(MoveWithScore { m, score: 0 });
                 }
 
@@ -187,11 +244,22 @@ impl MovePicker {
                         if self.mode == MovePickerMode::Quiescence {
                             return None;
                         }
-                        self.stage = MovePickerStage::ScoreQuiets;
+                        self.stage = MovePickerStage::Killers(0);
                         self.next(position, history)
                     }
                 }
             }
+            MovePickerStage::Killers(index) => {
+                if index == self.killers.len() {
+                    self.stage = MovePickerStage::ScoreQuiets;
+                    return self.next(position, history);
+                }
+                self.stage = MovePickerStage::Killers(index + 1);
+                match self.killer(index, position) {
+                    Some(killer) => Some(killer),
+                    None => self.next(position, history),
+                }
+            }
             MovePickerStage::ScoreQuiets => {
                 self.stage = MovePickerStage::Quiets;
                 self.scored_moves.clear();
@@ -199,30 +267,41 @@ impl MovePicker {
                 self.move_generator.set_mask(Bitboard::FULL);
 
                 for m in self.move_generator.by_ref() {
+                    if m == self.tt_move || m == self.killers[0] || m == self.killers[1] {
+                        continue;
+                    }
                     self.scored_moves.push(MoveWithScore { m, score: 0 });
                 }
 
                 self.score_quiets(position, history);
+                self.partial_sort_quiets();
                 self.next(position, history)
             }
-            MovePickerStage::Quiets => match self.select_sorted() {
-                Some(m) => {
-                    if m == self.tt_move {
-                        return self.next(position, history);
-                    }
-                    Some(m)
-                }
-                None => None,
-            },
+            MovePickerStage::Quiets => {
+                let m = self.scored_moves.get(self.scored_index)?.m;
+                self.scored_index += 1;
+                Some(m)
+            }
         }
     }
 }
 
+fn is_underpromotion(m: Move) -> bool {
+    m.promotion().is_some_and(|role| role != Role::Queen)
+}
+
 #[cfg(test)]
 mod test {
+    use super::HISTORY_BUCKETS;
     use crate::{
+        chess::{
+            Color,
+            Role,
+            Square,
+        },
         fen::Fen,
         movegen::init_tables,
+        moves::Move,
         zobrist::init_zobrist,
     };
 
@@ -243,7 +322,7 @@ mod test {
 
         let mut moves = Vec::new();
 
-        while let Some(m) = mp.next(&pos, &[[[0; 64]; 64]; 2]) {
+        while let Some(m) = mp.next(&pos, &[[[0; 64]; HISTORY_BUCKETS]; 2]) {
             moves.push(m);
         }
 
@@ -263,4 +342,48 @@ mod test {
         // killer 2
         assert_eq!(moves[5], "g1f3".parse().unwrap());
     }
+
+    #[test]
+    fn quiescence_promotions() {
+        init_tables();
+        init_zobrist();
+
+        let Fen(pos) = "1r2k3/2P5/8/8/8/8/8/4KR2 w - - 0 1".parse().unwrap();
+        let mut mp = super::MovePicker::new_quiescence(&pos, "c7b8r".parse().unwrap());
+        let mut moves = Vec::new();
+        while let Some(m) = mp.next(&pos, &[[[0; 64]; HISTORY_BUCKETS]; 2]) {
+            moves.push(m);
+        }
+
+        // the underpromotions are left out, even as the tt move
+        assert_eq!(moves, ["c7b8q".parse().unwrap()]);
+
+        // winning a rook and promoting beats winning the queen
+        let Fen(pos) = "1r2k3/2P5/8/3q4/8/8/8/3RK3 w - - 0 1".parse().unwrap();
+        let mut mp = super::MovePicker::new_quiescence(&pos, Move::NONE);
+        let first = mp.next(&pos, &[[[0; 64]; HISTORY_BUCKETS]; 2]);
+        assert_eq!(first, Some("c7b8q".parse().unwrap()));
+    }
+
+    #[test]
+    fn per_piece_history() {
+        init_tables();
+        init_zobrist();
+
+        // the knight, bishop, queen and king can all go to e2
+        let Fen(pos) = "rnbqkbnr/pppp1ppp/8/4p3/4P3/8/PPPP1PPP/RNBQKBNR w KQkq - 0 2"
+            .parse()
+            .unwrap();
+        let mut history = [[[0; 64]; HISTORY_BUCKETS]; 2];
+        history[Color::White][Role::Bishop as usize][Square::E2] = 1000;
+        history[Color::White][Role::Knight as usize][Square::E2] = -2000;
+
+        let mut mp = super::MovePicker::new_ab_search(&pos, Move::NONE, [Move::NONE; 2]);
+        let mut moves = Vec::new();
+        while let Some(m) = mp.next(&pos, &history) {
+            moves.push(m);
+        }
+        assert_eq!(moves[0], "f1e2".parse().unwrap());
+        assert_eq!(moves.last(), Some(&"g1e2".parse().unwrap()));
+    }
 }
@@ -26,18 +26,50 @@ pub enum MoveType {
     Promotion,
 }
 
+// From and to squares in the low 12 bits, then 4 bits of flags: the move type, and for
+// promotions the role promoted to
 #[derive(Clone, Copy, PartialEq, Eq)]
 pub struct Move(u16);
 
 impl Move {
+    const DOUBLE_PAWN_PUSH: u16 = 1;
+    const CASTLE: u16 = 2;
+    const EN_PASSANT: u16 = 3;
+    // set along with the role in the low 3 flag bits
+    const PROMOTION: u16 = 0b1000;
+
+    #[inline]
+    fn with_flags(from: Square, to: Square, flags: u16) -> Move {
+        Move(from as u16 | (to as u16) << 6 | flags << 12)
+    }
+
+    // A normal move or capture, or a promotion. The other move types have their own
+    // constructors, since making a move trusts the flags.
     #[inline]
     pub fn new(from: Square, to: Square, promotion: Option<Role>) -> Move {
-        let from = from as u16;
-        let to = to as u16;
-        let promotion = promotion
-            .map(|role| role as u16)
-            .unwrap_or(Role::NUM as u16);
-        Move(from | (to << 6) | (promotion << 12))
+        let flags = promotion.map_or(0, |role| Move::PROMOTION | role as u16);
+        Move::with_flags(from, to, flags)
+    }
+
+    #[inline]
+    pub fn new_double_pawn_push(from: Square, to: Square) -> Move {
+        Move::with_flags(from, to, Move::DOUBLE_PAWN_PUSH)
+    }
+
+    // castles are encoded as the king moving two squares
+    #[inline]
+    pub fn new_castle(from: Square, to: Square) -> Move {
+        Move::with_flags(from, to, Move::CASTLE)
+    }
+
+    #[inline]
+    pub fn new_en_passant(from: Square, to: Square) -> Move {
+        Move::with_flags(from, to, Move::EN_PASSANT)
+    }
+
+    #[inline]
+    fn flags(self) -> u16 {
+        self.0 >> 12
     }
 
     #[inline]
@@ -52,37 +84,43 @@ impl Move {
 
     #[inline]
     pub fn promotion(self) -> Option<Role> {
-        unsafe { std::mem::transmute((self.0 >> 12) as u8) }
-    }
-
-    // This only works for valid moves
-    pub fn move_type(self, role: Role, ep_square: Option<Square>) -> MoveType {
-        if self.promotion().is_some() {
-            MoveType::Promotion
-        } else if role == Role::Pawn
-            && self.from().file() != self.to().file()
-            && ep_square.is_some_and(|sq| sq == self.to())
-        {
-            MoveType::EnPassant
-        } else if role == Role::Pawn && self.from().rank().distance(self.to().rank()) == 2 {
-            MoveType::DoublePawnPush
-        } else if role == Role::King && self.from().file().distance(self.to().file()) == 2 {
-            if self.from().rank() == self.to().rank() {
-                MoveType::Castle
-            } else {
-                MoveType::Normal
-            }
+        if self.flags() & Move::PROMOTION != 0 {
+            Some(Role::new((self.flags() & 0b111) as u8))
         } else {
-            MoveType::Normal
+            None
+        }
+    }
+
+    #[inline]
+    pub fn move_type(self) -> MoveType {
+        match self.flags() {
+            0 => MoveType::Normal,
+            Move::DOUBLE_PAWN_PUSH => MoveType::DoublePawnPush,
+            Move::CASTLE => MoveType::Castle,
+            Move::EN_PASSANT => MoveType::EnPassant,
+            _ => MoveType::Promotion,
         }
     }
 
+    // Same squares and promotion, ignoring the move type. Moves parsed from uci or read from
+    // elsewhere don't know their type until they're matched against the generated ones.
+    #[inline]
+    pub fn same_squares(self, other: Move) -> bool {
+        self.from() == other.from()
+            && self.to() == other.to()
+            && self.promotion() == other.promotion()
+    }
+
     pub const NULL: Move = Move(u16::MAX);
     pub const NONE: Move = Move(0);
 }
 
 impl Display for Move {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        // uci null move, what we send when there's nothing to play
+        if *self == Move::NONE {
+            return write!(f, "0000");
+        }
         write!(f, "{}{}", self.from(), self.to())?;
         if let Some(promotion) = self.promotion() {
             write!(f, "{}", promotion)?;
@@ -103,6 +141,12 @@ impl From<Move> for u16 {
     }
 }
 
+impl From<u16> for Move {
+    fn from(bits: u16) -> Move {
+        Move(bits)
+    }
+}
+
 #[derive(Debug, Error)]
 pub enum ParseMoveError {
     #[error("expected 4 or 5 characters, found {0}")]
@@ -0,0 +1,308 @@
+use std::sync::OnceLock;
+
+// Vector kernels for nnue inference: the accumulator updates of the feature transformer and
+// the clipped relu dot product of the output layer. The best instruction set the cpu supports
+// is picked once at runtime, with a scalar fallback everywhere else.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    Avx2,
+    Ssse3,
+    Neon,
+    Scalar,
+}
+
+impl Backend {
+    pub fn detect() -> Backend {
+        #[cfg(target_arch = "x86_64")]
+        {
+            if is_x86_feature_detected!("avx2") {
+                return Backend::Avx2;
+            }
+            if is_x86_feature_detected!("ssse3") {
+                return Backend::Ssse3;
+            }
+        }
+        #[cfg(target_arch = "aarch64")]
+        {
+            if std::arch::is_aarch64_feature_detected!("neon") {
+                return Backend::Neon;
+            }
+        }
+        Backend::Scalar
+    }
+}
+
+static BACKEND: OnceLock<Backend> = OnceLock::new();
+
+pub fn backend() -> Backend {
+    *BACKEND.get_or_init(Backend::detect)
+}
+
+// acc += weights, for a feature that was added
+pub fn add(acc: &mut [i16], weights: &[i16]) {
+    assert_eq!(acc.len(), weights.len());
+    match backend() {
+        #[cfg(target_arch = "x86_64")]
+        Backend::Avx2 => unsafe { x86::add_avx2(acc, weights) },
+        #[cfg(target_arch = "x86_64")]
+        Backend::Ssse3 => unsafe { x86::add_ssse3(acc, weights) },
+        #[cfg(target_arch = "aarch64")]
+        Backend::Neon => unsafe { neon::add(acc, weights) },
+        _ => scalar::add(acc, weights),
+    }
+}
+
+// acc -= weights, for a feature that was removed
+pub fn sub(acc: &mut [i16], weights: &[i16]) {
+    assert_eq!(acc.len(), weights.len());
+    match backend() {
+        #[cfg(target_arch = "x86_64")]
+        Backend::Avx2 => unsafe { x86::sub_avx2(acc, weights) },
+        #[cfg(target_arch = "x86_64")]
+        Backend::Ssse3 => unsafe { x86::sub_ssse3(acc, weights) },
+        #[cfg(target_arch = "aarch64")]
+        Backend::Neon => unsafe { neon::sub(acc, weights) },
+        _ => scalar::sub(acc, weights),
+    }
+}
+
+// sum(clamp(input, 0, max) * weights), the output layer on top of an accumulator
+pub fn crelu_dot(input: &[i16], weights: &[i16], max: i16) -> i32 {
+    assert_eq!(input.len(), weights.len());
+    match backend() {
+        #[cfg(target_arch = "x86_64")]
+        Backend::Avx2 => unsafe { x86::crelu_dot_avx2(input, weights, max) },
+        #[cfg(target_arch = "x86_64")]
+        Backend::Ssse3 => unsafe { x86::crelu_dot_ssse3(input, weights, max) },
+        #[cfg(target_arch = "aarch64")]
+        Backend::Neon => unsafe { neon::crelu_dot(input, weights, max) },
+        _ => scalar::crelu_dot(input, weights, max),
+    }
+}
+
+mod scalar {
+    pub fn add(acc: &mut [i16], weights: &[i16]) {
+        for (a, w) in acc.iter_mut().zip(weights) {
+            *a = a.wrapping_add(*w);
+        }
+    }
+
+    pub fn sub(acc: &mut [i16], weights: &[i16]) {
+        for (a, w) in acc.iter_mut().zip(weights) {
+            *a = a.wrapping_sub(*w);
+        }
+    }
+
+    pub fn crelu_dot(input: &[i16], weights: &[i16], max: i16) -> i32 {
+        input
+            .iter()
+            .zip(weights)
+            .map(|(&i, &w)| i.clamp(0, max) as i32 * w as i32)
+            .sum()
+    }
+}
+
+// The vector loops handle whole registers and leave any remainder to the scalar code
+#[cfg(target_arch = "x86_64")]
+mod x86 {
+    use std::arch::x86_64::*;
+
+    use super::scalar;
+
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn add_avx2(acc: &mut [i16], weights: &[i16]) {
+        let n = acc.len() / 16 * 16;
+        for i in (0..n).step_by(16) {
+            let a = _mm256_loadu_si256(acc.as_ptr().add(i) as *const __m256i);
+            let w = _mm256_loadu_si256(weights.as_ptr().add(i) as *const __m256i);
+            _mm256_storeu_si256(
+                acc.as_mut_ptr().add(i) as *mut __m256i,
+                _mm256_add_epi16(a, w),
+            );
+        }
+        scalar::add(&mut acc[n..], &weights[n..]);
+    }
+
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn sub_avx2(acc: &mut [i16], weights: &[i16]) {
+        let n = acc.len() / 16 * 16;
+        for i in (0..n).step_by(16) {
+            let a = _mm256_loadu_si256(acc.as_ptr().add(i) as *const __m256i);
+            let w = _mm256_loadu_si256(weights.as_ptr().add(i) as *const __m256i);
+            _mm256_storeu_si256(
+                acc.as_mut_ptr().add(i) as *mut __m256i,
+                _mm256_sub_epi16(a, w),
+            );
+        }
+        scalar::sub(&mut acc[n..], &weights[n..]);
+    }
+
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn crelu_dot_avx2(input: &[i16], weights: &[i16], max: i16) -> i32 {
+        let n = input.len() / 16 * 16;
+        let zero = _mm256_setzero_si256();
+        let ceiling = _mm256_set1_epi16(max);
+        let mut sum = _mm256_setzero_si256();
+        for i in (0..n).step_by(16) {
+            let x = _mm256_loadu_si256(input.as_ptr().add(i) as *const __m256i);
+            let w = _mm256_loadu_si256(weights.as_ptr().add(i) as *const __m256i);
+            let x = _mm256_min_epi16(_mm256_max_epi16(x, zero), ceiling);
+            sum = _mm256_add_epi32(sum, _mm256_madd_epi16(x, w));
+        }
+
+        let sum = _mm_add_epi32(
+            _mm256_castsi256_si128(sum),
+            _mm256_extracti128_si256(sum, 1),
+        );
+        hsum_128(sum) + scalar::crelu_dot(&input[n..], &weights[n..], max)
+    }
+
+    #[target_feature(enable = "ssse3")]
+    pub unsafe fn add_ssse3(acc: &mut [i16], weights: &[i16]) {
+        let n = acc.len() / 8 * 8;
+        for i in (0..n).step_by(8) {
+            let a = _mm_loadu_si128(acc.as_ptr().add(i) as *const __m128i);
+            let w = _mm_loadu_si128(weights.as_ptr().add(i) as *const __m128i);
+            _mm_storeu_si128(acc.as_mut_ptr().add(i) as *mut __m128i, _mm_add_epi16(a, w));
+        }
+        scalar::add(&mut acc[n..], &weights[n..]);
+    }
+
+    #[target_feature(enable = "ssse3")]
+    pub unsafe fn sub_ssse3(acc: &mut [i16], weights: &[i16]) {
+        let n = acc.len() / 8 * 8;
+        for i in (0..n).step_by(8) {
+            let a = _mm_loadu_si128(acc.as_ptr().add(i) as *const __m128i);
+            let w = _mm_loadu_si128(weights.as_ptr().add(i) as *const __m128i);
+            _mm_storeu_si128(acc.as_mut_ptr().add(i) as *mut __m128i, _mm_sub_epi16(a, w));
+        }
+        scalar::sub(&mut acc[n..], &weights[n..]);
+    }
+
+    #[target_feature(enable = "ssse3")]
+    pub unsafe fn crelu_dot_ssse3(input: &[i16], weights: &[i16], max: i16) -> i32 {
+        let n = input.len() / 8 * 8;
+        let zero = _mm_setzero_si128();
+        let ceiling = _mm_set1_epi16(max);
+        let mut sum = _mm_setzero_si128();
+        for i in (0..n).step_by(8) {
+            let x = _mm_loadu_si128(input.as_ptr().add(i) as *const __m128i);
+            let w = _mm_loadu_si128(weights.as_ptr().add(i) as *const __m128i);
+            let x = _mm_min_epi16(_mm_max_epi16(x, zero), ceiling);
+            sum = _mm_add_epi32(sum, _mm_madd_epi16(x, w));
+        }
+        hsum_128(sum) + scalar::crelu_dot(&input[n..], &weights[n..], max)
+    }
+
+    #[target_feature(enable = "ssse3")]
+    unsafe fn hsum_128(sum: __m128i) -> i32 {
+        let sum = _mm_hadd_epi32(sum, sum);
+        let sum = _mm_hadd_epi32(sum, sum);
+        _mm_cvtsi128_si32(sum)
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+mod neon {
+    use std::arch::aarch64::*;
+
+    use super::scalar;
+
+    #[target_feature(enable = "neon")]
+    pub unsafe fn add(acc: &mut [i16], weights: &[i16]) {
+        let n = acc.len() / 8 * 8;
+        for i in (0..n).step_by(8) {
+            let a = vld1q_s16(acc.as_ptr().add(i));
+            let w = vld1q_s16(weights.as_ptr().add(i));
+            vst1q_s16(acc.as_mut_ptr().add(i), vaddq_s16(a, w));
+        }
+        scalar::add(&mut acc[n..], &weights[n..]);
+    }
+
+    #[target_feature(enable = "neon")]
+    pub unsafe fn sub(acc: &mut [i16], weights: &[i16]) {
+        let n = acc.len() / 8 * 8;
+        for i in (0..n).step_by(8) {
+            let a = vld1q_s16(acc.as_ptr().add(i));
+            let w = vld1q_s16(weights.as_ptr().add(i));
+            vst1q_s16(acc.as_mut_ptr().add(i), vsubq_s16(a, w));
+        }
+        scalar::sub(&mut acc[n..], &weights[n..]);
+    }
+
+    #[target_feature(enable = "neon")]
+    pub unsafe fn crelu_dot(input: &[i16], weights: &[i16], max: i16) -> i32 {
+        let n = input.len() / 8 * 8;
+        let zero = vdupq_n_s16(0);
+        let ceiling = vdupq_n_s16(max);
+        let mut sum = vdupq_n_s32(0);
+        for i in (0..n).step_by(8) {
+            let x = vld1q_s16(input.as_ptr().add(i));
+            let w = vld1q_s16(weights.as_ptr().add(i));
+            let x = vminq_s16(vmaxq_s16(x, zero), ceiling);
+            sum = vmlal_s16(sum, vget_low_s16(x), vget_low_s16(w));
+            sum = vmlal_high_s16(sum, x, w);
+        }
+        vaddvq_s32(sum) + scalar::crelu_dot(&input[n..], &weights[n..], max)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::{rngs::SmallRng, Rng, SeedableRng};
+
+    fn random(rng: &mut SmallRng, len: usize) -> Vec<i16> {
+        (0..len).map(|_| rng.gen_range(-400..400)).collect()
+    }
+
+    // odd lengths so the scalar remainder gets exercised too
+    #[test]
+    fn test_kernels_match_scalar() {
+        let mut rng = SmallRng::seed_from_u64(7);
+        for len in [0, 7, 16, 37, 768] {
+            let input = random(&mut rng, len);
+            let weights = random(&mut rng, len);
+
+            let mut acc = input.clone();
+            let mut expected = input.clone();
+            add(&mut acc, &weights);
+            scalar::add(&mut expected, &weights);
+            assert_eq!(acc, expected);
+
+            sub(&mut acc, &weights);
+            assert_eq!(acc, input);
+
+            assert_eq!(
+                crelu_dot(&input, &weights, 255),
+                scalar::crelu_dot(&input, &weights, 255)
+            );
+        }
+    }
+
+    // the dispatcher only ever runs the best backend, so check the older one directly
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_ssse3_matches_scalar() {
+        if !is_x86_feature_detected!("ssse3") {
+            return;
+        }
+        let mut rng = SmallRng::seed_from_u64(7);
+        for len in [7, 16, 37, 768] {
+            let input = random(&mut rng, len);
+            let weights = random(&mut rng, len);
+
+            let mut acc = input.clone();
+            unsafe { x86::add_ssse3(&mut acc, &weights) };
+            let mut expected = input.clone();
+            scalar::add(&mut expected, &weights);
+            assert_eq!(acc, expected);
+
+            unsafe { x86::sub_ssse3(&mut acc, &weights) };
+            assert_eq!(acc, input);
+
+            let dot = unsafe { x86::crelu_dot_ssse3(&input, &weights, 255) };
+            assert_eq!(dot, scalar::crelu_dot(&input, &weights, 255));
+        }
+    }
+}
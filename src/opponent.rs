@@ -0,0 +1,112 @@
+use std::str::FromStr;
+
+use thiserror::Error;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum OpponentParseError {
+    #[error("expected `<title> <elo> <computer|human> <name>`, got `{0}`")]
+    TooShort(String),
+    #[error("invalid elo `{0}`")]
+    InvalidElo(String),
+    #[error("expected computer or human, got `{0}`")]
+    InvalidKind(String),
+}
+
+// Who we're playing, as sent in the `UCI_Opponent` option. `none` leaves the title or elo out.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Opponent {
+    pub title: Option<String>,
+    pub elo: Option<u32>,
+    pub computer: bool,
+    pub name: String,
+}
+
+impl FromStr for Opponent {
+    type Err = OpponentParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut tokens = s.split_whitespace();
+        let (Some(title), Some(elo), Some(kind)) = (tokens.next(), tokens.next(), tokens.next())
+        else {
+            return Err(OpponentParseError::TooShort(s.to_string()));
+        };
+
+        let elo = match elo {
+            "none" => None,
+            elo => Some(
+                elo.parse::<u32>()
+                    .map_err(|_| OpponentParseError::InvalidElo(elo.to_string()))?,
+            ),
+        };
+        let computer = match kind {
+            "computer" => true,
+            "human" => false,
+            kind => return Err(OpponentParseError::InvalidKind(kind.to_string())),
+        };
+
+        Ok(Opponent {
+            title: (title != "none").then(|| title.to_string()),
+            elo,
+            computer,
+            name: tokens.collect::<Vec<_>>().join(" "),
+        })
+    }
+}
+
+// How much contempt to play with against an opponent. Only opponents known to be rated below
+// `below_elo` get it, an unknown opponent is treated as an equal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ContemptConfig {
+    pub contempt: i16,
+    pub below_elo: u32,
+}
+
+impl ContemptConfig {
+    pub fn contempt(&self, opponent: Option<&Opponent>) -> i16 {
+        match opponent.and_then(|opponent| opponent.elo) {
+            Some(elo) if elo < self.below_elo => self.contempt,
+            _ => 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_opponent() {
+        let opponent = "GM 2800 human Gary Kasparov".parse::<Opponent>().unwrap();
+        assert_eq!(
+            opponent,
+            Opponent {
+                title: Some("GM".to_string()),
+                elo: Some(2800),
+                computer: false,
+                name: "Gary Kasparov".to_string(),
+            }
+        );
+
+        let opponent = "none none computer Shredder".parse::<Opponent>().unwrap();
+        assert_eq!((opponent.title, opponent.elo), (None, None));
+        assert!(opponent.computer);
+
+        assert!("".parse::<Opponent>().is_err());
+        assert!("GM 2800".parse::<Opponent>().is_err());
+        assert!("GM strong human Gary".parse::<Opponent>().is_err());
+        assert!("GM 2800 alien Gary".parse::<Opponent>().is_err());
+    }
+
+    #[test]
+    fn test_contempt() {
+        let config = ContemptConfig {
+            contempt: 20,
+            below_elo: 2800,
+        };
+        let opponent = |s: &str| s.parse::<Opponent>().unwrap();
+        assert_eq!(config.contempt(Some(&opponent("none 2000 human Joe"))), 20);
+        assert_eq!(config.contempt(Some(&opponent("none 3400 computer Fish"))), 0);
+        assert_eq!(config.contempt(Some(&opponent("none none human Joe"))), 0);
+        assert_eq!(config.contempt(None), 0);
+    }
+}
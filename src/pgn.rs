@@ -0,0 +1,345 @@
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Lines, Write},
+    path::PathBuf,
+    str::FromStr,
+};
+
+use anyhow::{Context, Result};
+use thiserror::Error;
+
+use crate::{
+    chess::{GameResult, Role},
+    fen::Fen,
+    movegen::MoveGen,
+    moves::{Move, MoveType},
+    position::Position,
+    uci::Uci,
+};
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum ParseSanError {
+    #[error("`{0}` is not a legal move here")]
+    Illegal(String),
+    #[error("`{0}` matches more than one legal move")]
+    Ambiguous(String),
+}
+
+// Resolves a SAN move like `Nbd7`, `exd8=Q+` or `O-O` against the legal moves of `pos`
+pub fn parse_san(pos: &Position, san: &str) -> Result<Move, ParseSanError> {
+    let stripped = san.trim_end_matches(['+', '#', '!', '?']);
+    let castle = match stripped {
+        "O-O" | "0-0" => Some(6),
+        "O-O-O" | "0-0-0" => Some(2),
+        _ => None,
+    };
+
+    let candidates = MoveGen::new(pos)
+        .filter(|&mv| {
+            if let Some(file) = castle {
+                return mv.move_type() == MoveType::Castle && mv.to().file() as u8 == file;
+            }
+            matches_san(stripped, mv, pos.role_at(mv.from()))
+        })
+        .collect::<Vec<_>>();
+
+    match candidates.as_slice() {
+        [mv] => Ok(*mv),
+        [] => Err(ParseSanError::Illegal(san.to_string())),
+        _ => Err(ParseSanError::Ambiguous(san.to_string())),
+    }
+}
+
+fn matches_san(san: &str, mv: Move, role: Option<Role>) -> bool {
+    let (body, promotion) = match san.split_once('=') {
+        Some((body, promo)) => (body, Role::from_str(promo).ok()),
+        None => (san, None),
+    };
+    if promotion != mv.promotion() {
+        return false;
+    }
+
+    let (piece, rest) = match body.chars().next() {
+        Some(c @ ('N' | 'B' | 'R' | 'Q' | 'K')) => {
+            (Role::from_str(&c.to_string()).ok(), &body[1..])
+        }
+        _ => (Some(Role::Pawn), body),
+    };
+    if piece != role || rest.len() < 2 || !rest.is_char_boundary(rest.len() - 2) {
+        return false;
+    }
+
+    let (from_hint, to) = rest.split_at(rest.len() - 2);
+    if to != mv.to().to_string() {
+        return false;
+    }
+    let from = mv.from().to_string();
+    from_hint
+        .chars()
+        .filter(|&c| c != 'x')
+        .all(|c| from.contains(c))
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PgnGame {
+    pub tags: Vec<(String, String)>,
+    // SAN moves of the main line
+    pub moves: Vec<String>,
+    // result from white's point of view
+    pub result: Option<GameResult>,
+}
+
+impl PgnGame {
+    pub fn tag(&self, name: &str) -> Option<&str> {
+        self.tags
+            .iter()
+            .find(|(tag, _)| tag == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+fn parse_result(token: &str) -> Option<Option<GameResult>> {
+    match token {
+        "1-0" => Some(Some(GameResult::Win)),
+        "0-1" => Some(Some(GameResult::Loss)),
+        "1/2-1/2" => Some(Some(GameResult::Draw)),
+        "*" => Some(None),
+        _ => None,
+    }
+}
+
+// Reads games one at a time from a PGN file. Comments, variations and NAGs are skipped.
+pub struct PgnReader<R> {
+    lines: Lines<R>,
+}
+
+impl<R: BufRead> PgnReader<R> {
+    pub fn new(reader: R) -> Self {
+        PgnReader {
+            lines: reader.lines(),
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for PgnReader<R> {
+    type Item = std::io::Result<PgnGame>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut game = PgnGame::default();
+        let mut in_movetext = false;
+        let mut comment = false;
+        let mut variation = 0;
+
+        for line in self.lines.by_ref() {
+            let line = match line {
+                Ok(line) => line,
+                Err(e) => return Some(Err(e)),
+            };
+            let line = line.trim();
+
+            if !comment && variation == 0 && line.starts_with('[') {
+                if in_movetext {
+                    // a game without a result token, the tags belong to the next one
+                    break;
+                }
+                let inner = line.trim_start_matches('[').trim_end_matches(']');
+                if let Some((name, value)) = inner.split_once(' ') {
+                    game.tags
+                        .push((name.to_string(), value.trim().trim_matches('"').to_string()));
+                }
+                continue;
+            }
+            if line.is_empty() || line.starts_with('%') {
+                continue;
+            }
+            in_movetext = true;
+
+            let mut token = String::new();
+            let mut tokens = Vec::new();
+            for c in line.chars() {
+                match c {
+                    '{' => comment = true,
+                    '}' => comment = false,
+                    ';' if !comment => break,
+                    _ if comment => {}
+                    '(' => variation += 1,
+                    ')' => variation -= 1,
+                    _ if variation > 0 => {}
+                    c if c.is_whitespace() => tokens.push(std::mem::take(&mut token)),
+                    c => token.push(c),
+                }
+            }
+            tokens.push(token);
+
+            for token in tokens.into_iter().filter(|t| !t.is_empty()) {
+                if let Some(result) = parse_result(&token) {
+                    game.result = result;
+                    return Some(Ok(game));
+                }
+                if token.starts_with('$') {
+                    continue;
+                }
+                // strip move numbers, `12.` or `12...` possibly glued to the move
+                let mv = token.trim_start_matches(|c: char| c.is_ascii_digit() || c == '.');
+                if !mv.is_empty() {
+                    game.moves.push(mv.to_string());
+                }
+            }
+        }
+
+        (in_movetext || !game.tags.is_empty()).then_some(Ok(game))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FensConfig {
+    pub in_files: Vec<PathBuf>,
+    pub out_file: PathBuf,
+    // take every nth position of a game
+    pub every: usize,
+    pub min_ply: usize,
+    pub max_ply: Option<usize>,
+    // skip positions already written
+    pub unique: bool,
+}
+
+impl FensConfig {
+    fn wants(&self, ply: usize) -> bool {
+        ply >= self.min_ply
+            && self.max_ply.is_none_or(|max| ply <= max)
+            && (ply - self.min_ply).is_multiple_of(self.every.max(1))
+    }
+}
+
+// Walks the main line of every game and writes the sampled positions, one FEN per line
+pub fn extract_fens(config: &FensConfig) -> Result<()> {
+    let mut writer = BufWriter::new(
+        File::create(&config.out_file)
+            .with_context(|| format!("creating {}", config.out_file.display()))?,
+    );
+    let mut seen = HashSet::new();
+    let mut games = 0;
+    let mut written = 0;
+
+    for path in &config.in_files {
+        let file = File::open(path).with_context(|| format!("opening {}", path.display()))?;
+        for game in PgnReader::new(BufReader::new(file)) {
+            let game = game?;
+            let Fen(mut pos) = match game.tag("FEN") {
+                Some(fen) => fen.parse()?,
+                None => Uci::STARTPOS.parse()?,
+            };
+
+            games += 1;
+            for ply in 0..=game.moves.len() {
+                if config.wants(ply) && (!config.unique || seen.insert(u64::from(pos.key))) {
+                    writeln!(writer, "{}", Fen(pos.clone()))?;
+                    written += 1;
+                }
+                if config.max_ply.is_some_and(|max| ply >= max) {
+                    break;
+                }
+                let Some(Ok(mv)) = game.moves.get(ply).map(|san| parse_san(&pos, san)) else {
+                    break;
+                };
+                pos.make_move(mv);
+            }
+        }
+    }
+    writer.flush()?;
+
+    println!(
+        "{} games, {} positions written to {}",
+        games,
+        written,
+        config.out_file.display()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{fen::Fen, movegen::init_tables, zobrist::init_zobrist};
+
+    const STARTPOS: &str = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1";
+
+    fn play(fen: &str, sans: &[&str]) -> Vec<String> {
+        init_tables();
+        init_zobrist();
+        let Fen(mut pos) = fen.parse().unwrap();
+        sans.iter()
+            .map(|san| {
+                let mv = parse_san(&pos, san).unwrap();
+                pos.make_move(mv);
+                mv.to_string().to_lowercase()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_san() {
+        let moves = play(
+            STARTPOS,
+            &[
+                "e4", "e5", "Nf3", "Nc6", "Bc4", "Nf6", "O-O", "Nxe4", "Re1", "d5",
+            ],
+        );
+        assert_eq!(moves[6], "e1g1");
+        assert_eq!(moves[7], "f6e4");
+
+        let moves = play(
+            "4k3/1P6/8/8/8/8/8/R3K2R w KQ - 0 1",
+            &["b8=Q+", "Kd7", "O-O-O+"],
+        );
+        assert_eq!(moves, vec!["b7b8q", "e8d7", "e1c1"]);
+    }
+
+    #[test]
+    fn test_parse_san_disambiguation() {
+        init_tables();
+        init_zobrist();
+        let Fen(pos) = "4k3/8/8/8/8/8/4K3/R6R w - - 0 1".parse().unwrap();
+        assert_eq!(parse_san(&pos, "Rad1").unwrap().to_string(), "a1d1");
+        assert_eq!(parse_san(&pos, "Rhd1").unwrap().to_string(), "h1d1");
+        assert!(matches!(
+            parse_san(&pos, "Rd1"),
+            Err(ParseSanError::Ambiguous(_))
+        ));
+        assert!(matches!(
+            parse_san(&pos, "Nf3"),
+            Err(ParseSanError::Illegal(_))
+        ));
+    }
+
+    #[test]
+    fn test_pgn_reader() {
+        let pgn =
+            "[Event \"?\"]\n[Result \"1-0\"]\n\n1. e4 {best by test} e5 2. Nf3 (2. f4 exf4)\n\
+                   Nc6 $1 3. Bb5 1-0\n\n[Event \"?\"]\n\n1. d4 d5 *\n";
+        let games = PgnReader::new(pgn.as_bytes())
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(games.len(), 2);
+        assert_eq!(games[0].moves, vec!["e4", "e5", "Nf3", "Nc6", "Bb5"]);
+        assert_eq!(games[0].result, Some(GameResult::Win));
+        assert_eq!(games[0].tag("Result"), Some("1-0"));
+        assert_eq!(games[1].moves, vec!["d4", "d5"]);
+        assert_eq!(games[1].result, None);
+    }
+
+    #[test]
+    fn test_fens_sampling() {
+        let config = FensConfig {
+            in_files: vec![],
+            out_file: PathBuf::new(),
+            every: 4,
+            min_ply: 16,
+            max_ply: Some(30),
+            unique: false,
+        };
+        let plies = (0..40).filter(|&ply| config.wants(ply)).collect::<Vec<_>>();
+        assert_eq!(plies, vec![16, 20, 24, 28]);
+    }
+}
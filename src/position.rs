@@ -41,6 +41,75 @@ pub struct State {
     pub key: ZobristHash,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DirtyPiece {
+    pub piece: Piece,
+    pub square: Square,
+    // false when the piece was removed from the square
+    pub added: bool,
+}
+
+// The pieces added and removed by the last make or unmake, so evals with incremental state
+// (nnue accumulators) can apply just those changes instead of refreshing at every node
+#[derive(Debug, Clone, Copy)]
+pub struct DirtyPieces {
+    pieces: [DirtyPiece; DirtyPieces::CAPACITY],
+    len: u8,
+    // more changes than fit, e.g. after setting up a position from scratch
+    overflowed: bool,
+}
+
+impl DirtyPieces {
+    // castling moves two pieces, the most of any move
+    const CAPACITY: usize = 4;
+
+    fn new() -> Self {
+        DirtyPieces {
+            pieces: [DirtyPiece {
+                piece: Piece::new(Color::White, Role::Pawn),
+                square: Square::A1,
+                added: false,
+            }; Self::CAPACITY],
+            len: 0,
+            overflowed: true,
+        }
+    }
+
+    #[inline]
+    fn clear(&mut self) {
+        self.len = 0;
+        self.overflowed = false;
+    }
+
+    #[inline]
+    fn push(&mut self, piece: DirtyPiece) {
+        if (self.len as usize) < Self::CAPACITY {
+            self.pieces[self.len as usize] = piece;
+            self.len += 1;
+        } else {
+            self.overflowed = true;
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = DirtyPiece> + '_ {
+        self.pieces[..self.len as usize].iter().copied()
+    }
+
+    // The changes can't be applied incrementally and the eval has to start over
+    pub fn needs_refresh(&self) -> bool {
+        self.overflowed
+    }
+
+    // Where `color`'s king moved from and to, for evals whose inputs depend on the king
+    // square (king buckets) and need a refresh when it changes
+    pub fn king_move(&self, color: Color) -> Option<(Square, Square)> {
+        let king = Piece::new(color, Role::King);
+        let from = self.iter().find(|d| d.piece == king && !d.added)?;
+        let to = self.iter().find(|d| d.piece == king && d.added)?;
+        Some((from.square, to.square))
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Position {
     pub by_color: [Bitboard; Color::NUM],
@@ -60,11 +129,16 @@ pub struct Position {
     pub fullmove_number: NonZeroU32,
 
     pub key: ZobristHash,
+    // keys of just the pawns, and of the knights, bishops, rooks and queens
+    pub pawn_key: ZobristHash,
+    pub major_minor_key: ZobristHash,
 
     pub history: Vec<State>,
 
     pub psqt_mg: i32,
     pub psqt_eg: i32,
+
+    dirty: DirtyPieces,
 }
 
 impl Position {
@@ -82,9 +156,12 @@ impl Position {
             halfmove_clock: 0,
             fullmove_number: NonZeroU32::new(1).unwrap(),
             key: ZobristHash::new(),
+            pawn_key: ZobristHash::new(),
+            major_minor_key: ZobristHash::new(),
             history: Vec::new(),
             psqt_mg: 0,
             psqt_eg: 0,
+            dirty: DirtyPieces::new(),
         }
     }
 }
@@ -157,39 +234,56 @@ impl Position {
     }
 
     #[inline]
-    pub fn is_draw(&self) -> Option<GameResult> {
-        if self.halfmove_clock >= 100 {
-            let num_moves = MoveGen::new(self).len();
-            if num_moves > 0 && self.in_check() {
-                return Some(GameResult::Loss);
+    pub fn has_legal_move(&self) -> bool {
+        MoveGen::has_legal_move(self)
+    }
+
+    // The legal move with `mv`'s squares and promotion, flagged with its move type. Moves that
+    // didn't come from the generator (uci, text files) go through this before being made.
+    pub fn legal_move(&self, mv: Move) -> Option<Move> {
+        MoveGen::new(self).find(|legal| legal.same_squares(mv))
+    }
+
+    // How the game stands for the side to move, if it's over. Repetitions are left to the caller
+    // since it knows how many it wants to count.
+    pub fn game_result(&self) -> Option<GameResult> {
+        if !self.has_legal_move() {
+            return Some(if self.in_check() {
+                GameResult::Loss
             } else {
-                return Some(GameResult::Draw);
-            }
+                GameResult::Draw
+            });
+        }
+        self.is_draw().then_some(GameResult::Draw)
+    }
+
+    // Draws by the fifty move rule or insufficient material. Checkmate on the hundredth half
+    // move still wins, but checking for it takes a movegen, so that's only done once the
+    // clock has run out.
+    #[inline]
+    pub fn is_draw(&self) -> bool {
+        if self.halfmove_clock >= 100 {
+            return !self.in_check() || self.has_legal_move();
         }
 
         let num_pieces = self.occupancy.count();
         if num_pieces == 2 {
-            return Some(GameResult::Draw);
+            return true;
         }
 
         if num_pieces == 3
             && (self.by_role[Role::Bishop].count() > 0 || self.by_role[Role::Knight].count() > 0)
         {
-            return Some(GameResult::Draw);
+            return true;
         }
 
         let wbishops = self.by_color_role(Color::White, Role::Bishop);
         let bbishops = self.by_color_role(Color::Black, Role::Bishop);
 
-        if num_pieces == 4
+        num_pieces == 4
             && wbishops.count() == 1
             && bbishops.count() == 1
             && Square::from(wbishops).same_color(Square::from(bbishops))
-        {
-            return Some(GameResult::Draw);
-        }
-
-        None
     }
 
     pub fn is_repetition(&self, count: u32) -> bool {
@@ -233,6 +327,16 @@ impl Position {
         false
     }
 
+    #[inline]
+    fn toggle_piece_keys(&mut self, sq: Square, piece: Piece) {
+        self.key.toggle_piece(sq, piece);
+        match piece.role {
+            Role::Pawn => self.pawn_key.toggle_piece(sq, piece),
+            Role::King => {}
+            _ => self.major_minor_key.toggle_piece(sq, piece),
+        }
+    }
+
     #[inline]
     pub fn discard(&mut self, sq: Square, piece: Piece) {
         match piece.color {
@@ -250,7 +354,12 @@ impl Position {
         self.by_role.iter_mut().for_each(|bb| bb.clear(sq));
         self.occupancy.clear(sq);
         self.mailbox[sq] = None;
-        self.key.toggle_piece(sq, piece);
+        self.toggle_piece_keys(sq, piece);
+        self.dirty.push(DirtyPiece {
+            piece,
+            square: sq,
+            added: false,
+        });
     }
 
     #[inline]
@@ -272,7 +381,17 @@ impl Position {
         self.by_role[piece.role as usize].set(sq);
         self.occupancy.set(sq);
         self.mailbox[sq] = Some(piece);
-        self.key.toggle_piece(sq, piece);
+        self.toggle_piece_keys(sq, piece);
+        self.dirty.push(DirtyPiece {
+            piece,
+            square: sq,
+            added: true,
+        });
+    }
+
+    #[inline]
+    pub fn dirty_pieces(&self) -> &DirtyPieces {
+        &self.dirty
     }
 
     #[inline]
@@ -282,6 +401,7 @@ impl Position {
 
         let piece = self.piece_at(from).unwrap();
         debug_assert!(piece.color == self.side);
+        self.dirty.clear();
         let mut state = State {
             castling: self.castling,
             ep_square: self.ep_square,
@@ -292,15 +412,13 @@ impl Position {
             key: self.key,
         };
 
-        let prev_ep_square = self.ep_square;
-
         self.key.toggle_ep(self.ep_square);
         self.ep_square = None;
         self.halfmove_clock += 1;
 
         // reset the en passant square
 
-        match mv.move_type(piece.role, prev_ep_square) {
+        match mv.move_type() {
             MoveType::Normal => {
                 state.captured = self.piece_at(to);
                 self.discard(from, piece);
@@ -328,7 +446,6 @@ impl Position {
                 self.set(to, piece);
             }
             MoveType::Castle => {
-                self.halfmove_clock = 0;
                 if from.file().direction(to.file()) == 2 {
                     let rook_from = Square::make(File::H, self.side.back_rank());
                     let rook_to = Square::make(File::F, self.side.back_rank());
@@ -353,8 +470,7 @@ impl Position {
             }
         }
 
-        // update halfmove clock
-        // castling was handled above
+        // only captures and pawn moves reset the halfmove clock
         if state.captured.is_some() || piece.role == Role::Pawn {
             self.halfmove_clock = 0;
         }
@@ -382,13 +498,17 @@ impl Position {
         self.update_checks_and_pins(mv, Some(mv.promotion().unwrap_or(piece.role)));
 
         self.history.push(state);
-        self.fullmove_number = NonZeroU32::new(self.fullmove_number.get() + 1).unwrap();
+        // the fullmove number goes up after black's move
+        if self.side == Color::Black {
+            self.fullmove_number = NonZeroU32::new(self.fullmove_number.get() + 1).unwrap();
+        }
 
         self.side = self.side.opponent();
         self.key.toggle_side();
     }
 
     pub fn unmake_move(&mut self, mv: Move) {
+        self.dirty.clear();
         self.side = self.side.opponent();
         self.key.toggle_side();
 
@@ -410,7 +530,9 @@ impl Position {
         }
 
         self.halfmove_clock = past.halfmove_clock;
-        self.fullmove_number = NonZeroU32::new(self.fullmove_number.get() - 1).unwrap();
+        if self.side == Color::Black {
+            self.fullmove_number = NonZeroU32::new(self.fullmove_number.get() - 1).unwrap();
+        }
         self.pinned = past.pinned;
         self.checkers = past.checkers;
 
@@ -420,7 +542,7 @@ impl Position {
             .piece_at(to)
             .expect("unmake called without a piece at destination");
 
-        match mv.move_type(piece.role, self.ep_square) {
+        match mv.move_type() {
             MoveType::Normal | MoveType::DoublePawnPush => {
                 self.discard(to, piece);
                 self.set(from, piece);
@@ -480,6 +602,7 @@ impl Position {
 
         debug_assert!(self.checkers.none());
 
+        self.dirty.clear();
         self.checkers = Bitboard::EMPTY;
         self.update_checks_and_pins(Move::NULL, None);
 
@@ -488,13 +611,16 @@ impl Position {
         self.key.toggle_ep(self.ep_square);
 
         self.history.push(state);
-        self.fullmove_number = NonZeroU32::new(self.fullmove_number.get() + 1).unwrap();
+        if self.side == Color::Black {
+            self.fullmove_number = NonZeroU32::new(self.fullmove_number.get() + 1).unwrap();
+        }
 
         self.key.toggle_side();
         self.side = self.side.opponent();
     }
 
     pub fn unmake_null_move(&mut self) {
+        self.dirty.clear();
         self.side = self.side.opponent();
         self.key.toggle_side();
 
@@ -508,12 +634,18 @@ impl Position {
         self.key.toggle_ep(self.ep_square);
 
         self.halfmove_clock = past.halfmove_clock;
-        self.fullmove_number = NonZeroU32::new(self.fullmove_number.get() - 1).unwrap();
+        if self.side == Color::Black {
+            self.fullmove_number = NonZeroU32::new(self.fullmove_number.get() - 1).unwrap();
+        }
         self.pinned = past.pinned;
         self.checkers = past.checkers;
     }
 
     #[inline]
+    // `piece` is the role that landed on the destination, so a knight promotion checks like a
+    // knight. Only the moved piece can give a direct knight or pawn check, but every slider is
+    // looked at, which covers the rook of a castle and checks discovered by the moved piece or
+    // by the pawn taken en passant.
     fn update_checks_and_pins(&mut self, mv: Move, piece: Option<Role>) {
         // we update side at the very end of make move, so we're looking for checks
         // we make against the opponent
@@ -556,7 +688,8 @@ impl Position {
         let ksq = Square::new_unchecked(self.our_king().0.trailing_zeros() as u8);
 
         let knight_attackers = self.their(Role::Knight) & get_knight_moves(ksq);
-        let pawn_attackers = self.their(Role::Pawn) & get_pawn_attacks(ksq, self.side.opponent());
+        // their pawns attack the king from the squares our pawn on the king square would attack
+        let pawn_attackers = self.their(Role::Pawn) & get_pawn_attacks(ksq, self.side);
 
         self.checkers |= knight_attackers | pawn_attackers;
 
@@ -576,3 +709,188 @@ impl Position {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{fen::Fen, movegen::init_tables, zobrist::init_zobrist};
+
+    fn dirty(pos: &Position) -> Vec<(Square, bool)> {
+        pos.dirty_pieces()
+            .iter()
+            .map(|d| (d.square, d.added))
+            .collect()
+    }
+
+    #[test]
+    fn test_game_result() {
+        init_tables();
+        init_zobrist();
+
+        let result = |fen: &str| fen.parse::<Fen>().unwrap().0.game_result();
+        // mated on the hundredth half move, the mate stands
+        assert_eq!(
+            result("7k/6Q1/6K1/8/8/8/8/8 b - - 100 80"),
+            Some(GameResult::Loss)
+        );
+        // in check with a way out, it's a draw
+        assert_eq!(
+            result("7k/8/5K2/8/8/8/8/7Q b - - 100 80"),
+            Some(GameResult::Draw)
+        );
+        assert_eq!(result("7k/8/5K2/8/8/8/8/7Q b - - 3 80"), None);
+        assert_eq!(
+            result("7k/5Q2/6K1/8/8/8/8/8 b - - 3 80"),
+            Some(GameResult::Draw)
+        );
+        assert_eq!(
+            result("7k/8/6K1/8/8/8/8/5N2 b - - 3 80"),
+            Some(GameResult::Draw)
+        );
+    }
+
+    // Checks the incremental checkers and pins against a full refresh at every node and
+    // counts the checks at the leaves
+    fn count_checks(pos: &mut Position, depth: u8) -> usize {
+        let mut refreshed = pos.clone();
+        refreshed.refresh_checks_and_pins();
+        assert_eq!(pos.checkers, refreshed.checkers, "{}", pos.to_fen());
+        assert_eq!(pos.pinned, refreshed.pinned, "{}", pos.to_fen());
+
+        if depth == 0 {
+            return pos.in_check() as usize;
+        }
+        let mut checks = 0;
+        for mv in MoveGen::new(pos) {
+            pos.make_move(mv);
+            checks += count_checks(pos, depth - 1);
+            pos.unmake_move(mv);
+        }
+        checks
+    }
+
+    #[test]
+    fn test_checks_and_pins() {
+        init_tables();
+        init_zobrist();
+
+        let checks = |fen: &str, depth: u8| {
+            let Fen(mut pos) = fen.parse().unwrap();
+            count_checks(&mut pos, depth)
+        };
+        // check counts from the standard perft tables
+        assert_eq!(
+            checks("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1", 4),
+            469
+        );
+        assert_eq!(
+            checks(
+                "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1",
+                3
+            ),
+            993
+        );
+        assert_eq!(checks("8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - - 0 1", 4), 1_680);
+        assert_eq!(
+            checks(
+                "r3k2r/Pppp1ppp/1b3nbN/nP6/BBP1P3/q4N2/Pp1P2PP/R2Q1RK1 w kq - 0 1",
+                3
+            ),
+            38
+        );
+
+        let gives_check = |fen: &str, mv: &str| {
+            let Fen(mut pos) = fen.parse().unwrap();
+            let mv = pos.legal_move(mv.parse().unwrap()).unwrap();
+            pos.make_move(mv);
+            count_checks(&mut pos, 0) == 1
+        };
+        // the castled rook, a knight promotion and a file opened by en passant
+        assert!(gives_check("5k2/8/8/8/8/8/8/4K2R w K - 0 1", "e1g1"));
+        assert!(gives_check("8/3P4/4k3/8/8/8/8/K7 w - - 0 1", "d7d8n"));
+        assert!(!gives_check("8/3P4/4k3/8/8/8/8/K7 w - - 0 1", "d7d8q"));
+        assert!(gives_check("4k3/8/8/3pP3/8/8/8/K3R3 w - d6 0 1", "e5d6"));
+
+        // a pawn check in a position set up from scratch
+        let Fen(pos) = "4k3/3P4/8/8/8/8/8/4K3 b - - 0 1".parse().unwrap();
+        assert_eq!(pos.checkers, Bitboard::from(Square::D7));
+    }
+
+    #[test]
+    fn test_clocks() {
+        init_tables();
+        init_zobrist();
+
+        let game = [
+            ("e2e4", "rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq e3 0 1"),
+            ("e7e5", "rnbqkbnr/pppp1ppp/8/4p3/4P3/8/PPPP1PPP/RNBQKBNR w KQkq e6 0 2"),
+            ("g1f3", "rnbqkbnr/pppp1ppp/8/4p3/4P3/5N2/PPPP1PPP/RNBQKB1R b KQkq - 1 2"),
+            ("b8c6", "r1bqkbnr/pppp1ppp/2n5/4p3/4P3/5N2/PPPP1PPP/RNBQKB1R w KQkq - 2 3"),
+            ("f1c4", "r1bqkbnr/pppp1ppp/2n5/4p3/2B1P3/5N2/PPPP1PPP/RNBQK2R b KQkq - 3 3"),
+            ("g8f6", "r1bqkb1r/pppp1ppp/2n2n2/4p3/2B1P3/5N2/PPPP1PPP/RNBQK2R w KQkq - 4 4"),
+            // castling doesn't reset the halfmove clock
+            ("e1g1", "r1bqkb1r/pppp1ppp/2n2n2/4p3/2B1P3/5N2/PPPP1PPP/RNBQ1RK1 b kq - 5 4"),
+            ("f6e4", "r1bqkb1r/pppp1ppp/2n5/4p3/2B1n3/5N2/PPPP1PPP/RNBQ1RK1 w kq - 0 5"),
+        ];
+
+        let start = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1";
+        let Fen(mut pos) = start.parse().unwrap();
+        let mut played = Vec::new();
+        for (mv, fen) in game {
+            let mv = pos.legal_move(mv.parse().unwrap()).unwrap();
+            pos.make_move(mv);
+            played.push(mv);
+            assert_eq!(pos.to_fen(), fen);
+            assert_eq!(fen.parse::<Fen>().unwrap().0.to_fen(), fen);
+        }
+
+        pos.make_null_move();
+        assert_eq!(
+            pos.to_fen(),
+            "r1bqkb1r/pppp1ppp/2n5/4p3/2B1n3/5N2/PPPP1PPP/RNBQ1RK1 b kq - 0 5"
+        );
+        pos.unmake_null_move();
+
+        for (i, mv) in played.into_iter().enumerate().rev() {
+            assert_eq!(pos.to_fen(), game[i].1);
+            pos.unmake_move(mv);
+        }
+        assert_eq!(pos.to_fen(), start);
+    }
+
+    #[test]
+    fn test_dirty_pieces() {
+        init_tables();
+        init_zobrist();
+
+        let Fen(mut pos) = "r3k2r/8/8/8/8/8/6p1/R3KB1R b Qkq - 0 1".parse().unwrap();
+        assert!(pos.dirty_pieces().needs_refresh());
+
+        // capture promotion: pawn off, rook off, queen on
+        let promotion = Move::new(Square::G2, Square::H1, Some(Role::Queen));
+        pos.make_move(promotion);
+        assert!(!pos.dirty_pieces().needs_refresh());
+        assert_eq!(
+            dirty(&pos),
+            vec![(Square::G2, false), (Square::H1, false), (Square::H1, true)]
+        );
+        assert_eq!(pos.dirty_pieces().king_move(Color::Black), None);
+
+        let castle = Move::new_castle(Square::E1, Square::C1);
+        pos.make_move(castle);
+        assert_eq!(dirty(&pos).len(), 4);
+        assert_eq!(
+            pos.dirty_pieces().king_move(Color::White),
+            Some((Square::E1, Square::C1))
+        );
+
+        pos.unmake_move(castle);
+        assert_eq!(
+            pos.dirty_pieces().king_move(Color::White),
+            Some((Square::C1, Square::E1))
+        );
+
+        pos.make_null_move();
+        assert_eq!(dirty(&pos), vec![]);
+    }
+}
@@ -1,7 +1,16 @@
 use std::{
+    io::Write,
     sync::{
-        atomic::AtomicBool,
+        atomic::{
+            AtomicBool,
+            AtomicU64,
+        },
+        mpsc::{
+            self,
+            RecvTimeoutError,
+        },
         Arc,
+        OnceLock,
     },
     time::{
         Duration,
@@ -10,58 +19,159 @@ use std::{
 };
 
 use arrayvec::ArrayVec;
+pub use thread::SharedHistory;
+use thread::ThreadData;
+pub use tree::{
+    Bound,
+    SearchTree,
+    TreeDump,
+    TreeNode,
+};
 
 use crate::{
+    affinity::{
+        pin_current_thread,
+        Topology,
+    },
     chess::{
         Color,
-        GameResult,
-        Square,
+        Piece,
+        Rank,
+        Role,
+    },
+    eval::{
+        self,
+        Score,
     },
-    eval,
     limits::Limits,
+    movegen::MoveGen,
     movepicker::{
+        quiet_history,
         MovePicker,
         MAX_MOVES,
     },
     moves::Move,
     position::Position,
     tt::{
+        score_from_tt,
+        score_to_tt,
         Entry,
         EntryType,
         Table,
+        DEPTH_QS,
+    },
+    util::{
+        output,
+        uci_println,
     },
 };
 
+mod thread;
+mod tree;
+
 pub struct SearchCop {
     pub depth: Option<u8>,
     pub nodes: Option<u64>,
+    pub soft_nodes: Option<u64>,
     pub adjust: bool,
     pub optimal_time: Option<Duration>,
     pub max_time: Option<Duration>,
+    // the clock doesn't run until ponderhit
+    pub pondering: bool,
+    // consecutive iterations that kept the same bestmove
+    stability: usize,
+    last_bestmove: Move,
 }
 
-const MAX_DEPTH: u8 = 64;
-pub const MAX_PLY: u8 = 128;
+const MAX_DEPTH: i32 = 128;
+// the speed deterministic searches pretend to run at when turning time into nodes
+const DETERMINISTIC_NPS: u64 = 1_000_000;
+// milliseconds, only cut into when the clock has less than twice this left
+const MIN_THINK_TIME: u64 = 5;
+// milliseconds per move kept back for everything outside the search, unless told otherwise
+pub const MOVE_OVERHEAD: u64 = 10;
+// with less than this left of the budget the root is searched without an aspiration window
+const LOW_TIME: Duration = Duration::from_millis(100);
+// failed aspiration searches of one depth before giving up on the window
+const MAX_ASPIRATION_FAILS: usize = 4;
+// how far below the singular beta the other moves have to fail for two plies of extension, and
+// how many of those a line can have
+const DOUBLE_EXTENSION_MARGIN: i16 = 20;
+const MAX_DOUBLE_EXTENSIONS: u8 = 6;
+// nodes a thread searches between merges of its history into the shared one
+const HISTORY_MERGE_NODES: u64 = 1 << 16;
+// how many nodes a thread counts locally before adding them to the shared counter
+const NODE_BATCH: u64 = 1024;
+pub const MAX_PLY: usize = 256;
+// how often the main thread reports on a long iteration
+const INFO_INTERVAL: Duration = Duration::from_secs(1);
+// a table this full (per mille) this far into a search is too small for the time control
+const HASHFULL_WARNING: f64 = 900.;
+const HASHFULL_WARNING_AFTER: Duration = Duration::from_secs(10);
+// the hint to grow the table is only given once per process
+static HASHFULL_WARNED: AtomicBool = AtomicBool::new(false);
+
+// late move reductions are base + ln(depth) * ln(move count) / divisor
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LmrParams {
+    pub base: f32,
+    pub divisor: f32,
+}
 
-static mut REDUCTIONS: [[u8; MAX_MOVES]; MAX_DEPTH as usize] = [[0; MAX_MOVES]; MAX_DEPTH as usize];
+// quiets are reduced more than captures and promotions
+pub const LMR_QUIET: LmrParams = LmrParams {
+    base: 1.,
+    divisor: 2.,
+};
+pub const LMR_NOISY: LmrParams = LmrParams {
+    base: 0.25,
+    divisor: 2.75,
+};
 
-pub fn init_reductions() {
-    unsafe {
-        #[allow(clippy::needless_range_loop)]
-        for m in 1..MAX_MOVES {
-            for depth in 1..MAX_DEPTH as usize {
-                let reduction = 1. + ((depth as f32).ln() * (m as f32).ln()) / 2.;
-                REDUCTIONS[depth][m] = reduction as u8;
-            }
+type ReductionTable = [[u8; MAX_MOVES]; MAX_DEPTH as usize];
+
+// indexed by whether the move is quiet
+static REDUCTIONS: OnceLock<[ReductionTable; 2]> = OnceLock::new();
+
+fn reduction_table(params: LmrParams) -> ReductionTable {
+    let mut table = [[0; MAX_MOVES]; MAX_DEPTH as usize];
+    #[allow(clippy::needless_range_loop)]
+    for m in 1..MAX_MOVES {
+        for depth in 1..MAX_DEPTH as usize {
+            let reduction = params.base + ((depth as f32).ln() * (m as f32).ln()) / params.divisor;
+            table[depth][m] = reduction.max(0.) as u8;
         }
     }
+    table
+}
+
+fn reductions() -> &'static [ReductionTable; 2] {
+    REDUCTIONS.get_or_init(|| [reduction_table(LMR_NOISY), reduction_table(LMR_QUIET)])
+}
+
+// The base late move reduction before any of the search's adjustments
+pub fn reduction(depth: i32, move_count: u8, quiet: bool) -> i32 {
+    let depth = depth.clamp(0, MAX_DEPTH - 1) as usize;
+    reductions()[quiet as usize][depth][move_count as usize] as i32
+}
+
+// builds the table up front so the first search doesn't pay for it
+pub fn init_reductions() {
+    reductions();
 }
 
 impl SearchCop {
-    pub fn new(
+    pub fn new(limits: Limits, side: Color) -> Self {
+        SearchCop::with_overhead(limits, side, MOVE_OVERHEAD)
+    }
+
+    // `overhead` is the milliseconds lost per move outside the search: sending the move, the
+    // gui, the network
+    pub fn with_overhead(
         Limits {
             depth,
             nodes,
+            soft_nodes,
             wtime,
             btime,
             winc,
@@ -69,16 +179,22 @@ impl SearchCop {
             movestogo,
             movetime,
             infinite,
+            ponder,
         }: Limits,
         side: Color,
+        overhead: u64,
     ) -> Self {
         if infinite {
             return SearchCop {
                 depth,
                 nodes,
+                soft_nodes,
                 adjust: false,
                 optimal_time: None,
                 max_time: None,
+                pondering: ponder,
+                stability: 0,
+                last_bestmove: Move::NONE,
             };
         }
 
@@ -86,9 +202,13 @@ impl SearchCop {
             return SearchCop {
                 depth,
                 nodes,
+                soft_nodes,
                 adjust: false,
                 optimal_time: Some(Duration::from_millis(movetime as u64)),
                 max_time: Some(Duration::from_millis(movetime as u64)),
+                pondering: ponder,
+                stability: 0,
+                last_bestmove: Move::NONE,
             };
         }
 
@@ -102,19 +222,26 @@ impl SearchCop {
             return SearchCop {
                 depth,
                 nodes,
+                soft_nodes,
                 adjust: false,
                 optimal_time: None,
                 max_time: None,
+                pondering: ponder,
+                stability: 0,
+                last_bestmove: Move::NONE,
             };
         }
 
         // inspired by weiss
-        let overhead = 10;
+        let overhead = overhead as i32;
+
+        // a negative clock means we're already flagging, think as little as possible
+        let remaining = time_remaining.unwrap().max(0);
 
-        // plan as if there are at most 50 moves left
-        let mtg = 50.min(movestogo.unwrap_or(50)) as i32;
+        // plan as if there are at most 50 moves left, movestogo 0 is treated as 1
+        let mtg = movestogo.unwrap_or(50).clamp(1, 50) as i32;
 
-        let time_left = 0.max(time_remaining.unwrap() + mtg * inc - mtg * overhead);
+        let time_left = 0.max(remaining + mtg * inc - mtg * overhead);
 
         let opt = if movestogo.is_none() {
             // one time control for the whole game
@@ -126,47 +253,471 @@ impl SearchCop {
             (time_left as f32 * scale).min(0.8 * time_left as f32) as u64
         };
 
+        // never plan past what is actually left on the clock, but always think a little so the
+        // first iteration can produce a move
+        let min_time = (MIN_THINK_TIME as i32).min(remaining / 2).max(1) as u64;
         let max = (opt).min((0.8 * time_left as f32) as u64);
-        let max = max.min(time_remaining.unwrap() as u64 - 3 * overhead as u64);
+        let max = max.min(0.max(remaining - 3 * overhead) as u64).max(min_time);
+        let opt = opt.clamp(min_time, max);
 
         SearchCop {
             depth,
             nodes,
+            soft_nodes,
             adjust: true,
             optimal_time: Some(Duration::from_millis(opt)),
             max_time: Some(Duration::from_millis(max)),
+            pondering: ponder,
+            stability: 0,
+            last_bestmove: Move::NONE,
         }
     }
 
+    // Spend more time while the bestmove keeps changing and less once it has settled
+    const STABILITY_SCALE: [f32; 5] = [1.5, 1.2, 1.0, 0.9, 0.8];
+
+    pub fn update_stability(&mut self, bestmove: Move) {
+        if bestmove == self.last_bestmove {
+            self.stability += 1;
+        } else {
+            self.stability = 0;
+            self.last_bestmove = bestmove;
+        }
+    }
+
+    pub fn stability_scale(&self) -> f32 {
+        Self::STABILITY_SCALE[self.stability.min(Self::STABILITY_SCALE.len() - 1)]
+    }
+
+    // Swap the clock for node budgets so the search doesn't depend on how fast the machine is
+    pub fn make_deterministic(&mut self) {
+        self.use_node_clock(DETERMINISTIC_NPS / 1000);
+    }
+
+    // Counts time in nodes instead, every millisecond of budget is `nodes_per_ms` nodes
+    pub fn use_node_clock(&mut self, nodes_per_ms: u64) {
+        let to_nodes = |time: Duration| (time.as_millis() as u64 * nodes_per_ms).max(1);
+
+        if let Some(max_time) = self.max_time.take() {
+            let nodes = to_nodes(max_time);
+            self.nodes = Some(self.nodes.map_or(nodes, |n| n.min(nodes)));
+        }
+        if let Some(optimal_time) = self.optimal_time.take() {
+            let nodes = to_nodes(optimal_time);
+            self.soft_nodes = Some(self.soft_nodes.map_or(nodes, |n| n.min(nodes)));
+        }
+        self.adjust = false;
+    }
+
+    // Restart the clock once the pondered move is played. Part of the time already spent pondering
+    // is credited since that search carries over through the tt.
+    pub fn on_ponderhit(&mut self, start_time: &mut Instant) {
+        self.pondering = false;
+        let credit = (start_time.elapsed() / 2).min(self.optimal_time.unwrap_or_default() / 2);
+        *start_time = Instant::now() - credit;
+    }
+
     pub fn time_up(&self, start_time: Instant) -> bool {
+        if self.pondering {
+            return false;
+        }
         if let Some(time) = self.max_time {
             return start_time.elapsed() >= time;
         }
         false
     }
+
+    // Too little of the budget left to spend any of it searching the same depth twice
+    pub fn low_on_time(&self, start_time: Instant) -> bool {
+        !self.pondering
+            && self
+                .max_time
+                .is_some_and(|time| time.saturating_sub(start_time.elapsed()) < LOW_TIME)
+    }
 }
 
 pub struct SearchResult {
     pub bestmove: Move,
     pub score: i16,
+    pub depth: i32,
+}
+
+// How `smp_search` runs, mostly set from uci options
+#[derive(Debug, Clone)]
+pub struct SearchOptions {
+    pub threads: usize,
+    pub debug: bool,
+    // reproducible searches: one thread and a node budget instead of the clock
+    pub deterministic: bool,
+    // pin each search thread to its own cpu, see `affinity::Topology`
+    pub pin_threads: bool,
+    // the uci `nodestime` option: search a node budget worth this many nodes per millisecond
+    // of the clock, so time controls play the same on any hardware
+    pub nodestime: Option<u64>,
+    // what a draw is worth to the root side in centipawns, taken off the draw score
+    pub contempt: i16,
+    // root moves not to search, see `Search::set_excluded_moves`
+    pub excluded: Vec<Move>,
+    // milliseconds per move the clock loses outside the search
+    pub move_overhead: u64,
+    // threads pool their quiet history instead of each keeping its own, see `SharedHistory`
+    pub share_history: bool,
+    // write out the main thread's last root search, see `Search::set_tree_dump`
+    pub tree_dump: Option<TreeDump>,
+}
+
+impl Default for SearchOptions {
+    fn default() -> Self {
+        SearchOptions {
+            threads: 1,
+            debug: false,
+            deterministic: false,
+            pin_threads: false,
+            nodestime: None,
+            contempt: 0,
+            excluded: Vec::new(),
+            move_overhead: MOVE_OVERHEAD,
+            share_history: false,
+            tree_dump: None,
+        }
+    }
+}
+
+// Lazy SMP: the helper threads search the same position and only share work through the tt.
+// Once the main thread is done the helpers are stopped and the bestmove is picked by a vote.
+pub fn smp_search(
+    position: Position,
+    limits: Limits,
+    tt: Arc<Table>,
+    stop: Arc<AtomicBool>,
+    ponder: Arc<AtomicBool>,
+    options: SearchOptions,
+) -> SearchResult {
+    let threads = if options.deterministic {
+        1
+    } else {
+        options.threads
+    };
+
+    let topology = options.pin_threads.then(Topology::detect);
+    if let Some(topology) = &topology {
+        uci_println!(
+            "info string topology {}, pinning {} threads",
+            topology,
+            threads
+        );
+    }
+
+    let node_counter = Arc::new(AtomicU64::new(0));
+    let shared_history =
+        (options.share_history && threads > 1).then(Arc::<SharedHistory>::default);
+    std::thread::scope(|s| {
+        let helpers = (1..threads)
+            .map(|i| {
+                let mut search = Search::new(position.clone(), limits, tt.clone(), stop.clone());
+                search.set_move_overhead(options.move_overhead);
+                search.set_silent(true);
+                search.set_ponder(ponder.clone());
+                search.set_node_counter(node_counter.clone());
+                search.set_nodestime(options.nodestime);
+                search.set_contempt(options.contempt);
+                search.set_excluded_moves(&options.excluded);
+                if let Some(shared) = &shared_history {
+                    search.set_shared_history(shared.clone());
+                }
+                let cpu = topology.as_ref().map(|t| t.cpu_for(i));
+                s.spawn(move || {
+                    if let Some(cpu) = cpu {
+                        pin_current_thread(cpu);
+                    }
+                    search.think()
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let mut search = Search::new(position.clone(), limits, tt.clone(), stop.clone());
+        search.set_move_overhead(options.move_overhead);
+        search.set_debug(options.debug);
+        search.set_deterministic(options.deterministic);
+        search.set_nodestime(options.nodestime);
+        search.set_contempt(options.contempt);
+        search.set_excluded_moves(&options.excluded);
+        search.set_ponder(ponder.clone());
+        search.set_node_counter(node_counter.clone());
+        if let Some(shared) = &shared_history {
+            search.set_shared_history(shared.clone());
+        }
+        if let Some(dump) = &options.tree_dump {
+            search.set_tree_dump(dump.plies);
+        }
+        // the caller outlives the search, so the main search gets a thread of its own to pin
+        let main_cpu = topology.as_ref().map(|t| t.cpu_for(0));
+        let (search, main) = match main_cpu {
+            Some(cpu) => s
+                .spawn(move || {
+                    pin_current_thread(cpu);
+                    let main = search.think();
+                    (search, main)
+                })
+                .join()
+                .unwrap(),
+            None => {
+                let main = search.think();
+                (search, main)
+            }
+        };
+        stop.store(true, std::sync::atomic::Ordering::Relaxed);
+        if let (Some(dump), Some(tree)) = (&options.tree_dump, search.tree()) {
+            if let Err(e) = dump.write(tree) {
+                uci_println!("info string failed to write the tree dump: {}", e);
+            }
+        }
+
+        let mut results = vec![main];
+        results.extend(helpers.into_iter().map(|h| h.join().unwrap()));
+        if results.len() == 1 {
+            return results.pop().unwrap();
+        }
+
+        let (best, agreeing, depth) = vote(&results);
+        uci_println!(
+            "info string vote bestmove {} threads {}/{} depth {}",
+            results[best].bestmove,
+            agreeing,
+            results.len(),
+            depth
+        );
+        results.swap_remove(best)
+    })
+}
+
+// Weights every thread's bestmove by depth and by how far its score is above the worst one.
+// Returns the winning result along with how many threads agree with it and the deepest of those.
+fn vote(results: &[SearchResult]) -> (usize, usize, i32) {
+    let min_score = results.iter().map(|r| r.score as i64).min().unwrap_or(0);
+    let weight = |r: &SearchResult| (r.score as i64 - min_score + 14) * r.depth as i64;
+    let votes = |mv: Move| {
+        results
+            .iter()
+            .filter(|r| r.bestmove == mv)
+            .map(weight)
+            .sum::<i64>()
+    };
+
+    let mut best = 0;
+    for (i, result) in results.iter().enumerate() {
+        if result.bestmove == Move::NONE {
+            continue;
+        }
+        let (current, candidate) = (votes(results[best].bestmove), votes(result.bestmove));
+        if results[best].bestmove == Move::NONE
+            || candidate > current
+            || (candidate == current && result.depth > results[best].depth)
+        {
+            best = i;
+        }
+    }
+
+    let agreeing = results
+        .iter()
+        .filter(|r| r.bestmove == results[best].bestmove);
+    let depth = agreeing.clone().map(|r| r.depth).max().unwrap_or(0);
+    (best, agreeing.count(), depth)
+}
+
+const PV_SIZE: usize = MAX_PLY * (MAX_PLY + 1) / 2;
+
+// Triangular pv table: the line starting at a ply only needs room for the plies after it, so
+// the rows are packed back to back and each one is a move shorter than the last.
+struct PvTable {
+    moves: [Move; PV_SIZE],
+    length: [usize; MAX_PLY + 1],
+}
+
+impl PvTable {
+    fn new() -> Self {
+        PvTable {
+            moves: [Move::NONE; PV_SIZE],
+            length: [0; MAX_PLY + 1],
+        }
+    }
+
+    #[inline]
+    fn row(ply: usize) -> usize {
+        ply * (2 * MAX_PLY - ply + 1) / 2
+    }
+
+    #[inline]
+    fn clear(&mut self, ply: usize) {
+        self.length[ply] = ply;
+    }
+
+    // the line at `ply` becomes `mv` followed by the line found at the next ply
+    #[inline]
+    fn update(&mut self, ply: usize, mv: Move) {
+        let row = Self::row(ply);
+        let child = Self::row(ply + 1);
+        let length = self.length[ply + 1].max(ply + 1);
+
+        self.moves[row] = mv;
+        let count = length - ply - 1;
+        self.moves.copy_within(child..child + count, row + 1);
+        self.length[ply] = length;
+    }
+
+    fn best_move(&self) -> Move {
+        self.moves[0]
+    }
+
+    fn line(&self) -> &[Move] {
+        &self.moves[..self.length[0]]
+    }
+}
+
+// Counters for tuning pruning and ordering, printed at the end of the search in debug mode
+#[derive(Default)]
+struct SearchStats {
+    tt_probes: u64,
+    tt_hits: u64,
+    null_tries: u64,
+    null_cutoffs: u64,
+    lmr_searches: u64,
+    lmr_researches: u64,
+    qsearch_nodes: u64,
+    // index of the move that caused a beta cutoff, the last bucket is everything after
+    cutoff_index: [u64; 8],
+    history_merges: u64,
+}
+
+fn percent(part: u64, total: u64) -> f64 {
+    part as f64 * 100. / total.max(1) as f64
+}
+
+impl SearchStats {
+    fn print(&self, nodes: u64) {
+        let mut out = output();
+        let _ = writeln!(
+            out,
+            "info string tt probes {} hits {:.1}%",
+            self.tt_probes,
+            percent(self.tt_hits, self.tt_probes)
+        );
+        let _ = writeln!(
+            out,
+            "info string null move tries {} cutoffs {:.1}%",
+            self.null_tries,
+            percent(self.null_cutoffs, self.null_tries)
+        );
+        let _ = writeln!(
+            out,
+            "info string lmr searches {} researches {:.1}%",
+            self.lmr_searches,
+            percent(self.lmr_researches, self.lmr_searches)
+        );
+        let _ = writeln!(
+            out,
+            "info string qsearch nodes {:.1}%",
+            percent(self.qsearch_nodes, nodes)
+        );
+
+        let cutoffs = self.cutoff_index.iter().sum::<u64>();
+        let distribution = self
+            .cutoff_index
+            .iter()
+            .enumerate()
+            .map(|(i, count)| {
+                let index = if i + 1 == self.cutoff_index.len() {
+                    format!("{}+", i + 1)
+                } else {
+                    (i + 1).to_string()
+                };
+                format!("{} {:.1}%", index, percent(*count, cutoffs))
+            })
+            .collect::<Vec<String>>()
+            .join(" ");
+        let _ = writeln!(out, "info string beta cutoffs {} by move {}", cutoffs, distribution);
+        if self.history_merges > 0 {
+            let _ = writeln!(out, "info string history merges {}", self.history_merges);
+        }
+        let _ = out.flush();
+    }
+}
+
+// Per-ply state of the line currently being searched, indexed by ply
+#[derive(Clone, Copy)]
+struct SearchStack {
+    current_move: Move,
+    moved: Option<Piece>,
+    static_eval: i16,
+    // skipped when searching this node, for singular search
+    excluded: Move,
+    double_extensions: u8,
+}
+
+impl Default for SearchStack {
+    fn default() -> Self {
+        SearchStack {
+            current_move: Move::NONE,
+            moved: None,
+            static_eval: 0,
+            excluded: Move::NONE,
+            double_extensions: 0,
+        }
+    }
+}
+
+// A legal move at the root along with what the previous iterations found out about it
+#[derive(Clone, Copy)]
+struct RootMove {
+    mv: Move,
+    score: i16,
+    nodes: u64,
 }
 
 pub struct Search {
     position: Position,
     limits: SearchCop,
+    // as given, to rebuild `limits` from with another move overhead
+    go_limits: Limits,
     tt: Arc<Table>,
 
-    pv: [[Move; MAX_PLY as usize]; MAX_PLY as usize],
-    pv_length: [u8; MAX_PLY as usize],
-    killers: [[Move; 2]; MAX_PLY as usize],
-    current_move: [Move; MAX_PLY as usize],
-    history: [[[i16; Square::NUM]; Square::NUM]; Color::NUM],
+    pv: PvTable,
+    // one extra entry so a node at the last ply can still set up its children
+    stack: [SearchStack; MAX_PLY + 1],
+    thread: ThreadData,
+    // pooled with the other threads every HISTORY_MERGE_NODES when set
+    shared_history: Option<Arc<SharedHistory>>,
+    last_merge: u64,
     start_time: Instant,
     stop: Arc<AtomicBool>,
     silent: bool,
-    effort: [[u64; Square::NUM]; Square::NUM],
-
+    debug: bool,
+    // depth of the current iteration
+    root_depth: i32,
+    // deepest ply reached in the current iteration, quiescence included
+    seldepth: usize,
+    // cleared by the uci thread on ponderhit
+    ponder: Arc<AtomicBool>,
+    // dropping this cancels the hard deadline timer
+    watchdog: Option<mpsc::Sender<()>>,
+    last_info: Instant,
+    // searched in this order at the root, sorted between iterations
+    root_moves: Vec<RootMove>,
+    // root moves left out of the search
+    excluded: Vec<Move>,
+    stats: SearchStats,
+    // draws are scored this much below zero for the side to move at the root
+    contempt: i16,
+    root_side: Color,
+    // only recorded when dumping the tree
+    tree: Option<SearchTree>,
+    // why the node at a ply returned early, picked up by `search` for the tree
+    prune_reason: Option<(usize, &'static str)>,
+
+    // nodes searched by this thread, the shared counter lags behind by up to NODE_BATCH
     pub nodes: u64,
+    flushed_nodes: u64,
+    node_counter: Arc<AtomicU64>,
 }
 
 impl Search {
@@ -175,30 +726,191 @@ impl Search {
         Search {
             position,
             limits: SearchCop::new(limits, side),
+            go_limits: limits,
             tt,
-            pv: [[Move::NONE; MAX_PLY as usize]; MAX_PLY as usize],
-            pv_length: [0; MAX_PLY as usize],
-            killers: [[Move::NONE; 2]; MAX_PLY as usize],
-            current_move: [Move::NONE; MAX_PLY as usize],
-            history: [[[0; Square::NUM]; Square::NUM]; Color::NUM],
+            pv: PvTable::new(),
+            stack: [SearchStack::default(); MAX_PLY + 1],
+            thread: ThreadData::new(),
+            shared_history: None,
+            last_merge: 0,
             start_time: Instant::now(),
             stop,
             silent: false,
-            effort: [[0; Square::NUM]; Square::NUM],
+            debug: false,
+            root_depth: 0,
+            seldepth: 0,
+            ponder: Arc::new(AtomicBool::new(false)),
+            watchdog: None,
+            last_info: Instant::now(),
+            root_moves: Vec::new(),
+            excluded: Vec::new(),
+            stats: SearchStats::default(),
+            contempt: 0,
+            root_side: side,
+            tree: None,
+            prune_reason: None,
             nodes: 0,
+            flushed_nodes: 0,
+            node_counter: Arc::new(AtomicU64::new(0)),
         }
     }
 
     pub fn think(&mut self) -> SearchResult {
         self.start_time = Instant::now();
+        self.last_info = self.start_time;
+
+        self.root_moves = MoveGen::new(&self.position)
+            .map(|mv| RootMove {
+                mv,
+                score: -eval::INFINITY,
+                nodes: 0,
+            })
+            .collect();
+        if self.root_moves.is_empty() {
+            return self.terminal_result();
+        }
+        // excluding every move would leave nothing to play, so then none are
+        if self.root_moves.iter().any(|root_move| !self.excluded.contains(&root_move.mv)) {
+            self.root_moves.retain(|root_move| !self.excluded.contains(&root_move.mv));
+        }
+
+        if !self.limits.pondering {
+            self.start_watchdog();
+        }
+
+        let result = self.iterative_deepening();
+        self.flush_nodes();
+        if self.debug && !self.silent {
+            self.stats.print(self.nodes);
+        }
+        self.watchdog = None;
+        result
+    }
+
+    // No legal moves at the root, report the result instead of searching
+    fn terminal_result(&self) -> SearchResult {
+        let (score, result) = if self.position.in_check() {
+            (-eval::MATE, "checkmate")
+        } else {
+            (self.draw_value(), "stalemate")
+        };
+
+        if !self.silent {
+            let mut out = output();
+            let _ = writeln!(out, "info depth 0 score {}", Score(score).to_uci_string());
+            let _ = writeln!(out, "info string no legal moves, {}", result);
+            let _ = out.flush();
+        }
+
+        SearchResult {
+            bestmove: Move::NONE,
+            score,
+            depth: 0,
+        }
+    }
+
+    // The clock is only polled every few thousand nodes, so a timer thread makes sure the search
+    // is stopped at max time no matter how slow those nodes are.
+    fn start_watchdog(&mut self) {
+        let Some(max_time) = self.limits.max_time else {
+            return;
+        };
+
+        let (tx, rx) = mpsc::channel::<()>();
+        let stop = self.stop.clone();
+        let deadline = self.start_time + max_time;
+        std::thread::spawn(move || {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            if let Err(RecvTimeoutError::Timeout) = rx.recv_timeout(timeout) {
+                stop.store(true, std::sync::atomic::Ordering::Relaxed);
+            }
+        });
+        self.watchdog = Some(tx);
+    }
+
+    // Replaces the default MOVE_OVERHEAD. It starts the limits over, so it goes before the other
+    // setters that change them.
+    pub fn set_move_overhead(&mut self, overhead: u64) {
+        self.limits = SearchCop::with_overhead(self.go_limits, self.position.side, overhead);
+    }
+
+    pub fn set_deterministic(&mut self, deterministic: bool) {
+        if deterministic {
+            self.limits.make_deterministic();
+        }
+    }
+
+    // Searches every legal root move but these, e.g. to find the second best move by excluding
+    // the best one
+    pub fn set_excluded_moves(&mut self, moves: &[Move]) {
+        self.excluded = moves.to_vec();
+    }
+
+    pub fn set_nodestime(&mut self, nodestime: Option<u64>) {
+        if let Some(nodes_per_ms) = nodestime {
+            self.limits.use_node_clock(nodes_per_ms);
+        }
+    }
+
+    // Records the nodes of each root search up to `plies` deep, keeping the last one. Slows the
+    // search down, it's meant for debugging a single position.
+    pub fn set_tree_dump(&mut self, plies: usize) {
+        self.tree = Some(SearchTree::new(plies));
+    }
+
+    pub fn tree(&self) -> Option<&SearchTree> {
+        self.tree.as_ref()
+    }
+
+    // Pools this thread's history with the other threads using `shared` as it searches
+    pub fn set_shared_history(&mut self, shared: Arc<SharedHistory>) {
+        self.shared_history = Some(shared);
+    }
+
+    pub fn set_contempt(&mut self, contempt: i16) {
+        self.contempt = contempt;
+    }
 
-        self.iterative_deepening()
+    pub fn set_ponder(&mut self, ponder: Arc<AtomicBool>) {
+        self.ponder = ponder;
+    }
+
+    pub fn set_node_counter(&mut self, node_counter: Arc<AtomicU64>) {
+        self.node_counter = node_counter;
+    }
+
+    // A node is counted once, on entry to search or quiescence after the abort and max ply checks,
+    // whatever happens to it afterwards.
+    #[inline]
+    fn add_node(&mut self) {
+        self.nodes += 1;
+        if self.nodes - self.flushed_nodes >= NODE_BATCH {
+            self.flush_nodes();
+        }
+    }
+
+    fn flush_nodes(&mut self) {
+        self.node_counter.fetch_add(
+            self.nodes - self.flushed_nodes,
+            std::sync::atomic::Ordering::Relaxed,
+        );
+        self.flushed_nodes = self.nodes;
+    }
+
+    // nodes searched by all threads sharing the node counter
+    pub fn total_nodes(&self) -> u64 {
+        self.node_counter.load(std::sync::atomic::Ordering::Relaxed) + self.nodes
+            - self.flushed_nodes
     }
 
     fn iterative_deepening(&mut self) -> SearchResult {
-        let max_depth = self.limits.depth.unwrap_or(MAX_DEPTH) as i32;
+        let max_depth = self
+            .limits
+            .depth
+            .map_or(MAX_DEPTH, |d| (d as i32).min(MAX_DEPTH));
         let mut bestmove = Move::NONE;
         let mut score = 0;
+        let mut completed = 0;
 
         let mut scale = 1.;
 
@@ -207,6 +919,9 @@ impl Search {
                 break;
             }
 
+            self.root_depth = depth;
+            self.seldepth = 0;
+            self.sort_root_moves(bestmove);
             let depth_score = self.aspiration(depth, score);
 
             if self.done_thinking() {
@@ -214,14 +929,33 @@ impl Search {
             }
 
             score = depth_score;
-            bestmove = self.pv[0][0];
+            bestmove = self.pv.best_move();
+            completed = depth;
             self.uci_info(depth, score);
+            if self.debug {
+                self.effort_info();
+            }
+
+            // soft node limit, don't start another iteration
+            if self.limits.soft_nodes.is_some_and(|n| self.total_nodes() >= n) {
+                break;
+            }
+
+            // while pondering keep searching until ponderhit or stop
+            if self.limits.pondering {
+                continue;
+            }
 
             //TODO: Move this into search cop
             if self.limits.adjust {
-                let bm_nodes = self.effort[self.pv[0][0].from()][self.pv[0][0].to()];
+                let bm_nodes = self
+                    .root_moves
+                    .iter()
+                    .find(|root_move| root_move.mv == bestmove)
+                    .map_or(0, |root_move| root_move.nodes);
                 let bm_frac = bm_nodes as f32 / self.nodes as f32;
-                scale = (0.4 + 2. * (1. - bm_frac)).max(0.5);
+                self.limits.update_stability(bestmove);
+                scale = (0.4 + 2. * (1. - bm_frac)).max(0.5) * self.limits.stability_scale();
             }
 
             // stop search if we're past optimum
@@ -239,27 +973,37 @@ impl Search {
             }
         }
 
+        // a pondering search can't send its bestmove before ponderhit or stop
+        while self.limits.pondering && !self.done_thinking() {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+
         if bestmove == Move::NONE {
-            bestmove = self.pv[0][0];
+            bestmove = self.pv.best_move();
         }
 
-        SearchResult { bestmove, score }
+        SearchResult {
+            bestmove,
+            score,
+            depth: completed,
+        }
     }
 
     fn aspiration(&mut self, depth: i32, prev: i16) -> i16 {
         let mut delta = 50;
-        let (mut alpha, mut beta) = if depth > 6 {
+        let (mut alpha, mut beta) = if depth > 6 && !self.limits.low_on_time(self.start_time) {
             (prev - delta, prev + delta)
         } else {
             (-eval::INFINITY, eval::INFINITY)
         };
 
+        let mut fails = 0;
         loop {
             if self.done_thinking() {
                 return 0;
             }
 
-            let score = self.search(depth, alpha, beta, 0, true, true);
+            let score = self.search(depth, alpha, beta, 0, true, false);
 
             if score <= alpha {
                 beta = (alpha + beta) / 2;
@@ -270,75 +1014,135 @@ impl Search {
                 return score;
             }
 
+            // re-searching a window that keeps failing costs more than an open one
+            fails += 1;
             delta += delta / 2;
-            if delta > 1000 {
+            if delta > 1000
+                || fails >= MAX_ASPIRATION_FAILS
+                || self.limits.low_on_time(self.start_time)
+            {
                 alpha = -eval::INFINITY;
                 beta = eval::INFINITY;
             }
         }
     }
 
+    // Records the node in the tree when dumping, the search itself is `search_node`
     fn search(
+        &mut self,
+        depth: i32,
+        alpha: i16,
+        beta: i16,
+        ply: usize,
+        is_pv: bool,
+        cut_node: bool,
+    ) -> i16 {
+        let Some(tree) = self.tree.as_mut().filter(|tree| ply <= tree.max_ply) else {
+            return self.search_node(depth, alpha, beta, ply, is_pv, cut_node);
+        };
+
+        if ply == 0 {
+            tree.clear();
+        }
+        let mv = match ply {
+            0 => Move::NONE,
+            _ => self.stack[ply - 1].current_move,
+        };
+        let index = tree.enter(mv, depth, alpha, beta);
+        let score = self.search_node(depth, alpha, beta, ply, is_pv, cut_node);
+
+        let reason = self.prune_reason.take().filter(|&(at, _)| at == ply);
+        if let Some(tree) = self.tree.as_mut() {
+            tree.exit(index, score, reason.map(|(_, reason)| reason));
+        }
+        score
+    }
+
+    #[inline]
+    fn note_pruned(&mut self, ply: usize, reason: &'static str) {
+        if self.tree.is_some() {
+            self.prune_reason = Some((ply, reason));
+        }
+    }
+
+    fn search_node(
         &mut self,
         mut depth: i32,
         mut alpha: i16,
         mut beta: i16,
-        ply: u8,
+        ply: usize,
         is_pv: bool,
-        is_root: bool,
+        cut_node: bool,
     ) -> i16 {
+        let is_root = ply == 0;
+        let original_alpha = alpha;
+
         if self.done_thinking() {
+            self.note_pruned(ply, "stopped");
             return 0;
         }
-        if depth >= MAX_DEPTH as i32 || ply >= MAX_PLY {
-            return self.position.eval();
+        if ply >= MAX_PLY {
+            self.note_pruned(ply, "max ply");
+            return self.max_ply_score();
         }
-        self.nodes += 1;
+        self.seldepth = self.seldepth.max(ply);
+        // extensions can't push a node past the end of the tables
+        depth = depth.min(MAX_DEPTH - 1);
+        self.pv.clear(ply);
 
-        self.pv_length[ply as usize] = ply;
+        // Go to quiescence search if depth is 0, it counts the node itself
+        if depth <= 0 {
+            self.note_pruned(ply, "quiescence");
+            return self.quiescence_search(alpha, beta, ply, is_pv);
+        }
+        self.add_node();
 
         debug_assert!(alpha < beta);
         debug_assert_eq!(self.position.key, self.position.zobrist_hash());
 
         if !is_root {
-            match self.position.is_draw() {
-                Some(GameResult::Draw) => return eval::DRAW,
-                // test if this is between alpha and beta?
-                Some(GameResult::Loss) => return -eval::MATE + ply as i16,
-                _ => {}
+            // checkmate isn't a draw, it's found once there are no moves to search
+            if self.position.is_draw() {
+                self.note_pruned(ply, "draw");
+                return self.draw_value();
             }
 
             let repetition_count = if is_pv { 2 } else { 1 };
             if self.position.is_repetition(repetition_count) {
-                return eval::DRAW;
-            }
-        }
-
-        if self.position.in_check() {
-            depth += 1;
-            if depth >= MAX_DEPTH as i32 {
-                return self.position.eval();
+                self.note_pruned(ply, "repetition");
+                return self.draw_score();
             }
         }
 
-        // Go to quiescence search if depth is 0
-        if depth <= 0 {
-            return self.quiescence_search(alpha, beta, is_pv);
-        }
+        // a singular search of this node without its tt move, see `singular_extension`. It shares
+        // the node's key, so it can't take cutoffs from the table or store its result there.
+        let singular_search = self.stack[ply].excluded != Move::NONE;
 
         // Probe the transposition table
+        let mut tt_entry = None;
         let mut tt_eval = None;
         let mut tt_move = Move::NONE;
-        if let Some(entry) = self.tt.probe(self.position.key) {
+        // an exact score means this position was on a pv before
+        let mut tt_pv = false;
+        self.stats.tt_probes += 1;
+        if let Some(mut entry) = self.tt.probe(self.position.key) {
+            self.stats.tt_hits += 1;
+            entry.score = score_from_tt(entry.score, ply);
+            tt_entry = Some(entry);
             tt_move = entry.best_move;
             tt_eval = Some(entry.score);
+            tt_pv = matches!(entry.score_type, EntryType::Exact);
             if entry.depth as i32 >= depth
                 && !is_pv
-                && self.current_move[ply as usize - 1] != Move::NULL
+                && !singular_search
+                && self.stack[ply - 1].current_move != Move::NULL
             {
                 match entry.score_type {
                     // Exact score
-                    EntryType::Exact => return entry.score,
+                    EntryType::Exact => {
+                        self.note_pruned(ply, "tt cutoff");
+                        return entry.score;
+                    }
                     // Lower bound
                     EntryType::LowerBound => alpha = alpha.max(entry.score),
                     // Upper bound
@@ -346,12 +1150,27 @@ impl Search {
                     EntryType::None => {}
                 }
                 if alpha >= beta {
+                    self.note_pruned(ply, "tt cutoff");
                     return entry.score;
                 }
             }
         }
 
+        // internal iterative deepening: pv nodes are worth a shallow search to find a move to try
+        // first when the tt doesn't have one
+        if is_pv && !is_root && depth >= 6 && tt_move == Move::NONE {
+            self.search(depth - 2, alpha, beta, ply, true, false);
+            if let Some(entry) = self.tt.probe(self.position.key) {
+                tt_move = entry.best_move;
+            }
+        }
+
         let static_eval = tt_eval.unwrap_or(self.position.eval());
+        self.stack[ply].static_eval = static_eval;
+        // the eval went up since our last move, so fail highs are more likely
+        let improving = !self.position.in_check()
+            && ply >= 2
+            && static_eval > self.stack[ply - 2].static_eval;
 
         // internal iterative reduction
         if !is_root && depth >= 6 && !self.position.in_check() && tt_move == Move::NONE {
@@ -360,38 +1179,83 @@ impl Search {
 
         // Null move pruning
         if !is_pv
+            && !singular_search
             && depth >= 3
             && self.position.non_pawn_material(self.position.side)
             && !self.position.in_check()
             && static_eval >= beta
-            && (ply < 1 || self.current_move[(ply - 1) as usize] != Move::NULL)
+            && (ply < 1 || self.stack[ply - 1].current_move != Move::NULL)
         {
+            self.stats.null_tries += 1;
             self.position.make_null_move();
-            self.current_move[ply as usize] = Move::NULL;
+            self.stack[ply].current_move = Move::NULL;
+            self.stack[ply].moved = None;
 
             let reduced_depth = depth - (3 + (depth / 5));
-            let null_score = -self.search(reduced_depth, -beta, -beta + 1, ply + 1, false, false);
+            let null_score =
+                -self.search(reduced_depth, -beta, -beta + 1, ply + 1, false, !cut_node);
 
             self.position.unmake_null_move();
-            self.current_move[ply as usize] = Move::NONE;
+            self.stack[ply].current_move = Move::NONE;
 
             if null_score >= beta {
-                if null_score >= (eval::MATE - MAX_PLY as i16) {
+                self.stats.null_cutoffs += 1;
+                self.note_pruned(ply, "null move");
+                if null_score >= eval::MATE_IN_PLY {
                     return beta;
                 }
                 return null_score;
             }
         }
 
-        // Reverse futility pruning
+        // Multicut: at an expected cut node, if several of the first moves beat beta at reduced
+        // depth then one of them will most likely hold up in a full search too
+        if !is_pv
+            && cut_node
+            && !singular_search
+            && !is_root
+            && depth >= 8
+            && tt_move != Move::NONE
+            && tt_eval.is_some_and(|score| score >= beta)
+            && !self.position.in_check()
+        {
+            let mut cutoffs = 0;
+            let mut move_picker =
+                MovePicker::new_ab_search(&self.position, tt_move, self.thread.killers[ply]);
+            for _ in 0..6 {
+                let Some(mv) = move_picker.next(&self.position, &self.thread.history) else {
+                    break;
+                };
+                if mv == self.stack[ply].excluded {
+                    continue;
+                }
+
+                self.make_move(mv, ply);
+                let score = -self.search(depth - 4, -beta, -beta + 1, ply + 1, false, false);
+                self.unmake_move(mv, ply);
+
+                if score >= beta {
+                    cutoffs += 1;
+                    if cutoffs >= 3 {
+                        self.note_pruned(ply, "multicut");
+                        return beta;
+                    }
+                }
+            }
+        }
+
+        // Reverse futility pruning, with less margin when the eval is improving
+        let rfp_margin = 300 * (depth - improving as i32);
         if !is_pv
-            && (-31_000..31_000).contains(&beta)
-            && (-31_000..31_000).contains(&static_eval)
+            && !singular_search
+            && !eval::is_mate_score(beta)
+            && !eval::is_mate_score(static_eval)
             && !self.position.in_check()
             && depth < 7
-            && (static_eval - 300 * depth as i16) >= beta
+            && (static_eval as i32 - rfp_margin) >= beta as i32
         {
-            return static_eval - 300 * depth as i16;
+            self.note_pruned(ply, "reverse futility");
+            return static_eval - rfp_margin as i16;
         }
 
         let mut best_move = Move::NONE;
@@ -400,74 +1264,132 @@ impl Search {
         let mut quiets: ArrayVec<Move, 64> = ArrayVec::new();
 
         let mut move_picker =
-            MovePicker::new_ab_search(&self.position, tt_move, self.killers[ply as usize]);
-        while let Some(mv) = move_picker.next(&self.position, &self.history) {
+            MovePicker::new_ab_search(&self.position, tt_move, self.thread.killers[ply]);
+        let in_check = self.position.in_check();
+        let mut root_index = 0;
+        loop {
+            // the root has its own ordering carried over from the last iteration
+            let mv = if is_root {
+                let Some(root_move) = self.root_moves.get(root_index) else {
+                    break;
+                };
+                root_index += 1;
+                root_move.mv
+            } else {
+                let Some(mv) = move_picker.next(&self.position, &self.thread.history) else {
+                    break;
+                };
+                mv
+            };
+
+            if mv == self.stack[ply].excluded {
+                continue;
+            }
+
             move_count += 1;
             let capture = (self.position.occupancy & mv.to()).any();
+            let quiet = !capture && mv.promotion().is_none();
+            let history = quiet_history(&self.thread.history, &self.position, mv) as i32;
+
+            // History pruning: skip quiets that have been failing low everywhere else
+            if !is_pv
+                && quiet
+                && depth <= 3
+                && move_count > 1
+                && best > -eval::MATE_IN_PLY
+                && !self.position.in_check()
+                && history < -1024 * depth
+            {
+                if let Some(tree) = self.tree.as_mut().filter(|tree| ply < tree.max_ply) {
+                    tree.pruned(mv, depth - 1, "history pruning");
+                }
+                continue;
+            }
+
+            let singular = match tt_entry {
+                Some(entry) if mv == tt_move && !is_root && !singular_search => {
+                    self.singular_extension(entry, depth, ply, is_pv, cut_node)
+                }
+                _ => 0,
+            };
+            self.stack[ply + 1].double_extensions =
+                self.stack[ply].double_extensions + (singular == 2) as u8;
 
             // store node count for effort calculation
             let before_nodes = self.nodes;
 
-            self.position.make_move(mv);
-            self.current_move[ply as usize] = mv;
+            self.make_move(mv, ply);
+
+            let new_depth = depth - 1 + self.extension(mv, capture, ply, is_pv).max(singular);
 
             let mut score = -eval::INFINITY;
 
             // LMR
             let needs_full_search = if depth >= 3 && !self.position.in_check() && move_count > 4 {
-                let reduction = self.reduction(depth, move_count);
-                let mut rdepth = (depth - 1 - reduction).clamp(1, depth - 2);
+                let mut reduction = reduction(depth, move_count, quiet);
 
-                // Reduce less in PV nodes
-                if is_pv {
-                    rdepth += 1;
-                }
+                // Reduce less in PV nodes, positions that were on a pv, and when evading check
+                reduction -= is_pv as i32 + tt_pv as i32 + in_check as i32;
+
+                // reduce more where a fail high is expected anyway
+                reduction += cut_node as i32;
 
                 // reduce more in non-capture moves
                 if move_count > 15 && !capture {
-                    rdepth -= 1;
+                    reduction += 1;
                 }
 
-                score = -self.search(rdepth, -alpha - 1, -alpha, ply + 1, false, false);
+                // and adjust quiets by how their history looks
+                if quiet && history < -2048 {
+                    reduction += 1;
+                } else if quiet && history > 2048 {
+                    reduction -= 1;
+                }
+
+                let rdepth = (new_depth - reduction).clamp(1, new_depth);
+                score = -self.search(rdepth, -alpha - 1, -alpha, ply + 1, false, true);
+                self.stats.lmr_searches += 1;
 
-                score > alpha && rdepth < depth - 1
+                let research = score > alpha && rdepth < new_depth;
+                self.stats.lmr_researches += research as u64;
+                research
             } else {
                 move_count > 1 || !is_pv
             };
 
             if needs_full_search {
-                score = -self.search(depth - 1, -alpha - 1, -alpha, ply + 1, false, false);
+                score = -self.search(new_depth, -alpha - 1, -alpha, ply + 1, false, !cut_node);
             }
 
             if is_pv && (move_count == 1 || score > alpha && score < beta) {
-                score = -self.search(depth - 1, -beta, -alpha, ply + 1, true, false);
+                score = -self.search(new_depth, -beta, -alpha, ply + 1, true, false);
             }
 
-            self.position.unmake_move(mv);
-            self.current_move[ply as usize] = Move::NONE;
+            self.unmake_move(mv, ply);
 
-            // store effort at root
+            // remember how the move did for ordering and time management
             if is_root {
-                self.effort[mv.from()][mv.to()] = self.nodes - before_nodes;
+                let root_move = &mut self.root_moves[root_index - 1];
+                root_move.score = score;
+                root_move.nodes = self.nodes - before_nodes;
             }
 
             if score > best {
                 best = score;
                 best_move = mv;
 
-                self.pv[ply as usize][ply as usize] = mv;
-                for j in (ply + 1)..self.pv_length[ply as usize + 1] {
-                    self.pv[ply as usize][j as usize] = self.pv[ply as usize + 1][j as usize];
-                }
-
-                self.pv_length[ply as usize] = self.pv_length[ply as usize + 1];
+                self.pv.update(ply, mv);
 
                 if score > alpha {
                     alpha = score;
                     if score >= beta {
+                        let bucket =
+                            (move_count as usize - 1).min(self.stats.cutoff_index.len() - 1);
+                        self.stats.cutoff_index[bucket] += 1;
+
                         if !capture {
                             self.update_killers(mv, ply);
-                            let bonus = 2000.min(350 * depth as i16 - 350);
+                            let bonus = (350 * depth - 350).clamp(0, 2000) as i16;
                             self.update_history(mv, bonus);
 
                             for quiet in quiets.iter() {
@@ -486,26 +1408,35 @@ impl Search {
         }
 
         if move_count == 0 {
+            // the tt move was the only one, which doesn't make the position a mate or stalemate
+            if singular_search {
+                return alpha;
+            }
             if self.position.in_check() {
-                return -eval::MATE + ply as i16;
+                self.note_pruned(ply, "checkmate");
+                return eval::mated_in(ply);
             } else {
-                return 0;
+                self.note_pruned(ply, "stalemate");
+                return self.draw_value();
             }
         }
 
         let entry_type = if best >= beta {
             EntryType::LowerBound
-        } else if is_pv && best_move != Move::NULL {
+        } else if best > original_alpha {
             EntryType::Exact
         } else {
-            EntryType::LowerBound
+            EntryType::UpperBound
         };
 
-        if !self.stop.load(std::sync::atomic::Ordering::Relaxed) {
+        // with moves excluded the root score isn't the position's, keep it out of the table
+        let excluding = is_root && !self.excluded.is_empty();
+        let stopped = self.stop.load(std::sync::atomic::Ordering::Relaxed);
+        if !stopped && !excluding && !singular_search {
             self.tt.set(Entry::new(
                 self.position.key,
-                depth as u8,
-                best,
+                depth as i8,
+                score_to_tt(best, ply),
                 entry_type,
                 best_move,
             ));
@@ -513,30 +1444,35 @@ impl Search {
         best
     }
 
-    fn quiescence_search(&mut self, mut alpha: i16, beta: i16, is_pv: bool) -> i16 {
-        self.nodes += 1;
-
+    fn quiescence_search(&mut self, mut alpha: i16, beta: i16, ply: usize, is_pv: bool) -> i16 {
         if self.done_thinking() {
             return 0;
         }
+        if ply >= MAX_PLY {
+            return self.max_ply_score();
+        }
+        self.seldepth = self.seldepth.max(ply);
+        self.add_node();
+        self.stats.qsearch_nodes += 1;
+        let original_alpha = alpha;
 
-        match self.position.is_draw() {
-            Some(GameResult::Draw) => return eval::DRAW,
-            // don't have ply here so this is a guess
-            Some(GameResult::Loss) => return -eval::MATE + MAX_PLY as i16,
-            _ => {}
+        if self.position.is_draw() {
+            return self.draw_value();
         }
 
         let repetition_count = if is_pv { 2 } else { 1 };
         if self.position.is_repetition(repetition_count) {
-            return eval::DRAW;
+            return self.draw_score();
         }
 
         // Probe tt
         let mut tt_move = Move::NONE;
-        if let Some(entry) = self.tt.probe(self.position.key) {
+        self.stats.tt_probes += 1;
+        if let Some(mut entry) = self.tt.probe(self.position.key) {
+            self.stats.tt_hits += 1;
+            entry.score = score_from_tt(entry.score, ply);
             tt_move = entry.best_move;
-            if !is_pv {
+            if entry.depth >= DEPTH_QS && !is_pv {
                 match entry.score_type {
                     EntryType::Exact => return entry.score,
                     EntryType::LowerBound => {
@@ -554,32 +1490,45 @@ impl Search {
             }
         }
 
-        let stand_pat = self.position.eval();
-        if stand_pat >= beta {
-            return stand_pat;
-        }
-
-        if stand_pat > alpha {
-            alpha = stand_pat;
+        // In check doing nothing isn't an option, so there's no standing pat and every evasion
+        // is searched. Without one it's mate.
+        let in_check = self.position.in_check();
+        let stand_pat = if in_check {
+            eval::mated_in(ply)
+        } else {
+            self.position.eval()
+        };
+        if !in_check {
+            if stand_pat >= beta {
+                return stand_pat;
+            }
+            if stand_pat > alpha {
+                alpha = stand_pat;
+            }
         }
 
         let mut best = stand_pat;
         let mut best_move = Move::NONE;
 
-        let mut move_picker = MovePicker::new_quiescence(&self.position, tt_move);
-        while let Some(mv) = move_picker.next(&self.position, &self.history) {
-            // delta pruning
-            let captured = self.position.role_at(mv.to()).unwrap();
-            if mv.promotion().is_none()
-                && !self.position.in_check()
-                && ((stand_pat + 500 + eval::PIECE_VALUES_EG[captured] as i16) < alpha)
+        let mut move_picker = if in_check {
+            MovePicker::new_evasions(&self.position, tt_move)
+        } else {
+            MovePicker::new_quiescence(&self.position, tt_move)
+        };
+        while let Some(mv) = move_picker.next(&self.position, &self.thread.history) {
+            // delta pruning, evasions can be quiet so there may be nothing captured
+            if !in_check
+                && mv.promotion().is_none()
                 && self.position.non_pawn_material(self.position.side)
+                && self.position.role_at(mv.to()).is_some_and(|captured| {
+                    stand_pat + 500 + (eval::PIECE_VALUES.eg[captured] as i16) < alpha
+                })
             {
                 continue;
             }
 
             self.position.make_move(mv);
-            let score = -self.quiescence_search(-beta, -alpha, is_pv);
+            let score = -self.quiescence_search(-beta, -alpha, ply + 1, is_pv);
             self.position.unmake_move(mv);
 
             if score > best {
@@ -596,6 +1545,8 @@ impl Search {
 
         let entry_type = if best >= beta {
             EntryType::LowerBound
+        } else if best > original_alpha {
+            EntryType::Exact
         } else {
             EntryType::UpperBound
         };
@@ -603,8 +1554,8 @@ impl Search {
         if !self.stop.load(std::sync::atomic::Ordering::Relaxed) {
             self.tt.set(Entry::new(
                 self.position.key,
-                0,
-                best,
+                DEPTH_QS,
+                score_to_tt(best, ply),
                 entry_type,
                 best_move,
             ));
@@ -613,75 +1564,639 @@ impl Search {
         best
     }
 
-    pub fn update_killers(&mut self, mv: Move, ply: u8) {
-        self.killers[ply as usize][1] = self.killers[ply as usize][0];
-        self.killers[ply as usize][0] = mv;
+    // Repetitions score a point either side of a draw, picked by the node count, so the search
+    // doesn't walk into a repetition when it's better. Normalizing rounds it away, a drawn root
+    // still reports cp 0.
+    fn draw_score(&self) -> i16 {
+        self.draw_value() - 1 + (self.nodes & 2) as i16
     }
 
-    fn update_history(&mut self, mv: Move, bonus: i16) {
-        self.history[self.position.side][mv.from()][mv.to()] += bonus
-            - ((self.history[self.position.side][mv.from()][mv.to()] as i32 * bonus.abs() as i32)
-                / 16384) as i16;
+    // A draw from the side to move's point of view, contempt included
+    fn draw_value(&self) -> i16 {
+        if self.position.side == self.root_side {
+            eval::DRAW - self.contempt
+        } else {
+            eval::DRAW + self.contempt
+        }
     }
 
-    fn reduction(&self, depth: i32, move_count: u8) -> i32 {
-        unsafe { REDUCTIONS[depth as usize][move_count as usize] as i32 }
+    // A line this long can't be searched any further, so it gets the static eval. Positions in
+    // check don't have a meaningful one and are called a draw instead.
+    fn max_ply_score(&self) -> i16 {
+        if self.position.in_check() {
+            eval::DRAW
+        } else {
+            self.position.eval()
+        }
+    }
+
+    // Extend checks by one ply, and in pv nodes also recaptures and pawn pushes to the seventh.
+    // Lines are only extended up to twice the root depth so a long series of checks can't blow
+    // up the search. Called after the move has been made.
+    fn extension(&self, mv: Move, capture: bool, ply: usize, is_pv: bool) -> i32 {
+        if ply as i32 >= 2 * self.root_depth {
+            return 0;
+        }
+
+        let gives_check = self.position.in_check();
+        if gives_check || !is_pv {
+            return gives_check as i32;
+        }
+
+        let recapture = capture && ply > 0 && {
+            let previous = self.stack[ply - 1].current_move;
+            previous != Move::NULL && previous != Move::NONE && previous.to() == mv.to()
+        };
+        // the side to move has already flipped
+        let seventh = match self.position.side {
+            Color::White => Rank::R2,
+            Color::Black => Rank::R7,
+        };
+        let pawn_push = self.stack[ply].moved.is_some_and(|p| p.role == Role::Pawn)
+            && mv.to().rank() == seventh;
+
+        (recapture || pawn_push) as i32
     }
 
-    pub fn done_thinking(&self) -> bool {
+    // Singular extensions: when every move but the tt move fails low against a margin below the
+    // tt score at reduced depth, the tt move is the only good one and gets an extra ply, or two
+    // if the others fall well short. Call it before the tt move is made.
+    fn singular_extension(
+        &mut self,
+        entry: Entry,
+        depth: i32,
+        ply: usize,
+        is_pv: bool,
+        cut_node: bool,
+    ) -> i32 {
+        if depth < 8
+            || ply as i32 >= 2 * self.root_depth
+            || (entry.depth as i32) < depth - 3
+            || !matches!(entry.score_type, EntryType::LowerBound | EntryType::Exact)
+            || eval::is_mate_score(entry.score)
+        {
+            return 0;
+        }
+
+        let singular_beta = entry.score - 2 * depth as i16;
+        self.stack[ply].excluded = entry.best_move;
+        let score = self.search(
+            (depth - 1) / 2,
+            singular_beta - 1,
+            singular_beta,
+            ply,
+            false,
+            cut_node,
+        );
+        self.stack[ply].excluded = Move::NONE;
+
+        if score >= singular_beta {
+            return 0;
+        }
+        if !is_pv
+            && score < singular_beta - DOUBLE_EXTENSION_MARGIN
+            && self.stack[ply].double_extensions < MAX_DOUBLE_EXTENSIONS
+        {
+            return 2;
+        }
+        1
+    }
+
+    fn make_move(&mut self, mv: Move, ply: usize) {
+        self.stack[ply].current_move = mv;
+        self.stack[ply].moved = self.position.piece_at(mv.from());
+        self.position.make_move(mv);
+    }
+
+    fn unmake_move(&mut self, mv: Move, ply: usize) {
+        self.position.unmake_move(mv);
+        self.stack[ply].current_move = Move::NONE;
+        self.stack[ply].moved = None;
+    }
+
+    pub fn update_killers(&mut self, mv: Move, ply: usize) {
+        self.thread.update_killers(mv, ply);
+    }
+
+    fn update_history(&mut self, mv: Move, bonus: i16) {
+        self.thread.update_history(&self.position, mv, bonus);
+    }
+
+    pub fn done_thinking(&mut self) -> bool {
+        if self.limits.pondering && !self.ponder.load(std::sync::atomic::Ordering::Relaxed) {
+            self.limits.on_ponderhit(&mut self.start_time);
+            self.start_watchdog();
+        }
+
         if self.stop.load(std::sync::atomic::Ordering::Relaxed)
-            || self.limits.nodes.is_some_and(|n| self.nodes >= n)
+            || self.limits.nodes.is_some_and(|n| self.total_nodes() >= n)
         {
             return true;
         }
 
-        if self.nodes % 2048 == 0 && self.limits.time_up(self.start_time) {
-            self.stop.store(true, std::sync::atomic::Ordering::Relaxed);
-            return true;
+        if self.nodes % 2048 == 0 {
+            if self.limits.time_up(self.start_time) {
+                self.stop.store(true, std::sync::atomic::Ordering::Relaxed);
+                return true;
+            }
+            self.periodic_info();
+            self.merge_history();
         }
 
         false
     }
 
+    fn merge_history(&mut self) {
+        let Some(shared) = &self.shared_history else {
+            return;
+        };
+        if self.nodes - self.last_merge >= HISTORY_MERGE_NODES {
+            shared.merge(&mut self.thread.history);
+            self.last_merge = self.nodes;
+            self.stats.history_merges += 1;
+        }
+    }
+
+    // Iterations can take long at high depth, so progress and table usage are also reported
+    // every INFO_INTERVAL in between them
+    fn periodic_info(&mut self) {
+        if self.silent || self.last_info.elapsed() < INFO_INTERVAL {
+            return;
+        }
+        self.last_info = Instant::now();
+
+        let elapsed = self.start_time.elapsed();
+        let nodes = self.total_nodes();
+        let hashfull = self.tt.hashfull();
+        uci_println!(
+            "info depth {} seldepth {} time {} nodes {} nps {} hashfull {}",
+            self.root_depth,
+            self.seldepth,
+            elapsed.as_millis(),
+            nodes,
+            (nodes as u128 * 1000) / (elapsed.as_millis() + 1),
+            hashfull
+        );
+
+        if hashfull >= HASHFULL_WARNING
+            && elapsed >= HASHFULL_WARNING_AFTER
+            && !HASHFULL_WARNED.swap(true, std::sync::atomic::Ordering::Relaxed)
+        {
+            uci_println!(
+                "info string hash is {:.0}% full, consider a larger Hash",
+                hashfull / 10.
+            );
+        }
+    }
+
     pub fn set_silent(&mut self, silent: bool) {
         self.silent = silent;
     }
 
+    pub fn set_debug(&mut self, debug: bool) {
+        self.debug = debug;
+    }
+
+    // pv move first, then by what the moves scored and how much effort they took last time
+    fn sort_root_moves(&mut self, pv_move: Move) {
+        self.root_moves.sort_by_key(|root_move| {
+            (
+                root_move.mv != pv_move,
+                std::cmp::Reverse(root_move.score),
+                std::cmp::Reverse(root_move.nodes),
+            )
+        });
+    }
+
+    // Root moves ordered by their scores from the last iteration. Only the best move's score is
+    // exact, the rest are upper bounds from failing low against it.
+    pub fn root_move_scores(&self) -> Vec<(Move, i16)> {
+        let mut scores = self
+            .root_moves
+            .iter()
+            .map(|root_move| (root_move.mv, root_move.score))
+            .collect::<Vec<_>>();
+        scores.sort_by_key(|&(_, score)| std::cmp::Reverse(score));
+        scores
+    }
+
+    // dump how the nodes of the last iteration were split between the root moves
+    fn effort_info(&self) {
+        if self.silent {
+            return;
+        }
+
+        let mut efforts = self
+            .root_moves
+            .iter()
+            .map(|root_move| (root_move.mv, root_move.nodes))
+            .collect::<Vec<(Move, u64)>>();
+        efforts.sort_by_key(|(_, nodes)| std::cmp::Reverse(*nodes));
+
+        let total = efforts.iter().map(|(_, nodes)| nodes).sum::<u64>().max(1);
+        let efforts = efforts
+            .iter()
+            .map(|(mv, nodes)| format!("{} {:.1}%", mv, *nodes as f64 * 100. / total as f64))
+            .collect::<Vec<String>>()
+            .join(" ");
+        uci_println!("info string effort {}", efforts);
+    }
+
     fn uci_info(&self, depth: i32, score: i16) {
         if self.silent {
             return;
         }
 
         let elapsed = self.start_time.elapsed().as_millis() + 1;
-        let nps = (self.nodes as u128 * 1000) / elapsed;
-        let pv = (0..self.pv_length[0])
-            .map(|i| self.pv[0][i as usize].to_string())
+        let nodes = self.total_nodes();
+        let nps = (nodes as u128 * 1000) / elapsed;
+        let pv = self
+            .pv
+            .line()
+            .iter()
+            .map(|mv| mv.to_string())
             .collect::<Vec<String>>()
             .join(" ");
-        if score.abs() > eval::MATE - MAX_PLY as i16 {
-            let ply = score.signum() * (eval::MATE - score.abs()) / 2;
+        uci_println!(
+            "info depth {} seldepth {} score {} time {} nodes {} nps {} hashfull {} pv {}",
+            depth,
+            self.seldepth,
+            Score(score).to_uci_string(),
+            elapsed,
+            nodes,
+            nps,
+            self.tt.hashfull(),
+            pv
+        );
+    }
+}
 
-            println!(
-                "info depth {} score mate {} time {} nodes {} nps {} hashfull {} pv {}",
-                depth,
-                ply,
-                elapsed,
-                self.nodes,
-                nps,
-                self.tt.hashfull(),
-                pv
-            );
-        } else {
-            println!(
-                "info depth {} score cp {} time {} nodes {} nps {}, hashfull {} pv {}",
-                depth,
-                score,
-                elapsed,
-                self.nodes,
-                nps,
-                self.tt.hashfull(),
-                pv
-            );
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{fen::Fen, movegen::init_tables, movepicker::history_bucket, zobrist::init_zobrist};
+
+    fn cop(wtime: i32, winc: Option<u32>, movestogo: Option<u32>) -> SearchCop {
+        SearchCop::new(
+            Limits {
+                wtime: Some(wtime),
+                winc,
+                movestogo,
+                ..Limits::new()
+            },
+            Color::White,
+        )
+    }
+
+    #[test]
+    fn test_node_clock() {
+        let mut cop = cop(10_000, None, Some(1));
+        let max = cop.max_time.unwrap().as_millis() as u64;
+        let opt = cop.optimal_time.unwrap().as_millis() as u64;
+        cop.use_node_clock(500);
+        assert_eq!(cop.nodes, Some(max * 500));
+        assert_eq!(cop.soft_nodes, Some(opt * 500));
+        assert!(cop.max_time.is_none() && !cop.adjust);
+    }
+
+    #[test]
+    fn test_time_edge_cases() {
+        for (wtime, winc, movestogo) in [
+            (60_000, None, None),
+            (60_000, Some(1000), Some(1)),
+            (1000, None, Some(1)),
+            (500, Some(100), None),
+            (25, None, None),
+            (3, None, Some(1)),
+            (0, None, None),
+            (-100, Some(50), Some(0)),
+        ] {
+            let cop = cop(wtime, winc, movestogo);
+            let (opt, max) = (cop.optimal_time.unwrap(), cop.max_time.unwrap());
+            assert!(opt > Duration::ZERO && opt <= max, "{} {:?} {:?}", wtime, opt, max);
+            if wtime > 30 {
+                assert!(max.as_millis() as i32 <= wtime - 30, "{} {:?}", wtime, max);
+            }
+        }
+    }
+
+    #[test]
+    fn test_pv_table() {
+        let moves = ["e2e4", "e7e5", "g1f3"].map(|mv| mv.parse::<Move>().unwrap());
+        let mut pv = PvTable::new();
+
+        // entering each node clears its line, then they're filled in while unwinding
+        for ply in 0..=3 {
+            pv.clear(ply);
+        }
+        for ply in (0..3).rev() {
+            pv.update(ply, moves[ply]);
+        }
+        assert_eq!(pv.line(), &moves);
+        assert_eq!(pv.best_move(), moves[0]);
+
+        // the last row still has room for its one move
+        pv.clear(MAX_PLY);
+        pv.update(MAX_PLY - 1, moves[0]);
+        assert_eq!(pv.length[MAX_PLY - 1], MAX_PLY);
+    }
+
+    #[test]
+    fn test_movestogo_one() {
+        let cop = cop(10_000, None, Some(1));
+        let max = cop.max_time.unwrap().as_millis();
+        assert!(max > 5000 && max <= 9970);
+    }
+
+    #[test]
+    fn test_low_on_time() {
+        let start = Instant::now();
+        assert!(!cop(60_000, None, None).low_on_time(start));
+        assert!(cop(200, None, None).low_on_time(start));
+
+        // a long budget mostly spent
+        let movetime = Limits {
+            movetime: Some(1000),
+            ..Limits::new()
+        };
+        let cop = SearchCop::new(movetime, Color::White);
+        assert!(!cop.low_on_time(start));
+        assert!(cop.low_on_time(start - Duration::from_millis(950)));
+
+        // no clock, no hurry
+        let cop = SearchCop::new(Limits::new(), Color::White);
+        assert!(!cop.low_on_time(start));
+    }
+
+    #[test]
+    fn test_move_overhead() {
+        let limits = Limits {
+            wtime: Some(10_000),
+            movestogo: Some(1),
+            ..Limits::new()
+        };
+        let max = |overhead| {
+            let cop = SearchCop::with_overhead(limits, Color::White, overhead);
+            cop.max_time.unwrap().as_millis()
+        };
+        assert_eq!(max(MOVE_OVERHEAD), cop(10_000, None, Some(1)).max_time.unwrap().as_millis());
+        assert!(max(1000) <= 7000);
+        assert!(max(1000) < max(MOVE_OVERHEAD));
+    }
+
+    #[test]
+    fn test_fail_low_stores_upper_bound() {
+        init_tables();
+        init_zobrist();
+        init_reductions();
+
+        let Fen(position) = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1"
+            .parse()
+            .unwrap();
+        let key = position.key;
+        let tt = Arc::new(Table::new_mb(1));
+        let stop = Arc::new(AtomicBool::new(false));
+        let mut search = Search::new(position, Limits::new(), tt.clone(), stop);
+
+        // nothing in the start position is worth a queen, so this has to fail low
+        let score = search.search(3, 900, 901, 1, false, false);
+        assert!(score <= 900);
+        let entry = tt.probe(key).unwrap();
+        assert!(matches!(entry.score_type, EntryType::UpperBound));
+    }
+
+    #[test]
+    fn test_singular_search_skips_tt() {
+        init_tables();
+        init_zobrist();
+        init_reductions();
+
+        let Fen(position) = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1"
+            .parse()
+            .unwrap();
+        let key = position.key;
+        let tt = Arc::new(Table::new_mb(1));
+        let stop = Arc::new(AtomicBool::new(false));
+        let mut search = Search::new(position, Limits::new(), tt.clone(), stop);
+
+        // the position without e2e4 isn't the position, nothing of it goes in the table
+        search.stack[1].excluded = "e2e4".parse().unwrap();
+        search.search(3, -100, 100, 1, false, false);
+        assert!(tt.probe(key).is_none());
+
+        search.stack[1].excluded = Move::NONE;
+        search.search(3, -100, 100, 1, false, false);
+        assert!(tt.probe(key).is_some());
+    }
+
+    #[test]
+    fn test_qsearch_entry_no_main_cutoff() {
+        init_tables();
+        init_zobrist();
+        init_reductions();
+
+        let Fen(position) = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1"
+            .parse()
+            .unwrap();
+        let key = position.key;
+        let tt = Arc::new(Table::new_mb(1));
+        let stop = Arc::new(AtomicBool::new(false));
+        let mut search = Search::new(position, Limits::new(), tt.clone(), stop);
+
+        tt.set(Entry::new(
+            key,
+            DEPTH_QS,
+            5000,
+            EntryType::Exact,
+            Move::NONE,
+        ));
+        assert_eq!(search.quiescence_search(-100, 100, 1, false), 5000);
+        assert!(search.search(1, -100, 100, 1, false, false) < 5000);
+    }
+
+    #[test]
+    fn test_tt_mate_distance() {
+        init_tables();
+        init_zobrist();
+        init_reductions();
+
+        // Ra8 mates
+        let Fen(position) = "6k1/5ppp/8/8/8/8/5PPP/R5K1 w - - 0 1".parse().unwrap();
+        let key = position.key;
+        let tt = Arc::new(Table::new_mb(1));
+        let stop = Arc::new(AtomicBool::new(false));
+        let mut search = Search::new(position, Limits::new(), tt.clone(), stop);
+        let (alpha, beta) = (-eval::INFINITY, eval::INFINITY);
+
+        assert_eq!(search.search(2, alpha, beta, 4, false, false), eval::mate_in(5));
+        assert_eq!(tt.probe(key).unwrap().score, eval::mate_in(1));
+        // the same mate found through the table closer to the root is closer to the root
+        assert_eq!(search.search(2, alpha, beta, 2, false, false), eval::mate_in(3));
+    }
+
+    #[test]
+    fn test_qsearch_in_check() {
+        init_tables();
+        init_zobrist();
+        init_reductions();
+
+        // two queens up, but mated on the back rank
+        let Fen(position) = "7Q/7Q/k7/8/8/8/5PPP/3r2K1 w - - 0 1".parse().unwrap();
+        let tt = Arc::new(Table::new_mb(1));
+        let stop = Arc::new(AtomicBool::new(false));
+        let mut search = Search::new(position, Limits::new(), tt, stop);
+        assert_eq!(search.quiescence_search(-100, 100, 3, false), eval::mated_in(3));
+
+        // with f2 gone the king walks out, a quiet move searching only captures would miss
+        let Fen(position) = "7Q/7Q/k7/8/8/8/6PP/3r2K1 w - - 0 1".parse().unwrap();
+        let tt = Arc::new(Table::new_mb(1));
+        let stop = Arc::new(AtomicBool::new(false));
+        let mut search = Search::new(position, Limits::new(), tt, stop);
+        let score = search.quiescence_search(-100, 100, 3, false);
+        assert!(score > -eval::MATE_IN_PLY);
+    }
+
+    #[test]
+    fn test_draw_score_jitter() {
+        init_tables();
+        init_zobrist();
+
+        let Fen(position) = "8/8/4k3/8/8/4K3/8/8 w - - 0 1".parse().unwrap();
+        let tt = Arc::new(Table::new_mb(1));
+        let mut search = Search::new(
+            position,
+            Limits::new(),
+            tt,
+            Arc::new(AtomicBool::new(false)),
+        );
+
+        let mut scores = (0..4)
+            .map(|nodes| {
+                search.nodes = nodes;
+                search.draw_score()
+            })
+            .collect::<Vec<_>>();
+        scores.sort();
+        scores.dedup();
+        assert_eq!(scores, [-1, 1]);
+        assert!(scores.iter().all(|&score| eval::normalize(score) == 0));
+    }
+
+    #[test]
+    fn test_stalemate_contempt() {
+        init_tables();
+        init_zobrist();
+        init_reductions();
+
+        // black has no moves and isn't in check
+        let Fen(position) = "k7/8/1Q6/8/8/8/8/7K b - - 0 1".parse().unwrap();
+        let search = |root_side| {
+            let tt = Arc::new(Table::new_mb(1));
+            let stop = Arc::new(AtomicBool::new(false));
+            let mut search = Search::new(position.clone(), Limits::new(), tt, stop);
+            search.set_silent(true);
+            search.set_contempt(20);
+            search.root_side = root_side;
+            search
+        };
+
+        assert_eq!(search(Color::Black).think().score, -20);
+        // reached in the search it's still worse than level for the side with contempt
+        assert_eq!(search(Color::White).search(1, -100, 100, 1, false, false), 20);
+        assert_eq!(search(Color::Black).search(1, -100, 100, 1, false, false), -20);
+    }
+
+    #[test]
+    fn test_excluded_moves() {
+        init_tables();
+        init_zobrist();
+        init_reductions();
+
+        // the queen hangs to the rook
+        let Fen(position) = "3qk3/8/8/8/8/8/8/3RK3 w - - 0 1".parse().unwrap();
+        let limits = Limits {
+            depth: Some(4),
+            ..Limits::new()
+        };
+        let rxd8 = "d1d8".parse::<Move>().unwrap();
+        let search = |excluded: &[Move]| {
+            let tt = Arc::new(Table::new_mb(1));
+            let stop = Arc::new(AtomicBool::new(false));
+            let mut search = Search::new(position.clone(), limits, tt, stop);
+            search.set_silent(true);
+            search.set_excluded_moves(excluded);
+            search.think()
+        };
+
+        assert_eq!(search(&[]).bestmove, rxd8);
+        let second = search(&[rxd8]).bestmove;
+        assert!(second != rxd8 && second != Move::NONE);
+
+        // excluding everything searches everything
+        let all = MoveGen::new(&position).collect::<Vec<_>>();
+        assert_eq!(search(&all).bestmove, rxd8);
+    }
+
+    #[test]
+    fn test_history_bonus_at_max_depth() {
+        init_tables();
+        init_zobrist();
+        init_reductions();
+
+        // the rook mates at once, the only root move left is a quiet cutoff at full depth
+        let Fen(position) = "6k1/5ppp/8/8/8/8/8/4R1K1 w - - 0 1".parse().unwrap();
+        let re8 = "e1e8".parse::<Move>().unwrap();
+        let tt = Arc::new(Table::new_mb(1));
+        let stop = Arc::new(AtomicBool::new(false));
+        let mut search = Search::new(position.clone(), Limits::new(), tt, stop);
+        search.root_moves = vec![RootMove {
+            mv: re8,
+            score: -eval::INFINITY,
+            nodes: 0,
+        }];
+
+        let score = search.search(MAX_DEPTH - 1, -100, 100, 0, true, false);
+        assert!(score >= 100);
+        let bucket = history_bucket(&position, re8);
+        assert_eq!(search.thread.history[Color::White][bucket][re8.to()], 2000);
+    }
+
+    #[test]
+    fn test_tree_dump() {
+        init_tables();
+        init_zobrist();
+        init_reductions();
+
+        let Fen(position) = "3qk3/8/8/8/8/8/8/3RK3 w - - 0 1".parse().unwrap();
+        let limits = Limits {
+            depth: Some(4),
+            ..Limits::new()
+        };
+        let tt = Arc::new(Table::new_mb(1));
+        let stop = Arc::new(AtomicBool::new(false));
+        let mut search = Search::new(position.clone(), limits, tt, stop);
+        search.set_silent(true);
+        search.set_tree_dump(1);
+        let result = search.think();
+
+        // only the last root search and the root moves under it
+        let nodes = search.tree().unwrap().nodes();
+        assert_eq!(nodes[0].mv, Move::NONE);
+        assert_eq!(nodes[0].depth, 4);
+        assert!(nodes[1..].iter().all(|node| node.level == 1));
+        assert!(nodes[1..].iter().all(|node| position.legal_move(node.mv).is_some()));
+        let best = nodes.iter().rev().find(|node| node.mv == result.bestmove).unwrap();
+        assert_eq!(best.score.map(|score| -score), nodes[0].score);
+    }
+
+    #[test]
+    fn test_quiets_reduced_more() {
+        for depth in 1..MAX_DEPTH {
+            for move_count in 1..MAX_MOVES as u8 {
+                assert!(reduction(depth, move_count, true) >= reduction(depth, move_count, false));
+            }
         }
+        assert!(reduction(12, 20, true) > reduction(12, 20, false));
+        assert_eq!(reduction(1, 1, false), 0);
     }
 }
@@ -0,0 +1,113 @@
+use std::sync::atomic::{
+    AtomicI16,
+    Ordering,
+};
+
+use crate::{
+    chess::{
+        Color,
+        Square,
+    },
+    movepicker::{
+        history_bucket,
+        History,
+        HISTORY_BUCKETS,
+    },
+    moves::Move,
+    position::Position,
+    search::MAX_PLY,
+};
+
+// What a search thread learns about move ordering as it goes. Every thread has its own, so
+// threads don't slow each other down writing it, and can share the history through a
+// `SharedHistory` if asked to.
+pub(crate) struct ThreadData {
+    // quiets that caused a beta cutoff at each ply, most recent first
+    pub killers: [[Move; 2]; MAX_PLY + 1],
+    pub history: History,
+}
+
+impl ThreadData {
+    pub fn new() -> Self {
+        ThreadData {
+            killers: [[Move::NONE; 2]; MAX_PLY + 1],
+            history: [[[0; Square::NUM]; HISTORY_BUCKETS]; Color::NUM],
+        }
+    }
+
+    pub fn update_killers(&mut self, mv: Move, ply: usize) {
+        let killers = &mut self.killers[ply];
+        killers[1] = killers[0];
+        killers[0] = mv;
+    }
+
+    // Call it before `mv` is made, the bonus shrinks as the entry nears its bound
+    pub fn update_history(&mut self, position: &Position, mv: Move, bonus: i16) {
+        let bucket = history_bucket(position, mv);
+        let entry = &mut self.history[position.side][bucket][mv.to()];
+        *entry += bonus - ((*entry as i32 * bonus.abs() as i32) / 16384) as i16;
+    }
+}
+
+// History pooled between threads. Each merge averages a thread's history with the pool and
+// hands the result back, so what every thread found counts and older merges age out by half
+// each time. Entries are averaged in place atomically, so merges racing on one don't lose
+// each other's updates.
+pub struct SharedHistory {
+    table: [[[AtomicI16; Square::NUM]; HISTORY_BUCKETS]; Color::NUM],
+}
+
+impl SharedHistory {
+    pub fn new() -> Self {
+        SharedHistory {
+            table: std::array::from_fn(|_| {
+                std::array::from_fn(|_| std::array::from_fn(|_| AtomicI16::new(0)))
+            }),
+        }
+    }
+
+    pub(crate) fn merge(&self, history: &mut History) {
+        let shared = self.table.iter().flatten().flatten();
+        let local = history.iter_mut().flatten().flatten();
+        let average = |a: i16, b: i16| ((a as i32 + b as i32) / 2) as i16;
+        for (shared, local) in shared.zip(local) {
+            let old = shared
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |old| {
+                    Some(average(old, *local))
+                })
+                .unwrap();
+            *local = average(old, *local);
+        }
+    }
+}
+
+impl Default for SharedHistory {
+    fn default() -> Self {
+        SharedHistory::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_merge() {
+        let shared = SharedHistory::new();
+        let mut first = ThreadData::new();
+        let mut second = ThreadData::new();
+        first.history[0][1][2] = 1000;
+        second.history[0][1][2] = -200;
+        second.history[1][0][5] = 300;
+
+        shared.merge(&mut first.history);
+        assert_eq!(first.history[0][1][2], 500);
+        // the second thread gets half of what the first one found, aged once
+        shared.merge(&mut second.history);
+        assert_eq!(second.history[0][1][2], 150);
+        assert_eq!(second.history[1][0][5], 150);
+        shared.merge(&mut first.history);
+        assert_eq!(first.history[0][1][2], 325);
+        assert_eq!(first.history[1][0][5], 75);
+    }
+}
@@ -0,0 +1,255 @@
+use std::{
+    fmt::{
+        self,
+        Display,
+        Formatter,
+    },
+    io::Write,
+    path::PathBuf,
+};
+
+use crate::moves::Move;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bound {
+    Exact,
+    Lower,
+    Upper,
+}
+
+impl Display for Bound {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Bound::Exact => write!(f, "exact"),
+            Bound::Lower => write!(f, "lower"),
+            Bound::Upper => write!(f, "upper"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TreeNode {
+    // how many nodes up the root is, a re-search of the same position nests one deeper
+    pub level: usize,
+    // the move into the node, NONE at the root and NULL after a null move
+    pub mv: Move,
+    pub depth: i32,
+    pub alpha: i16,
+    pub beta: i16,
+    // none for moves pruned before they were searched
+    pub score: Option<i16>,
+    pub bound: Option<Bound>,
+    // why the node returned early, if it did
+    pub reason: Option<&'static str>,
+}
+
+// The nodes of the last root search up to `max_ply`, in the order they were entered. Only kept
+// when dumping, see `Search::set_tree_dump`.
+#[derive(Debug, Clone, Default)]
+pub struct SearchTree {
+    pub max_ply: usize,
+    nodes: Vec<TreeNode>,
+    // the nodes entered but not returned from yet
+    open: Vec<usize>,
+}
+
+impl SearchTree {
+    pub fn new(max_ply: usize) -> Self {
+        SearchTree {
+            max_ply,
+            ..Default::default()
+        }
+    }
+
+    pub fn nodes(&self) -> &[TreeNode] {
+        &self.nodes
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.nodes.clear();
+        self.open.clear();
+    }
+
+    pub(crate) fn enter(&mut self, mv: Move, depth: i32, alpha: i16, beta: i16) -> usize {
+        self.nodes.push(TreeNode {
+            level: self.open.len(),
+            mv,
+            depth,
+            alpha,
+            beta,
+            score: None,
+            bound: None,
+            reason: None,
+        });
+        self.open.push(self.nodes.len() - 1);
+        self.nodes.len() - 1
+    }
+
+    pub(crate) fn exit(&mut self, index: usize, score: i16, reason: Option<&'static str>) {
+        self.open.pop();
+        let node = &mut self.nodes[index];
+        node.score = Some(score);
+        node.bound = Some(if score <= node.alpha {
+            Bound::Upper
+        } else if score >= node.beta {
+            Bound::Lower
+        } else {
+            Bound::Exact
+        });
+        node.reason = reason;
+    }
+
+    // A move of the current node that was skipped without being searched
+    pub(crate) fn pruned(&mut self, mv: Move, depth: i32, reason: &'static str) {
+        self.nodes.push(TreeNode {
+            level: self.open.len(),
+            mv,
+            depth,
+            alpha: 0,
+            beta: 0,
+            score: None,
+            bound: None,
+            reason: Some(reason),
+        });
+    }
+
+    // Nested objects with their children under "children"
+    pub fn to_json(&self) -> String {
+        let mut out = String::new();
+        let mut index = 0;
+        out.push('[');
+        while index < self.nodes.len() {
+            if index > 0 {
+                out.push_str(", ");
+            }
+            index = self.node_json(index, &mut out);
+        }
+        out.push(']');
+        out
+    }
+
+    // Writes the node at `index` and everything under it, returning the index after them
+    fn node_json(&self, index: usize, out: &mut String) -> usize {
+        let node = &self.nodes[index];
+        out.push_str(&format!(
+            "{{\"move\": \"{}\", \"depth\": {}",
+            move_name(node.mv),
+            node.depth
+        ));
+        if let (Some(score), Some(bound)) = (node.score, node.bound) {
+            out.push_str(&format!(
+                ", \"alpha\": {}, \"beta\": {}, \"score\": {}, \"bound\": \"{}\"",
+                node.alpha, node.beta, score, bound
+            ));
+        }
+        if let Some(reason) = node.reason {
+            out.push_str(&format!(", \"reason\": \"{}\"", reason));
+        }
+
+        out.push_str(", \"children\": [");
+        let mut next = index + 1;
+        while next < self.nodes.len() && self.nodes[next].level > node.level {
+            if next > index + 1 {
+                out.push_str(", ");
+            }
+            next = self.node_json(next, out);
+        }
+        out.push_str("]}");
+        next
+    }
+}
+
+impl Display for SearchTree {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        for node in &self.nodes {
+            write!(
+                f,
+                "{:indent$}{} d={}",
+                "",
+                move_name(node.mv),
+                node.depth,
+                indent = 2 * node.level
+            )?;
+            if let (Some(score), Some(bound)) = (node.score, node.bound) {
+                write!(f, " [{}, {}] {} {}", node.alpha, node.beta, score, bound)?;
+            }
+            if let Some(reason) = node.reason {
+                write!(f, " ({})", reason)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+fn move_name(mv: Move) -> String {
+    match mv {
+        Move::NONE => "root".to_string(),
+        Move::NULL => "null".to_string(),
+        mv => mv.to_string(),
+    }
+}
+
+// Where and how a tree dump is written
+#[derive(Debug, Clone)]
+pub struct TreeDump {
+    pub plies: usize,
+    pub json: bool,
+    // stdout when there's no file
+    pub path: Option<PathBuf>,
+}
+
+impl TreeDump {
+    pub fn write(&self, tree: &SearchTree) -> std::io::Result<()> {
+        let dump = match self.json {
+            true => tree.to_json() + "\n",
+            false => tree.to_string(),
+        };
+        match &self.path {
+            Some(path) => std::fs::write(path, dump),
+            None => {
+                let mut out = crate::util::output();
+                out.write_all(dump.as_bytes())?;
+                out.flush()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_tree_formats() {
+        let e2e4 = "e2e4".parse::<Move>().unwrap();
+        let e7e5 = "e7e5".parse::<Move>().unwrap();
+        let d7d5 = "d7d5".parse::<Move>().unwrap();
+
+        let mut tree = SearchTree::new(2);
+        let root = tree.enter(Move::NONE, 3, -100, 100);
+        let child = tree.enter(e2e4, 2, -100, 100);
+        let grandchild = tree.enter(e7e5, 1, -100, 100);
+        tree.exit(grandchild, -30, Some("quiescence"));
+        tree.pruned(d7d5, 1, "history pruning");
+        tree.exit(child, 30, None);
+        tree.exit(root, 30, None);
+
+        assert_eq!(
+            tree.to_string(),
+            "root d=3 [-100, 100] 30 exact\n  e2e4 d=2 [-100, 100] 30 exact\n    e7e5 d=1 \
+             [-100, 100] -30 exact (quiescence)\n    d7d5 d=1 (history pruning)\n"
+        );
+        let json = tree.to_json();
+        assert!(json.starts_with("[{\"move\": \"root\", \"depth\": 3, \"alpha\": -100"));
+        assert!(json.contains("{\"move\": \"d7d5\", \"depth\": 1, \"reason\": \"history pruning\""));
+        assert!(json.ends_with("\"children\": []}]}]}]"));
+
+        // a new root search starts over
+        tree.clear();
+        let root = tree.enter(Move::NONE, 4, -100, 100);
+        tree.exit(root, 150, Some("tt cutoff"));
+        assert_eq!(tree.nodes().len(), 1);
+        assert_eq!(tree.nodes()[0].bound, Some(Bound::Lower));
+    }
+}
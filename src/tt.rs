@@ -1,10 +1,45 @@
-use std::sync::Mutex;
+use std::{
+    collections::TryReserveError,
+    sync::atomic::{
+        AtomicU64,
+        AtomicU8,
+        Ordering,
+    },
+};
 
 use crate::{
+    eval,
     moves::Move,
     zobrist::ZobristHash,
 };
 
+// Depth stored by quiescence search. It's below any main search depth, so quiescence results
+// only ever cut off other quiescence nodes.
+pub const DEPTH_QS: i8 = -1;
+
+// Mate scores count plies from the root, but the table holds them as plies from the node that
+// stored them so they still hold when the position comes up again at another ply
+pub fn score_to_tt(score: i16, ply: usize) -> i16 {
+    if score >= eval::MATE_IN_PLY {
+        score + ply as i16
+    } else if score <= -eval::MATE_IN_PLY {
+        score - ply as i16
+    } else {
+        score
+    }
+}
+
+// The other way, for a probe `ply` plies from the root
+pub fn score_from_tt(score: i16, ply: usize) -> i16 {
+    if score >= eval::MATE_IN_PLY {
+        score - ply as i16
+    } else if score <= -eval::MATE_IN_PLY {
+        score + ply as i16
+    } else {
+        score
+    }
+}
+
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 #[repr(u8)]
 pub enum EntryType {
@@ -18,16 +53,18 @@ pub enum EntryType {
 #[repr(C)]
 pub struct Entry {
     pub key: ZobristHash,
-    pub depth: u8,
+    pub depth: i8,
     pub score: i16,
     pub score_type: EntryType,
     pub best_move: Move,
+    // the search that stored it, filled in by `Table::set`
+    pub generation: u8,
 }
 
 impl Entry {
     pub fn new(
         key: ZobristHash,
-        depth: u8,
+        depth: i8,
         score: i16,
         score_type: EntryType,
         best_move: Move,
@@ -38,6 +75,41 @@ impl Entry {
             score,
             score_type,
             best_move,
+            generation: 0,
+        }
+    }
+}
+
+impl EntryType {
+    fn from_bits(bits: u8) -> EntryType {
+        match bits {
+            1 => EntryType::Exact,
+            2 => EntryType::LowerBound,
+            3 => EntryType::UpperBound,
+            _ => EntryType::None,
+        }
+    }
+}
+
+impl Entry {
+    // Everything but the key in one word: depth, score, type, move and generation from the low
+    // byte up
+    fn data(&self) -> u64 {
+        self.depth as u8 as u64
+            | (self.score as u16 as u64) << 8
+            | (self.score_type as u64) << 24
+            | (u16::from(self.best_move) as u64) << 32
+            | (self.generation as u64) << 48
+    }
+
+    fn from_data(key: ZobristHash, data: u64) -> Entry {
+        Entry {
+            key,
+            depth: data as u8 as i8,
+            score: (data >> 8) as u16 as i16,
+            score_type: EntryType::from_bits((data >> 24) as u8),
+            best_move: ((data >> 32) as u16).into(),
+            generation: (data >> 48) as u8,
         }
     }
 }
@@ -50,59 +122,253 @@ impl Default for Entry {
             score: 0,
             score_type: EntryType::None,
             best_move: Move::NONE,
+            generation: 0,
         }
     }
 }
 
+// One entry as two words that are read and written without a lock. The key is stored xored
+// with the data, so when two threads write a slot at once and it ends up with one's key and the
+// other's data, the key check on probe fails instead of returning the wrong position's entry.
+#[derive(Default)]
+struct Slot {
+    key: AtomicU64,
+    data: AtomicU64,
+}
+
+impl Slot {
+    fn load(&self) -> (u64, u64) {
+        (
+            self.key.load(Ordering::Relaxed),
+            self.data.load(Ordering::Relaxed),
+        )
+    }
+
+    fn store(&self, key: u64, data: u64) {
+        self.key.store(key ^ data, Ordering::Relaxed);
+        self.data.store(data, Ordering::Relaxed);
+    }
+}
+
+const BUCKET_SIZE: usize = 4;
+
+// The slots a key can go in, one cache line so a probe only touches one
+#[derive(Default)]
+#[repr(align(64))]
+struct Bucket {
+    slots: [Slot; BUCKET_SIZE],
+}
+
 pub struct Table {
-    entries: Mutex<Vec<Entry>>,
+    buckets: Vec<Bucket>,
     max_size: usize,
+    generation: AtomicU8,
 }
 
 impl Table {
+    // Room for at least one bucket, `size` entries rounded down to whole buckets
     pub fn new(size: usize) -> Table {
+        let size = (size / BUCKET_SIZE).max(1);
+        let mut buckets = Vec::with_capacity(size);
+        buckets.resize_with(size, Bucket::default);
         Table {
-            entries: Mutex::new(vec![Entry::default(); size]),
+            buckets,
             max_size: size,
+            generation: AtomicU8::new(0),
         }
     }
 
     pub fn new_mb(size_mb: usize) -> Table {
-        Table::new(size_mb * 1024 * 1024 / std::mem::size_of::<Entry>())
+        Table::new(Table::entries_for_mb(size_mb))
+    }
+
+    // Like `new_mb`, but a failed allocation comes back as an error instead of aborting, so a
+    // resize can keep the old table
+    pub fn try_new_mb(size_mb: usize) -> Result<Table, TryReserveError> {
+        let size = (Table::entries_for_mb(size_mb) / BUCKET_SIZE).max(1);
+        let mut buckets = Vec::new();
+        buckets.try_reserve_exact(size)?;
+        buckets.resize_with(size, Bucket::default);
+        Ok(Table {
+            buckets,
+            max_size: size,
+            generation: AtomicU8::new(0),
+        })
     }
 
+    fn entries_for_mb(size_mb: usize) -> usize {
+        size_mb * 1024 * 1024 / std::mem::size_of::<Slot>()
+    }
+
+    // Not synchronized with searches using the table, clear it between them
     pub fn clear(&self) {
-        self.entries.lock().unwrap().iter_mut().for_each(|entry| {
-            *entry = Entry::default();
-        });
+        for slot in self.buckets.iter().flat_map(|bucket| &bucket.slots) {
+            slot.store(0, 0);
+        }
     }
 
-    fn index(&self, key: ZobristHash) -> usize {
-        usize::from(key) % self.max_size
+    fn bucket(&self, key: ZobristHash) -> &Bucket {
+        &self.buckets[usize::from(key) % self.max_size]
     }
 
     pub fn probe(&self, key: ZobristHash) -> Option<Entry> {
-        let idx = self.index(key);
-        let entry = &self.entries.lock().unwrap()[idx];
-        match entry.key == key {
-            true => Some(*entry),
-            false => None,
-        }
+        self.bucket(key).slots.iter().find_map(|slot| {
+            let (stored, data) = slot.load();
+            match stored ^ data == u64::from(key) {
+                true => Some(Entry::from_data(key, data)),
+                false => None,
+            }
+        })
     }
 
-    pub fn set(&self, entry: Entry) {
-        let idx = self.index(entry.key);
-        self.entries.lock().unwrap()[idx] = entry;
+    // Starts a new generation, call it before every search. Entries from earlier ones give way
+    // to anything the new search stores.
+    pub fn new_search(&self) {
+        self.generation.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Goes in the bucket's slot for the same position if there is one, otherwise it takes the
+    // place of the least useful entry: an empty one, then one from an older search, then the
+    // shallowest. That one is only replaced if it's from an older search or no deeper than the
+    // new entry, otherwise a bucket of deep entries from this search would be lost to the
+    // quiescence stores below them.
+    pub fn set(&self, mut entry: Entry) {
+        entry.generation = self.generation.load(Ordering::Relaxed);
+        let entries = self.bucket(entry.key).slots.iter().map(|slot| {
+            let (stored, data) = slot.load();
+            (slot, Entry::from_data(ZobristHash::from(stored ^ data), data))
+        });
+
+        let (slot, old) = match entries.clone().find(|(_, old)| old.key == entry.key) {
+            Some(same) => same,
+            None => entries
+                .min_by_key(|(_, old)| {
+                    (
+                        old.score_type != EntryType::None,
+                        old.generation == entry.generation,
+                        old.depth,
+                    )
+                })
+                .unwrap(),
+        };
+
+        let same_position = old.key == entry.key;
+        let replace = old.score_type == EntryType::None
+            || old.generation != entry.generation
+            || same_position
+            || entry.depth >= old.depth;
+        if !replace {
+            return;
+        }
+        // a store without a move, like a fail low, keeps the move found before
+        if same_position && entry.best_move == Move::NONE {
+            entry.best_move = old.best_move;
+        }
+        slot.store(u64::from(entry.key), entry.data());
     }
 
     pub fn hashfull(&self) -> f64 {
-        self.entries.lock().unwrap()[..1000]
+        self.buckets
             .iter()
-            .filter(|entry| entry.score_type != EntryType::None)
+            .flat_map(|bucket| &bucket.slots)
+            .take(1000)
+            .filter(|slot| {
+                let (_, data) = slot.load();
+                Entry::from_data(ZobristHash::new(), data).score_type != EntryType::None
+            })
             .count() as f64
     }
 
+    pub fn num_entries(&self) -> usize {
+        self.max_size * BUCKET_SIZE
+    }
+
     pub fn size_mb(&self) -> usize {
-        self.max_size * std::mem::size_of::<Entry>() / 1024 / 1024
+        self.max_size * std::mem::size_of::<Bucket>() / 1024 / 1024
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_entry_roundtrip() {
+        let table = Table::new(1024);
+        let key = ZobristHash::from(0x1234_5678_9abc_def0);
+        let mv = "e7e8q".parse::<Move>().unwrap();
+        assert!(table.probe(key).is_none());
+
+        for (depth, score) in [(DEPTH_QS, -32_000), (0, 0), (127, 31_999), (12, -5)] {
+            table.set(Entry::new(key, depth, score, EntryType::LowerBound, mv));
+            let entry = table.probe(key).unwrap();
+            assert_eq!(
+                (entry.key, entry.depth, entry.score, entry.score_type, entry.best_move),
+                (key, depth, score, EntryType::LowerBound, mv)
+            );
+        }
+
+        // a different key in the same bucket misses
+        let other = ZobristHash::from(u64::from(key) + 1024);
+        assert!(table.probe(other).is_none());
+
+        // and so does a torn write, one key with another's data
+        let slot = &table.bucket(key).slots[0];
+        let torn = Entry::new(other, 3, 100, EntryType::Exact, Move::NONE);
+        slot.data.store(torn.data(), Ordering::Relaxed);
+        assert!(table.probe(key).is_none());
+        assert!(table.probe(other).is_none());
+
+        table.clear();
+        assert!(table.probe(key).is_none());
+        assert_eq!(table.hashfull(), 0.0);
+    }
+
+    #[test]
+    fn test_mate_score_plies() {
+        // mate in 3 from a node 4 plies in is mate in 7 from the root
+        assert_eq!(score_to_tt(eval::mate_in(7), 4), eval::mate_in(3));
+        assert_eq!(score_from_tt(eval::mate_in(3), 4), eval::mate_in(7));
+        assert_eq!(score_to_tt(eval::mated_in(6), 2), eval::mated_in(4));
+        assert_eq!(score_from_tt(eval::mated_in(4), 10), eval::mated_in(14));
+        assert_eq!(score_to_tt(eval::MAX_EVAL, 9), eval::MAX_EVAL);
+        assert_eq!(score_from_tt(-150, 9), -150);
+    }
+
+    #[test]
+    fn test_replacement() {
+        // 256 buckets, so these all share one
+        let table = Table::new(1024);
+        let keys = (0..=BUCKET_SIZE as u64)
+            .map(|i| ZobristHash::from(7 + 256 * i))
+            .collect::<Vec<_>>();
+        let mv = "e2e4".parse::<Move>().unwrap();
+
+        for (depth, &key) in keys[..BUCKET_SIZE].iter().enumerate() {
+            table.set(Entry::new(key, 10 + depth as i8, 50, EntryType::Exact, mv));
+        }
+        assert!(keys[..BUCKET_SIZE].iter().all(|&key| table.probe(key).is_some()));
+
+        // a full bucket from this search keeps its deeper entries
+        let shallow = keys[BUCKET_SIZE];
+        table.set(Entry::new(shallow, DEPTH_QS, 20, EntryType::Exact, Move::NONE));
+        assert!(table.probe(shallow).is_none());
+
+        // the same position is always updated, keeping its move
+        table.set(Entry::new(keys[2], 3, -10, EntryType::UpperBound, Move::NONE));
+        let entry = table.probe(keys[2]).unwrap();
+        assert_eq!((entry.depth, entry.best_move), (3, mv));
+
+        // which makes it the shallowest, the one to go for anything at least as deep
+        table.set(Entry::new(shallow, 3, 20, EntryType::Exact, Move::NONE));
+        assert!(table.probe(keys[2]).is_none());
+        assert!(table.probe(shallow).is_some());
+
+        // and in a new search the old entries give way, shallowest first
+        table.new_search();
+        table.set(Entry::new(keys[2], DEPTH_QS, 20, EntryType::Exact, Move::NONE));
+        assert!(table.probe(shallow).is_none());
+        assert_eq!(table.probe(keys[2]).unwrap().generation, 1);
+        assert_eq!(table.probe(keys[0]).unwrap().depth, 10);
     }
 }
@@ -1,10 +1,15 @@
 use std::{
+    any::Any,
     borrow::Borrow,
     collections::HashMap,
     fmt::Display,
+    io::Write,
     ops::ControlFlow,
-    sync::{atomic::AtomicBool, Arc},
+    panic::{self, AssertUnwindSafe},
+    path::Path,
+    sync::{atomic::AtomicBool, Arc, Mutex},
     thread,
+    time::{Duration, Instant},
 };
 
 use anyhow::{anyhow, Context, Result};
@@ -12,14 +17,20 @@ use rustyline::{error::ReadlineError, DefaultEditor};
 
 use crate::{
     bench::bench,
+    book::Book,
+    chess::Color,
+    eval::Score,
     fen::Fen,
-    limits::Limits,
-    movegen::{perft, MoveGen},
+    limits::{Limits, OverheadTracker},
+    movegen::{perft_divide, split_perft, MoveGen},
     moves::Move,
+    opponent::{ContemptConfig, Opponent},
+    pgn::parse_san,
     position::Position,
-    search::Search,
+    positions,
+    search::{smp_search, SearchCop, SearchOptions, TreeDump, MOVE_OVERHEAD},
     tt::Table,
-    util::engine_name,
+    util::{engine_name, output, uci_println},
 };
 
 #[derive(Debug, Clone, Copy)]
@@ -30,6 +41,32 @@ pub enum UciOption {
         min: i32,
         max: i32,
     },
+    Check {
+        name: &'static str,
+        default: bool,
+    },
+    String {
+        name: &'static str,
+        default: &'static str,
+    },
+}
+
+impl UciOption {
+    pub fn name(&self) -> &'static str {
+        match self {
+            UciOption::Spin { name, .. }
+            | UciOption::Check { name, .. }
+            | UciOption::String { name, .. } => name,
+        }
+    }
+
+    pub fn default_value(&self) -> String {
+        match self {
+            UciOption::Spin { default, .. } => default.to_string(),
+            UciOption::Check { default, .. } => default.to_string(),
+            UciOption::String { default, .. } => default.to_string(),
+        }
+    }
 }
 
 impl Display for UciOption {
@@ -47,6 +84,13 @@ impl Display for UciOption {
                     name, default, min, max
                 )
             }
+            UciOption::Check { name, default } => {
+                write!(f, "option name {} type check default {}", name, default)
+            }
+            UciOption::String { name, default } => {
+                let default = if default.is_empty() { "<empty>" } else { default };
+                write!(f, "option name {} type string default {}", name, default)
+            }
         }
     }
 }
@@ -65,11 +109,19 @@ impl UciOptionSet {
             UciOption::Spin { name, default, .. } => {
                 self.values.insert(name.to_string(), default.to_string());
             }
+            UciOption::Check { name, default } => {
+                self.values.insert(name.to_string(), default.to_string());
+            }
+            UciOption::String { name, default } => {
+                self.values.insert(name.to_string(), default.to_string());
+            }
         }
 
         self.options.push(option);
     }
 
+    // Applies a `setoption` command. Unknown names and values of the wrong type or out of range
+    // are rejected without touching the current value.
     pub fn parse<T>(&mut self, tokens: &[T]) -> Result<()>
     where
         T: AsRef<str> + Borrow<str>,
@@ -82,8 +134,8 @@ impl UciOptionSet {
 
         let mut parse_stage = ParseStage::Pre;
 
-        let mut name = String::new();
-        let mut value = String::new();
+        let mut name: Vec<&str> = Vec::new();
+        let mut value: Vec<&str> = Vec::new();
 
         for token in tokens {
             match token.as_ref() {
@@ -95,25 +147,103 @@ impl UciOptionSet {
                 }
                 _ => match parse_stage {
                     ParseStage::Name => {
-                        name = token.as_ref().to_string();
+                        name.push(token.borrow());
                     }
                     ParseStage::Value => {
-                        value = token.as_ref().to_string();
+                        value.push(token.borrow());
                     }
                     _ => {}
                 },
             }
         }
 
-        self.values.insert(name, value);
+        self.set(&name.join(" "), &value.join(" "))
+    }
+
+    // Sets one option by name, with the same checks as `setoption`
+    pub fn set(&mut self, name: &str, value: &str) -> Result<()> {
+        let option = self
+            .options
+            .iter()
+            .find(|option| option.name().eq_ignore_ascii_case(name))
+            .ok_or_else(|| anyhow!("unknown option {}", name))?;
+
+        match *option {
+            UciOption::Spin { name, min, max, .. } => {
+                let value = value
+                    .parse::<i32>()
+                    .map_err(|_| anyhow!("{} expects a number, got '{}'", name, value))?;
+                if !(min..=max).contains(&value) {
+                    return Err(anyhow!(
+                        "{} must be between {} and {}, got {}",
+                        name,
+                        min,
+                        max,
+                        value
+                    ));
+                }
+                self.values.insert(name.to_string(), value.to_string());
+            }
+            UciOption::Check { name, .. } => {
+                let value = value
+                    .parse::<bool>()
+                    .map_err(|_| anyhow!("{} expects true or false, got '{}'", name, value))?;
+                self.values.insert(name.to_string(), value.to_string());
+            }
+            UciOption::String { name, .. } => {
+                let value = if value == "<empty>" { "" } else { value };
+                self.values.insert(name.to_string(), value.to_string());
+            }
+        }
         Ok(())
     }
 
+    pub fn set_int(&mut self, name: &str, value: i32) {
+        self.values.insert(name.to_string(), value.to_string());
+    }
+
     pub fn get_int(&self, name: &str) -> Option<i32> {
         self.values
             .get(name)
             .and_then(|val| val.parse::<i32>().ok())
     }
+
+    pub fn get_bool(&self, name: &str) -> Option<bool> {
+        self.values
+            .get(name)
+            .and_then(|val| val.parse::<bool>().ok())
+    }
+
+    pub fn get_string(&self, name: &str) -> Option<&str> {
+        self.values.get(name).map(String::as_str)
+    }
+
+    // The current values as a profile, one `Name = value` line per option
+    pub fn to_profile(&self) -> String {
+        self.options
+            .iter()
+            .map(|option| format!("{} = {}\n", option.name(), self.values[option.name()]))
+            .collect()
+    }
+}
+
+// Parses a profile: `Name = value` lines, the flat subset of TOML. Blank lines, `#` comments and
+// `[section]` headers are skipped, quotes around names and values are dropped.
+pub fn parse_profile(s: &str) -> Result<Vec<(String, String)>> {
+    let unquote = |s: &str| s.trim().trim_matches('"').to_string();
+
+    let mut settings = Vec::new();
+    for (i, line) in s.lines().enumerate() {
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() || line.starts_with('[') {
+            continue;
+        }
+        let (name, value) = line
+            .split_once('=')
+            .ok_or_else(|| anyhow!("line {}: expected `Name = value`, got `{}`", i + 1, line))?;
+        settings.push((unquote(name), unquote(value)));
+    }
+    Ok(settings)
 }
 
 impl Default for UciOptionSet {
@@ -139,20 +269,117 @@ pub struct Uci {
     stop: Arc<AtomicBool>,
     tt: Arc<Table>,
     options: UciOptionSet,
+    debug: bool,
+    // set while a `go ponder` search is waiting for ponderhit
+    ponder: Arc<AtomicBool>,
+    // updated by the search thread once the bestmove is out
+    overhead: Arc<Mutex<OverheadTracker>>,
+    // loaded from BookFile, played from when OwnBook is on
+    book: Option<Book>,
+}
+
+// Every option the engine offers, at its default
+pub fn default_options() -> Vec<UciOption> {
+    engine_options().options
+}
+
+fn engine_options() -> UciOptionSet {
+    let mut options = UciOptionSet::new();
+    options.add_option(UciOption::Spin {
+        name: "Hash",
+        default: 64,
+        min: 1,
+        max: 16384,
+    });
+    options.add_option(UciOption::Spin {
+        name: "Threads",
+        default: 1,
+        min: 1,
+        max: 256,
+    });
+    options.add_option(UciOption::Check {
+        name: "Deterministic",
+        default: false,
+    });
+    options.add_option(UciOption::Check {
+        name: "PinThreads",
+        default: false,
+    });
+    // threads average their quiet history every so often instead of each keeping its own, with
+    // debug on the beta cutoff stats show how ordering fares either way
+    options.add_option(UciOption::Check {
+        name: "ShareHistory",
+        default: false,
+    });
+    // milliseconds per move lost outside the search, grown during a game when moves come late
+    options.add_option(UciOption::Spin {
+        name: "MoveOverhead",
+        default: MOVE_OVERHEAD as i32,
+        min: 0,
+        max: 5000,
+    });
+    // nodes per millisecond, 0 searches on the real clock
+    options.add_option(UciOption::Spin {
+        name: "nodestime",
+        default: 0,
+        min: 0,
+        max: 100_000,
+    });
+    options.add_option(UciOption::String {
+        name: "UCI_Opponent",
+        default: "",
+    });
+    options.add_option(UciOption::Check {
+        name: "UCI_AnalyseMode",
+        default: false,
+    });
+    // centipawns a draw is worth less than zero, played against opponents rated below
+    // ContemptElo and never in analysis
+    options.add_option(UciOption::Spin {
+        name: "Contempt",
+        default: 20,
+        min: 0,
+        max: 200,
+    });
+    options.add_option(UciOption::Spin {
+        name: "ContemptElo",
+        default: 2800,
+        min: 0,
+        max: 4000,
+    });
+    // with debug on, write out the main thread's last root search this many plies deep as text
+    // or json, to a file or just before the bestmove when there's no file
+    options.add_option(UciOption::Spin {
+        name: "TreeDump",
+        default: 0,
+        min: 0,
+        max: 16,
+    });
+    options.add_option(UciOption::Check {
+        name: "TreeDumpJson",
+        default: false,
+    });
+    options.add_option(UciOption::String {
+        name: "TreeDumpFile",
+        default: "",
+    });
+    // a book from `pounce makebook`, moves are picked by weight as long as the position is in it
+    options.add_option(UciOption::Check {
+        name: "OwnBook",
+        default: false,
+    });
+    options.add_option(UciOption::String {
+        name: "BookFile",
+        default: "",
+    });
+    options
 }
 
 impl Uci {
     pub fn new() -> Self {
         let Fen(position) = Uci::STARTPOS.parse().unwrap();
 
-        let mut options = UciOptionSet::new();
-        options.add_option(UciOption::Spin {
-            name: "Hash",
-            default: 64,
-            min: 1,
-            max: 16384,
-        });
-
+        let options = engine_options();
         let tt = Table::new_mb(options.get_int("Hash").unwrap() as usize);
 
         Uci {
@@ -160,10 +387,14 @@ impl Uci {
             stop: Arc::new(AtomicBool::new(false)),
             tt: Arc::new(tt),
             options,
+            debug: false,
+            ponder: Arc::new(AtomicBool::new(false)),
+            overhead: Arc::new(Mutex::new(OverheadTracker::default())),
+            book: None,
         }
     }
 
-    pub const STARTPOS: &'static str = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1";
+    pub const STARTPOS: &'static str = positions::STARTPOS;
 }
 
 impl Default for Uci {
@@ -174,7 +405,7 @@ impl Default for Uci {
 
 impl Uci {
     pub fn run_loop(&mut self) -> Result<()> {
-        println!("{}", engine_name());
+        uci_println!("{}", engine_name());
 
         let mut rl = DefaultEditor::new()?;
 
@@ -203,7 +434,7 @@ impl Uci {
                 Err(e) => return Err(e).context("Error reading input"),
             }
         }
-        println!("Exiting...");
+        uci_println!("Exiting...");
         Ok(())
     }
 
@@ -213,23 +444,32 @@ impl Uci {
     {
         match cmd {
             Some("uci") => {
-                println!("id name {}", engine_name());
-                println!("id author alex flick");
-                println!("{}", self.options);
-                println!("uciok");
+                let mut out = output();
+                writeln!(out, "id name {}", engine_name())?;
+                writeln!(out, "id author alex flick")?;
+                writeln!(out, "{}", self.options)?;
+                writeln!(out, "uciok")?;
+                out.flush()?;
             }
+            Some("debug") => match rest.first().map(|s| s.as_ref()) {
+                Some("on") => self.debug = true,
+                Some("off") => self.debug = false,
+                _ => return Err(anyhow!("Expected debug on or off")),
+            },
             Some("isready") => {
-                println!("readyok");
+                uci_println!("readyok");
             }
             Some("setoption") => {
-                self.options.parse(rest)?;
-
-                if let Some(hash_size) = self.options.get_int("Hash") {
-                    if self.tt.size_mb() != hash_size as usize {
-                        self.tt = Arc::new(Table::new_mb(hash_size as usize));
-                    }
+                if let Err(e) = self.options.parse(rest) {
+                    uci_println!("info string ignoring setoption: {}", e);
+                    return Ok(ControlFlow::Continue(()));
                 }
-                self.tt = Arc::new(Table::new_mb(self.options.get_int("Hash").unwrap() as usize));
+                self.apply_options();
+            }
+            Some("saveprofile") => {
+                let path = rest.first().ok_or(anyhow!("No profile path provided"))?;
+                std::fs::write(path.as_ref(), self.options.to_profile())
+                    .with_context(|| format!("failed to write profile {}", path.as_ref()))?;
             }
             Some("quit") => {
                 return Ok(ControlFlow::Break(()));
@@ -246,21 +486,63 @@ impl Uci {
                 let psqt_eg = self.position.psqt_eg;
                 let psqt_mg_calc = self.position.psqt_mg();
                 let psqt_eg_calc = self.position.psqt_eg();
-                println!(
+                uci_println!(
                     "Eval: {}, PSQT MG: {} - {}, PSQT EG: {} - {}",
-                    eval, psqt_mg, psqt_mg_calc, psqt_eg, psqt_eg_calc
+                    eval,
+                    psqt_mg,
+                    psqt_mg_calc,
+                    psqt_eg,
+                    psqt_eg_calc
                 );
             }
             Some("stop") => {
                 self.cmd_stop();
             }
+            Some("ponderhit") => {
+                self.ponder
+                    .store(false, std::sync::atomic::Ordering::Relaxed);
+            }
             Some("ucinewgame") => {
                 self.tt.clear();
+                self.tt.new_search();
+                self.overhead.lock().unwrap().reset();
             }
+            // checks the incrementally updated keys against ones computed from scratch
             Some("zobrist") => {
-                let hash = self.position.zobrist_hash();
-                println!("Zobrist hash: {:x}", u64::from(hash));
-                println!("Zobrist hash: {:x}", u64::from(self.position.key));
+                let position = &self.position;
+                let keys = [
+                    ("Zobrist hash", position.key, position.zobrist_hash()),
+                    ("Pawn hash", position.pawn_key, position.pawn_hash()),
+                    (
+                        "Major/minor hash",
+                        position.major_minor_key,
+                        position.major_minor_hash(),
+                    ),
+                ];
+
+                let mut out = output();
+                for (name, incremental, fresh) in keys {
+                    write!(out, "{}: {:x}", name, u64::from(incremental))?;
+                    if incremental == fresh {
+                        writeln!(out, " ok")?;
+                        continue;
+                    }
+                    let diff = match incremental.explain_diff(fresh) {
+                        Some(toggles) => toggles
+                            .iter()
+                            .map(|toggle| toggle.to_string())
+                            .collect::<Vec<_>>()
+                            .join(", "),
+                        None => "more than two toggles".to_string(),
+                    };
+                    writeln!(
+                        out,
+                        ", {:x} from scratch, differs by {}",
+                        u64::from(fresh),
+                        diff
+                    )?;
+                }
+                out.flush()?;
             }
             Some(val) => {
                 eprintln!("Unknown command: {}", val);
@@ -270,6 +552,74 @@ impl Uci {
         Ok(ControlFlow::Continue(()))
     }
 
+    // Applies the options of a profile file, as if each was given to `setoption`
+    pub fn load_profile(&mut self, path: &Path) -> Result<()> {
+        let profile = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read profile {}", path.display()))?;
+        for (name, value) in parse_profile(&profile)? {
+            self.options
+                .set(&name, &value)
+                .with_context(|| format!("in profile {}", path.display()))?;
+        }
+        self.apply_options();
+        Ok(())
+    }
+
+    // Brings state that depends on options in line with their current values
+    fn apply_options(&mut self) {
+        if let Some(hash_size) = self.options.get_int("Hash") {
+            if self.tt.size_mb() != hash_size as usize {
+                self.resize_tt(hash_size as usize);
+            }
+        }
+        let book_file = self.options.get_string("BookFile").unwrap_or("");
+        if self.book.as_ref().map_or("", |book| book.path.to_str().unwrap_or("")) != book_file {
+            self.load_book(book_file.to_string());
+        }
+    }
+
+    // A book that can't be read is reported and leaves no book rather than the old one
+    fn load_book(&mut self, path: String) {
+        self.book = None;
+        if path.is_empty() {
+            return;
+        }
+        match Book::open(Path::new(&path)) {
+            Ok(book) => {
+                uci_println!("info string loaded {} book entries from {}", book.len(), path);
+                self.book = Some(book);
+            }
+            Err(e) => uci_println!("info string could not load the book: {:#}", e),
+        }
+    }
+
+    // The new table is allocated while the old one is still around, so if that fails the old
+    // one, and the Hash value matching it, are kept
+    fn resize_tt(&mut self, size_mb: usize) {
+        let start = Instant::now();
+        match Table::try_new_mb(size_mb) {
+            Ok(tt) => {
+                self.tt = Arc::new(tt);
+                uci_println!(
+                    "info string Hash set to {} MB ({} entries) in {} ms",
+                    size_mb,
+                    self.tt.num_entries(),
+                    start.elapsed().as_millis()
+                );
+            }
+            Err(e) => {
+                let old_size = self.tt.size_mb();
+                self.options.set_int("Hash", old_size as i32);
+                uci_println!(
+                    "info string could not allocate {} MB of Hash, keeping {} MB: {}",
+                    size_mb,
+                    old_size,
+                    e
+                );
+            }
+        }
+    }
+
     fn cmd_position<T>(&mut self, tokens: &[T]) -> Result<()>
     where
         T: AsRef<str> + Borrow<str>,
@@ -283,7 +633,9 @@ impl Uci {
 
         let mut parse_stage = ParseStage::Pre;
         let mut fen: Vec<&str> = Vec::new();
-        let mut moves: Vec<Move> = Vec::new();
+        let mut moves: Vec<&str> = Vec::new();
+        // `position kiwipete moves ...`, any of the named positions in place of startpos
+        let mut named = None;
 
         for token in tokens {
             match token.as_ref() {
@@ -301,25 +653,37 @@ impl Uci {
                         fen.push(token.borrow());
                     }
                     ParseStage::Moves => {
-                        moves.push(token.borrow().parse::<Move>()?);
+                        moves.push(token.borrow());
+                    }
+                    ParseStage::Pre => {
+                        let name = token.as_ref();
+                        named = Some(positions::named(name).ok_or_else(|| {
+                            let names = positions::NAMED.map(|(name, _)| name).join(", ");
+                            anyhow!("Unknown position {}, expected fen or one of {}", name, names)
+                        })?);
                     }
                     _ => {}
                 },
             }
         }
 
-        if !fen.is_empty() {
-            let fen_str = fen.join(" ");
-            let Fen(position) = Fen::parse(fen_str.as_str())?;
-            self.position = position;
+        let Fen(mut position) = if !fen.is_empty() {
+            Fen::parse(fen.join(" ").as_str())?
         } else {
-            let Fen(position) = Uci::STARTPOS.parse().unwrap();
-            self.position = position;
-        }
+            named.unwrap_or(Uci::STARTPOS).parse().unwrap()
+        };
 
-        for mv in moves {
-            self.position.make_move(mv);
+        // moves can be given in SAN too, e.g. pasted from a book
+        for token in moves {
+            let mv = match token.parse::<Move>() {
+                Ok(mv) => position
+                    .legal_move(mv)
+                    .ok_or(anyhow!("Illegal move: {}", token))?,
+                Err(_) => parse_san(&position, token)?,
+            };
+            position.make_move(mv);
         }
+        self.position = position;
         Ok(())
     }
 
@@ -332,31 +696,54 @@ impl Uci {
             .ok_or(anyhow!("No depth provided"))?
             .as_ref()
             .parse::<u8>()?;
+        // `split <d>` prints counts for every line of d moves instead of just the root moves
+        let split = match tokens.get(1).map(|t| t.as_ref()) {
+            Some("split") => Some(
+                tokens
+                    .get(2)
+                    .ok_or(anyhow!("No split depth provided"))?
+                    .as_ref()
+                    .parse::<u8>()?,
+            ),
+            Some(token) => return Err(anyhow!("Unexpected perft argument: {}", token)),
+            None => None,
+        };
 
-        let mut nodes = 0;
         let now = std::time::Instant::now();
+        let counts = match split {
+            Some(split) => split_perft(&mut self.position, depth, split)
+                .into_iter()
+                .map(|(line, count)| {
+                    let line = line
+                        .iter()
+                        .map(|mv| mv.to_string())
+                        .collect::<Vec<_>>()
+                        .join(" ");
+                    (line, count)
+                })
+                .collect::<Vec<_>>(),
+            None => perft_divide(&mut self.position, depth)
+                .into_iter()
+                .map(|(mv, count)| (mv.to_string(), count))
+                .collect(),
+        };
+        let nodes = counts.iter().map(|(_, count)| count).sum::<u64>();
+        let elapsed = now.elapsed();
 
-        if depth > 0 {
-            let mg = MoveGen::new(&self.position);
-
-            for mv in mg {
-                self.position.make_move(mv);
-                let count = perft(&mut self.position, depth - 1);
-                nodes += count;
-                self.position.unmake_move(mv);
-                println!("{}: {}", mv, count);
-            }
+        let mut out = output();
+        for (line, count) in counts {
+            writeln!(out, "{}: {}", line, count)?;
         }
-
-        let elapsed = now.elapsed();
-        println!();
-        println!(
+        writeln!(out)?;
+        writeln!(
+            out,
             "Nodes: {}, Time: {}s {}ms, Nodes/s: {:.2}M",
             nodes,
             elapsed.as_secs(),
             elapsed.subsec_millis(),
             (nodes as f64 / elapsed.as_secs_f64() / 1_000_000.0)
-        );
+        )?;
+        out.flush()?;
         Ok(())
     }
 
@@ -374,32 +761,375 @@ impl Uci {
                 depth: Some(7),
                 ..Default::default()
             };
-            return bench(self.tt.size_mb() as u32, limits);
+            let threads = self.options.get_int("Threads").unwrap_or(1).max(1) as usize;
+            return bench(self.tt.size_mb() as u32, limits, threads);
+        }
+
+        // from receiving `go`, what the gui sees of the search's time
+        let start = Instant::now();
+
+        // `go ... wait` blocks until the search is done, for scripts driving the engine
+        let wait = tokens.iter().any(|t| t.as_ref() == "wait");
+        let mut tokens = tokens
+            .iter()
+            .map(|t| t.as_ref())
+            .filter(|&t| t != "wait")
+            .collect::<Vec<_>>();
+
+        // `excludemoves <moves>` searches every root move but these, the opposite of searchmoves
+        let mut excluded = Vec::new();
+        if let Some(start) = tokens.iter().position(|&t| t == "excludemoves") {
+            let moves = tokens[start + 1..]
+                .iter()
+                .take_while(|t| t.parse::<Move>().is_ok())
+                .count();
+            for token in tokens.drain(start..start + 1 + moves).skip(1) {
+                let mv = token.parse::<Move>().unwrap();
+                excluded.push(
+                    self.position
+                        .legal_move(mv)
+                        .ok_or(anyhow!("Illegal move: {}", token))?,
+                );
+            }
         }
 
         let limits = if !tokens.is_empty() {
-            Limits::from_tokens(tokens)?
+            Limits::from_tokens(&tokens)?
         } else {
             let mut limits = Limits::new();
             limits.infinite = true;
             limits
         };
+        // nothing reads stdin while we wait, so a stop would never arrive
+        if wait && !limits.is_bounded() {
+            return Err(anyhow!(
+                "go wait needs a depth, nodes, movetime or clock limit"
+            ));
+        }
+
+        // a book move needs no search, unless the gui wants one that runs until it says stop
+        if let Some(mv) = self.book_move(&limits, &excluded) {
+            uci_println!("bestmove {}", mv);
+            if wait {
+                uci_println!("info string result bestmove {} book", mv);
+            }
+            return Ok(());
+        }
 
         let stop = Arc::new(AtomicBool::new(false));
         self.stop = stop.clone();
+        self.tt.new_search();
         let tt = self.tt.clone();
+        let ponder = Arc::new(AtomicBool::new(limits.ponder));
+        self.ponder = ponder.clone();
+
+        let extra_overhead = self.overhead.lock().unwrap().extra().as_millis() as u64;
+        let move_overhead =
+            self.options.get_int("MoveOverhead").unwrap_or(0) as u64 + extra_overhead;
+        // only clocked moves can be late, the others have no deadline or one not set by the gui
+        let clock = match self.position.side {
+            Color::White => limits.wtime,
+            Color::Black => limits.btime,
+        };
+        let clocked =
+            clock.is_some() && limits.movetime.is_none() && !limits.ponder && !limits.infinite;
+        // the search stops by its max time, the gui only needs the move by that plus the overhead
+        let deadline = SearchCop::with_overhead(limits, self.position.side, move_overhead)
+            .max_time
+            .map(|max_time| max_time + Duration::from_millis(move_overhead))
+            .filter(|_| clocked);
+        let overhead = self.overhead.clone();
+        let debug = self.debug;
 
         let position = self.position.clone();
+        let options = SearchOptions {
+            threads: self.options.get_int("Threads").unwrap_or(1).max(1) as usize,
+            debug: self.debug,
+            deterministic: self.options.get_bool("Deterministic").unwrap_or(false),
+            pin_threads: self.options.get_bool("PinThreads").unwrap_or(false),
+            nodestime: self
+                .options
+                .get_int("nodestime")
+                .filter(|&n| n > 0)
+                .map(|n| n as u64),
+            contempt: self.contempt(),
+            excluded,
+            move_overhead,
+            share_history: self.options.get_bool("ShareHistory").unwrap_or(false),
+            tree_dump: self.tree_dump(),
+        };
+        let deadline =
+            deadline.filter(|_| !options.deterministic && options.nodestime.is_none());
+
+        let handle = thread::spawn(move || {
+            // if the search blows up the gui still gets a legal move rather than waiting forever
+            let fallback = MoveGen::new(&position).next().unwrap_or(Move::NONE);
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                smp_search(position, limits, tt, stop, ponder, options)
+            }));
+            let bestmove = match &result {
+                Ok(result) => result.bestmove,
+                Err(e) => {
+                    uci_println!("info string search panicked: {}", panic_message(&**e));
+                    fallback
+                }
+            };
+            uci_println!("bestmove {}", bestmove);
 
-        thread::spawn(move || {
-            let mut search = Search::new(position, limits, tt, stop.clone());
-            let bestmove = search.think().bestmove;
-            println!("bestmove {}", bestmove);
+            if let Some(deadline) = deadline {
+                let mut overhead = overhead.lock().unwrap();
+                if let Some(overrun) = overhead.record(deadline, start.elapsed()) {
+                    if debug {
+                        uci_println!(
+                            "info string bestmove {} ms late, adding {} ms to the move overhead",
+                            overrun.as_millis(),
+                            overhead.extra().as_millis()
+                        );
+                    }
+                }
+            }
+            result.ok()
         });
+
+        if wait {
+            match handle.join().ok().flatten() {
+                Some(result) => uci_println!(
+                    "info string result bestmove {} score {} depth {} time {}",
+                    result.bestmove,
+                    Score(result.score).to_uci_string(),
+                    result.depth,
+                    start.elapsed().as_millis()
+                ),
+                None => uci_println!("info string result none"),
+            }
+        }
         Ok(())
     }
 
+    // Contempt for the current UCI_Opponent, a malformed one is reported and played as an equal
+    fn contempt(&self) -> i16 {
+        if self.options.get_bool("UCI_AnalyseMode").unwrap_or(false) {
+            return 0;
+        }
+        let opponent = match self.options.get_string("UCI_Opponent").unwrap_or("") {
+            "" => None,
+            s => match s.parse::<Opponent>() {
+                Ok(opponent) => Some(opponent),
+                Err(e) => {
+                    uci_println!("info string ignoring UCI_Opponent: {}", e);
+                    None
+                }
+            },
+        };
+        let config = ContemptConfig {
+            contempt: self.options.get_int("Contempt").unwrap_or(0) as i16,
+            below_elo: self.options.get_int("ContemptElo").unwrap_or(0) as u32,
+        };
+        let contempt = config.contempt(opponent.as_ref());
+        if self.debug {
+            if let Some(opponent) = &opponent {
+                uci_println!(
+                    "info string opponent {} elo {:?}, contempt {}",
+                    opponent.name,
+                    opponent.elo,
+                    contempt
+                );
+            }
+        }
+        contempt
+    }
+
+    fn book_move(&self, limits: &Limits, excluded: &[Move]) -> Option<Move> {
+        if !self.options.get_bool("OwnBook").unwrap_or(false)
+            || limits.infinite
+            || limits.ponder
+            || !excluded.is_empty()
+        {
+            return None;
+        }
+        let book = self.book.as_ref()?;
+        let mv = book.pick(&self.position, &mut rand::thread_rng())?;
+        if self.debug {
+            let moves = book
+                .moves(&self.position)
+                .iter()
+                .map(|(mv, weight)| format!("{} {}", mv, weight))
+                .collect::<Vec<_>>();
+            uci_println!("info string book move {}, weights {}", mv, moves.join(", "));
+        }
+        Some(mv)
+    }
+
+    // The TreeDump options, only honored in debug mode
+    fn tree_dump(&self) -> Option<TreeDump> {
+        let plies = self.options.get_int("TreeDump").unwrap_or(0);
+        if !self.debug || plies <= 0 {
+            return None;
+        }
+        Some(TreeDump {
+            plies: plies as usize,
+            json: self.options.get_bool("TreeDumpJson").unwrap_or(false),
+            path: match self.options.get_string("TreeDumpFile").unwrap_or("") {
+                "" => None,
+                path => Some(path.into()),
+            },
+        })
+    }
+
     fn cmd_stop(&mut self) {
         self.stop.store(true, std::sync::atomic::Ordering::Relaxed);
     }
 }
+
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s
+    } else {
+        "unknown error"
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn options() -> UciOptionSet {
+        let mut options = UciOptionSet::new();
+        options.add_option(UciOption::Spin {
+            name: "Hash",
+            default: 64,
+            min: 1,
+            max: 16384,
+        });
+        options.add_option(UciOption::Check {
+            name: "Ponder",
+            default: false,
+        });
+        options
+    }
+
+    fn setoption(options: &mut UciOptionSet, cmd: &str) -> Result<()> {
+        options.parse(&cmd.split_whitespace().collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn test_setoption() {
+        let mut options = options();
+        setoption(&mut options, "name hash value 128").unwrap();
+        assert_eq!(options.get_int("Hash"), Some(128));
+        setoption(&mut options, "name Ponder value true").unwrap();
+        assert_eq!(options.get_bool("Ponder"), Some(true));
+    }
+
+    #[test]
+    fn test_setoption_rejects_invalid() {
+        let mut options = options();
+        assert!(setoption(&mut options, "name Contempt value 10").is_err());
+        assert!(setoption(&mut options, "name Hash value lots").is_err());
+        assert!(setoption(&mut options, "name Hash value 0").is_err());
+        assert!(setoption(&mut options, "name Ponder value 1").is_err());
+
+        assert_eq!(options.get_int("Hash"), Some(64));
+        assert_eq!(options.get_bool("Ponder"), Some(false));
+        assert!(!options.values.contains_key("Contempt"));
+    }
+
+    #[test]
+    fn test_profile() {
+        let profile = "# analysis\n[options]\nhash = 256\n\n\"Ponder\" = true # for now\n";
+        let settings = parse_profile(profile).unwrap();
+        assert_eq!(
+            settings,
+            vec![
+                ("hash".to_string(), "256".to_string()),
+                ("Ponder".to_string(), "true".to_string())
+            ]
+        );
+        assert!(parse_profile("Hash 256").is_err());
+
+        let mut saved = options();
+        let mut options = options();
+        for (name, value) in &settings {
+            options.set(name, value).unwrap();
+        }
+        assert_eq!(options.to_profile(), "Hash = 256\nPonder = true\n");
+
+        for (name, value) in parse_profile(&options.to_profile()).unwrap() {
+            saved.set(&name, &value).unwrap();
+        }
+        assert_eq!(saved.values, options.values);
+    }
+
+    #[test]
+    fn test_opponent_contempt() {
+        let mut uci = Uci::new();
+        assert_eq!(uci.contempt(), 0);
+        setoption(&mut uci.options, "name UCI_Opponent value IM 2450 human Jane Doe").unwrap();
+        assert_eq!(uci.contempt(), 20);
+        setoption(&mut uci.options, "name ContemptElo value 2400").unwrap();
+        assert_eq!(uci.contempt(), 0);
+        setoption(&mut uci.options, "name UCI_Opponent value none none computer Fish").unwrap();
+        assert_eq!(uci.contempt(), 0);
+
+        setoption(&mut uci.options, "name UCI_Opponent value none 1500 human Joe").unwrap();
+        assert_eq!(uci.contempt(), 20);
+        setoption(&mut uci.options, "name UCI_AnalyseMode value true").unwrap();
+        assert_eq!(uci.contempt(), 0);
+    }
+
+    #[test]
+    fn test_panic_message() {
+        let e = panic::catch_unwind(|| panic!("bad tt move {}", 7)).unwrap_err();
+        assert_eq!(panic_message(&*e), "bad tt move 7");
+        let e = panic::catch_unwind(|| panic!("static")).unwrap_err();
+        assert_eq!(panic_message(&*e), "static");
+    }
+
+    #[test]
+    fn test_position_san_moves() {
+        crate::movegen::init_tables();
+        crate::zobrist::init_zobrist();
+
+        let mut uci = Uci::new();
+        uci.cmd_position(&["startpos", "moves", "e4", "e7e5", "Nf3", "Nc6", "Bb5"])
+            .unwrap();
+        let mut expected = Uci::new();
+        expected
+            .cmd_position(&["startpos", "moves", "e2e4", "e7e5", "g1f3", "b8c6", "f1b5"])
+            .unwrap();
+        assert_eq!(uci.position.key, expected.position.key);
+
+        // a bad move leaves the position alone
+        assert!(uci
+            .cmd_position(&["startpos", "moves", "e4", "Ke3"])
+            .is_err());
+        assert_eq!(uci.position.key, expected.position.key);
+    }
+
+    #[test]
+    fn test_position_named() {
+        crate::movegen::init_tables();
+        crate::zobrist::init_zobrist();
+
+        let mut uci = Uci::new();
+        uci.cmd_position(&["kiwipete", "moves", "e1g1"]).unwrap();
+        let mut expected = Uci::new();
+        expected
+            .cmd_position(&["fen", positions::KIWIPETE, "moves", "e1g1"])
+            .unwrap();
+        assert_eq!(uci.position.key, expected.position.key);
+
+        assert!(uci.cmd_position(&["kiwipeet"]).is_err());
+        assert_eq!(uci.position.key, expected.position.key);
+    }
+
+    #[test]
+    fn test_go_wait_needs_limit() {
+        let mut uci = Uci::new();
+        assert!(uci.cmd_go(&["wait"]).is_err());
+        assert!(uci.cmd_go(&["infinite", "wait"]).is_err());
+        assert!(uci.cmd_go(&["wtime", "1000", "ponder", "wait"]).is_err());
+    }
+}
@@ -1,3 +1,8 @@
+use std::{
+    io::{BufWriter, Stdout},
+    sync::{Mutex, MutexGuard, OnceLock},
+};
+
 const VERSION: &str = env!("VERGEN_GIT_DESCRIBE");
 const DIRTY: &str = env!("VERGEN_GIT_DIRTY");
 const BUILD_DATE: &str = env!("VERGEN_BUILD_DATE");
@@ -15,3 +20,25 @@ pub fn engine_name() -> String {
 
     format!("pounce {}-{}-{}", release_type, date, version)
 }
+
+static OUTPUT: OnceLock<Mutex<BufWriter<Stdout>>> = OnceLock::new();
+
+// All engine output goes through one buffered stdout. Holding the guard keeps other threads'
+// lines out of a multi-line reply, flush once the reply is complete.
+pub fn output() -> MutexGuard<'static, BufWriter<Stdout>> {
+    OUTPUT
+        .get_or_init(|| Mutex::new(BufWriter::new(std::io::stdout())))
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+}
+
+// println! for engine output: writes one complete line and flushes it
+macro_rules! uci_println {
+    ($($arg:tt)*) => {{
+        use std::io::Write;
+        let mut out = $crate::util::output();
+        let _ = writeln!(out, $($arg)*);
+        let _ = out.flush();
+    }};
+}
+pub(crate) use uci_println;
@@ -1,3 +1,12 @@
+use std::{
+    collections::HashMap,
+    fmt::{
+        self,
+        Display,
+        Formatter,
+    },
+};
+
 use rand::{
     rngs::SmallRng,
     Rng,
@@ -10,6 +19,7 @@ use crate::{
         Color,
         File,
         Piece,
+        Rank,
         Role,
         Square,
     },
@@ -67,12 +77,94 @@ impl ZobristHash {
     }
 }
 
+// One key that goes into a hash, named by what it stands for
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Toggle {
+    Piece(Square, Piece),
+    Side,
+    EpFile(File),
+    Castling(CastleRights),
+}
+
+impl Toggle {
+    fn all() -> Vec<Toggle> {
+        let mut toggles = Vec::new();
+        for square in Square::ALL {
+            for color in Color::ALL {
+                for role in Role::ALL {
+                    toggles.push(Toggle::Piece(square, Piece { color, role }));
+                }
+            }
+        }
+        toggles.push(Toggle::Side);
+        toggles.extend(File::ALL.map(Toggle::EpFile));
+        toggles.extend((0..16).map(|bits| Toggle::Castling(CastleRights::from_bits_retain(bits))));
+        toggles
+    }
+
+    fn key(self) -> u64 {
+        let mut hash = ZobristHash::new();
+        match self {
+            Toggle::Piece(square, piece) => hash.toggle_piece(square, piece),
+            Toggle::Side => hash.toggle_side(),
+            Toggle::EpFile(file) => hash.toggle_ep(Some(Square::make(file, Rank::R3))),
+            Toggle::Castling(castling) => hash.toggle_castling(castling),
+        }
+        hash.0
+    }
+}
+
+impl Display for Toggle {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Toggle::Piece(square, piece) => write!(f, "{} on {}", piece, square),
+            Toggle::Side => write!(f, "side to move"),
+            Toggle::EpFile(file) => {
+                write!(f, "en passant on the {} file", (b'a' + *file as u8) as char)
+            }
+            Toggle::Castling(castling) => write!(f, "castling {}", castling.to_fen()),
+        }
+    }
+}
+
+impl ZobristHash {
+    // The one or two toggles whose keys make up the difference between two hashes, e.g. the
+    // update a make/unmake missed or applied twice. Changing castling rights takes two. Empty
+    // when the hashes are the same, None when it takes more than two toggles to explain.
+    pub fn explain_diff(self, other: ZobristHash) -> Option<Vec<Toggle>> {
+        let diff = self.0 ^ other.0;
+        if diff == 0 {
+            return Some(Vec::new());
+        }
+
+        let toggles = Toggle::all();
+        let by_key = toggles
+            .iter()
+            .map(|&toggle| (toggle.key(), toggle))
+            .collect::<HashMap<_, _>>();
+        if let Some(&toggle) = by_key.get(&diff) {
+            return Some(vec![toggle]);
+        }
+        toggles.iter().find_map(|&toggle| {
+            by_key
+                .get(&(diff ^ toggle.key()))
+                .map(|&other| vec![toggle, other])
+        })
+    }
+}
+
 impl From<ZobristHash> for u64 {
     fn from(hash: ZobristHash) -> u64 {
         hash.0
     }
 }
 
+impl From<u64> for ZobristHash {
+    fn from(bits: u64) -> ZobristHash {
+        ZobristHash(bits)
+    }
+}
+
 impl From<ZobristHash> for usize {
     fn from(hash: ZobristHash) -> usize {
         hash.0 as usize
@@ -103,14 +195,27 @@ impl Position {
 
         hash
     }
+
+    pub fn pawn_hash(&self) -> ZobristHash {
+        let mut hash = ZobristHash::new();
+        for square in self.by_role[Role::Pawn] {
+            hash.toggle_piece(square, self.piece_at(square).unwrap());
+        }
+        hash
+    }
+
+    pub fn major_minor_hash(&self) -> ZobristHash {
+        let mut hash = ZobristHash::new();
+        for square in self.occupancy & !self.by_role[Role::Pawn] & !self.by_role[Role::King] {
+            hash.toggle_piece(square, self.piece_at(square).unwrap());
+        }
+        hash
+    }
 }
 
 #[cfg(test)]
 fn perft_zobrist(pos: &mut Position, depth: u8) {
-    use crate::{
-        fen::Fen,
-        movegen::MoveGen,
-    };
+    use crate::{fen::Fen, movegen::MoveGen};
 
     if depth == 0 {
         return;
@@ -118,6 +223,12 @@ fn perft_zobrist(pos: &mut Position, depth: u8) {
 
     let before = pos.zobrist_hash();
     assert_eq!(before, pos.key, "hash mismatch");
+    assert_eq!(pos.pawn_hash(), pos.pawn_key, "pawn hash mismatch");
+    assert_eq!(
+        pos.major_minor_hash(),
+        pos.major_minor_key,
+        "major/minor hash mismatch"
+    );
 
     let mg = MoveGen::new(pos);
     for m in mg {
@@ -131,6 +242,18 @@ fn perft_zobrist(pos: &mut Position, depth: u8) {
             m,
             Fen(pos.clone())
         );
+        assert_eq!(
+            pos.pawn_hash(),
+            pos.pawn_key,
+            "pawn hash mismatch after {}",
+            m
+        );
+        assert_eq!(
+            pos.major_minor_hash(),
+            pos.major_minor_key,
+            "major/minor hash mismatch after {}",
+            m
+        );
 
         perft_zobrist(pos, depth - 1);
         pos.unmake_move(m);
@@ -148,22 +271,31 @@ fn perft_zobrist(pos: &mut Position, depth: u8) {
 
 #[cfg(test)]
 mod test {
-    use super::init_zobrist;
+    use super::{
+        init_zobrist,
+        Toggle,
+    };
     use crate::{
+        chess::{
+            CastleRights,
+            Color,
+            Piece,
+            Role,
+            Square,
+        },
         fen::Fen,
         movegen::init_tables,
+        positions::{
+            KIWIPETE,
+            POSITION_3,
+            POSITION_4,
+            POSITION_5,
+            POSITION_6,
+            STARTPOS,
+        },
         zobrist::perft_zobrist,
     };
 
-    const STARTPOS: &str = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1";
-    const KIWIPETE_FEN: &str =
-        "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1";
-    const POSITTION_3_FEN: &str = "8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - - 0 1";
-    const POSITION_4_FEN: &str = "r3k2r/Pppp1ppp/1b3nbN/nP6/BBP1P3/q4N2/Pp1P2PP/R2Q1RK1 w kq - 0 1";
-    const POSITION_5_FEN: &str = "rnbq1k1r/pp1Pbppp/2p5/8/2B5/8/PPP1NnPP/RNBQK2R w KQ - 1 8";
-    const POSITION_6_FEN: &str =
-        "r4rk1/1pp1qppp/p1np1n2/2b1p1B1/2B1P1b1/P1NP1N2/1PP1QPPP/R4RK1 w - - 0 10";
-
     #[test]
     fn test_zobrist() {
         init_tables();
@@ -179,11 +311,43 @@ mod test {
         assert_eq!(hash, position.key);
     }
 
+    #[test]
+    fn test_explain_diff() {
+        init_tables();
+        init_zobrist();
+
+        let Fen(position) = KIWIPETE.parse().unwrap();
+        let key = position.zobrist_hash();
+        assert_eq!(key.explain_diff(key), Some(vec![]));
+
+        let knight = Piece {
+            color: Color::White,
+            role: Role::Knight,
+        };
+        let mut missed = key;
+        missed.toggle_piece(Square::F3, knight);
+        assert_eq!(
+            key.explain_diff(missed),
+            Some(vec![Toggle::Piece(Square::F3, knight)])
+        );
+
+        let mut missed = key;
+        missed.toggle_castling(CastleRights::all());
+        missed.toggle_castling(CastleRights::BLACK_KING_SIDE);
+        let toggles = key.explain_diff(missed).unwrap();
+        assert_eq!(toggles.len(), 2);
+        assert!(toggles.contains(&Toggle::Castling(CastleRights::all())));
+
+        missed.toggle_side();
+        assert_eq!(key.explain_diff(missed), None);
+        assert_eq!(Toggle::Piece(Square::F3, knight).to_string(), "N on f3");
+    }
+
     #[test]
     fn test_zobrist_kiwipete() {
         init_tables();
         init_zobrist();
-        let Fen(mut position) = KIWIPETE_FEN.parse().unwrap();
+        let Fen(mut position) = KIWIPETE.parse().unwrap();
         let hash = position.zobrist_hash();
         assert_eq!(hash, position.key);
         perft_zobrist(&mut position, 4);
@@ -195,7 +359,7 @@ mod test {
     fn test_zobrist_position_3() {
         init_tables();
         init_zobrist();
-        let Fen(mut position) = POSITTION_3_FEN.parse().unwrap();
+        let Fen(mut position) = POSITION_3.parse().unwrap();
         let hash = position.zobrist_hash();
         assert_eq!(hash, position.key);
         perft_zobrist(&mut position, 4);
@@ -207,7 +371,7 @@ mod test {
     fn test_zobrist_position_4() {
         init_tables();
         init_zobrist();
-        let Fen(mut position) = POSITION_4_FEN.parse().unwrap();
+        let Fen(mut position) = POSITION_4.parse().unwrap();
         let hash = position.zobrist_hash();
         assert_eq!(hash, position.key);
         perft_zobrist(&mut position, 4);
@@ -219,7 +383,7 @@ mod test {
     fn test_zobrist_position_5() {
         init_tables();
         init_zobrist();
-        let Fen(mut position) = POSITION_5_FEN.parse().unwrap();
+        let Fen(mut position) = POSITION_5.parse().unwrap();
         let hash = position.zobrist_hash();
         assert_eq!(hash, position.key);
         perft_zobrist(&mut position, 4);
@@ -231,7 +395,7 @@ mod test {
     fn test_zobrist_position_6() {
         init_tables();
         init_zobrist();
-        let Fen(mut position) = POSITION_6_FEN.parse().unwrap();
+        let Fen(mut position) = POSITION_6.parse().unwrap();
         let hash = position.zobrist_hash();
         assert_eq!(hash, position.key);
         perft_zobrist(&mut position, 4);
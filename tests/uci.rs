@@ -0,0 +1,373 @@
+// Scripted UCI sessions against the engine binary, checking what a GUI would see
+use std::{
+    io::{
+        BufRead,
+        BufReader,
+        Write,
+    },
+    process::{
+        Child,
+        ChildStdin,
+        Command,
+        Stdio,
+    },
+    sync::mpsc::{
+        self,
+        Receiver,
+        RecvTimeoutError,
+    },
+    thread,
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+use pounce::{
+    fen::Fen,
+    movegen::init_tables,
+    moves::Move,
+    position::Position,
+    zobrist::init_zobrist,
+};
+
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+struct Engine {
+    child: Child,
+    stdin: ChildStdin,
+    lines: Receiver<String>,
+}
+
+impl Engine {
+    fn start() -> Self {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_pounce"))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        let stdin = child.stdin.take().unwrap();
+        let stdout = child.stdout.take().unwrap();
+
+        // a reader thread so a missing reply times out instead of hanging the test
+        let (tx, lines) = mpsc::channel();
+        thread::spawn(move || {
+            for line in BufReader::new(stdout).lines() {
+                let Ok(line) = line else {
+                    break;
+                };
+                if tx.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let engine = Engine {
+            child,
+            stdin,
+            lines,
+        };
+        // the banner
+        engine.next_line();
+        engine
+    }
+
+    fn send(&mut self, cmd: &str) {
+        writeln!(self.stdin, "{}", cmd).unwrap();
+        self.stdin.flush().unwrap();
+    }
+
+    fn next_line(&self) -> String {
+        match self.lines.recv_timeout(TIMEOUT) {
+            Ok(line) => line,
+            Err(e) => panic!("no output from the engine: {:?}", e),
+        }
+    }
+
+    // Every line up to and including the first one starting with `prefix`
+    fn read_until(&self, prefix: &str) -> Vec<String> {
+        let mut lines = Vec::new();
+        loop {
+            let line = self.next_line();
+            let done = line.starts_with(prefix);
+            lines.push(line);
+            if done {
+                return lines;
+            }
+        }
+    }
+
+    // Fails if a line starting with `prefix` shows up within `wait`
+    fn expect_none(&self, prefix: &str, wait: Duration) {
+        let deadline = Instant::now() + wait;
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            match self.lines.recv_timeout(left) {
+                Ok(line) => assert!(!line.starts_with(prefix), "unexpected `{}`", line),
+                Err(RecvTimeoutError::Timeout) => return,
+                Err(RecvTimeoutError::Disconnected) => panic!("engine exited"),
+            }
+        }
+    }
+
+    // Sends `isready` and waits for the reply, returning everything printed before it
+    fn sync(&mut self) -> Vec<String> {
+        self.send("isready");
+        let mut lines = self.read_until("readyok");
+        lines.pop();
+        lines
+    }
+
+    fn bestmove(&self) -> (Vec<String>, String) {
+        let lines = self.read_until("bestmove");
+        let last = lines.last().unwrap();
+        let mv = last.split_whitespace().nth(1).unwrap().to_string();
+        (lines, mv)
+    }
+
+    fn quit(mut self) {
+        self.send("quit");
+        let deadline = Instant::now() + TIMEOUT;
+        while Instant::now() < deadline {
+            if self.child.try_wait().unwrap().is_some() {
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        self.child.kill().unwrap();
+        panic!("engine didn't quit");
+    }
+}
+
+fn position(fen: &str, moves: &[&str]) -> Position {
+    init_tables();
+    init_zobrist();
+    let Fen(mut position) = fen.parse().unwrap();
+    for mv in moves {
+        let mv = position.legal_move(mv.parse().unwrap()).unwrap();
+        position.make_move(mv);
+    }
+    position
+}
+
+fn is_legal(position: &Position, mv: &str) -> bool {
+    mv.parse::<Move>()
+        .ok()
+        .and_then(|mv| position.legal_move(mv))
+        .is_some()
+}
+
+const STARTPOS: &str = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1";
+
+#[test]
+fn test_handshake() {
+    let mut engine = Engine::start();
+    engine.send("uci");
+    let lines = engine.read_until("uciok");
+    assert!(lines[0].starts_with("id name pounce"));
+    assert!(lines.iter().any(|line| line.starts_with("id author")));
+    for option in ["Hash", "Threads", "UCI_Opponent", "UCI_AnalyseMode"] {
+        let prefix = format!("option name {} type ", option);
+        assert!(lines.iter().any(|line| line.starts_with(&prefix)));
+    }
+    assert!(engine.sync().is_empty());
+    engine.quit();
+}
+
+#[test]
+fn test_position_setups() {
+    let mut engine = Engine::start();
+    engine.send("uci");
+    engine.read_until("uciok");
+
+    let moves = ["e2e4", "c7c5", "g1f3"];
+    engine.send("ucinewgame");
+    engine.send(&format!("position startpos moves {}", moves.join(" ")));
+    engine.send("go depth 4");
+    let (lines, mv) = engine.bestmove();
+    assert!(is_legal(&position(STARTPOS, &moves), &mv), "illegal bestmove {}", mv);
+    assert!(lines.iter().any(|line| line.starts_with("info depth 4 ")));
+
+    // mate in one, from a fen with moves after it
+    let fen = "6k1/5ppp/8/1p6/8/8/5PPP/R5K1 b - - 0 1";
+    engine.send(&format!("position fen {} moves b5b4", fen));
+    engine.send("go depth 3");
+    let (lines, mv) = engine.bestmove();
+    assert_eq!(mv, "a1a8");
+    assert!(lines.iter().any(|line| line.contains(" score mate ")));
+
+    // a bad position is reported on stderr and leaves the engine answering
+    engine.send("position startpos moves e2e5");
+    assert!(engine.sync().is_empty());
+    engine.quit();
+}
+
+#[test]
+fn test_stop_and_isready() {
+    let mut engine = Engine::start();
+    engine.send("position startpos");
+    engine.send("go infinite");
+
+    // isready is answered while searching, without ending the search
+    let lines = engine.sync();
+    assert!(lines.iter().all(|line| !line.starts_with("bestmove")));
+    engine.expect_none("bestmove", Duration::from_millis(200));
+
+    engine.send("stop");
+    let (_, mv) = engine.bestmove();
+    assert!(is_legal(&position(STARTPOS, &[]), &mv));
+
+    // exactly one bestmove per go, a second stop is a no-op
+    engine.send("stop");
+    let lines = engine.sync();
+    assert!(lines.iter().all(|line| !line.starts_with("bestmove")));
+    engine.quit();
+}
+
+#[test]
+fn test_ponderhit() {
+    let mut engine = Engine::start();
+    engine.send("position startpos moves e2e4 e7e5");
+    engine.send("go ponder movetime 100");
+    engine.expect_none("bestmove", Duration::from_millis(500));
+
+    engine.send("ponderhit");
+    let (_, mv) = engine.bestmove();
+    assert!(is_legal(&position(STARTPOS, &["e2e4", "e7e5"]), &mv));
+    engine.quit();
+}
+
+#[test]
+fn test_options_mid_game() {
+    let mut engine = Engine::start();
+    engine.send("uci");
+    engine.read_until("uciok");
+
+    engine.send("position startpos moves d2d4");
+    engine.send("go depth 3");
+    engine.bestmove();
+
+    // resizing the table and adding threads between moves of the same game
+    engine.send("setoption name Hash value 8");
+    engine.send("setoption name Threads value 2");
+    let lines = engine.sync();
+    assert_eq!(lines.len(), 1);
+    assert!(lines[0].starts_with("info string Hash set to 8 MB"));
+    engine.send("position startpos moves d2d4 d7d5");
+    engine.send("go depth 4");
+    let (_, mv) = engine.bestmove();
+    assert!(is_legal(&position(STARTPOS, &["d2d4", "d7d5"]), &mv));
+
+    // bad values are reported and ignored
+    engine.send("setoption name Hash value lots");
+    let lines = engine.sync();
+    assert_eq!(lines.len(), 1);
+    assert!(lines[0].starts_with("info string ignoring setoption"));
+    engine.send("setoption name NoSuchOption value 1");
+    let lines = engine.sync();
+    assert!(lines[0].starts_with("info string ignoring setoption"));
+
+    // and a malformed opponent doesn't stop the engine from playing
+    engine.send("setoption name UCI_Opponent value GM lots human Somebody");
+    engine.send("go depth 2");
+    let (lines, _) = engine.bestmove();
+    assert!(lines.iter().any(|line| line.starts_with("info string ignoring UCI_Opponent")));
+    engine.quit();
+}
+
+#[test]
+fn test_go_wait() {
+    let mut engine = Engine::start();
+    engine.send("position startpos");
+    engine.send("go depth 3 wait");
+    engine.bestmove();
+    let line = engine.next_line();
+    assert!(line.starts_with("info string result bestmove "), "{}", line);
+
+    // unbounded searches can't wait, nothing would stop them
+    engine.send("go infinite wait");
+    engine.expect_none("bestmove", Duration::from_millis(200));
+    assert!(engine.sync().is_empty());
+    engine.quit();
+}
+
+#[test]
+fn test_tree_dump() {
+    let mut engine = Engine::start();
+    engine.send("setoption name TreeDump value 1");
+    engine.send("position startpos");
+
+    // nothing without debug on
+    engine.send("go depth 2");
+    let (lines, _) = engine.bestmove();
+    assert!(lines.iter().all(|line| !line.starts_with("root ")));
+
+    engine.send("debug on");
+    engine.send("go depth 2");
+    let (lines, mv) = engine.bestmove();
+    let root = lines.iter().position(|line| line.starts_with("root d=2 ")).unwrap();
+    let children = lines[root + 1..lines.len() - 1]
+        .iter()
+        .take_while(|line| line.starts_with("  "))
+        .collect::<Vec<_>>();
+    // a root move shows up again when it's re-searched with a wider window
+    let mut moves = children
+        .iter()
+        .map(|line| line.split_whitespace().next().unwrap())
+        .collect::<Vec<_>>();
+    moves.sort();
+    moves.dedup();
+    assert_eq!(moves.len(), 20);
+    assert!(children.iter().any(|line| line.starts_with(&format!("  {} d=1 ", mv))));
+
+    engine.send("setoption name TreeDumpJson value true");
+    engine.send("go depth 2");
+    let (lines, _) = engine.bestmove();
+    assert!(lines.iter().any(|line| line.starts_with("[{\"move\": \"root\", \"depth\": 2")));
+    engine.quit();
+}
+
+#[test]
+fn test_own_book() {
+    let dir = std::env::temp_dir().join(format!("pounce_uci_book_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let pgn = dir.join("games.pgn");
+    let book = dir.join("book.bin");
+    std::fs::write(&pgn, "[Result \"1-0\"]\n\n1. d4 d5 2. c4 1-0\n").unwrap();
+    let status = Command::new(env!("CARGO_BIN_EXE_pounce"))
+        .args(["makebook", "--min-count", "1", "--out"])
+        .arg(&book)
+        .arg(&pgn)
+        .stdout(Stdio::null())
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let mut engine = Engine::start();
+    engine.send(&format!("setoption name BookFile value {}", book.display()));
+    let lines = engine.sync();
+    assert!(lines[0].starts_with("info string loaded 2 book entries"), "{:?}", lines);
+
+    // black lost, so only white's moves are in it, and only played with OwnBook on
+    engine.send("position startpos");
+    engine.send("go depth 1");
+    let (lines, _) = engine.bestmove();
+    assert!(lines.iter().any(|line| line.starts_with("info depth")));
+
+    engine.send("setoption name OwnBook value true");
+    engine.send("go depth 10");
+    let (lines, mv) = engine.bestmove();
+    assert_eq!((lines.len(), mv.as_str()), (1, "d2d4"));
+    engine.send("position startpos moves d2d4 d7d5");
+    engine.send("go depth 10");
+    assert_eq!(engine.bestmove().1, "c2c4");
+
+    // out of the book it searches again
+    engine.send("position startpos moves e2e4");
+    engine.send("go depth 1");
+    let (lines, _) = engine.bestmove();
+    assert!(lines.iter().any(|line| line.starts_with("info depth")));
+    engine.quit();
+    std::fs::remove_dir_all(&dir).unwrap();
+}